@@ -0,0 +1,139 @@
+//! Implements administrative endpoints.
+
+use crate::api_error::APIError;
+use crate::audit;
+use crate::cleaner;
+use crate::reload::{Live, Reloader};
+
+use actix_web::{get, post, web, HttpRequest, Responder, Result};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+/// Report the jobs the cleaner would remove, and why, without
+/// removing them.
+#[get("/admin/cleaner/preview")]
+async fn preview_cleaner(
+    request: HttpRequest,
+    retention: web::Data<Live<cleaner::Retention>>,
+    cleanup: web::Data<cleaner::Cleanup>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let plan = cleaner::plan(&retention.get(), &cleanup, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    info!("Previewed cleaning of {} jobs", plan.len());
+    audit::record(audit::Entry {
+        action: "cleaner_previewed",
+        namespace: namespace.to_string(),
+        actor: audit::Actor::of(&request),
+        ..Default::default()
+    });
+    Ok(web::Json(plan))
+}
+
+/// Default states of jobs to purge.
+fn default_purge_states() -> Vec<String> {
+    vec![String::from("exited"), String::from("dead")]
+}
+
+/// Criteria for selecting the jobs to purge.
+#[derive(Deserialize)]
+struct PurgeRequest {
+    #[serde(default = "default_purge_states")]
+    status: Vec<String>,
+    older_than: Option<u32>,
+    name: Option<String>,
+    #[serde(default)]
+    include_services: bool,
+}
+
+/// Immediately remove the jobs matching the given criteria.
+#[post("/admin/purge")]
+async fn purge(
+    request: HttpRequest,
+    body: web::Json<PurgeRequest>,
+    cleanup: web::Data<cleaner::Cleanup>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    if body.status.is_empty() {
+        Err(APIError::bad_request("At least one status must be given"))?;
+    }
+    if let Some(status) = body
+        .status
+        .iter()
+        .find(|status| !cleaner::PURGEABLE_STATES.contains(&status.as_str()))
+    {
+        Err(APIError::bad_request(format!(
+            "Status {:?} can't be purged; expected one of {:?}",
+            status,
+            cleaner::PURGEABLE_STATES
+        )))?;
+    }
+    let pattern = body
+        .name
+        .as_deref()
+        .map(Pattern::new)
+        .transpose()
+        .map_err(|e| APIError::bad_request(format!("Invalid name pattern: {}", e)))?;
+    let purge = cleaner::purge(
+        &body.status,
+        body.older_than,
+        pattern.as_ref(),
+        body.include_services,
+        &cleanup,
+        &namespace,
+    )
+    .await
+    .map_err(APIError::bad_gateway)?;
+    info!(
+        "Purged {} jobs, failed to purge {}",
+        purge.removed.len(),
+        purge.failed.len()
+    );
+    let actor = audit::Actor::of(&request);
+    for removal in &purge.removed {
+        audit::record(audit::Entry {
+            action: "job_purged",
+            namespace: namespace.to_string(),
+            job: Some(removal.id.clone()),
+            detail: Some(removal.reason.clone()),
+            actor: actor.clone(),
+            ..Default::default()
+        });
+    }
+    Ok(web::Json(purge))
+}
+
+/// Outcome of a reload.
+#[derive(Serialize)]
+struct ReloadResponse {
+    changes: Vec<String>,
+}
+
+/// Re-read the filter file and the settings file, and apply their
+/// changes.
+#[post("/admin/reload")]
+async fn reload(
+    request: HttpRequest,
+    reloader: web::Data<Reloader>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let changes = reloader.reload().map_err(|e| {
+        warn!(
+            "Couldn't reload; keeping the current filter and settings: {:?}",
+            e
+        );
+        APIError::bad_request(format!("Couldn't reload: {:#}", e))
+    })?;
+    if !changes.is_empty() {
+        audit::record(audit::Entry {
+            action: "settings_reloaded",
+            namespace: namespace.to_string(),
+            detail: Some(changes.join(", ")),
+            actor: audit::Actor::of(&request),
+            ..Default::default()
+        });
+    }
+    Ok(web::Json(ReloadResponse { changes }))
+}
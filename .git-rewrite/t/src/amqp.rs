@@ -0,0 +1,161 @@
+//! Consumes jobs from an AMQP queue, such as an existing RabbitMQ work
+//! queue, as an alternative to the HTTP API. Each message goes through
+//! the filter like a request body would, and is acknowledged once its
+//! job is created.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, Context, Result};
+use futures::stream::TryStreamExt;
+use lapin::message::Delivery;
+use lapin::options::{BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicQosOptions};
+use lapin::types::FieldTable;
+use lapin::uri::AMQPUri;
+use lapin::{Connection, ConnectionProperties};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+
+/// Delay before requeueing a message whose job couldn't be created.
+const REQUEUE_DELAY: Duration = Duration::from_secs(10);
+
+/// Where and how to consume messages.
+pub struct Source {
+    pub url: String,
+    pub queue: String,
+    /// Maximum number of unacknowledged messages, processed
+    /// concurrently.
+    pub prefetch: u16,
+    /// Credentials overriding those of the URL.
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Source {
+    /// Connect to the broker.
+    async fn connect(&self) -> Result<Connection> {
+        let mut uri = self
+            .url
+            .parse::<AMQPUri>()
+            .map_err(|e| anyhow!("invalid AMQP URL: {}", e))?;
+        if let Some(username) = &self.username {
+            uri.authority.userinfo.username = username.clone();
+        }
+        if let Some(password) = &self.password {
+            uri.authority.userinfo.password = password.clone();
+        }
+        Connection::connect_uri(
+            uri,
+            ConnectionProperties::default().with_connection_name(env!("CARGO_PKG_NAME").into()),
+        )
+        .await
+        .context("while connecting to the AMQP broker")
+    }
+}
+
+/// Create the job of a message, made available to the filter as
+/// `$MESSAGE`, and acknowledge it once created. Messages that will
+/// never make a job are rejected without requeueing, so that they go
+/// to the queue's dead letter exchange if it has one, and the rest are
+/// requeued after a delay.
+async fn process(intake: &Intake, queue: &str, delivery: Delivery) -> Result<()> {
+    let routing_key = delivery.routing_key.as_str();
+    let outcome = match serde_json::from_slice::<Value>(&delivery.data) {
+        Ok(input) => {
+            let submission = Submission {
+                input,
+                path: format!("/amqp/{}", queue),
+                claims: Value::Null,
+                message: json!({
+                    "queue": queue,
+                    "exchange": delivery.exchange.as_str(),
+                    "routing_key": routing_key,
+                    "redelivered": delivery.redelivered,
+                }),
+                reply_subject: None,
+                actor: audit::Actor {
+                    submitter: Some(format!("amqp:{}", queue)),
+                    ..Default::default()
+                },
+            };
+            docker_service::submit(intake, submission)
+                .await
+                .map(|_| ())
+                .map_err(|e| {
+                    (
+                        e.as_response_error().status_code().is_client_error(),
+                        e.to_string(),
+                    )
+                })
+        }
+        Err(e) => Err((true, format!("payload is not valid JSON: {}", e))),
+    };
+    match outcome {
+        Ok(()) => {
+            debug!("AMQP message from {:?} made a job", routing_key);
+            delivery
+                .ack(BasicAckOptions::default())
+                .await
+                .context("while acknowledging an AMQP message")
+        }
+        Err((rejected, e)) => {
+            if rejected {
+                warn!("Rejected an AMQP message from {:?}: {}", routing_key, e);
+            } else {
+                warn!(
+                    "Couldn't create the job of an AMQP message from {:?}; requeueing: {}",
+                    routing_key, e
+                );
+                tokio::select! {
+                    _ = sleep(REQUEUE_DELAY) => (),
+                    _ = shutdown::reached(Phase::Stopping) => (),
+                }
+            }
+            delivery
+                .nack(BasicNackOptions {
+                    requeue: !rejected,
+                    ..Default::default()
+                })
+                .await
+                .context("while rejecting an AMQP message")
+        }
+    }
+}
+
+/// Consume messages from the queue, creating a job for each one, with
+/// up to the prefetch count of them in flight.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let connection = source.connect().await?;
+    let channel = connection
+        .create_channel()
+        .await
+        .context("while opening an AMQP channel")?;
+    channel
+        .basic_qos(source.prefetch, BasicQosOptions::default())
+        .await
+        .context("while setting the AMQP prefetch count")?;
+    let consumer = channel
+        .basic_consume(
+            &source.queue,
+            "",
+            BasicConsumeOptions::default(),
+            FieldTable::default(),
+        )
+        .await
+        .with_context(|| format!("while consuming from the AMQP queue {:?}", source.queue))?;
+    info!(
+        "Consuming jobs from the AMQP queue {:?}, {} at a time",
+        source.queue, source.prefetch
+    );
+    let consuming = consumer
+        .map_err(|e| anyhow!(e).context("while receiving an AMQP message"))
+        .try_for_each_concurrent(usize::from(source.prefetch), |delivery| {
+            process(&intake, &source.queue, delivery)
+        });
+    tokio::select! {
+        result = consuming => result.and(Err(anyhow!("the AMQP consumer was cancelled"))),
+        _ = shutdown::reached(Phase::Stopping) => Ok(()),
+    }
+}
@@ -0,0 +1,89 @@
+//! Provides an error type for API responses.
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
+use serde::Serialize;
+use serde_json::{json, to_string_pretty};
+use std::fmt::{Display, Formatter, Result};
+
+/// An error serialized as JSON and sent as a response.
+#[derive(Debug, Serialize)]
+pub struct APIError {
+    status: u16,
+    msg: String,
+    #[serde(skip)]
+    retry_after: Option<u64>,
+}
+
+impl APIError {
+    fn new<S: ToString>(status: u16, msg: S) -> Self {
+        Self {
+            status,
+            msg: msg.to_string(),
+            retry_after: None,
+        }
+    }
+
+    /// Tell the client to retry after the given amount of seconds.
+    pub fn retry_after(self, seconds: u64) -> Self {
+        Self {
+            retry_after: Some(seconds),
+            ..self
+        }
+    }
+
+    pub fn bad_request<S: ToString>(msg: S) -> Self {
+        Self::new(400, msg)
+    }
+
+    pub fn bad_gateway<S: ToString>(msg: S) -> Self {
+        Self::new(502, msg)
+    }
+
+    pub fn unauthorized<S: ToString>(msg: S) -> Self {
+        Self::new(401, msg)
+    }
+
+    pub fn forbidden<S: ToString>(msg: S) -> Self {
+        Self::new(403, msg)
+    }
+
+    pub fn not_found<S: ToString>(msg: S) -> Self {
+        Self::new(404, msg)
+    }
+
+    pub fn misdirected<S: ToString>(msg: S) -> Self {
+        Self::new(421, msg)
+    }
+
+    pub fn too_many_requests<S: ToString>(msg: S) -> Self {
+        Self::new(429, msg)
+    }
+
+    pub fn service_unavailable<S: ToString>(msg: S) -> Self {
+        Self::new(503, msg)
+    }
+
+    pub fn internal_error<S: ToString>(msg: S) -> Self {
+        Self::new(500, msg)
+    }
+}
+
+impl Display for APIError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", to_string_pretty(self).unwrap())
+    }
+}
+
+impl ResponseError for APIError {
+    fn error_response(&self) -> HttpResponse {
+        let err_json = json!({ "error": { "code": self.status, "message": self.msg }});
+        let mut response = HttpResponse::build(StatusCode::from_u16(self.status).unwrap());
+        if let Some(seconds) = self.retry_after {
+            response.insert_header((RETRY_AFTER, seconds));
+        }
+        response.json(err_json)
+    }
+}
@@ -0,0 +1,1585 @@
+//! The dispatcher as a whole: its configuration, its HTTP services
+//! and its background tasks, ready to be run standalone or embedded in
+//! a larger actix-web application.
+
+use crate::{
+    access_log, admin_service, amqp, api_error, archive, attempts, audit, auth, authorization,
+    backend, circuit_breaker, cleaner, cpusets, dispatch_queue, docker, docker_service,
+    error_report, exits, gpu, health_service, health_watcher, image_pruner, internal, ip_filter,
+    job_store, jwt, kafka, kubernetes, leader, metrics_service, nats, nomad, notifier,
+    object_store, otlp_metrics, pushgateway, rate_limit, redact, registry_auth, reload, request_id,
+    scheduler, secrets, shard, shutdown, signature, socket_activation, ssh_tunnel, startup, statsd,
+    supervisor::supervise, telemetry, tls,
+};
+use actix_web::dev::ServerHandle;
+use actix_web::{
+    http::{header::ContentType, KeepAlive},
+    middleware, web, App, Error, HttpResponse, HttpServer, Result as RouteResult,
+};
+use anyhow::{bail, Context, Result};
+use clap::{value_parser, Parser, ValueEnum};
+use futures::future::{join, join_all, select_all};
+use ipnet::IpNet;
+use shutdown::Phase;
+use socket_activation::Listener;
+use std::fs;
+use std::future::Future;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{self, Duration};
+use tracing::{error, info, warn};
+use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa_rapidoc::RapiDoc;
+
+const DEFAULT_FILTER: &str = include_str!("default_filter.jq");
+
+/// A format of log lines.
+#[derive(Clone, ValueEnum)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Job-dispatching interface acting as a docker container scheduler.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Filter converting requests to container manifests
+    pub filter: Option<String>,
+
+    /// Read filter from a file
+    #[arg(short, long, env)]
+    pub from_file: Option<PathBuf>,
+
+    /// File of reloadable settings given as flags, overriding the ones
+    /// given otherwise; it's re-read along with the filter file on
+    /// SIGHUP
+    #[arg(long, env)]
+    pub settings_file: Option<PathBuf>,
+
+    /// TCP port to listen on
+    #[arg(short, long, env, default_value_t = 8000)]
+    pub port: u16,
+
+    /// Unix domain socket to listen on, besides the TCP port
+    #[arg(long, env)]
+    pub listen_socket: Option<PathBuf>,
+
+    /// Permissions of the unix domain socket, in octal
+    #[arg(long, env, default_value_t = String::from("660"))]
+    pub listen_socket_mode: String,
+
+    /// Listen only on the unix domain socket, and not on the TCP port
+    #[arg(long, env, requires = "listen_socket")]
+    pub socket_only: bool,
+
+    /// Number of HTTP worker threads serving the API; default is the
+    /// number of CPUs
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..))]
+    pub workers: Option<u16>,
+
+    /// Seconds to keep idle API connections open for; 0 disables
+    /// keep-alive
+    #[arg(long, env, default_value_t = 5)]
+    pub keep_alive: u16,
+
+    /// Seconds given to API clients to send the request headers; 0
+    /// disables the timeout
+    #[arg(long, env, default_value_t = 5)]
+    pub client_timeout: u16,
+
+    /// Maximum number of concurrent API connections per worker
+    #[arg(long, env, default_value_t = 25000)]
+    pub max_connections: usize,
+
+    /// Number of workers making the docker calls of job creations; 0
+    /// makes them within each request instead
+    #[arg(long, env, default_value_t = 16)]
+    pub dispatch_workers: u16,
+
+    /// Maximum number of docker calls waiting for a dispatch worker;
+    /// job creations are refused beyond it
+    #[arg(long, env, default_value_t = 256, value_parser = value_parser!(u16).range(1..))]
+    pub dispatch_queue_size: u16,
+
+    /// Certificate chain (PEM) to serve the API over TLS with on the
+    /// TCP port
+    #[arg(long, env, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of the TLS certificate
+    #[arg(long, env, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) client certificates must be signed by;
+    /// default is to not require client certificates
+    #[arg(long, env, requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Accept clients without a certificate too, identifying only the
+    /// ones presenting one
+    #[arg(long, env, requires = "tls_client_ca")]
+    pub tls_client_optional: bool,
+
+    /// Seconds to wait for in-flight requests, the current upkeep
+    /// cycles and metrics to finish when shutting down
+    #[arg(long, env, default_value_t = 30)]
+    pub shutdown_timeout: u16,
+
+    /// TCP port to serve metrics and API docs on, instead of the main
+    /// port; default is to serve them on the main port
+    #[arg(long, env)]
+    pub internal_port: Option<u16>,
+
+    /// Bearer token required to access metrics and API docs; default
+    /// is to not require one
+    #[arg(long, env, hide_env_values = true)]
+    pub internal_token: Option<String>,
+
+    /// API key required to access the job and admin endpoints, given
+    /// as name=key; default is to not require one
+    #[arg(long, env, value_delimiter = ',', hide_env_values = true)]
+    pub api_key: Vec<String>,
+
+    /// File of API keys, given as name=key, one per line
+    #[arg(long, env)]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Namespace and path prefix an API key is restricted to, given as
+    /// name=namespace:/job/prefix; jobs created through other paths
+    /// are hidden from it
+    #[arg(long, env, value_delimiter = ',')]
+    pub api_key_scope: Vec<String>,
+
+    /// Issuer of the JWTs accepted as bearer tokens on the job and
+    /// admin endpoints; default is to not accept tokens
+    #[arg(long, env)]
+    pub jwt_issuer: Option<String>,
+
+    /// URL of the JWKS holding the keys tokens are signed with; default
+    /// is the one advertised in the issuer's OpenID Connect discovery
+    /// document
+    #[arg(long, env, requires = "jwt_issuer")]
+    pub jwks_url: Option<String>,
+
+    /// Audience tokens must be issued for; default is to not check it
+    #[arg(long, env, requires = "jwt_issuer")]
+    pub jwt_audience: Option<String>,
+
+    /// Scope tokens must carry; default is to not require one
+    #[arg(long, env, requires = "jwt_issuer")]
+    pub jwt_scope: Option<String>,
+
+    /// JSON file of rules allowing identities to create, read or delete
+    /// jobs, or to administer the dispatcher; default is to allow
+    /// every operation to anyone authenticated
+    #[arg(long, env)]
+    pub authorization_rules: Option<PathBuf>,
+
+    /// Job creation requests allowed per minute to each client,
+    /// identified by its API key, token or certificate, or else by its
+    /// IP address; default is to not limit them
+    #[arg(long, env, value_parser = value_parser!(u32).range(1..))]
+    pub rate_limit: Option<u32>,
+
+    /// Job creation requests each client may make in a burst, above
+    /// --rate-limit; default is the per-minute rate
+    #[arg(long, env, requires = "rate_limit", value_parser = value_parser!(u32).range(1..))]
+    pub rate_limit_burst: Option<u32>,
+
+    /// Networks (in CIDR notation) or addresses allowed to reach the
+    /// job and admin endpoints; default is to allow any not denied
+    #[arg(long, env, value_delimiter = ',', value_parser = ip_filter::parse_network)]
+    pub allow_ip: Vec<IpNet>,
+
+    /// Networks or addresses denied the job and admin endpoints
+    #[arg(long, env, value_delimiter = ',', value_parser = ip_filter::parse_network)]
+    pub deny_ip: Vec<IpNet>,
+
+    /// Networks or addresses additionally required to reach the admin
+    /// endpoints
+    #[arg(long, env, value_delimiter = ',', value_parser = ip_filter::parse_network)]
+    pub admin_allow_ip: Vec<IpNet>,
+
+    /// Networks or addresses additionally denied the admin endpoints
+    #[arg(long, env, value_delimiter = ',', value_parser = ip_filter::parse_network)]
+    pub admin_deny_ip: Vec<IpNet>,
+
+    /// Header set by a reverse proxy with the client address (e.g.
+    /// X-Forwarded-For), honored only in requests from --trusted-proxy
+    #[arg(long, env, requires = "trusted_proxy")]
+    pub client_ip_header: Option<String>,
+
+    /// Networks or addresses of the reverse proxies trusted to set
+    /// --client-ip-header
+    #[arg(long, env, value_delimiter = ',', value_parser = ip_filter::parse_network)]
+    pub trusted_proxy: Vec<IpNet>,
+
+    /// Secret webhook providers sign job creation requests with;
+    /// default is to not require signatures
+    #[arg(long, env, hide_env_values = true)]
+    pub webhook_secret: Option<String>,
+
+    /// How job creation requests are signed
+    #[arg(long, env, value_enum, default_value_t = signature::Style::Github)]
+    pub webhook_signature: signature::Style,
+
+    /// Header holding the signature; default is the one used by the
+    /// provider given in --webhook-signature
+    #[arg(long, env, requires = "webhook_secret")]
+    pub webhook_signature_header: Option<String>,
+
+    /// File to append an audit log of job creations and removals,
+    /// admin actions and policy rejections to, as JSON lines
+    #[arg(long, env)]
+    pub audit_log: Option<PathBuf>,
+
+    /// URL to POST each audit log entry to
+    #[arg(long, env)]
+    pub audit_webhook: Option<String>,
+
+    /// Sentry DSN to report panics, repeated upkeep failures and
+    /// server errors to
+    #[arg(long, env, hide_env_values = true)]
+    pub sentry_dsn: Option<String>,
+
+    /// URL to POST reports of panics, repeated upkeep failures and
+    /// server errors to
+    #[arg(long, env)]
+    pub error_webhook: Option<String>,
+
+    /// Directory of an embedded database recording every dispatched
+    /// job, which survives restarts and the removal of jobs
+    #[arg(long, env)]
+    pub job_store: Option<PathBuf>,
+
+    /// Lock file shared by replicas of the dispatcher, used to elect
+    /// the only one running the scheduler, the cleaner and the image
+    /// pruner; default is to always run them
+    #[arg(long, env)]
+    pub leader_lock: Option<PathBuf>,
+
+    /// File to save job counters to, so that they're restored along
+    /// with missed events when the dispatcher restarts
+    #[arg(long, env)]
+    pub metrics_state_file: Option<PathBuf>,
+
+    /// Number of GPUs exposed by each docker host, which jobs taking
+    /// GPUs through DeviceRequests are scheduled against; default is
+    /// to not keep track of GPUs
+    #[arg(long, env)]
+    pub gpus: Option<u16>,
+
+    /// Hold back the start of pending jobs while any running job with
+    /// a healthcheck is yet to become healthy
+    #[arg(long, env)]
+    pub wait_healthy: bool,
+
+    /// URL notified with a POST request whenever a job turns unhealthy
+    #[arg(long, env)]
+    pub unhealthy_webhook: Option<String>,
+
+    /// URL notified with a POST request whenever a job is created,
+    /// started, succeeds, fails or is cleaned
+    #[arg(long, env)]
+    pub lifecycle_webhook: Option<String>,
+
+    /// Header sent along with lifecycle notifications, given as
+    /// key=value
+    #[arg(
+        long,
+        env,
+        requires = "lifecycle_webhook",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub lifecycle_webhook_header: Vec<String>,
+
+    /// Secret lifecycle notifications are signed with, as a hex
+    /// HMAC-SHA256 of the body in the X-Dispatcher-Signature header
+    #[arg(long, env, requires = "lifecycle_webhook", hide_env_values = true)]
+    pub lifecycle_webhook_secret: Option<String>,
+
+    /// Kafka brokers to consume jobs from, as a comma-separated list
+    /// of host:port
+    #[arg(long, env, requires = "kafka_topic")]
+    pub kafka_brokers: Option<String>,
+
+    /// Kafka topic to consume jobs from; may be given several times
+    #[arg(long, env, requires = "kafka_brokers", value_delimiter = ',')]
+    pub kafka_topic: Vec<String>,
+
+    /// Consumer group Kafka offsets are committed as
+    #[arg(long, env, default_value = "docker-job-dispatcher")]
+    pub kafka_group: String,
+
+    /// Additional Kafka consumer option, given as key=value (e.g.
+    /// security.protocol=SASL_SSL)
+    #[arg(long, env, value_delimiter = ',', hide_env_values = true)]
+    pub kafka_option: Vec<String>,
+
+    /// NATS server to consume jobs from
+    #[arg(long, env, requires = "nats_subject")]
+    pub nats_url: Option<String>,
+
+    /// NATS subject to consume jobs from
+    #[arg(long, env, requires = "nats_url")]
+    pub nats_subject: Option<String>,
+
+    /// Queue group the NATS subscription joins, so that each message
+    /// is taken by a single dispatcher
+    #[arg(long, env, default_value = "docker-job-dispatcher")]
+    pub nats_queue_group: String,
+
+    /// JetStream stream to consume jobs from through a durable
+    /// consumer, instead of a core NATS subscription
+    #[arg(long, env, requires = "nats_url")]
+    pub nats_stream: Option<String>,
+
+    /// Name of the durable JetStream consumer
+    #[arg(long, env, default_value = "docker-job-dispatcher")]
+    pub nats_consumer: String,
+
+    /// NATS credentials file to authenticate with
+    #[arg(long, env, requires = "nats_url")]
+    pub nats_credentials: Option<PathBuf>,
+
+    /// AMQP broker to consume jobs from, as an amqp:// or amqps:// URL
+    #[arg(long, env, requires = "amqp_queue", hide_env_values = true)]
+    pub amqp_url: Option<String>,
+
+    /// AMQP queue to consume jobs from
+    #[arg(long, env, requires = "amqp_url")]
+    pub amqp_queue: Option<String>,
+
+    /// Maximum number of AMQP messages taken at a time
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 1)]
+    pub amqp_prefetch: u16,
+
+    /// Username to authenticate with the AMQP broker, instead of the
+    /// one in the URL
+    #[arg(long, env, requires = "amqp_url")]
+    pub amqp_username: Option<String>,
+
+    /// Password to authenticate with the AMQP broker, instead of the
+    /// one in the URL
+    #[arg(long, env, requires = "amqp_url", hide_env_values = true)]
+    pub amqp_password: Option<String>,
+
+    /// Maximum number of attempts at starting a job before it's
+    /// considered failed
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 3)]
+    pub max_start_attempts: u16,
+
+    /// Remove the anonymous volumes of jobs when removing them
+    #[arg(long, env)]
+    pub remove_volumes: bool,
+
+    /// Remove networks labeled with the namespace once they're no
+    /// longer used by any job
+    #[arg(long, env)]
+    pub remove_networks: bool,
+
+    /// Attach jobs that don't specify a network to a bridge network
+    /// dedicated to the namespace, removed once the namespace has no
+    /// jobs left
+    #[arg(long, env)]
+    pub namespace_network: bool,
+
+    /// Logging driver given to jobs that don't set one (e.g.
+    /// json-file or local); default is the docker daemon's
+    #[arg(long, env)]
+    pub log_driver: Option<String>,
+
+    /// Option of the --log-driver given as key=value (e.g.
+    /// max-size=10m)
+    #[arg(long, env, value_delimiter = ',')]
+    pub log_opt: Vec<String>,
+
+    /// Parent cgroup given to jobs that don't set one; default is the
+    /// docker daemon's
+    #[arg(long, env)]
+    pub cgroup_parent: Option<String>,
+
+    /// CPU set assigned to jobs as they're started (e.g. 0-3), given
+    /// several times or separated by semicolons to form a pool; jobs
+    /// share sets round-robin unless they declare X-CpuSet exclusive
+    #[arg(long, env, value_delimiter = ';')]
+    pub cpuset_pool: Vec<String>,
+
+    /// Path at which each job gets a dedicated volume mounted, removed
+    /// along with the job; default is to not provision volumes
+    #[arg(long, env)]
+    pub workspace_path: Option<String>,
+
+    /// Interval in seconds after which images used only by jobs are
+    /// removed once unused; default is to never remove images
+    #[arg(long, env)]
+    pub prune_images_after: Option<u32>,
+
+    /// Image (either repository or repository:tag) to never remove
+    /// when pruning images
+    #[arg(long, env, value_delimiter = ',')]
+    pub keep_image: Vec<String>,
+
+    /// Interval in seconds to check for images to prune
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 60)]
+    pub image_prune_interval: u16,
+
+    /// Maximum number of jobs to inspect and remove in each cleaning
+    /// cycle; default is unlimited
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..))]
+    pub clean_batch_size: Option<u16>,
+
+    /// Only report the jobs the cleaner would remove, without removing
+    /// them
+    #[arg(long, env)]
+    pub clean_dry_run: bool,
+
+    /// Docker config file (e.g. ~/.docker/config.json) holding the
+    /// credentials used to pull missing images
+    #[arg(long, env)]
+    pub registry_config: Option<PathBuf>,
+
+    /// Credentials used to pull missing images, given as
+    /// registry=username:password
+    #[arg(long, env, value_delimiter = ',', hide_env_values = true)]
+    pub registry_auth: Vec<String>,
+
+    /// Secret jobs may request with X-Secrets, given as
+    /// name=file:path or name=env:variable; values are read from the
+    /// dispatcher's host whenever a job starts
+    #[arg(long, env, value_delimiter = ',')]
+    pub secret: Vec<String>,
+
+    /// Directory of job containers where requested secrets are placed
+    /// as files
+    #[arg(long, env, default_value = "/run/secrets")]
+    pub secrets_path: PathBuf,
+
+    /// Pattern of the JSON keys (e.g. *password*) whose values are
+    /// redacted from logged requests and manifests, matched
+    /// case-insensitively
+    #[arg(
+        long,
+        env,
+        value_delimiter = ',',
+        default_value = "*password*,*token*,*secret*,*credential*"
+    )]
+    pub redact_key: Vec<String>,
+
+    /// Directory where the logs of exited jobs are archived before
+    /// they're removed; default is to not archive logs
+    #[arg(long, env)]
+    pub log_archive_dir: Option<PathBuf>,
+
+    /// S3 bucket where the logs and metadata of exited jobs are
+    /// uploaded before they're removed; default is to not upload them
+    #[arg(long, env)]
+    pub s3_bucket: Option<String>,
+
+    /// Prefix prepended to the keys of uploaded objects
+    #[arg(long, env, default_value_t = String::new())]
+    pub s3_prefix: String,
+
+    /// Endpoint URL of the S3-compatible storage service; default is
+    /// AWS S3
+    #[arg(long, env)]
+    pub s3_endpoint: Option<String>,
+
+    /// Region of the S3 bucket; default is taken from the standard AWS
+    /// configuration sources
+    #[arg(long, env)]
+    pub s3_region: Option<String>,
+
+    /// Access key ID for the S3-compatible storage service; default is
+    /// taken from the standard AWS configuration sources
+    #[arg(long, env)]
+    pub s3_access_key_id: Option<String>,
+
+    /// Secret access key for the S3-compatible storage service
+    #[arg(long, env, hide_env_values = true)]
+    pub s3_secret_access_key: Option<String>,
+
+    /// Use path-style addressing for S3 buckets, as required by some
+    /// S3-compatible services
+    #[arg(long, env)]
+    pub s3_path_style: bool,
+
+    /// Interval in seconds to perform periodic scheduling and cleanup
+    /// upkeep
+    #[arg(short, long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 3)]
+    pub upkeep_interval: u16,
+
+    /// Means of connection to the docker daemon
+    #[arg(short, long, env, value_enum, default_value_t = docker::Transport::Socket)]
+    pub transport: docker::Transport,
+
+    /// URL of the docker daemon, either as unix:///path/to/socket for
+    /// the socket transport, as tcp://host:port for the HTTP and TLS
+    /// transports, or as ssh://[user@]host[:port] for the SSH
+    /// transport; may be given several times to spread jobs across
+    /// several daemons; default depends on the transport
+    #[arg(long, env = "DOCKER_HOST", value_delimiter = ',')]
+    pub docker_host: Vec<String>,
+
+    /// Strategy used to spread jobs across several docker hosts;
+    /// by-name places jobs of the same name in the same host, so that
+    /// concurrent creations of a job can't both succeed
+    #[arg(long, env, value_enum, default_value_t = docker::Placement::ByName)]
+    pub placement: docker::Placement,
+
+    /// Path to the docker daemon's socket, for the socket transport
+    /// (where it takes precedence over --docker-host) or on the remote
+    /// host for the SSH transport
+    #[arg(long, env)]
+    pub docker_socket: Option<String>,
+
+    /// CA certificate used to verify the docker daemon, for the TLS
+    /// transport; default is ca.pem in the certificates directory
+    #[arg(long, env)]
+    pub docker_ca: Option<PathBuf>,
+
+    /// Client certificate presented to the docker daemon, for the TLS
+    /// transport; default is cert.pem in the certificates directory
+    #[arg(long, env)]
+    pub docker_cert: Option<PathBuf>,
+
+    /// Client private key, for the TLS transport; default is key.pem
+    /// in the certificates directory
+    #[arg(long, env)]
+    pub docker_key: Option<PathBuf>,
+
+    /// User to log in as on the remote host, for the SSH transport;
+    /// takes precedence over the user given in --docker-host
+    #[arg(long, env)]
+    pub ssh_user: Option<String>,
+
+    /// Private key used to authenticate with the remote host, for the
+    /// SSH transport; default is to use the SSH client's configuration
+    #[arg(long, env)]
+    pub ssh_key: Option<PathBuf>,
+
+    /// Timeout in seconds for pinging the docker daemon, which is done
+    /// by health checks
+    #[arg(long, env, default_value_t = 5)]
+    pub docker_connect_timeout: u16,
+
+    /// Timeout in seconds for receiving a response to any other request
+    /// to the docker daemon
+    #[arg(long, env, default_value_t = 30)]
+    pub docker_read_timeout: u16,
+
+    /// Amount of consecutive failed calls to the docker daemon after
+    /// which new jobs are refused and upkeep is paused, until the
+    /// daemon responds again; 0 disables this
+    #[arg(long, env, default_value_t = 5)]
+    pub circuit_breaker_threshold: u32,
+
+    /// Docker API version to use, as major.minor; default is the newest
+    /// version supported by both the docker daemon and the dispatcher
+    #[arg(long, env)]
+    pub docker_api_version: Option<String>,
+
+    /// Runtime jobs are dispatched to; the swarm backend requires the
+    /// docker daemon to be a swarm manager
+    #[arg(long, env, value_enum, default_value_t = backend::Kind::Docker)]
+    pub backend: backend::Kind,
+
+    /// Kubernetes namespace jobs are created in, for the kubernetes
+    /// backend; default is the namespace of the current configuration
+    #[arg(long, env)]
+    pub kubernetes_namespace: Option<String>,
+
+    /// Address of the Nomad API, for the nomad backend
+    #[arg(long, env = "NOMAD_ADDR", default_value_t = String::from("http://127.0.0.1:4646"))]
+    pub nomad_addr: String,
+
+    /// ACL token for the Nomad API, for the nomad backend
+    #[arg(long, env = "NOMAD_TOKEN", hide_env_values = true)]
+    pub nomad_token: Option<String>,
+
+    /// Nomad namespace jobs are registered in, for the nomad backend;
+    /// default is Nomad's default namespace
+    #[arg(long, env = "NOMAD_NAMESPACE")]
+    pub nomad_namespace: Option<String>,
+
+    /// Nomad datacenters jobs may be placed in, for the nomad backend
+    #[arg(long, env, value_delimiter = ',', default_value = "*")]
+    pub nomad_datacenter: Vec<String>,
+
+    /// Label applied to jobs created to group them
+    #[arg(short, long, env, default_value_t = String::from("default"))]
+    pub namespace: String,
+
+    /// Instance serving another namespace, given as namespace=URL;
+    /// requests naming that namespace in the X-Dispatcher-Namespace
+    /// header are refused, pointing to that URL
+    #[arg(long, env, value_delimiter = ',')]
+    pub shard: Vec<String>,
+
+    /// Request header holding the identity of the submitter of a job,
+    /// as set by an authenticating proxy; recorded as a job label
+    #[arg(long, env, default_value_t = String::from("X-Forwarded-User"))]
+    pub submitter_header: String,
+
+    /// Log level
+    #[arg(long, env, default_value_t = tracing::Level::INFO)]
+    pub log_level: tracing::Level,
+
+    /// Level at which every API request is logged, with its status,
+    /// response size and latency
+    #[arg(long, env, default_value_t = tracing::Level::INFO)]
+    pub access_log_level: tracing::Level,
+
+    /// Log format; json lines include timestamps, fields and the
+    /// context of enclosing spans
+    #[arg(long, env, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Endpoint of an OpenTelemetry collector to export traces to over
+    /// OTLP (e.g. http://localhost:4317); default is to not export
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// OTLP/HTTP endpoint of an OpenTelemetry collector to push metrics
+    /// to (e.g. http://localhost:4318/v1/metrics); default is to not
+    /// push metrics
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_METRICS_ENDPOINT")]
+    pub otlp_metrics_endpoint: Option<String>,
+
+    /// Interval in seconds between metric pushes
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 60)]
+    pub otlp_metrics_interval: u16,
+
+    /// Header sent with metric pushes, given as key=value (e.g. for
+    /// authentication)
+    #[arg(
+        long,
+        env = "OTEL_EXPORTER_OTLP_METRICS_HEADERS",
+        value_delimiter = ',',
+        hide_env_values = true
+    )]
+    pub otlp_metrics_header: Vec<String>,
+
+    /// URL of a Prometheus Pushgateway to push metrics to (e.g.
+    /// http://localhost:9091); default is to not push metrics
+    #[arg(long, env)]
+    pub pushgateway_url: Option<String>,
+
+    /// Job name metrics are grouped by in the Pushgateway
+    #[arg(long, env, default_value_t = String::from(env!("CARGO_PKG_NAME")))]
+    pub pushgateway_job: String,
+
+    /// Interval in seconds between pushes to the Pushgateway
+    #[arg(long, env, value_parser = value_parser!(u16).range(1..), default_value_t = 60)]
+    pub pushgateway_interval: u16,
+
+    /// Address (host:port) of a StatsD server to emit job and upkeep
+    /// metrics to; default is to not emit them
+    #[arg(long, env)]
+    pub statsd_address: Option<String>,
+
+    /// Prefix of the names of metrics emitted to StatsD
+    #[arg(long, env, default_value_t = String::from("docker_job_dispatcher"))]
+    pub statsd_prefix: String,
+
+    /// Dialect of StatsD to emit; only dogstatsd includes tags
+    #[arg(long, env, value_enum, default_value_t = statsd::Flavor::Statsd)]
+    pub statsd_flavor: statsd::Flavor,
+
+    #[command(flatten)]
+    pub tunables: reload::Tunables,
+}
+/// Install the tracing subscriber logging to stderr, and exporting
+/// traces if so configured.
+pub fn init_tracing(cli: &Cli) -> Result<()> {
+    let telemetry_layer = cli
+        .otlp_endpoint
+        .as_deref()
+        .map(telemetry::layer)
+        .transpose()?;
+    tracing_subscriber::registry()
+        .with(telemetry_layer)
+        .with(matches!(cli.log_format, LogFormat::Text).then(|| {
+            tracing_subscriber::fmt::layer()
+                .with_target(false)
+                .without_time()
+        }))
+        .with(
+            matches!(cli.log_format, LogFormat::Json)
+                .then(|| tracing_subscriber::fmt::layer().json()),
+        )
+        .with(LevelFilter::from_level(cli.log_level))
+        .init();
+    if let Some(endpoint) = &cli.otlp_endpoint {
+        info!("Exporting traces to {:?}", endpoint);
+    }
+    Ok(())
+}
+
+/// Default 404 response
+pub async fn no_route() -> RouteResult<HttpResponse> {
+    Err::<_, Error>(api_error::APIError::not_found("Route not found").into())
+}
+
+/// OpenAPI schema
+const OPENAPI: &str = include_str!("openapi.json");
+
+/// Register the internal endpoints: metrics and API docs.
+pub fn internal_services(cfg: &mut web::ServiceConfig) {
+    cfg.service(metrics_service::expose)
+        .route(
+            "/openapi.json",
+            web::get().to(|| async {
+                HttpResponse::Ok()
+                    .content_type(ContentType::json())
+                    .body(OPENAPI)
+            }),
+        )
+        .service(RapiDoc::new("/openapi.json").path("/docs"));
+}
+
+/// An initialized dispatcher, holding the state shared by its HTTP
+/// services. Cloning it is cheap.
+#[derive(Clone)]
+pub struct Dispatcher {
+    cli: Arc<Cli>,
+    containers_can_start: web::Data<bool>,
+    namespace: web::Data<String>,
+    shards: web::Data<shard::Shards>,
+    provenance: web::Data<docker_service::Provenance>,
+    internal_access: web::Data<internal::Access>,
+    api_keys: web::Data<auth::Keys>,
+    authorization: web::Data<authorization::Rules>,
+    rate_limiter: web::Data<rate_limit::Limiter>,
+    ip_filter: web::Data<ip_filter::Filter>,
+    signatures: web::Data<signature::Signatures>,
+    access_log: web::Data<access_log::Settings>,
+    reloader: Arc<reload::Reloader>,
+    cleanup: web::Data<cleaner::Cleanup>,
+}
+
+impl Dispatcher {
+    /// Compile the filter, validate the configuration and connect to
+    /// the backend. This may only be done once per process, since most
+    /// of the dispatcher's state is global.
+    pub async fn init(cli: Cli) -> Result<Self> {
+        if let Some(address) = &cli.statsd_address {
+            statsd::init(address, &cli.statsd_prefix, cli.statsd_flavor.clone())?;
+            info!("Emitting metrics to StatsD at {:?}", address);
+        }
+
+        // Initialize application state
+        let filter_source = if let Some(filter_file) = &cli.from_file {
+            if cli.filter.is_some() {
+                warn!("Filter given both as file and argument; argument will be ignored");
+            }
+            std::fs::read_to_string(filter_file)
+        } else if let Some(filter_str) = &cli.filter {
+            Ok(filter_str.clone())
+        } else {
+            warn!("No filter given; the default filter will be used");
+            Ok(DEFAULT_FILTER.to_string())
+        }?;
+        let reloader = Arc::new(reload::Reloader::new(
+            filter_source,
+            cli.from_file.clone(),
+            cli.tunables.clone(),
+            cli.settings_file.clone(),
+        )?);
+        let tunables = reloader.current();
+        let containers_can_start =
+            web::Data::new(tunables.max_concurrent.is_none() && !cli.wait_healthy);
+        let namespace = web::Data::new(cli.namespace.clone());
+        let shards = web::Data::new(shard::Shards::new(cli.namespace.clone(), &cli.shard)?);
+        for (namespace, url) in &shards.owners {
+            info!("Namespace {:?} is served at {}", namespace, url);
+        }
+        let provenance = web::Data::new(docker_service::Provenance {
+            submitter_header: cli.submitter_header.clone(),
+        });
+        let internal_access = web::Data::new(internal::Access {
+            token: cli.internal_token.clone(),
+        });
+        let api_keys = web::Data::new(auth::Keys::load(
+            &cli.api_key,
+            cli.api_keys_file.as_deref(),
+            &cli.api_key_scope,
+        )?);
+        if api_keys.is_enabled() {
+            info!("Requiring an API key for the job and admin endpoints");
+        }
+        let authorization = web::Data::new(match &cli.authorization_rules {
+            Some(path) => {
+                info!("Enforcing the authorization rules in {:?}", path);
+                authorization::Rules::load(path, cli.namespace.clone())?
+            }
+            None => authorization::Rules::default(),
+        });
+        let rate_limiter = web::Data::new(match cli.rate_limit {
+            Some(per_minute) => {
+                let burst = cli.rate_limit_burst.unwrap_or(per_minute);
+                info!(
+                    "Limiting job creations to {} per minute per client, in bursts of {}",
+                    per_minute, burst
+                );
+                rate_limit::Limiter::new(per_minute, burst)
+            }
+            None => rate_limit::Limiter::default(),
+        });
+        let ip_filter = web::Data::new(ip_filter::Filter {
+            allow: cli.allow_ip.clone(),
+            deny: cli.deny_ip.clone(),
+            admin_allow: cli.admin_allow_ip.clone(),
+            admin_deny: cli.admin_deny_ip.clone(),
+            client_ip_header: cli.client_ip_header.clone(),
+            trusted_proxies: cli.trusted_proxy.clone(),
+        });
+        if ip_filter.is_enabled() {
+            info!("Filtering requests to the job and admin endpoints by client address");
+        }
+        let signatures = web::Data::new(signature::Signatures {
+            secret: cli.webhook_secret.clone(),
+            style: cli.webhook_signature.clone(),
+            header: cli
+                .webhook_signature_header
+                .clone()
+                .unwrap_or_else(|| cli.webhook_signature.default_header().to_string()),
+        });
+        if signatures.is_enabled() {
+            info!(
+                "Requiring job creation requests to be signed in the {:?} header",
+                signatures.header
+            );
+        }
+        if let Some(header) = &cli.client_ip_header {
+            info!(
+                "Reading client addresses from the {:?} header set by trusted proxies",
+                header
+            );
+        }
+        if let Some(issuer) = &cli.jwt_issuer {
+            jwt::init(
+                issuer.clone(),
+                cli.jwks_url.clone(),
+                cli.jwt_audience.clone(),
+                cli.jwt_scope.clone(),
+            )
+            .await?;
+        }
+        let access_log = web::Data::new(access_log::Settings {
+            level: cli.access_log_level,
+        });
+        let cleanup = web::Data::new(cleaner::Cleanup {
+            volumes: cli.remove_volumes,
+            networks: cli.remove_networks,
+            batch_size: cli.clean_batch_size.map(usize::from),
+            dry_run: cli.clean_dry_run,
+        });
+        attempts::init(cli.max_start_attempts);
+        gpu::init(cli.gpus);
+        if let Some(log_archive_dir) = &cli.log_archive_dir {
+            info!("Archiving logs of removed jobs at {:?}", log_archive_dir);
+            archive::init(log_archive_dir.clone());
+        }
+        if let Some(bucket) = &cli.s3_bucket {
+            info!(
+                "Uploading logs and metadata of removed jobs to S3 bucket {:?}",
+                bucket
+            );
+            object_store::init(object_store::Settings {
+                bucket: bucket.clone(),
+                prefix: cli.s3_prefix.clone(),
+                endpoint: cli.s3_endpoint.clone(),
+                region: cli.s3_region.clone(),
+                access_key_id: cli.s3_access_key_id.clone(),
+                secret_access_key: cli.s3_secret_access_key.clone(),
+                force_path_style: cli.s3_path_style,
+            })
+            .await;
+        }
+        registry_auth::init(cli.registry_config.as_deref(), &cli.registry_auth)?;
+        secrets::init(&cli.secret, cli.secrets_path.clone())?;
+        redact::init(&cli.redact_key)?;
+        cpusets::init(&cli.cpuset_pool)?;
+        // a backend set beforehand by an embedding application stands
+        // in for the configured one
+        let custom_backend = backend::is_alternative();
+        if (custom_backend || backend::is_standalone(&cli.backend))
+            && (cli.prune_images_after.is_some() || cli.remove_networks)
+        {
+            bail!("image pruning and network removal require a docker backend");
+        }
+        if (cli.namespace_network
+            || cli.workspace_path.is_some()
+            || cli.log_driver.is_some()
+            || cli.cgroup_parent.is_some()
+            || cpusets::is_enabled()
+            || secrets::is_enabled())
+            && (custom_backend || cli.backend != backend::Kind::Docker)
+        {
+            bail!(
+                "namespace networks, workspace volumes, log drivers, cgroups, \
+                 CPU set pools and secrets require the docker backend"
+            );
+        }
+        if custom_backend {
+            info!("Dispatching jobs through a custom backend");
+        } else if cli.backend == backend::Kind::Kubernetes {
+            backend::init(Box::new(
+                kubernetes::Kubernetes::connect(cli.kubernetes_namespace.clone()).await?,
+            ));
+        } else if cli.backend == backend::Kind::Nomad {
+            backend::init(Box::new(
+                nomad::Nomad::connect(nomad::Settings {
+                    address: cli.nomad_addr.clone(),
+                    token: cli.nomad_token.clone(),
+                    namespace: cli.nomad_namespace.clone(),
+                    datacenters: cli.nomad_datacenter.clone(),
+                })
+                .await?,
+            ));
+        } else {
+            docker::init(
+                docker::Connection {
+                    transport: cli.transport.clone(),
+                    hosts: cli.docker_host.clone(),
+                    socket: cli.docker_socket.clone(),
+                    ca: cli.docker_ca.clone(),
+                    cert: cli.docker_cert.clone(),
+                    key: cli.docker_key.clone(),
+                    ssh_user: cli.ssh_user.clone(),
+                    ssh_key: cli.ssh_key.clone(),
+                    connect_timeout: cli.docker_connect_timeout,
+                    read_timeout: cli.docker_read_timeout,
+                    api_version: cli
+                        .docker_api_version
+                        .as_deref()
+                        .map(docker::parse_api_version)
+                        .transpose()?,
+                },
+                cli.placement.clone(),
+                &cli.backend,
+            )
+            .await?;
+        }
+
+        if cli.namespace_network {
+            let network = docker::init_network(&cli.namespace).await?;
+            info!("Attaching jobs to network {:?} by default", network);
+        }
+
+        if let Some(log_driver) = &cli.log_driver {
+            docker::init_log_config(log_driver.clone(), &cli.log_opt)?;
+        } else if !cli.log_opt.is_empty() {
+            warn!("Log options given without a log driver; they will be ignored");
+        }
+        if let Some(cgroup_parent) = &cli.cgroup_parent {
+            info!("Placing jobs under cgroup {:?} by default", cgroup_parent);
+            docker::init_cgroup_parent(cgroup_parent.clone());
+        }
+        if cpusets::is_enabled() {
+            info!("Assigning jobs one of {} CPU sets", cpusets::size());
+        }
+        if let Some(workspace_path) = &cli.workspace_path {
+            info!(
+                "Mounting a workspace volume at {:?} in each job",
+                workspace_path
+            );
+            docker::init_workspace(workspace_path.clone());
+        }
+
+        match cli.backend {
+            _ if custom_backend => (),
+            backend::Kind::Docker => (),
+            backend::Kind::Swarm => {
+                info!("Dispatching jobs as swarm services");
+                if tunables.max_concurrent.is_some() {
+                    warn!("Jobs are scheduled by the swarm; the concurrency limit won't apply");
+                }
+            }
+            backend::Kind::Kubernetes => info!("Dispatching jobs as Kubernetes jobs"),
+            backend::Kind::Nomad => info!("Dispatching jobs as Nomad jobs"),
+        }
+
+        metrics_service::describe(&[
+            (
+                "max_concurrent",
+                "Maximum number of concurrently-running jobs",
+                tunables.max_concurrent.map(i64::from),
+            ),
+            (
+                "keep_exited_for_seconds",
+                "Interval to keep exited jobs for",
+                tunables.keep_exited_for.map(i64::from),
+            ),
+            (
+                "upkeep_interval_seconds",
+                "Interval of scheduling and cleanup upkeep",
+                Some(cli.upkeep_interval.into()),
+            ),
+        ])
+        .await;
+        Ok(Self {
+            cli: Arc::new(cli),
+            containers_can_start,
+            namespace,
+            shards,
+            provenance,
+            internal_access,
+            api_keys,
+            authorization,
+            rate_limiter,
+            ip_filter,
+            signatures,
+            access_log,
+            reloader,
+            cleanup,
+        })
+    }
+
+    /// Register the job and admin endpoints, the health checks, and
+    /// the state they need, in an application or scope. The internal
+    /// endpoints are registered separately through
+    /// [`internal_services`], and the middleware isn't registered at
+    /// all: wrap the application with
+    /// [`signature::verify`] to require signed job creation requests,
+    /// with [`internal::guard`] to protect the internal endpoints, with
+    /// [`rate_limit::limit`] to limit job creations per client, with
+    /// [`authorization::authorize`] to enforce authorization rules,
+    /// with [`auth::authenticate`] to require API keys, with
+    /// [`shard::route`] to refuse requests meant for other namespaces,
+    /// with [`ip_filter::filter`] to filter requests by client address,
+    /// and optionally with [`access_log::log_request`] and
+    /// [`request_id::correlate`], in that order.
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.access_log.clone())
+            .app_data(self.internal_access.clone())
+            .app_data(self.api_keys.clone())
+            .app_data(self.authorization.clone())
+            .app_data(self.rate_limiter.clone())
+            .app_data(self.ip_filter.clone())
+            .app_data(self.signatures.clone())
+            .app_data(web::Data::from(self.reloader.clone()))
+            .app_data(web::Data::from(self.reloader.filter.clone()))
+            .app_data(web::Data::from(self.reloader.policy.clone()))
+            .app_data(web::Data::from(self.reloader.retention.clone()))
+            .app_data(self.containers_can_start.clone())
+            .app_data(self.namespace.clone())
+            .app_data(self.shards.clone())
+            .app_data(self.provenance.clone())
+            .app_data(self.cleanup.clone())
+            .service(health_service::liveness_check)
+            .service(health_service::readiness_check)
+            .service(docker_service::create_job)
+            .service(docker_service::get_job)
+            .service(docker_service::get_job_logs)
+            .service(admin_service::preview_cleaner)
+            .service(admin_service::purge)
+            .service(admin_service::reload);
+    }
+
+    /// Start the background tasks: the scheduler, the cleaner, the
+    /// event watchers and the metrics exporters, as configured. Jobs
+    /// left by a previous instance are adopted before returning, so
+    /// this should be done before serving requests.
+    pub async fn spawn(&self) -> Result<Tasks> {
+        let cli = &self.cli;
+        // Background tasks are either upkeep loops, finishing their
+        // cycle on shutdown, metrics flushers, flushing metrics after
+        // that, or else just aborted
+        let mut tasks = Vec::new();
+        let mut upkeep = Vec::new();
+        // Tasks that may fail transiently (e.g. when the docker daemon
+        // restarts) are supervised, restarting them instead of exiting
+        let mut flushers = vec![tokio::spawn(supervise("metrics collector", {
+            let cli = cli.clone();
+            move || {
+                metrics_service::run(
+                    cli.namespace.clone(),
+                    cli.upkeep_interval,
+                    cli.metrics_state_file.clone(),
+                )
+            }
+        }))];
+        tasks.push(tokio::spawn(supervise("exit watcher", {
+            let namespace = cli.namespace.clone();
+            move || exits::watch(namespace.clone())
+        })));
+        tasks.push(tokio::spawn(reload::watch(self.reloader.clone())));
+        if cli.dispatch_workers > 0 {
+            tasks.push(tokio::spawn(dispatch_queue::init(
+                cli.dispatch_workers,
+                cli.dispatch_queue_size.into(),
+            )));
+        }
+        if cli.circuit_breaker_threshold > 0 {
+            circuit_breaker::init(cli.circuit_breaker_threshold);
+            tasks.push(tokio::spawn(circuit_breaker::probe()));
+        }
+        if let Some(path) = &cli.leader_lock {
+            tasks.push(tokio::spawn(leader::campaign(path.clone())));
+        }
+        if let Some(path) = &cli.job_store {
+            job_store::init(path)?;
+            info!("Recording jobs in {:?}", path);
+            tasks.push(tokio::spawn(supervise("job store", {
+                let namespace = cli.namespace.clone();
+                move || job_store::watch(namespace.clone())
+            })));
+        }
+        if let Err(e) = startup::reconcile(&cli.namespace).await {
+            warn!(
+                "Couldn't adopt the jobs left by a previous instance: {:?}",
+                e
+            );
+        }
+        if cli.sentry_dsn.is_some() || cli.error_webhook.is_some() {
+            info!("Reporting panics, repeated upkeep failures and server errors");
+            tasks.push(tokio::spawn(error_report::init(
+                cli.sentry_dsn.as_deref(),
+                cli.error_webhook.clone(),
+            )?));
+        }
+        if cli.audit_log.is_some() || cli.audit_webhook.is_some() {
+            info!("Auditing job creations and removals");
+            tasks.push(tokio::spawn(audit::init(
+                cli.audit_log.clone(),
+                cli.audit_webhook.clone(),
+            )));
+        }
+        if let Some(endpoint) = &cli.otlp_metrics_endpoint {
+            info!(
+                "Pushing metrics to {:?} every {} seconds",
+                endpoint, cli.otlp_metrics_interval
+            );
+            flushers.push(tokio::spawn(otlp_metrics::cycle(
+                otlp_metrics::client(&cli.otlp_metrics_header)?,
+                endpoint.clone(),
+                cli.otlp_metrics_interval,
+            )));
+        }
+        if let Some(url) = &cli.pushgateway_url {
+            let url = pushgateway::group_url(url, &cli.pushgateway_job)?;
+            info!(
+                "Pushing metrics to {:?} every {} seconds",
+                url.as_str(),
+                cli.pushgateway_interval
+            );
+            flushers.push(tokio::spawn(pushgateway::cycle(
+                url,
+                cli.pushgateway_interval,
+            )));
+        }
+        if let Some(webhook) = &cli.unhealthy_webhook {
+            info!("Notifying {:?} of unhealthy jobs", webhook);
+            tasks.push(tokio::spawn(supervise("health watcher", {
+                let (namespace, webhook) = (cli.namespace.clone(), webhook.clone());
+                move || health_watcher::watch(namespace.clone(), webhook.clone())
+            })));
+        }
+        if let Some(webhook) = &cli.lifecycle_webhook {
+            info!("Notifying {:?} of the lifecycle of jobs", webhook);
+            let sink = Arc::new(notifier::Sink::new(
+                webhook.clone(),
+                &cli.lifecycle_webhook_header,
+                cli.lifecycle_webhook_secret.clone(),
+            )?);
+            tasks.push(tokio::spawn(supervise("notifier", {
+                let namespace = cli.namespace.clone();
+                move || notifier::watch(namespace.clone(), sink.clone())
+            })));
+        }
+        if let Some(brokers) = &cli.kafka_brokers {
+            let source = Arc::new(kafka::Source {
+                brokers: brokers.clone(),
+                topics: cli.kafka_topic.clone(),
+                group: cli.kafka_group.clone(),
+                options: cli.kafka_option.clone(),
+            });
+            let intake = docker_service::Intake {
+                filter: self.reloader.filter.clone(),
+                policy: self.reloader.policy.clone(),
+                can_start: **self.containers_can_start,
+                namespace: cli.namespace.clone(),
+            };
+            tasks.push(tokio::spawn(supervise("Kafka consumer", move || {
+                kafka::consume(source.clone(), intake.clone())
+            })));
+        }
+        if let (Some(url), Some(subject)) = (&cli.nats_url, &cli.nats_subject) {
+            let source = Arc::new(nats::Source {
+                url: url.clone(),
+                subject: subject.clone(),
+                queue_group: cli.nats_queue_group.clone(),
+                stream: cli.nats_stream.clone(),
+                consumer: cli.nats_consumer.clone(),
+                credentials: cli.nats_credentials.clone(),
+            });
+            let intake = docker_service::Intake {
+                filter: self.reloader.filter.clone(),
+                policy: self.reloader.policy.clone(),
+                can_start: **self.containers_can_start,
+                namespace: cli.namespace.clone(),
+            };
+            tasks.push(tokio::spawn(supervise("NATS consumer", move || {
+                nats::consume(source.clone(), intake.clone())
+            })));
+        }
+        if let (Some(url), Some(queue)) = (&cli.amqp_url, &cli.amqp_queue) {
+            let source = Arc::new(amqp::Source {
+                url: url.clone(),
+                queue: queue.clone(),
+                prefetch: cli.amqp_prefetch,
+                username: cli.amqp_username.clone(),
+                password: cli.amqp_password.clone(),
+            });
+            let intake = docker_service::Intake {
+                filter: self.reloader.filter.clone(),
+                policy: self.reloader.policy.clone(),
+                can_start: **self.containers_can_start,
+                namespace: cli.namespace.clone(),
+            };
+            tasks.push(tokio::spawn(supervise("AMQP consumer", move || {
+                amqp::consume(source.clone(), intake.clone())
+            })));
+        }
+        if ssh_tunnel::is_open() {
+            tasks.push(tokio::spawn(ssh_tunnel::watch()));
+        }
+
+        // Optionally start the job scheduler and cleaner
+        match *self.reloader.max_concurrent.get() {
+            Some(0) => {
+                warn!("Maximum concurrent jobs set to 0; containers won't be started");
+            }
+            Some(max_concurrent) => {
+                info!(
+                    "Using a scheduler for {max_concurrent} concurrent containers, \
+                     scheduling every {} seconds",
+                    cli.upkeep_interval
+                );
+                upkeep.push(tokio::spawn(leader::led(self.scheduler())));
+            }
+            None => {
+                info!(
+                    "Using a scheduler for mutually exclusive jobs, scheduling every {} seconds",
+                    cli.upkeep_interval
+                );
+                upkeep.push(tokio::spawn(leader::led(self.scheduler())));
+            }
+        }
+        let retention = self.reloader.retention.get();
+        let cleanup = self.cleanup.get_ref().clone();
+        if retention.is_enabled() {
+            match (retention.keep_last, retention.keep_exited_for) {
+                (Some(keep_last), _) => info!(
+                    "Using a cleaner keeping the last {keep_last} exited jobs, \
+                     cleaning every {} seconds",
+                    cli.upkeep_interval
+                ),
+                (None, Some(keep_exited_for)) => info!(
+                    "Using a cleaner for exited jobs older than {keep_exited_for} \
+                     seconds, cleaning every {} seconds",
+                    cli.upkeep_interval
+                ),
+                (None, None) if retention.keep_failed_for.is_some() => {
+                    warn!("Successful jobs will be kept indefinitely")
+                }
+                (None, None) => warn!("Exited jobs will be kept indefinitely"),
+            }
+            if let (None, Some(keep_failed_for)) = (retention.keep_last, retention.keep_failed_for)
+            {
+                info!("Failed jobs will be kept for {keep_failed_for} seconds");
+            }
+            if let Some(keep_created_for) = retention.keep_created_for {
+                info!("Jobs never started will be kept for {keep_created_for} seconds");
+            }
+            if cleanup.dry_run {
+                warn!("The cleaner runs in dry-run mode; jobs won't be removed");
+            }
+            upkeep.push(tokio::spawn(leader::led(supervise("cleaner", {
+                let (retention, interval) = (self.reloader.retention.clone(), cli.upkeep_interval);
+                let namespace = cli.namespace.clone();
+                move || {
+                    cleaner::cycle(
+                        retention.clone(),
+                        cleanup.clone(),
+                        interval,
+                        namespace.clone(),
+                    )
+                }
+            }))));
+        } else {
+            warn!("Exited jobs will be kept indefinitely");
+        }
+        if let Some(prune_images_after) = cli.prune_images_after {
+            info!(
+                "Pruning images unused by jobs for {prune_images_after} seconds, \
+                 checking every {} seconds",
+                cli.image_prune_interval
+            );
+            upkeep.push(tokio::spawn(leader::led(supervise("image pruner", {
+                let (keep_image, interval) = (cli.keep_image.clone(), cli.image_prune_interval);
+                let namespace = cli.namespace.clone();
+                move || {
+                    image_pruner::cycle(
+                        prune_images_after,
+                        keep_image.clone(),
+                        interval,
+                        namespace.clone(),
+                    )
+                }
+            }))));
+        }
+        Ok(Tasks {
+            tasks,
+            upkeep,
+            flushers,
+        })
+    }
+
+    /// The supervised scheduler loop.
+    fn scheduler(&self) -> impl Future<Output = Result<()>> {
+        let max_concurrent = self.reloader.max_concurrent.clone();
+        let (wait_healthy, interval) = (self.cli.wait_healthy, self.cli.upkeep_interval);
+        let namespace = self.cli.namespace.clone();
+        supervise("scheduler", move || {
+            scheduler::cycle(
+                max_concurrent.clone(),
+                wait_healthy,
+                interval,
+                namespace.clone(),
+            )
+        })
+    }
+
+    /// Serve the API, and the internal endpoints on their own port if
+    /// so configured, run the background tasks, and shut down
+    /// gracefully on a termination signal. This is what the
+    /// `docker-job-dispatcher` binary does.
+    pub async fn serve(self) -> Result<()> {
+        let cli = self.cli.clone();
+        let separate_internal = cli.internal_port.is_some();
+        let dispatcher = self.clone();
+        let mut api = HttpServer::new(move || {
+            App::new()
+                .wrap(middleware::from_fn(signature::verify))
+                .wrap(middleware::from_fn(internal::guard))
+                .wrap(middleware::from_fn(rate_limit::limit))
+                .wrap(middleware::from_fn(authorization::authorize))
+                .wrap(middleware::from_fn(auth::authenticate))
+                .wrap(middleware::from_fn(shard::route))
+                .wrap(middleware::from_fn(ip_filter::filter))
+                .wrap(middleware::from_fn(error_report::capture_responses))
+                .wrap(middleware::from_fn(access_log::log_request))
+                .wrap(middleware::from_fn(request_id::correlate))
+                .wrap(middleware::NormalizePath::trim())
+                .configure(|cfg| {
+                    dispatcher.configure(cfg);
+                    if !separate_internal {
+                        internal_services(cfg);
+                    }
+                })
+                .default_service(web::route().to(no_route))
+        })
+        .disable_signals()
+        .shutdown_timeout(cli.shutdown_timeout.into())
+        .keep_alive(match cli.keep_alive {
+            0 => KeepAlive::Disabled,
+            seconds => KeepAlive::Timeout(Duration::from_secs(seconds.into())),
+        })
+        .client_request_timeout(Duration::from_secs(cli.client_timeout.into()))
+        .max_connections(cli.max_connections)
+        .on_connect(tls::on_connect);
+        if let Some(workers) = cli.workers {
+            api = api.workers(workers.into());
+        }
+        let tls_config = match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::server_config(
+                cert,
+                key,
+                cli.tls_client_ca.as_deref(),
+                cli.tls_client_optional,
+            )?),
+            _ => None,
+        };
+        let activated = socket_activation::listeners()?;
+        if !activated.is_empty() {
+            info!("Listening on {} sockets passed by systemd", activated.len());
+            for listener in activated {
+                api = match (listener, &tls_config) {
+                    (Listener::Tcp(listener), Some(config)) => {
+                        api.listen_rustls_0_23(listener, config.clone())?
+                    }
+                    (Listener::Tcp(listener), None) => api.listen(listener)?,
+                    (Listener::Unix(listener), _) => api.listen_uds(listener)?,
+                };
+            }
+        } else {
+            match (&tls_config, cli.socket_only) {
+                (_, true) => (),
+                (Some(config), false) => {
+                    api = api.bind_rustls_0_23(("0.0.0.0", cli.port), config.clone())?;
+                }
+                (None, false) => api = api.bind(("0.0.0.0", cli.port))?,
+            }
+            if let Some(path) = &cli.listen_socket {
+                let mode = u32::from_str_radix(&cli.listen_socket_mode, 8).with_context(|| {
+                    format!(
+                        "while parsing the socket permissions {:?}",
+                        cli.listen_socket_mode
+                    )
+                })?;
+                // a socket left by a previous run would make binding fail
+                if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+                {
+                    fs::remove_file(path)
+                        .with_context(|| format!("while removing the stale socket {:?}", path))?;
+                }
+                api = api
+                    .bind_uds(path)
+                    .with_context(|| format!("while binding to the socket {:?}", path))?;
+                fs::set_permissions(path, fs::Permissions::from_mode(mode)).with_context(|| {
+                    format!("while setting the permissions of the socket {:?}", path)
+                })?;
+                info!("Listening on {:?}", path);
+            }
+        }
+        if tls_config.is_some() {
+            if cli.tls_client_optional {
+                info!("Serving the API over TLS, accepting client certificates");
+            } else if cli.tls_client_ca.is_some() {
+                info!("Serving the API over TLS, requiring client certificates");
+            } else {
+                info!("Serving the API over TLS");
+            }
+        }
+        let internal_api = cli
+            .internal_port
+            .map(|port| {
+                let access_log = self.access_log.clone();
+                let internal_access = self.internal_access.clone();
+                HttpServer::new(move || {
+                    App::new()
+                        .wrap(middleware::from_fn(internal::guard))
+                        .wrap(middleware::from_fn(access_log::log_request))
+                        .wrap(middleware::from_fn(request_id::correlate))
+                        .wrap(middleware::NormalizePath::trim())
+                        .app_data(access_log.clone())
+                        .app_data(internal_access.clone())
+                        .configure(internal_services)
+                        .default_service(web::route().to(no_route))
+                })
+                .disable_signals()
+                .shutdown_timeout(cli.shutdown_timeout.into())
+                .bind(("0.0.0.0", port))
+            })
+            .transpose()?;
+        let mut tasks = self.spawn().await?;
+        let mut servers = Vec::new();
+        if let Some(internal_api) = internal_api {
+            info!(
+                "Serving metrics and API docs on port {}",
+                cli.internal_port.unwrap_or_default()
+            );
+            let internal_api = internal_api.run();
+            servers.push(internal_api.handle());
+            tasks.tasks.push(tokio::spawn(async move {
+                internal_api
+                    .await
+                    .context("while serving metrics and API docs")
+            }));
+        }
+
+        // Start the API and wait for either it or any background task
+        // to finish, or for a termination signal
+        let api = api.run();
+        servers.push(api.handle());
+        let mut api = tokio::spawn(api);
+        let result = async {
+            let signaled = tokio::select! {
+                api_result = &mut api => {
+                    api_result??;
+                    false
+                }
+                task_result = tasks.wait() => {
+                    task_result?;
+                    false
+                }
+                signal_result = shutdown::signal() => {
+                    signal_result?;
+                    true
+                }
+            };
+            if signaled {
+                tasks.shutdown(servers, cli.shutdown_timeout).await;
+            }
+            Ok(())
+        }
+        .await;
+        error_report::flush(Duration::from_secs(5)).await;
+        telemetry::shutdown();
+        result
+    }
+}
+
+/// The running background tasks of a dispatcher.
+pub struct Tasks {
+    tasks: Vec<JoinHandle<Result<()>>>,
+    upkeep: Vec<JoinHandle<Result<()>>>,
+    flushers: Vec<JoinHandle<Result<()>>>,
+}
+
+impl Tasks {
+    /// Wait for any background task to finish, which they only do
+    /// when failing.
+    pub async fn wait(&mut self) -> Result<()> {
+        let (result, _, _) = select_all(
+            self.tasks
+                .iter_mut()
+                .chain(self.upkeep.iter_mut())
+                .chain(self.flushers.iter_mut()),
+        )
+        .await;
+        result?
+    }
+
+    /// Shut down in phases: refuse new jobs while in-flight requests
+    /// to the given servers and the current upkeep cycles finish, and
+    /// then flush metrics. Each phase is given up to the timeout, in
+    /// seconds.
+    pub async fn shutdown(self, servers: Vec<ServerHandle>, timeout: u16) {
+        let timeout = Duration::from_secs(timeout.into());
+        info!("Shutting down; finishing in-flight requests and upkeep cycles");
+        shutdown::enter(Phase::Stopping);
+        let stopping = join(
+            join_all(servers.iter().map(|server| server.stop(true))),
+            join_all(self.upkeep),
+        );
+        match time::timeout(timeout, stopping).await {
+            Ok((_, results)) => log_task_errors(results),
+            Err(_) => warn!("Timed out waiting for in-flight requests and upkeep cycles"),
+        }
+        info!("Flushing metrics");
+        shutdown::enter(Phase::Flushing);
+        match time::timeout(timeout, join_all(self.flushers)).await {
+            Ok(results) => log_task_errors(results),
+            Err(_) => warn!("Timed out flushing metrics"),
+        }
+    }
+}
+
+/// Log the errors of background tasks finished while shutting down.
+fn log_task_errors(results: Vec<Result<Result<()>, JoinError>>) {
+    for result in results {
+        match result {
+            Ok(Err(e)) => error!("Error while shutting down: {:?}", e),
+            Err(e) => error!("Error while shutting down: {:?}", e),
+            _ => (),
+        }
+    }
+}
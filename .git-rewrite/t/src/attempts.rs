@@ -0,0 +1,81 @@
+//! Keeps track of failed attempts at starting jobs.
+//!
+//! Docker doesn't allow updating the labels of an existing container,
+//! so start failures are kept in memory, keyed by job name. This
+//! means the attempt counters are reset when the dispatcher restarts,
+//! although they're also kept in the job store, if enabled.
+
+use crate::job_store;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Static failure registry.
+static FAILURES: OnceCell<Mutex<HashMap<String, StartFailure>>> = OnceCell::new();
+
+/// Static maximum amount of start attempts.
+static MAX_ATTEMPTS: OnceCell<u16> = OnceCell::new();
+
+/// A record of failed attempts at starting a job.
+#[derive(Clone, Debug, Serialize)]
+pub struct StartFailure {
+    pub attempts: u16,
+    pub last_error: String,
+}
+
+impl StartFailure {
+    /// Whether the job has run out of start attempts, and should be
+    /// considered failed.
+    pub fn exhausted(&self) -> bool {
+        self.attempts >= max_attempts()
+    }
+}
+
+/// Get the failure registry.
+fn failures() -> &'static Mutex<HashMap<String, StartFailure>> {
+    FAILURES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Set the maximum amount of start attempts for each job.
+pub fn init(max_attempts: u16) {
+    let _ = MAX_ATTEMPTS.set(max_attempts);
+}
+
+/// Get the maximum amount of start attempts for each job.
+fn max_attempts() -> u16 {
+    MAX_ATTEMPTS.get().copied().unwrap_or(1)
+}
+
+/// Record a failed attempt at starting a job.
+pub fn record_failure<S: ToString>(name: &str, error: S) -> StartFailure {
+    let mut failures = failures().lock().unwrap();
+    let failure = failures
+        .entry(name.to_string())
+        .or_insert_with(|| StartFailure {
+            attempts: 0,
+            last_error: String::new(),
+        });
+    failure.attempts = failure.attempts.saturating_add(1);
+    failure.last_error = error.to_string();
+    let failure = failure.clone();
+    drop(failures);
+    job_store::update(name, |record| {
+        record.attempts = failure.attempts;
+        record.last_error = Some(failure.last_error.clone());
+        if failure.exhausted() {
+            record.state = job_store::State::FailedToStart;
+        }
+    });
+    failure
+}
+
+/// Forget about previous failures of a job.
+pub fn clear(name: &str) {
+    failures().lock().unwrap().remove(name);
+}
+
+/// Get the failure record of a job, if any.
+pub fn get(name: &str) -> Option<StartFailure> {
+    failures().lock().unwrap().get(name).cloned()
+}
@@ -0,0 +1,148 @@
+//! Keeps an append-only record of job creations and removals, admin
+//! actions and policy rejections, along with who performed them.
+//!
+//! Entries are written as JSON lines to a file, or posted one by one
+//! to a webhook, or both. They're written by a single task, in the
+//! order they were recorded.
+
+use crate::auth;
+use crate::docker_service::Provenance;
+use crate::ip_filter;
+use crate::request_id;
+use crate::tls;
+use actix_web::{web, HttpRequest};
+use anyhow::{Context, Result};
+use chrono::{offset::Utc, SecondsFormat};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::path::PathBuf;
+use tokio::fs::OpenOptions;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tracing::error;
+
+/// Static channel to the audit writer.
+static SENDER: OnceCell<UnboundedSender<Value>> = OnceCell::new();
+
+/// Who performed an audited action. Actions performed by the
+/// dispatcher on its own have no actor details.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Actor {
+    pub submitter: Option<String>,
+    pub api_key: Option<String>,
+    pub subject: Option<String>,
+    pub scopes: Option<Vec<String>>,
+    pub client_cert: Option<String>,
+    pub source_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+impl Actor {
+    /// The actor behind an API request, identified by the submitter
+    /// header set by an authenticating proxy, or else by the subject
+    /// of the token used or the client certificate presented, and by
+    /// the name of the API key used, if any.
+    pub fn of(request: &HttpRequest) -> Self {
+        let token = auth::token(request);
+        let client_cert = tls::client_identity(request);
+        Self {
+            submitter: request
+                .app_data::<web::Data<Provenance>>()
+                .and_then(|provenance| request.headers().get(&provenance.submitter_header))
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty())
+                .map(String::from)
+                .or_else(|| token.as_ref().and_then(|token| token.subject.clone()))
+                .or_else(|| client_cert.clone()),
+            api_key: auth::key_name(request),
+            subject: token.as_ref().and_then(|token| token.subject.clone()),
+            scopes: token.map(|token| token.scopes),
+            client_cert,
+            source_ip: ip_filter::client_ip(request).map(|address| address.to_string()),
+            request_id: request_id::get(request),
+        }
+    }
+}
+
+/// An audited action.
+#[derive(Debug, Default, Serialize)]
+pub struct Entry {
+    pub action: &'static str,
+    pub namespace: String,
+    pub job: Option<String>,
+    pub manifest_hash: Option<String>,
+    pub detail: Option<String>,
+    #[serde(flatten)]
+    pub actor: Actor,
+}
+
+/// Compute the hash of a rendered manifest.
+pub fn manifest_hash<T: Serialize>(manifest: &T) -> Option<String> {
+    let manifest = serde_json::to_vec(manifest).ok()?;
+    Some(format!("sha256:{:x}", Sha256::digest(manifest)))
+}
+
+/// Record an action, timestamped now. Nothing is recorded if auditing
+/// is disabled.
+pub fn record(entry: Entry) {
+    let Some(sender) = SENDER.get() else {
+        return;
+    };
+    let Ok(mut value) = serde_json::to_value(&entry) else {
+        return;
+    };
+    value["time"] = Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true));
+    if sender.send(value).is_err() {
+        error!("Couldn't record audit entry {:?}", entry);
+    }
+}
+
+/// Start auditing actions, returning the task that writes entries to
+/// the given file and webhook. A failure to write to the file stops
+/// the task, while webhook failures are logged along with the entry.
+pub fn init(file: Option<PathBuf>, webhook: Option<String>) -> impl Future<Output = Result<()>> {
+    let (sender, mut receiver) = unbounded_channel::<Value>();
+    let _ = SENDER.set(sender);
+    async move {
+        let mut file = match file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+                    .with_context(|| format!("while opening the audit log {:?}", path))?,
+            ),
+            None => None,
+        };
+        let client = reqwest::Client::new();
+        while let Some(entry) = receiver.recv().await {
+            if let Some(file) = file.as_mut() {
+                let mut line = entry.to_string();
+                line.push('\n');
+                file.write_all(line.as_bytes())
+                    .await
+                    .context("while writing to the audit log")?;
+                file.flush()
+                    .await
+                    .context("while writing to the audit log")?;
+            }
+            if let Some(webhook) = &webhook {
+                let result = client
+                    .post(webhook)
+                    .json(&entry)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    error!("Couldn't send audit entry {}: {:?}", entry, e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Authenticates requests to the job and admin endpoints with API
+//! keys, each one named so that the jobs created with it and the
+//! requests made with it can be told apart, or with JWTs validated
+//! by the [`jwt`](crate::jwt) module.
+
+use crate::api_error::APIError;
+use crate::internal::equals;
+use crate::jwt::{self, Refusal, Token};
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    middleware::Next,
+    web, Error, HttpMessage, HttpRequest,
+};
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Request header holding an API key, as an alternative to a bearer
+/// token.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+/// Accepted API keys, along with their names, and the scopes some of
+/// them are restricted to.
+#[derive(Default)]
+pub struct Keys {
+    keys: Vec<(String, String)>,
+    scopes: HashMap<String, Scope>,
+}
+
+/// The namespace and path prefix an API key is restricted to, so that
+/// a tenant may only create and inspect its own jobs.
+#[derive(Clone, Debug)]
+pub struct Scope {
+    pub namespace: String,
+    pub path: String,
+}
+
+impl Scope {
+    /// Parse a scope given as name=namespace:/path/prefix.
+    fn parse(scope: &str) -> Result<(String, Self)> {
+        let (name, rest) = scope
+            .split_once('=')
+            .context("API key scopes must be given as name=namespace:path")?;
+        let (namespace, path) = rest
+            .split_once(':')
+            .filter(|(namespace, path)| !namespace.is_empty() && path.starts_with("/job"))
+            .context("API key scopes must be given as name=namespace:path, with a /job path")?;
+        Ok((
+            name.trim().to_string(),
+            Self {
+                namespace: namespace.to_string(),
+                path: path.to_string(),
+            },
+        ))
+    }
+
+    /// Whether the scope covers jobs created through the given path,
+    /// i.e. the path is its prefix or lies under it, so that a scope of
+    /// /job/team-a doesn't cover /job/team-ab.
+    pub fn covers(&self, path: Option<&str>) -> bool {
+        path.and_then(|path| path.strip_prefix(self.path.as_str()))
+            .is_some_and(|rest| {
+                rest.is_empty() || rest.starts_with('/') || self.path.ends_with('/')
+            })
+    }
+}
+
+/// The name of the API key a request was authenticated with.
+#[derive(Clone)]
+struct KeyName(String);
+
+/// Parse an API key given as name=key.
+fn parse(key: &str) -> Result<(String, String)> {
+    key.split_once('=')
+        .filter(|(name, secret)| !name.trim().is_empty() && !secret.trim().is_empty())
+        .map(|(name, secret)| (name.trim().to_string(), secret.trim().to_string()))
+        .context("API keys must be given as name=key pairs")
+}
+
+impl Keys {
+    /// Gather the API keys given directly and in a file, one per line,
+    /// ignoring empty lines and `#` comments, and the scopes of the
+    /// restricted ones.
+    pub fn load(given: &[String], file: Option<&Path>, scopes: &[String]) -> Result<Self> {
+        let mut keys = given
+            .iter()
+            .filter(|key| !key.is_empty())
+            .map(|key| parse(key))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(path) = file {
+            let contents = fs::read_to_string(path)
+                .with_context(|| format!("while reading the API keys file {:?}", path))?;
+            for (number, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                keys.push(parse(line).with_context(|| {
+                    format!("at line {} of the API keys file {:?}", number + 1, path)
+                })?);
+            }
+        }
+        let scopes = scopes
+            .iter()
+            .filter(|scope| !scope.is_empty())
+            .map(|scope| Scope::parse(scope))
+            .collect::<Result<HashMap<_, _>>>()?;
+        if let Some(name) = scopes
+            .keys()
+            .find(|name| !keys.iter().any(|(key_name, _)| key_name == *name))
+        {
+            bail!("scope given for unknown API key {:?}", name);
+        }
+        Ok(Self { keys, scopes })
+    }
+
+    /// Whether any key is required.
+    pub fn is_enabled(&self) -> bool {
+        !self.keys.is_empty()
+    }
+
+    /// The name of the given key, if accepted. Every key is compared,
+    /// so that the time taken doesn't tell which one came closest.
+    fn name_of(&self, given: &str) -> Option<&str> {
+        self.keys
+            .iter()
+            .fold(None, |found, (name, secret)| {
+                let matches = equals(given.as_bytes(), secret.as_bytes());
+                found.or(matches.then_some(name))
+            })
+            .map(String::as_str)
+    }
+}
+
+/// Whether a path belongs to an endpoint requiring an API key or a
+/// token.
+fn is_protected(path: &str) -> bool {
+    path == "/job" || path.starts_with("/job/") || path.starts_with("/admin/")
+}
+
+/// The key given in a request, either as a bearer token or in the
+/// API key header.
+fn given_key(request: &ServiceRequest) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| {
+            request
+                .headers()
+                .get(API_KEY_HEADER)
+                .and_then(|value| value.to_str().ok())
+        })
+        .map(str::trim)
+}
+
+/// Check the API key or token given in a request, and remember the
+/// name of the key or the token's identity in the request.
+async fn check(request: &ServiceRequest, keys: &Keys) -> Result<(), (APIError, &'static str)> {
+    let invalid = |msg| (APIError::unauthorized(msg), "Bearer");
+    match given_key(request) {
+        Some(given) if jwt::is_enabled() && jwt::is_token(given) => {
+            let token = jwt::verify(given).await.map_err(|refusal| match refusal {
+                Refusal::Invalid(msg) => (
+                    APIError::unauthorized(msg),
+                    "Bearer error=\"invalid_token\"",
+                ),
+                Refusal::Forbidden(msg) => (
+                    APIError::forbidden(msg),
+                    "Bearer error=\"insufficient_scope\"",
+                ),
+            })?;
+            request.extensions_mut().insert(token);
+        }
+        Some(given) => {
+            let name = keys
+                .name_of(given)
+                .ok_or_else(|| invalid("Missing or invalid API key"))?;
+            if let Some(scope) = keys.scopes.get(name) {
+                if request.path().starts_with("/admin/") {
+                    Err((
+                        APIError::forbidden("Scoped API keys may not use the admin endpoints"),
+                        "Bearer",
+                    ))?;
+                }
+                request.extensions_mut().insert(scope.clone());
+            }
+            request.extensions_mut().insert(KeyName(name.to_string()));
+        }
+        None => Err(invalid("Missing API key or token"))?,
+    }
+    Ok(())
+}
+
+/// Reject requests to the job and admin endpoints lacking one of the
+/// configured API keys or a valid token, if either is required.
+pub async fn authenticate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let keys = request
+        .app_data::<web::Data<Keys>>()
+        .filter(|keys| keys.is_enabled() || jwt::is_enabled())
+        .filter(|_| is_protected(request.path()))
+        .cloned();
+    if let Some(keys) = keys {
+        if let Err((error, challenge)) = check(&request, &keys).await {
+            let mut response = request.error_response(error);
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static(challenge));
+            return Ok(response.map_into_right_body());
+        }
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// The name of the API key a request was authenticated with, if any.
+pub fn key_name(request: &HttpRequest) -> Option<String> {
+    request
+        .extensions()
+        .get::<KeyName>()
+        .map(|name| name.0.clone())
+}
+
+/// The scope of the API key a request was authenticated with, if
+/// it's restricted.
+pub fn scope(request: &HttpRequest) -> Option<Scope> {
+    request.extensions().get::<Scope>().cloned()
+}
+
+/// The token a request was authenticated with, if any.
+pub fn token(request: &HttpRequest) -> Option<Token> {
+    request.extensions().get::<Token>().cloned()
+}
@@ -0,0 +1,252 @@
+//! Defines the interface of job runtimes: plain docker containers, or
+//! alternative runtimes standing in for the docker daemon, which
+//! describe their jobs with the docker container models.
+
+use crate::docker;
+use anyhow::Result;
+use async_trait::async_trait;
+use bollard::{
+    container::Config,
+    models::{
+        ContainerInspectResponse, ContainerSummary, EventActor, EventMessage, EventMessageTypeEnum,
+    },
+};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::stream::{self, BoxStream, StreamExt};
+use once_cell::sync::OnceCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// A runtime jobs may be dispatched to.
+#[derive(Clone, PartialEq, Eq, ValueEnum)]
+pub enum Kind {
+    Docker,
+    Swarm,
+    Kubernetes,
+    Nomad,
+}
+
+/// A job about to be created.
+pub struct Creation {
+    /// Name of the job.
+    pub name: String,
+    /// Namespace the job is created in.
+    pub namespace: String,
+    /// Container configuration of the job, labeled with its namespace.
+    pub config: Config<String>,
+    /// Platform the job runs on, if requested.
+    pub platform: Option<String>,
+    /// Instructions to build the job's image, only followed by the
+    /// docker backend.
+    pub build: Option<docker::Build>,
+    /// Containers run alongside the job, only supported by the docker
+    /// backend.
+    pub sidecars: Vec<docker::Sidecar>,
+}
+
+/// A job runtime.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Check that the runtime is reachable.
+    async fn ping(&self) -> Result<()>;
+
+    /// Create a job, and return its ID. Returns None if a job with the
+    /// same name exists.
+    async fn create(&self, job: Creation) -> Result<Option<String>>;
+
+    /// Start a created job.
+    async fn start(&self, name: &str) -> Result<()>;
+
+    /// List jobs as containers, applying the subset of container list
+    /// filters relevant to jobs: label, status and exact name.
+    async fn list(&self, filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>>;
+
+    /// Inspect a job as a container.
+    async fn inspect(&self, name: &str) -> Result<ContainerInspectResponse>;
+
+    /// Get the output of a job.
+    async fn logs(&self, name: &str) -> Result<Vec<u8>>;
+
+    /// Remove a job, optionally along with its anonymous volumes. Jobs
+    /// that no longer exist are considered removed.
+    async fn remove(&self, name: &str, volumes: bool) -> Result<()>;
+
+    /// Get the stream of the given events of the jobs of a namespace,
+    /// starting with the past events since the given time if possible.
+    /// Runtimes without an events stream return None, and their events
+    /// are derived from listing their jobs periodically.
+    fn events(
+        &self,
+        _namespace: &str,
+        _actions: &[&str],
+        _since: Option<DateTime<Utc>>,
+    ) -> Result<Option<BoxStream<'static, Result<EventMessage>>>> {
+        Ok(None)
+    }
+}
+
+/// Static alternative backend instance.
+static CURRENT: OnceCell<Box<dyn Backend>> = OnceCell::new();
+
+/// Set the alternative backend jobs are dispatched to.
+pub fn init(backend: Box<dyn Backend>) {
+    let _ = CURRENT.set(backend);
+}
+
+/// Get the backend jobs are dispatched to, which is plain docker
+/// containers unless an alternative backend was set.
+pub fn current() -> &'static dyn Backend {
+    CURRENT
+        .get()
+        .map(Box::as_ref)
+        .unwrap_or(&docker::Containers)
+}
+
+/// Whether jobs are dispatched to an alternative backend, rather than
+/// as plain docker containers.
+pub fn is_alternative() -> bool {
+    CURRENT.get().is_some()
+}
+
+/// Interval between the listings the events of backends without an
+/// events stream are derived from.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Get the name of a listed job.
+fn job_name(job: &ContainerSummary) -> Option<String> {
+    job.names
+        .as_ref()?
+        .first()
+        .map(|name| name.trim_start_matches('/').to_string())
+}
+
+/// Build a container event of a listed job.
+fn event(action: &str, name: &str, job: &ContainerSummary, exit_code: Option<i64>) -> EventMessage {
+    let now = Utc::now();
+    let mut attributes = job.labels.clone().unwrap_or_default();
+    attributes.insert(String::from("name"), name.to_string());
+    if let Some(image) = &job.image {
+        attributes.insert(String::from("image"), image.clone());
+    }
+    if let Some(exit_code) = exit_code {
+        attributes.insert(String::from("exitCode"), exit_code.to_string());
+    }
+    EventMessage {
+        typ: Some(EventMessageTypeEnum::CONTAINER),
+        action: Some(action.to_string()),
+        actor: Some(EventActor {
+            id: job.id.clone(),
+            attributes: Some(attributes),
+        }),
+        time: Some(now.timestamp()),
+        time_nano: now.timestamp_nanos_opt(),
+        ..Default::default()
+    }
+}
+
+/// Derive the creation, start, exit and removal events of the jobs of a
+/// namespace from the changes seen between consecutive listings of a
+/// backend. Only jobs changing after the stream starts produce events,
+/// and changes between two listings are reported in lifecycle order.
+pub fn poll_events(
+    backend: &'static dyn Backend,
+    namespace: &str,
+) -> BoxStream<'static, Result<EventMessage>> {
+    let label = format!("{}={}", docker::JOB_LABEL_KEY, namespace);
+    let seed: Option<HashMap<String, ContainerSummary>> = None;
+    stream::unfold((seed, VecDeque::new()), move |(mut known, mut pending)| {
+        let label = label.clone();
+        async move {
+            loop {
+                if let Some(event) = pending.pop_front() {
+                    return Some((Ok(event), (known, pending)));
+                }
+                if known.is_some() {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+                let mut filters = HashMap::new();
+                filters.insert("label", vec![label.as_str()]);
+                let jobs = match backend.list(&filters).await {
+                    Ok(jobs) => jobs,
+                    Err(e) => return Some((Err(e), (known, pending))),
+                };
+                let current: HashMap<String, ContainerSummary> = jobs
+                    .into_iter()
+                    .filter_map(|job| Some((job_name(&job)?, job)))
+                    .collect();
+                let Some(previous) = known.replace(current.clone()) else {
+                    continue;
+                };
+                let active = |state: &str| matches!(state, "running" | "restarting" | "paused");
+                let finished = |state: &str| matches!(state, "exited" | "dead");
+                let mut changes = Vec::new();
+                for (name, job) in &current {
+                    let before = previous.get(name).and_then(|job| job.state.as_deref());
+                    let now = job.state.as_deref().unwrap_or_default();
+                    if before.is_none() {
+                        changes.push((name, job, "create"));
+                    }
+                    // jobs may also have run to completion between
+                    // listings
+                    if (active(now) && !before.is_some_and(active))
+                        || (finished(now) && matches!(before, None | Some("created")))
+                    {
+                        changes.push((name, job, "start"));
+                    }
+                    if finished(now) && !before.is_some_and(finished) {
+                        changes.push((name, job, "die"));
+                    }
+                }
+                for (name, job) in &previous {
+                    if !current.contains_key(name) {
+                        changes.push((name, job, "destroy"));
+                    }
+                }
+                for (name, job, action) in changes {
+                    let exit_code = if action == "die" {
+                        backend
+                            .inspect(name)
+                            .await
+                            .ok()
+                            .and_then(|job| job.state?.exit_code)
+                    } else {
+                        None
+                    };
+                    pending.push_back(event(action, name, job, exit_code));
+                }
+            }
+        }
+    })
+    .boxed()
+}
+
+/// Extract the exact names sought by a container list name filter,
+/// given as anchored regular expressions (^/name$).
+pub fn filter_names<'a>(filters: &HashMap<&str, Vec<&'a str>>) -> Option<Vec<&'a str>> {
+    filters.get("name").map(|names| {
+        names
+            .iter()
+            .map(|name| {
+                name.trim_start_matches('^')
+                    .trim_start_matches('/')
+                    .trim_end_matches('$')
+            })
+            .collect()
+    })
+}
+
+/// Whether jobs of the given kind run without a docker daemon.
+pub fn is_standalone(kind: &Kind) -> bool {
+    matches!(kind, Kind::Kubernetes | Kind::Nomad)
+}
+
+/// Check whether a listed job passes a container list status filter.
+pub fn status_matches(filters: &HashMap<&str, Vec<&str>>, container: &ContainerSummary) -> bool {
+    filters.get("status").is_none_or(|statuses| {
+        container
+            .state
+            .as_deref()
+            .is_some_and(|state| statuses.contains(&state))
+    })
+}
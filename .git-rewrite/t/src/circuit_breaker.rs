@@ -0,0 +1,72 @@
+//! Stops sending work to the docker daemon while it's unreachable.
+//!
+//! After a number of consecutive docker calls fail for transient
+//! reasons, the circuit opens: job creations are refused right away,
+//! and upkeep cycles are skipped, instead of waiting for timeouts. The
+//! daemon is then probed in the background, and the circuit closes as
+//! soon as a call succeeds.
+
+use crate::docker;
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use tokio::time::{self, Duration};
+use tracing::{debug, info, warn};
+
+/// How often the daemon is probed while the circuit is open.
+pub const PROBE_PERIOD: Duration = Duration::from_secs(5);
+
+/// Static amount of consecutive failures that open the circuit.
+static THRESHOLD: OnceCell<u32> = OnceCell::new();
+
+/// Amount of consecutive failed calls.
+static FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Whether the circuit is open.
+static OPEN: AtomicBool = AtomicBool::new(false);
+
+/// Open the circuit after the given amount of consecutive failures.
+pub fn init(threshold: u32) {
+    let _ = THRESHOLD.set(threshold);
+}
+
+/// Whether calls to the docker daemon are currently being avoided.
+pub fn is_open() -> bool {
+    OPEN.load(Ordering::SeqCst)
+}
+
+/// Record a call that reached the daemon, closing the circuit.
+pub fn record_success() {
+    FAILURES.store(0, Ordering::SeqCst);
+    if OPEN.swap(false, Ordering::SeqCst) {
+        info!("The docker daemon is reachable again; resuming work");
+    }
+}
+
+/// Record a call that failed for a transient reason, opening the
+/// circuit if too many failed in a row.
+pub fn record_failure() {
+    let Some(threshold) = THRESHOLD.get() else {
+        return;
+    };
+    let failures = FAILURES.fetch_add(1, Ordering::SeqCst).saturating_add(1);
+    if failures >= *threshold && !OPEN.swap(true, Ordering::SeqCst) {
+        warn!(
+            "{} consecutive docker calls failed; holding off work until the daemon responds",
+            failures
+        );
+    }
+}
+
+/// Probe the docker daemon endlessly while the circuit is open.
+pub async fn probe() -> Result<()> {
+    let mut interval = time::interval(PROBE_PERIOD);
+    loop {
+        interval.tick().await;
+        if is_open() {
+            if let Err(e) = docker::ping().await {
+                debug!("The docker daemon is still unreachable: {:?}", e);
+            }
+        }
+    }
+}
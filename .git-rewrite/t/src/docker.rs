@@ -0,0 +1,2106 @@
+//! Defines the global docker clients.
+
+use crate::attempts;
+use crate::backend::{self, Backend, Creation};
+use crate::cpusets;
+use crate::metrics_service;
+use crate::registry_auth;
+use crate::retry::{self, retry};
+use crate::secrets;
+use crate::ssh_tunnel;
+use crate::swarm;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, ListContainersOptions, LogsOptions, RemoveContainerOptions,
+        StopContainerOptions, UpdateContainerOptions, UploadToContainerOptions,
+    },
+    errors::Error,
+    image::{BuildImageOptions, CreateImageOptions, ListImagesOptions, RemoveImageOptions},
+    models::{
+        ContainerInspectResponse, ContainerSummary, EventMessage, HostConfigLogConfig,
+        ImageSummary, Mount, MountTypeEnum, RestartPolicy, RestartPolicyNameEnum,
+    },
+    network::{CreateNetworkOptions, InspectNetworkOptions, PruneNetworksOptions},
+    system::EventsOptions,
+    volume::{CreateVolumeOptions, RemoveVolumeOptions},
+    ClientVersion, Docker, API_DEFAULT_VERSION,
+};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+use futures::{
+    future::{self, try_join_all},
+    stream::{self, BoxStream, Stream, StreamExt, TryStreamExt},
+};
+use itertools::Itertools;
+use once_cell::sync::{Lazy, OnceCell};
+use serde::Deserialize;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Mutex,
+};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// A docker daemon jobs may be placed in.
+struct Host {
+    name: String,
+    docker: Docker,
+    platform: Platform,
+}
+
+/// The operating system and architecture containers run on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: Option<String>,
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.architecture {
+            Some(architecture) => write!(f, "{}/{}", self.os, architecture),
+            None => write!(f, "{}", self.os),
+        }
+    }
+}
+
+/// Normalize an architecture name to the one used by docker, which
+/// follows Go's naming.
+fn normalize_architecture(architecture: &str) -> String {
+    match architecture.to_lowercase().as_str() {
+        "x86_64" | "x86-64" | "x64" => String::from("amd64"),
+        "aarch64" => String::from("arm64"),
+        "armhf" | "armel" | "armv6l" | "armv7l" => String::from("arm"),
+        "i386" | "i686" | "x86" => String::from("386"),
+        other => other.to_string(),
+    }
+}
+
+/// Parse a platform given as os[/architecture[/variant]]. The variant
+/// isn't considered when placing jobs.
+pub fn parse_platform(platform: &str) -> Result<Platform, String> {
+    let mut parts = platform.split('/');
+    let os = parts.next().unwrap_or_default().trim().to_lowercase();
+    let architecture = parts.next().map(normalize_architecture);
+    if os.is_empty() || architecture.as_deref() == Some("") || parts.nth(1).is_some() {
+        return Err(format!(
+            "Platform {:?} is not of the form os[/architecture[/variant]]",
+            platform
+        ));
+    }
+    Ok(Platform { os, architecture })
+}
+
+impl Platform {
+    /// Whether containers of the given platform can run on this one.
+    fn runs(&self, requested: &Platform) -> bool {
+        self.os == requested.os
+            && requested
+                .architecture
+                .as_ref()
+                .is_none_or(|architecture| self.architecture.as_ref() == Some(architecture))
+    }
+}
+
+/// Static docker hosts.
+static HOSTS: OnceCell<Vec<Host>> = OnceCell::new();
+
+/// Static job placement strategy.
+static PLACEMENT: OnceCell<Placement> = OnceCell::new();
+
+/// Counter used for round-robin placement.
+static NEXT_HOST: AtomicUsize = AtomicUsize::new(0);
+
+/// Known locations of jobs, as indices into the hosts list.
+static LOCATIONS: Lazy<Mutex<HashMap<String, usize>>> = Lazy::new(Default::default);
+
+/// Network jobs are attached to by default, if any.
+static NETWORK: OnceCell<String> = OnceCell::new();
+
+/// Path at which jobs get their workspace volume mounted, if any.
+static WORKSPACE: OnceCell<String> = OnceCell::new();
+
+/// Logging configuration given to jobs that don't set their own.
+static LOG_CONFIG: OnceCell<HostConfigLogConfig> = OnceCell::new();
+
+/// Parent cgroup given to jobs that don't set their own, if any.
+static CGROUP_PARENT: OnceCell<String> = OnceCell::new();
+
+/// Output of the image build of a job.
+type BuildLog = (String, Vec<u8>);
+
+/// Output of the image builds of existing jobs, ordered from oldest to
+/// newest build.
+static BUILD_LOGS: Lazy<Mutex<VecDeque<BuildLog>>> = Lazy::new(Default::default);
+
+/// Maximum amount of build outputs kept.
+const MAX_BUILD_LOGS: usize = 1000;
+
+/// Maximum size of a kept build output, beyond which its beginning is
+/// dropped.
+const MAX_BUILD_LOG_SIZE: usize = 1 << 20;
+
+/// A strategy for spreading jobs across docker hosts.
+#[derive(Clone, ValueEnum)]
+pub enum Placement {
+    ByName,
+    RoundRobin,
+    LeastLoaded,
+}
+
+/// A means of connecting to the docker daemon.
+#[derive(Clone, ValueEnum)]
+pub enum Transport {
+    Http,
+    Tls,
+    Socket,
+    Ssh,
+}
+
+/// Settings for connecting to the docker daemon. Unset values fall
+/// back to the defaults of each transport.
+pub struct Connection {
+    pub transport: Transport,
+    pub hosts: Vec<String>,
+    pub socket: Option<String>,
+    pub ca: Option<PathBuf>,
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    pub ssh_user: Option<String>,
+    pub ssh_key: Option<PathBuf>,
+    pub connect_timeout: u16,
+    pub read_timeout: u16,
+    pub api_version: Option<ClientVersion>,
+}
+
+/// Static timeout for pinging the docker hosts.
+static PING_TIMEOUT: OnceCell<Duration> = OnceCell::new();
+
+/// Default location of the docker daemon's socket.
+const DEFAULT_SOCKET: &str = "/var/run/docker.sock";
+
+/// Default location of the TLS certificates, following the docker
+/// CLI's conventions.
+fn default_cert_path() -> Result<PathBuf> {
+    if let Some(path) = env::var_os("DOCKER_CERT_PATH") {
+        return Ok(PathBuf::from(path));
+    }
+    env::var_os("HOME")
+        .map(|home| PathBuf::from(home).join(".docker"))
+        .context("couldn't determine the location of TLS certificates")
+}
+
+/// Default address of the docker daemon for the HTTP transport.
+const DEFAULT_HTTP_ADDRESS: &str = "localhost:2375";
+
+/// Default address of the docker daemon for the TLS transport.
+const DEFAULT_TLS_ADDRESS: &str = "localhost:2376";
+
+/// Get the paths of the CA certificate, the client certificate and
+/// the client key used with the TLS transport.
+fn tls_material(connection: &Connection) -> Result<(PathBuf, PathBuf, PathBuf)> {
+    let material = |given: Option<PathBuf>, default_name: &str| -> Result<PathBuf> {
+        match given {
+            Some(path) => Ok(path),
+            None => Ok(default_cert_path()?.join(default_name)),
+        }
+    };
+    Ok((
+        material(connection.ca.clone(), "ca.pem")?,
+        material(connection.cert.clone(), "cert.pem")?,
+        material(connection.key.clone(), "key.pem")?,
+    ))
+}
+
+/// Get the path of the local end of the SSH tunnel to the docker host
+/// of the given index.
+fn tunnel_socket(index: usize) -> PathBuf {
+    env::temp_dir().join(format!(
+        "{}-{}-{}.sock",
+        env!("CARGO_PKG_NAME"),
+        process::id(),
+        index
+    ))
+}
+
+/// Check that a file of TLS material exists and holds a PEM-encoded
+/// block of the expected kind, to fail early with a helpful message.
+fn check_pem(path: &Path, description: &str, kind: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("while reading the TLS {} at {:?}", description, path))?;
+    if !contents
+        .lines()
+        .any(|line| line.starts_with("-----BEGIN ") && line.contains(kind))
+    {
+        bail!(
+            "the TLS {} at {:?} doesn't contain a PEM-encoded {}",
+            description,
+            path,
+            kind.to_lowercase()
+        );
+    }
+    Ok(())
+}
+
+/// Get the socket path designated by a docker host URL.
+fn socket_path(host: &str) -> Result<&str> {
+    host.strip_prefix("unix://")
+        .with_context(|| format!("docker host {:?} is not a unix socket URL", host))
+}
+
+/// Get the TCP address designated by a docker host URL.
+fn tcp_address(host: &str) -> Result<&str> {
+    ["tcp://", "http://", "https://"]
+        .iter()
+        .find_map(|scheme| host.strip_prefix(scheme))
+        .with_context(|| format!("docker host {:?} is not a TCP URL", host))
+}
+
+/// Where a docker daemon is reached, once any tunnel to it is open.
+enum Endpoint {
+    Http(Option<String>),
+    Tls {
+        address: String,
+        ca: PathBuf,
+        cert: PathBuf,
+        key: PathBuf,
+    },
+    Socket(String),
+}
+
+/// Oldest docker API version the dispatcher works with, which is the
+/// first to accept a platform when creating containers (docker 20.10).
+const MIN_API_VERSION: ClientVersion = ClientVersion {
+    major_version: 1,
+    minor_version: 41,
+};
+
+/// Parse a docker API version given as major.minor.
+pub fn parse_api_version(version: &str) -> Result<ClientVersion> {
+    match version
+        .split_once('.')
+        .map(|(major, minor)| (major.parse(), minor.parse()))
+    {
+        Some((Ok(major_version), Ok(minor_version))) => Ok(ClientVersion {
+            major_version,
+            minor_version,
+        }),
+        _ => bail!("invalid docker API version {:?}", version),
+    }
+}
+
+/// Resolve the endpoint of a single docker daemon, given by its index
+/// in the list of hosts. For the SSH transport, a tunnel to the remote
+/// daemon is opened first.
+async fn open(connection: &Connection, host: Option<&str>, index: usize) -> Result<Endpoint> {
+    Ok(match connection.transport {
+        Transport::Http => Endpoint::Http(host.map(tcp_address).transpose()?.map(String::from)),
+        Transport::Tls => {
+            let address = host
+                .map(tcp_address)
+                .transpose()?
+                .unwrap_or(DEFAULT_TLS_ADDRESS);
+            let (ca, cert, key) = tls_material(connection)?;
+            check_pem(&ca, "CA certificate", "CERTIFICATE")?;
+            check_pem(&cert, "client certificate", "CERTIFICATE")?;
+            check_pem(&key, "client key", "PRIVATE KEY")?;
+            Endpoint::Tls {
+                address: address.to_string(),
+                ca,
+                cert,
+                key,
+            }
+        }
+        Transport::Socket => Endpoint::Socket(
+            match (connection.socket.as_deref(), host) {
+                (Some(socket), _) => socket,
+                (None, Some(host)) => socket_path(host)?,
+                (None, None) => DEFAULT_SOCKET,
+            }
+            .to_string(),
+        ),
+        Transport::Ssh => {
+            let (user, host, port) = ssh_tunnel::parse_url(
+                host.context("the SSH transport requires --docker-host ssh://[user@]host[:port]")?,
+            )?;
+            let local_socket = tunnel_socket(index);
+            ssh_tunnel::open(
+                ssh_tunnel::Destination {
+                    host,
+                    port,
+                    user: connection.ssh_user.clone().or(user),
+                    key: connection.ssh_key.clone(),
+                    remote_socket: connection
+                        .socket
+                        .clone()
+                        .unwrap_or_else(|| String::from(DEFAULT_SOCKET)),
+                },
+                &local_socket,
+            )
+            .await
+            .context("while opening an SSH tunnel to the docker daemon")?;
+            Endpoint::Socket(local_socket.to_string_lossy().into_owned())
+        }
+    })
+}
+
+/// Build an HTTP client for the parts of the docker API the docker
+/// client doesn't cover, along with the base URL of its requests. It
+/// reaches the docker host of the given index the same way the docker
+/// client does, once connected, and speaks the same API version.
+fn http_client(
+    connection: &Connection,
+    host: Option<&str>,
+    index: usize,
+    version: &ClientVersion,
+) -> Result<(reqwest::Client, String)> {
+    let builder = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(u64::from(connection.connect_timeout)))
+        .timeout(Duration::from_secs(u64::from(connection.read_timeout)));
+    let (builder, address) = match connection.transport {
+        Transport::Http => (
+            builder,
+            format!(
+                "http://{}",
+                host.map(tcp_address)
+                    .transpose()?
+                    .unwrap_or(DEFAULT_HTTP_ADDRESS)
+            ),
+        ),
+        Transport::Tls => {
+            let (ca, cert, key) = tls_material(connection)?;
+            let ca = std::fs::read(ca).context("while reading the CA certificate")?;
+            let identity = [
+                std::fs::read(cert).context("while reading the client certificate")?,
+                std::fs::read(key).context("while reading the client key")?,
+            ]
+            .concat();
+            (
+                builder
+                    .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+                    .identity(reqwest::Identity::from_pem(&identity)?),
+                format!(
+                    "https://{}",
+                    host.map(tcp_address)
+                        .transpose()?
+                        .unwrap_or(DEFAULT_TLS_ADDRESS)
+                ),
+            )
+        }
+        Transport::Socket => {
+            let path = match (connection.socket.as_deref(), host) {
+                (Some(socket), _) => socket,
+                (None, Some(host)) => socket_path(host)?,
+                (None, None) => DEFAULT_SOCKET,
+            };
+            (builder.unix_socket(path), String::from("http://localhost"))
+        }
+        Transport::Ssh => (
+            builder.unix_socket(tunnel_socket(index)),
+            String::from("http://localhost"),
+        ),
+    };
+    Ok((
+        builder
+            .build()
+            .context("while building an HTTP client for the docker daemon")?,
+        format!("{}/v{}", address, version),
+    ))
+}
+
+/// Build a client for a docker daemon, speaking the given API version.
+fn client(endpoint: &Endpoint, timeout: u64, version: &ClientVersion) -> Result<Docker> {
+    Ok(match endpoint {
+        Endpoint::Http(address) => Docker::connect_with_http(
+            &format!(
+                "http://{}",
+                address.as_deref().unwrap_or(DEFAULT_HTTP_ADDRESS)
+            ),
+            timeout,
+            version,
+        )
+        .context("while connecting to the docker daemon via HTTP")?,
+        Endpoint::Tls {
+            address,
+            ca,
+            cert,
+            key,
+        } => Docker::connect_with_ssl(
+            &format!("https://{}", address),
+            key,
+            cert,
+            ca,
+            timeout,
+            version,
+        )
+        .context("while connecting to the docker daemon via HTTP over TLS")?,
+        Endpoint::Socket(path) => {
+            Docker::connect_with_unix(path, timeout, version).with_context(|| {
+                format!(
+                    "while connecting to the docker daemon via socket {:?}",
+                    path
+                )
+            })?
+        }
+    })
+}
+
+/// Settle on the docker API version used with a daemon: the pinned
+/// one, or else the newest supported by both the daemon and the
+/// dispatcher. Daemons too old for the dispatcher are rejected.
+async fn negotiate(
+    endpoint: &Endpoint,
+    timeout: u64,
+    pinned: Option<ClientVersion>,
+) -> Result<(ClientVersion, Platform)> {
+    let query = |version| async move {
+        let probe = client(endpoint, timeout, version)?;
+        Ok::<_, anyhow::Error>(retry(&retry::READ, || probe.version()).await)
+    };
+    // daemons reject requests for versions newer or older than they
+    // support, so the oldest usable version is tried last
+    let daemon = match query(API_DEFAULT_VERSION).await? {
+        Err(Error::DockerResponseServerError {
+            status_code: 400, ..
+        }) => query(&MIN_API_VERSION).await?,
+        result => result,
+    }
+    .context("while querying the docker daemon's version")?;
+    let supported = daemon
+        .api_version
+        .as_deref()
+        .map(parse_api_version)
+        .transpose()?
+        .context("the docker daemon didn't report its API version")?;
+    let daemon_version = daemon.version.unwrap_or_default();
+    if supported < MIN_API_VERSION {
+        bail!(
+            "docker {} supports API versions up to {}, but at least {} is required; \
+             upgrade the docker daemon to version 20.10 or later",
+            daemon_version,
+            supported,
+            MIN_API_VERSION
+        );
+    }
+    let version = match pinned {
+        Some(pinned) if pinned > supported => bail!(
+            "docker {} supports API versions up to {}, \
+             so --docker-api-version {} can't be used",
+            daemon_version,
+            supported,
+            pinned
+        ),
+        Some(pinned) => pinned,
+        None if supported < *API_DEFAULT_VERSION => supported,
+        None => *API_DEFAULT_VERSION,
+    };
+    if let Some(oldest) = daemon
+        .min_api_version
+        .as_deref()
+        .and_then(|oldest| parse_api_version(oldest).ok())
+        .filter(|oldest| version < *oldest)
+    {
+        bail!(
+            "docker {} supports API versions from {} on, \
+             so --docker-api-version {} can't be used",
+            daemon_version,
+            oldest,
+            version
+        );
+    }
+    let platform = Platform {
+        os: daemon.os.unwrap_or_else(|| String::from("linux")),
+        architecture: daemon.arch.as_deref().map(normalize_architecture),
+    };
+    Ok((version, platform))
+}
+
+/// Connect to a single docker daemon, given by its index in the list
+/// of hosts, negotiating the API version to use. The platform the
+/// daemon runs containers on is returned along with the client.
+async fn connect(
+    connection: &Connection,
+    host: Option<&str>,
+    index: usize,
+) -> Result<(Docker, Platform)> {
+    let timeout = u64::from(connection.read_timeout);
+    let endpoint = open(connection, host, index).await?;
+    let (version, platform) = negotiate(&endpoint, timeout, connection.api_version).await?;
+    debug!(
+        "Using docker API version {} with host {:?} running {}",
+        version,
+        host.unwrap_or(DEFAULT_HOST_NAME),
+        platform
+    );
+    Ok((client(&endpoint, timeout, &version)?, platform))
+}
+
+/// Initialize the global docker hosts, and the strategy used to place
+/// jobs in them. Without explicit hosts, a single host is connected to
+/// using the defaults of the transport. With the swarm backend, the
+/// single host must be a swarm manager.
+pub async fn init(
+    connection: Connection,
+    placement: Placement,
+    backend_kind: &backend::Kind,
+) -> Result<()> {
+    let names: Vec<Option<&str>> = match connection
+        .hosts
+        .iter()
+        .map(String::as_str)
+        .filter(|host| !host.is_empty())
+        .collect::<Vec<_>>()
+    {
+        hosts if hosts.is_empty() => vec![None],
+        hosts => hosts.into_iter().map(Some).collect(),
+    };
+    if connection
+        .api_version
+        .is_some_and(|version| version < MIN_API_VERSION)
+    {
+        bail!("--docker-api-version must be at least {}", MIN_API_VERSION);
+    }
+    if names.len() > 1 && connection.socket.is_some() {
+        if let Transport::Socket = connection.transport {
+            bail!("--docker-socket can't be used along with several docker hosts");
+        }
+    }
+    let mut hosts = Vec::new();
+    for (index, name) in names.into_iter().enumerate() {
+        let (docker, platform) = connect(&connection, name, index).await.with_context(|| {
+            format!(
+                "while connecting to docker host {:?}",
+                name.unwrap_or(DEFAULT_HOST_NAME)
+            )
+        })?;
+        hosts.push(Host {
+            name: name.unwrap_or(DEFAULT_HOST_NAME).to_string(),
+            docker,
+            platform,
+        });
+    }
+    if *backend_kind == backend::Kind::Swarm {
+        match hosts.as_slice() {
+            [host] => {
+                let (tasks, base_url) = http_client(
+                    &connection,
+                    connection.hosts.first().map(String::as_str),
+                    0,
+                    &host.docker.client_version(),
+                )?;
+                backend::init(Box::new(
+                    swarm::Swarm::connect(host.docker.clone(), tasks, base_url).await?,
+                ))
+            }
+            _ => bail!("the swarm backend can't be used along with several docker hosts"),
+        }
+    }
+    let _ = HOSTS.set(hosts);
+    let _ = PLACEMENT.set(placement);
+    let _ = PING_TIMEOUT.set(Duration::from_secs(u64::from(connection.connect_timeout)));
+    Ok(())
+}
+
+/// Name of the docker host connected to when none is given.
+const DEFAULT_HOST_NAME: &str = "default";
+
+/// Get the static docker hosts.
+fn hosts() -> Result<&'static [Host]> {
+    HOSTS
+        .get()
+        .map(Vec::as_slice)
+        .context("docker client has not been initialized")
+}
+
+/// Jobs dispatched as containers of the docker hosts, the default
+/// backend.
+pub struct Containers;
+
+#[async_trait]
+impl Backend for Containers {
+    async fn ping(&self) -> Result<()> {
+        ping_hosts().await
+    }
+
+    async fn create(&self, job: Creation) -> Result<Option<String>> {
+        create_in_host(job).await
+    }
+
+    async fn start(&self, name: &str) -> Result<()> {
+        start_in_host(name).await
+    }
+
+    async fn list(&self, filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>> {
+        list_in_hosts(filters).await
+    }
+
+    async fn inspect(&self, name: &str) -> Result<ContainerInspectResponse> {
+        let host = host_of(name).await?;
+        Ok(retry(&retry::READ, || host.docker.inspect_container(name, None)).await?)
+    }
+
+    async fn logs(&self, name: &str) -> Result<Vec<u8>> {
+        let options = LogsOptions::<String> {
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        };
+        let build_output = BUILD_LOGS
+            .lock()
+            .ok()
+            .and_then(|logs| {
+                logs.iter()
+                    .find(|(job, _)| job == name)
+                    .map(|(_, output)| output.clone())
+            })
+            .unwrap_or_default();
+        let host = host_of(name).await?;
+        Ok(retry(&retry::READ, || {
+            host.docker.logs(name, Some(options.clone())).try_fold(
+                build_output.clone(),
+                |mut output, chunk| async move {
+                    output.extend_from_slice(chunk.as_ref());
+                    Ok(output)
+                },
+            )
+        })
+        .await?)
+    }
+
+    async fn remove(&self, name: &str, volumes: bool) -> Result<()> {
+        remove_from_host(name, volumes).await
+    }
+
+    fn events(
+        &self,
+        namespace: &str,
+        actions: &[&str],
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Option<BoxStream<'static, Result<EventMessage>>>> {
+        Ok(Some(
+            host_events(namespace, actions, since)?
+                .map_err(anyhow::Error::from)
+                .boxed(),
+        ))
+    }
+}
+
+/// Test the connection with the backend.
+pub async fn ping() -> Result<()> {
+    backend::current().ping().await
+}
+
+/// Test the connection with every docker daemon.
+async fn ping_hosts() -> Result<()> {
+    try_join_all(hosts()?.iter().map(|host| async move {
+        let docker = match PING_TIMEOUT.get() {
+            Some(timeout) => host.docker.clone().with_timeout(*timeout),
+            None => host.docker.clone(),
+        };
+        retry(&retry::PING, || docker.ping())
+            .await
+            .with_context(|| format!("while pinging docker host {:?}", host.name))
+    }))
+    .await?;
+    Ok(())
+}
+
+/// List jobs through the backend. With an alternative backend, jobs
+/// are listed as containers.
+async fn list(options: ListContainersOptions<&str>) -> Result<Vec<ContainerSummary>> {
+    backend::current().list(&options.filters).await
+}
+
+/// List containers across every docker host, including stopped ones.
+async fn list_in_hosts(filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>> {
+    let options = ListContainersOptions {
+        all: true,
+        filters: filters.clone(),
+        ..Default::default()
+    };
+    Ok(try_join_all(hosts()?.iter().map(|host| {
+        retry(&retry::READ, || {
+            host.docker.list_containers(Some(options.clone()))
+        })
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .collect())
+}
+
+/// Find the host a job was placed in. Returns None if the job doesn't
+/// exist in any host. Should containers of the same name exist in
+/// several hosts, the one whose host label names its host is the job.
+async fn locate(name: &str) -> Result<Option<&'static Host>> {
+    let hosts = hosts()?;
+    if let [host] = hosts {
+        return Ok(Some(host));
+    }
+    if let Some(index) = LOCATIONS.lock().ok().and_then(|l| l.get(name).copied()) {
+        return Ok(hosts.get(index));
+    }
+    let found: Vec<Option<bool>> = try_join_all(hosts.iter().map(|host| async move {
+        match retry(&retry::READ, || host.docker.inspect_container(name, None)).await {
+            Ok(container) => Ok(Some(
+                container
+                    .config
+                    .and_then(|config| config.labels)
+                    .and_then(|labels| labels.get(HOST_LABEL_KEY).cloned())
+                    .is_some_and(|label| label == host.name),
+            )),
+            Err(Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }))
+    .await?;
+    let found = found
+        .iter()
+        .position(|labeled| *labeled == Some(true))
+        .or_else(|| found.iter().position(Option::is_some));
+    if let (Some(index), Ok(mut locations)) = (found, LOCATIONS.lock()) {
+        locations.insert(name.to_string(), index);
+    }
+    Ok(found.and_then(|index| hosts.get(index)))
+}
+
+/// Record the locations of existing jobs, as given by their host
+/// label, so they needn't be searched for. Returns the amount of jobs
+/// located.
+pub fn adopt<'a>(jobs: impl IntoIterator<Item = &'a ContainerSummary>) -> usize {
+    let (Ok(hosts), Ok(mut locations)) = (hosts(), LOCATIONS.lock()) else {
+        return 0;
+    };
+    jobs.into_iter()
+        .filter_map(|job| {
+            let name = job.names.as_ref()?.first()?.trim_start_matches('/');
+            let host = label(job, HOST_LABEL_KEY)?;
+            let index = hosts.iter().position(|h| h.name == host)?;
+            locations.insert(name.to_string(), index);
+            Some(())
+        })
+        .count()
+}
+
+/// Forget the location of a removed job.
+fn forget(name: &str) {
+    if let Ok(mut locations) = LOCATIONS.lock() {
+        locations.remove(name);
+    }
+}
+
+/// Get the host a job was placed in, failing if it doesn't exist.
+async fn host_of(name: &str) -> Result<&'static Host> {
+    locate(name)
+        .await?
+        .with_context(|| format!("job {:?} doesn't exist", name))
+}
+
+/// Pick the host of a job among the candidate hosts out of its name,
+/// by rendezvous hashing, so that jobs of the same name are always
+/// placed in the same host.
+fn host_by_name(hosts: &[Host], candidates: &[usize], name: &str) -> usize {
+    candidates
+        .iter()
+        .copied()
+        .max_by_key(|index| {
+            let digest = Sha1::digest(format!("{}/{}", hosts[*index].name, name));
+            u64::from_be_bytes(digest[..8].try_into().unwrap_or_default())
+        })
+        .unwrap_or_default()
+}
+
+/// Get the hosts able to run jobs of the given platform, as indices
+/// into the hosts list. Without a platform, every host is able to.
+fn compatible_hosts(hosts: &[Host], platform: Option<&Platform>) -> Vec<usize> {
+    hosts
+        .iter()
+        .enumerate()
+        .filter(|(_, host)| platform.is_none_or(|platform| host.platform.runs(platform)))
+        .map(|(index, _)| index)
+        .collect()
+}
+
+/// Check that some docker host is able to run jobs of the given
+/// platform. Alternative backends are left to validate platforms
+/// themselves.
+pub fn check_platform(platform: &str) -> Result<(), String> {
+    let requested = parse_platform(platform)?;
+    let Some(hosts) = HOSTS.get() else {
+        return Ok(());
+    };
+    if backend::is_alternative() || !compatible_hosts(hosts, Some(&requested)).is_empty() {
+        return Ok(());
+    }
+    Err(format!(
+        "no docker host runs platform {}; available platforms are {}",
+        requested,
+        hosts
+            .iter()
+            .map(|host| host.platform.to_string())
+            .unique()
+            .join(", ")
+    ))
+}
+
+/// Choose the host to place a new job of the given name and namespace
+/// in, among those able to run its platform, according to the
+/// placement strategy.
+async fn place(
+    name: &str,
+    namespace: &str,
+    platform: Option<&str>,
+) -> Result<(usize, &'static Host)> {
+    let hosts = hosts()?;
+    let requested = platform
+        .map(parse_platform)
+        .transpose()
+        .map_err(|e| anyhow!(e))?;
+    let candidates = compatible_hosts(hosts, requested.as_ref());
+    let index = match candidates.as_slice() {
+        [] => bail!(
+            "no docker host runs platform {}",
+            requested.map(|p| p.to_string()).unwrap_or_default()
+        ),
+        [index] => *index,
+        _ => match PLACEMENT.get() {
+            Some(Placement::LeastLoaded) => {
+                let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+                let mut filters = HashMap::new();
+                filters.insert("status", vec!["created", "restarting", "running"]);
+                filters.insert("label", vec![label_filter.as_str()]);
+                let options = ListContainersOptions {
+                    all: true,
+                    limit: None,
+                    size: false,
+                    filters,
+                };
+                try_join_all(
+                    candidates
+                        .iter()
+                        .map(|index| hosts[*index].docker.list_containers(Some(options.clone()))),
+                )
+                .await
+                .context("while measuring the load of docker hosts")?
+                .iter()
+                .zip(candidates.iter())
+                .min_by_key(|(containers, _)| containers.len())
+                .map(|(_, index)| *index)
+                .unwrap_or_default()
+            }
+            Some(Placement::RoundRobin) => {
+                candidates[NEXT_HOST.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            _ => host_by_name(hosts, &candidates, name),
+        },
+    };
+    Ok((index, &hosts[index]))
+}
+
+/// A label key to use when annotating containers.
+pub const JOB_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".namespace");
+
+/// A label key used to annotate containers with the path they were
+/// created through.
+pub const PATH_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".path");
+
+/// A label key used to annotate containers with the identity of whoever
+/// submitted them.
+pub const SUBMITTER_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".submitter");
+
+/// A label key used to annotate containers with the name of the API
+/// key they were submitted with.
+pub const API_KEY_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".api-key");
+
+/// A label key used to annotate containers with the identity of the
+/// client certificate they were submitted with.
+pub const CLIENT_CERT_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".client-cert");
+
+/// A label key used to annotate containers with the ID of the request
+/// they were created by.
+pub const REQUEST_ID_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".request-id");
+
+/// A label key used to annotate containers with the version of the
+/// dispatcher that created them.
+pub const VERSION_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".version");
+
+/// A label key used to annotate containers with their mutual
+/// exclusion group.
+pub const MUTEX_GROUP_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".mutex-group");
+
+/// A label key used to annotate containers with the amount of quota
+/// slots they take.
+pub const SLOTS_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".slots");
+
+/// A label key used to annotate containers with their kind.
+pub const KIND_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".kind");
+
+/// A label key used to annotate containers with the docker host they
+/// were placed in.
+pub const HOST_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".host");
+
+/// A label key used to annotate the dedicated network of a namespace,
+/// which is removed separately from the networks created by jobs.
+const NETWORK_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".network");
+
+/// A label key used to annotate containers with the amount of GPUs
+/// they take.
+pub const GPUS_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".gpus");
+
+/// A label key used to annotate sidecar containers with the job they
+/// belong to. Sidecars don't carry the namespace label, so that
+/// they're never considered jobs on their own.
+const SIDECAR_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".sidecar-of");
+
+/// A label key used to annotate containers with the comma-separated
+/// names of the secrets they're given.
+pub const SECRETS_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".secrets");
+
+/// Infix of the names of sidecar containers, between the names of the
+/// job and of the sidecar.
+pub const SIDECAR_INFIX: &str = ".sidecar-";
+
+/// Whether a job name holds the infix reserved for the names of
+/// sidecars, which could make it collide with the sidecar of another
+/// job.
+pub fn is_reserved_name(name: &str) -> bool {
+    name.contains(SIDECAR_INFIX)
+}
+
+/// A label key used to annotate containers removed automatically by
+/// docker once they exit.
+pub const AUTO_REMOVE_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".auto-remove");
+
+/// A label key used to annotate containers with the way they take a
+/// CPU set from the pool, either shared or exclusive.
+pub const CPUSET_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".cpuset");
+
+/// A label key used to annotate containers with the subject their
+/// completion is published to.
+pub const REPLY_SUBJECT_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".reply-subject");
+
+/// A label key used to annotate workspace volumes with the job they
+/// belong to.
+const WORKSPACE_LABEL_KEY: &str = concat!(env!("CARGO_PKG_NAME"), ".workspace");
+
+/// The kind label value of long-running service jobs.
+pub const SERVICE_KIND: &str = "service";
+
+/// Insert a label into a container configuration.
+pub fn insert_label(c: Config<String>, key: &str, value: &str) -> Config<String> {
+    let mut labels = c.labels.unwrap_or_default();
+    labels.insert(key.to_string(), value.to_string());
+    Config {
+        labels: Some(labels),
+        ..c
+    }
+}
+
+/// Insert the grouping annotation into a container configuration.
+fn insert_job_label(c: Config<String>, namespace: &str) -> Config<String> {
+    insert_label(c, JOB_LABEL_KEY, namespace)
+}
+
+/// Get the value of a label of a listed container.
+pub fn label<'a>(container: &'a ContainerSummary, key: &str) -> Option<&'a str> {
+    container
+        .labels
+        .as_ref()
+        .and_then(|labels| labels.get(key))
+        .map(String::as_str)
+}
+
+/// Whether a listed container is a long-running service job.
+pub fn is_service(container: &ContainerSummary) -> bool {
+    label(container, KIND_LABEL_KEY) == Some(SERVICE_KIND)
+}
+
+/// Make a container configuration that of a service job, which the
+/// docker daemon restarts whenever it exits, unless it sets a restart
+/// policy of its own. The daemon waits before each restart twice as
+/// long as before the previous one, and keeps count of the restarts
+/// on the container, so that crashing services don't restart in a
+/// tight loop.
+pub fn as_service(c: Config<String>) -> Config<String> {
+    let mut host_config = c.host_config.unwrap_or_default();
+    host_config.restart_policy.get_or_insert(RestartPolicy {
+        name: Some(RestartPolicyNameEnum::UNLESS_STOPPED),
+        maximum_retry_count: None,
+    });
+    insert_label(
+        Config {
+            host_config: Some(host_config),
+            ..c
+        },
+        KIND_LABEL_KEY,
+        SERVICE_KIND,
+    )
+}
+
+/// Add the implicit latest tag to an image reference lacking both a
+/// tag and a digest, to avoid pulling every tag of the repository.
+fn with_default_tag(image: &str) -> String {
+    let last = image.rsplit('/').next().unwrap_or(image);
+    if image.contains('@') || last.contains(':') {
+        image.to_string()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+/// Pull an image if it's not present locally, using the credentials
+/// configured for its registry. Pull progress is logged.
+#[tracing::instrument(skip(docker))]
+async fn ensure_image(docker: &Docker, image: &str, platform: Option<&str>) -> Result<()> {
+    match docker.inspect_image(image).await {
+        Ok(_) => return Ok(()),
+        Err(Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => (),
+        Err(e) => Err(e).context("while inspecting image")?,
+    }
+    let reference = with_default_tag(image);
+    let registry = registry_auth::registry_of(&reference).to_string();
+    info!("Pulling image {:?}", reference);
+    let result = docker
+        .create_image(
+            Some(CreateImageOptions {
+                from_image: reference.clone(),
+                platform: platform.map(String::from).unwrap_or_default(),
+                ..Default::default()
+            }),
+            None,
+            registry_auth::credentials_for(&reference),
+        )
+        .try_for_each(|progress| {
+            if let Some(status) = progress.status {
+                debug!(
+                    "Pulling image {:?}: {}{}",
+                    reference,
+                    progress
+                        .id
+                        .map(|id| format!("{}: ", id))
+                        .unwrap_or_default(),
+                    progress
+                        .progress
+                        .map(|bar| format!("{} {}", status, bar))
+                        .unwrap_or(status)
+                );
+            }
+            futures::future::ok(())
+        })
+        .await;
+    metrics_service::record_pull(&registry, result.is_ok());
+    result.with_context(|| format!("while pulling image {:?}", reference))?;
+    info!("Pulled image {:?}", reference);
+    Ok(())
+}
+
+/// Instructions to build the image of a job, given exactly one source
+/// for the build context.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Build {
+    /// URL of a git repository or a tarball holding the build context.
+    context: Option<String>,
+    /// Base64-encoded tar archive holding the build context.
+    tar: Option<String>,
+    /// Inline Dockerfile, built with an empty context.
+    dockerfile: Option<String>,
+    /// Build-time variables.
+    #[serde(default)]
+    build_args: HashMap<String, String>,
+}
+
+impl Build {
+    /// Get the tar archive sent as build context, if the context isn't
+    /// fetched by the docker daemon.
+    fn archive(&self) -> Result<Option<Vec<u8>>> {
+        match (&self.context, &self.tar, &self.dockerfile) {
+            (Some(_), None, None) => Ok(None),
+            (None, Some(tar), None) => Ok(Some(
+                STANDARD
+                    .decode(tar)
+                    .context("while decoding the build context")?,
+            )),
+            (None, None, Some(dockerfile)) => {
+                let mut builder = tar::Builder::new(Vec::new());
+                let mut header = tar::Header::new_gnu();
+                header.set_mode(0o644);
+                header.set_size(dockerfile.len() as u64);
+                builder.append_data(&mut header, "Dockerfile", dockerfile.as_bytes())?;
+                Ok(Some(builder.into_inner()?))
+            }
+            _ => bail!("builds must give exactly one of Context, Tar or Dockerfile"),
+        }
+    }
+}
+
+/// Get the tag of the image built for a job.
+pub fn build_tag(name: &str) -> String {
+    // tags are limited to 128 characters
+    format!(
+        "{}-build:{}",
+        env!("CARGO_PKG_NAME"),
+        name.chars().take(128).collect::<String>()
+    )
+}
+
+/// Build the image of a job, and keep the build output so that it's
+/// reported along with the job's logs.
+#[tracing::instrument(skip_all, fields(job = name))]
+async fn build_image(
+    docker: &Docker,
+    name: &str,
+    build: &Build,
+    platform: Option<&str>,
+) -> Result<()> {
+    let tag = build_tag(name);
+    let archive = build.archive()?;
+    info!("Building image {:?}", tag);
+    let mut output = Vec::new();
+    let mut builds = docker.build_image(
+        BuildImageOptions {
+            t: tag.clone(),
+            remote: build.context.clone().unwrap_or_default(),
+            buildargs: build.build_args.clone(),
+            platform: platform.map(String::from).unwrap_or_default(),
+            rm: true,
+            forcerm: true,
+            ..Default::default()
+        },
+        Some(registry_auth::build_credentials()),
+        archive.map(Vec::into),
+    );
+    while let Some(info) = builds
+        .try_next()
+        .await
+        .with_context(|| format!("while building image {:?}", tag))?
+    {
+        if let Some(stream) = info.stream {
+            debug!("Building image {:?}: {}", tag, stream.trim_end());
+            output.extend_from_slice(stream.as_bytes());
+        }
+        if let Some(error) = info.error {
+            bail!("image build failed: {}", error);
+        }
+    }
+    info!("Built image {:?}", tag);
+    if output.len() > MAX_BUILD_LOG_SIZE {
+        output.drain(..output.len() - MAX_BUILD_LOG_SIZE);
+    }
+    if let Ok(mut logs) = BUILD_LOGS.lock() {
+        logs.retain(|(job, _)| job != name);
+        logs.push_back((name.to_string(), output));
+        if logs.len() > MAX_BUILD_LOGS {
+            logs.pop_front();
+        }
+    }
+    Ok(())
+}
+
+/// A container run alongside a job's main container, sharing its
+/// network namespace and volumes.
+#[derive(Debug, Deserialize)]
+pub struct Sidecar {
+    /// Name of the sidecar, unique within the job.
+    #[serde(rename = "Name")]
+    pub name: String,
+    /// Configuration of the sidecar container.
+    #[serde(flatten)]
+    pub config: Config<String>,
+}
+
+/// Get the container name of a job's sidecar. Job names can't hold the
+/// infix, so sidecars never take the name of another job.
+fn sidecar_container(job: &str, sidecar: &str) -> String {
+    format!("{}{}{}", job, SIDECAR_INFIX, sidecar)
+}
+
+/// Create a sidecar container of a job, joining the network namespace
+/// and volumes of the job's container.
+async fn create_sidecar(
+    docker: &Docker,
+    job: &str,
+    sidecar: Sidecar,
+    platform: Option<&str>,
+) -> Result<()> {
+    if let Some(image) = sidecar.config.image.as_deref() {
+        ensure_image(docker, image, platform).await?;
+    }
+    let mut host_config = sidecar.config.host_config.unwrap_or_default();
+    host_config.network_mode = Some(format!("container:{}", job));
+    host_config
+        .volumes_from
+        .get_or_insert_with(Vec::new)
+        .push(job.to_string());
+    let config = with_cgroup_parent(with_log_config(Config {
+        host_config: Some(host_config),
+        ..sidecar.config
+    }));
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: sidecar_container(job, &sidecar.name),
+                platform: platform.map(String::from),
+            }),
+            insert_label(config, SIDECAR_LABEL_KEY, job),
+        )
+        .await
+        .with_context(|| format!("while creating sidecar {:?}", sidecar.name))?;
+    Ok(())
+}
+
+/// List the sidecar containers of a job in a docker host.
+async fn list_sidecars(docker: &Docker, job: &str) -> Result<Vec<ContainerSummary>> {
+    let mut filters = HashMap::new();
+    let label_filter = format!("{}={}", SIDECAR_LABEL_KEY, job);
+    filters.insert("label", vec![label_filter.as_str()]);
+    Ok(retry(&retry::READ, || {
+        docker.list_containers(Some(ListContainersOptions {
+            all: true,
+            filters: filters.clone(),
+            ..Default::default()
+        }))
+    })
+    .await?)
+}
+
+/// Get the sidecar containers of a job.
+pub async fn sidecars<S: AsRef<str>>(job: S) -> Result<Vec<ContainerSummary>> {
+    if backend::is_alternative() {
+        return Ok(Vec::new());
+    }
+    match locate(job.as_ref()).await? {
+        Some(host) => list_sidecars(&host.docker, job.as_ref()).await,
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Create a job with the given name and platform option, and the
+/// specified configuration, through the backend. The namespace
+/// parameter is included as a custom label in the job, used to group
+/// jobs created by this dispatcher. Returns the job's ID, or None if
+/// the job already exists.
+#[tracing::instrument(skip_all, fields(job = name))]
+pub async fn create(
+    name: String,
+    platform: Option<String>,
+    build: Option<Build>,
+    sidecars: Vec<Sidecar>,
+    config: Config<String>,
+    namespace: &str,
+) -> Result<Option<String>> {
+    if get(&name, namespace).await?.is_some() {
+        return Ok(None);
+    }
+    if (build.is_some() || !sidecars.is_empty()) && backend::is_alternative() {
+        bail!("image builds and sidecars require the docker backend");
+    }
+    backend::current()
+        .create(Creation {
+            name,
+            namespace: namespace.to_string(),
+            config: insert_job_label(config, namespace),
+            platform,
+            build,
+            sidecars,
+        })
+        .await
+}
+
+/// Create a job's container in the host chosen by the placement
+/// strategy, along with its sidecars. The job's image is built first
+/// if build instructions are given, or else pulled if it's missing. The
+/// chosen host is recorded in a label.
+async fn create_in_host(job: Creation) -> Result<Option<String>> {
+    let Creation {
+        name,
+        namespace,
+        config,
+        platform,
+        build,
+        sidecars,
+    } = job;
+    let namespace = namespace.as_str();
+    let (index, host) = place(&name, namespace, platform.as_deref()).await?;
+    match (&build, config.image.as_deref()) {
+        (Some(build), _) => build_image(&host.docker, &name, build, platform.as_deref()).await?,
+        (None, Some(image)) => ensure_image(&host.docker, image, platform.as_deref()).await?,
+        (None, None) => (),
+    }
+    let config = with_cgroup_parent(with_log_config(insert_label(
+        config,
+        HOST_LABEL_KEY,
+        &host.name,
+    )));
+    let config = match NETWORK.get() {
+        Some(network) => {
+            // the network is removed whenever the namespace runs out
+            // of jobs
+            ensure_network(&host.docker, network, namespace).await?;
+            attach_network(config, network)
+        }
+        None => config,
+    };
+    let config = match WORKSPACE.get() {
+        Some(path) => provision_workspace(&host.docker, config, &name, namespace, path).await?,
+        None => config,
+    };
+    let options = CreateContainerOptions {
+        name: name.clone(),
+        platform: platform.clone(),
+    };
+    let mut response = host
+        .docker
+        .create_container(Some(options.clone()), config.clone())
+        .await;
+    if let (
+        Some(network),
+        Err(Error::DockerResponseServerError {
+            status_code: 404,
+            message,
+        }),
+    ) = (NETWORK.get(), &response)
+    {
+        // the network may have been removed by the cleaner between
+        // ensuring it and creating the job, so it's created again
+        if message.contains(network.as_str()) {
+            ensure_network(&host.docker, network, namespace).await?;
+            response = host.docker.create_container(Some(options), config).await;
+        }
+    }
+    match response {
+        Ok(response) => {
+            if let Ok(mut locations) = LOCATIONS.lock() {
+                locations.insert(name.clone(), index);
+            }
+            for sidecar in sidecars {
+                if let Err(e) =
+                    create_sidecar(&host.docker, &name, sidecar, platform.as_deref()).await
+                {
+                    // the job is created as a whole or not at all
+                    let _ = remove(&name, true).await;
+                    return Err(e);
+                }
+            }
+            Ok(Some(response.id))
+        }
+        Err(Error::DockerResponseServerError {
+            status_code: 409, ..
+        }) => Ok(None),
+        Err(e) => {
+            // don't leave the workspace of a job that was never created
+            let _ = remove_workspace(&host.docker, &name).await;
+            Err(anyhow::Error::new(e))
+        }
+    }
+}
+
+/// Copy the current values of the secrets requested by a job into its
+/// container.
+async fn inject_secrets(docker: &Docker, container: &str) -> Result<()> {
+    let inspection = docker.inspect_container(container, None).await?;
+    let Some(names) = inspection
+        .config
+        .and_then(|config| config.labels)
+        .and_then(|labels| labels.get(SECRETS_LABEL_KEY).cloned())
+    else {
+        return Ok(());
+    };
+    let names: Vec<&str> = names.split(',').filter(|name| !name.is_empty()).collect();
+    docker
+        .upload_to_container(
+            container,
+            Some(UploadToContainerOptions {
+                path: "/",
+                ..Default::default()
+            }),
+            secrets::archive(&names)?.into(),
+        )
+        .await
+        .context("while copying secrets into the container")?;
+    Ok(())
+}
+
+/// Give a job about to start a CPU set from the pool, if it takes one.
+async fn assign_cpuset(host: &Host, container: &str) -> Result<()> {
+    let inspection = host.docker.inspect_container(container, None).await?;
+    let Some(mode) = inspection
+        .config
+        .and_then(|config| config.labels)
+        .and_then(|labels| labels.get(CPUSET_LABEL_KEY).cloned())
+    else {
+        return Ok(());
+    };
+    let cpuset = cpusets::assign(container, &host.name, mode == cpusets::EXCLUSIVE)
+        .context("no CPU set is left for the job")?;
+    debug!("Assigning CPU set {:?} to job {:?}", cpuset, container);
+    host.docker
+        .update_container(
+            container,
+            UpdateContainerOptions::<String> {
+                cpuset_cpus: Some(cpuset),
+                ..Default::default()
+            },
+        )
+        .await
+        .context("while assigning a CPU set to the job")?;
+    Ok(())
+}
+
+/// Start the sidecars of a job, once its container is running.
+async fn start_sidecars(docker: &Docker, job: &str) -> Result<()> {
+    for sidecar in list_sidecars(docker, job).await? {
+        let Some(name) = sidecar.names.as_ref().and_then(|names| names.first()) else {
+            continue;
+        };
+        match docker
+            .start_container::<String>(name.trim_start_matches('/'), None)
+            .await
+        {
+            Ok(_)
+            | Err(Error::DockerResponseServerError {
+                status_code: 304, ..
+            }) => (),
+            Err(e) => Err(e).with_context(|| format!("while starting sidecar {:?}", name))?,
+        }
+    }
+    Ok(())
+}
+
+/// Start a previously created job through the backend.
+#[tracing::instrument(skip_all, fields(job = container.as_ref()))]
+pub async fn start<S: AsRef<str>>(container: S) -> Result<()> {
+    backend::current().start(container.as_ref()).await
+}
+
+/// Start a previously created job's container, after giving it its
+/// secrets, and then its sidecars. Failures at starting the job are
+/// recorded so that the start may be retried a limited amount of
+/// times.
+async fn start_in_host<S: AsRef<str>>(container: S) -> Result<()> {
+    let host = host_of(container.as_ref()).await?;
+    if secrets::is_enabled() {
+        inject_secrets(&host.docker, container.as_ref()).await?;
+    }
+    if cpusets::is_enabled() {
+        assign_cpuset(host, container.as_ref()).await?;
+    }
+    let result = host
+        .docker
+        .start_container::<String>(container.as_ref(), None)
+        .await;
+    match result {
+        Ok(_) => {
+            attempts::clear(container.as_ref());
+            if let Err(e) = start_sidecars(&host.docker, container.as_ref()).await {
+                // the job runs as a whole or not at all
+                warn!(
+                    "Stopping job {:?} since its sidecars couldn't be started",
+                    container.as_ref()
+                );
+                host.docker
+                    .stop_container(container.as_ref(), None::<StopContainerOptions>)
+                    .await
+                    .context("while stopping the job")?;
+                return Err(e);
+            }
+            Ok(())
+        }
+        Err(e) => {
+            let failure = attempts::record_failure(container.as_ref(), &e);
+            Err(anyhow::Error::new(e)
+                .context(format!("while starting job (attempt {})", failure.attempts)))
+        }
+    }
+}
+
+/// Get a possibly non-existent job.
+pub async fn get<S: AsRef<str>>(name: S, namespace: &str) -> Result<Option<ContainerSummary>> {
+    let mut filters = HashMap::new();
+    let name_regex = format!("^/{}$", name.as_ref());
+    filters.insert("name", vec![name_regex.as_str()]);
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    let options = ListContainersOptions {
+        all: true,
+        limit: Some(1),
+        size: false,
+        filters,
+    };
+    Ok(list(options).await?.into_iter().next())
+}
+
+/// Inspect a possibly non-existent job.
+pub async fn inspect<S: AsRef<str>>(name: S) -> Result<ContainerInspectResponse> {
+    backend::current().inspect(name.as_ref()).await
+}
+
+/// Get the output of a job, both stdout and stderr, preceded by the
+/// output of its image build, if any.
+pub async fn logs<S: AsRef<str>>(name: S) -> Result<Vec<u8>> {
+    backend::current().logs(name.as_ref()).await
+}
+
+/// Remove a job, optionally along with its anonymous volumes. Jobs
+/// that no longer exist or are already being removed are considered
+/// removed.
+#[tracing::instrument(skip_all, fields(job = name.as_ref()))]
+pub async fn remove<S: AsRef<str>>(name: S, volumes: bool) -> Result<()> {
+    backend::current().remove(name.as_ref(), volumes).await?;
+    attempts::clear(name.as_ref());
+    Ok(())
+}
+
+/// Remove a job's container and its sidecars from its host.
+async fn remove_from_host<S: AsRef<str>>(name: S, volumes: bool) -> Result<()> {
+    let Some(host) = locate(name.as_ref()).await? else {
+        return Ok(());
+    };
+    for sidecar in list_sidecars(&host.docker, name.as_ref()).await? {
+        if let Some(id) = sidecar.id {
+            host.docker
+                .remove_container(
+                    &id,
+                    Some(RemoveContainerOptions {
+                        v: volumes,
+                        force: true,
+                        ..Default::default()
+                    }),
+                )
+                .await
+                .or_else(|e| match e {
+                    Error::DockerResponseServerError {
+                        status_code: 404, ..
+                    } => Ok(()),
+                    e => Err(e),
+                })?;
+        }
+    }
+    let result = retry(&retry::WRITE, || {
+        host.docker.remove_container(
+            name.as_ref(),
+            Some(RemoveContainerOptions {
+                v: volumes,
+                ..Default::default()
+            }),
+        )
+    })
+    .await;
+    match result {
+        Ok(_)
+        | Err(Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => (),
+        Err(Error::DockerResponseServerError {
+            status_code: 409,
+            ref message,
+        }) if message.contains("already in progress") => (),
+        Err(e) => Err(e)?,
+    }
+    remove_workspace(&host.docker, name.as_ref()).await?;
+    if let Ok(mut logs) = BUILD_LOGS.lock() {
+        logs.retain(|(job, _)| job != name.as_ref());
+    }
+    forget(name.as_ref());
+    Ok(())
+}
+
+/// Remove the unused networks created for the given namespace, other
+/// than its dedicated network, and return their names.
+pub async fn prune_networks(namespace: &str) -> Result<Vec<String>> {
+    let mut filters = HashMap::new();
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    filters.insert("label!", vec![NETWORK_LABEL_KEY]);
+    let options = PruneNetworksOptions { filters };
+    Ok(try_join_all(hosts()?.iter().map(|host| {
+        retry(&retry::WRITE, || {
+            host.docker.prune_networks(Some(options.clone()))
+        })
+    }))
+    .await?
+    .into_iter()
+    .flat_map(|response| response.networks_deleted.unwrap_or_default())
+    .collect())
+}
+
+/// Create the dedicated network of a namespace in a docker host, if
+/// it doesn't exist already.
+async fn ensure_network(docker: &Docker, network: &str, namespace: &str) -> Result<()> {
+    match docker
+        .inspect_network(network, None::<InspectNetworkOptions<String>>)
+        .await
+    {
+        Ok(_) => return Ok(()),
+        Err(Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => (),
+        Err(e) => Err(e).context("while inspecting network")?,
+    }
+    let result = docker
+        .create_network(CreateNetworkOptions {
+            name: network,
+            check_duplicate: true,
+            driver: "bridge",
+            labels: HashMap::from([(JOB_LABEL_KEY, namespace), (NETWORK_LABEL_KEY, "true")]),
+            ..Default::default()
+        })
+        .await;
+    match result {
+        Ok(_) => {
+            info!("Created network {:?}", network);
+            Ok(())
+        }
+        Err(Error::DockerResponseServerError {
+            status_code: 409, ..
+        }) => Ok(()),
+        Err(e) => Err(e).context("while creating network"),
+    }
+}
+
+/// Create the dedicated network of the namespace in every docker host,
+/// and attach jobs that don't specify a network to it from now on.
+/// Returns the name of the network.
+pub async fn init_network(namespace: &str) -> Result<String> {
+    let network = format!("{}-{}", env!("CARGO_PKG_NAME"), namespace);
+    try_join_all(
+        hosts()?
+            .iter()
+            .map(|host| ensure_network(&host.docker, &network, namespace)),
+    )
+    .await?;
+    let _ = NETWORK.set(network.clone());
+    Ok(network)
+}
+
+/// Attach a job to the given network, unless its configuration
+/// specifies a network of its own.
+fn attach_network(c: Config<String>, network: &str) -> Config<String> {
+    let mut host_config = c.host_config.unwrap_or_default();
+    let has_endpoints = c
+        .networking_config
+        .as_ref()
+        .is_some_and(|config| !config.endpoints_config.is_empty());
+    if !has_endpoints
+        && host_config
+            .network_mode
+            .as_deref()
+            .unwrap_or_default()
+            .is_empty()
+    {
+        host_config.network_mode = Some(network.to_string());
+    }
+    Config {
+        host_config: Some(host_config),
+        ..c
+    }
+}
+
+/// Remove the dedicated network of the namespace once the namespace
+/// has no jobs left, and return its name if it was removed.
+pub async fn remove_unused_network(namespace: &str) -> Result<Option<&'static str>> {
+    let Some(network) = NETWORK.get() else {
+        return Ok(None);
+    };
+    let mut filters = HashMap::new();
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    let options = ListContainersOptions {
+        all: true,
+        limit: Some(1),
+        size: false,
+        filters,
+    };
+    if !list(options).await?.is_empty() {
+        return Ok(None);
+    }
+    let removed = try_join_all(hosts()?.iter().map(|host| async move {
+        match host.docker.remove_network(network).await {
+            Ok(_) => Ok(true),
+            // the network may be gone already, or in use by a job
+            // created meanwhile (reported as forbidden by older
+            // daemons), in which case it's kept
+            Err(Error::DockerResponseServerError {
+                status_code: 403 | 404 | 409,
+                ..
+            }) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }))
+    .await
+    .context("while removing network")?;
+    Ok(removed.contains(&true).then_some(network.as_str()))
+}
+
+/// Set the logging driver and options given to jobs that don't set
+/// their own. Options are given as key=value pairs.
+pub fn init_log_config(driver: String, options: &[String]) -> Result<()> {
+    let options = options
+        .iter()
+        .filter(|option| !option.is_empty())
+        .map(|option| {
+            option
+                .split_once('=')
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .with_context(|| format!("log option {:?} is not a key=value pair", option))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+    let _ = LOG_CONFIG.set(HostConfigLogConfig {
+        typ: Some(driver),
+        config: Some(options),
+    });
+    Ok(())
+}
+
+/// Set the parent cgroup given to jobs that don't set their own.
+pub fn init_cgroup_parent(cgroup_parent: String) {
+    let _ = CGROUP_PARENT.set(cgroup_parent);
+}
+
+/// Give a container the default parent cgroup, unless it sets one of
+/// its own.
+fn with_cgroup_parent(c: Config<String>) -> Config<String> {
+    let Some(cgroup_parent) = CGROUP_PARENT.get() else {
+        return c;
+    };
+    let mut host_config = c.host_config.unwrap_or_default();
+    if host_config
+        .cgroup_parent
+        .as_deref()
+        .is_none_or(str::is_empty)
+    {
+        host_config.cgroup_parent = Some(cgroup_parent.clone());
+    }
+    Config {
+        host_config: Some(host_config),
+        ..c
+    }
+}
+
+/// Give a container the default logging configuration, unless it sets
+/// a logging driver of its own.
+fn with_log_config(c: Config<String>) -> Config<String> {
+    let Some(log_config) = LOG_CONFIG.get() else {
+        return c;
+    };
+    let mut host_config = c.host_config.unwrap_or_default();
+    let has_driver = host_config
+        .log_config
+        .as_ref()
+        .and_then(|log_config| log_config.typ.as_deref())
+        .is_some_and(|driver| !driver.is_empty());
+    if !has_driver {
+        host_config.log_config = Some(log_config.clone());
+    }
+    Config {
+        host_config: Some(host_config),
+        ..c
+    }
+}
+
+/// Give every job created from now on a workspace volume mounted at
+/// the given path.
+pub fn init_workspace(path: String) {
+    let _ = WORKSPACE.set(path);
+}
+
+/// Get the name of the workspace volume of a job.
+fn workspace_volume(name: &str) -> String {
+    format!("{}-workspace", name)
+}
+
+/// Create the workspace volume of a job, and mount it in the job's
+/// configuration at the given path.
+async fn provision_workspace(
+    docker: &Docker,
+    c: Config<String>,
+    name: &str,
+    namespace: &str,
+    path: &str,
+) -> Result<Config<String>> {
+    let volume = workspace_volume(name);
+    docker
+        .create_volume(CreateVolumeOptions {
+            name: volume.as_str(),
+            driver: "local",
+            labels: HashMap::from([(JOB_LABEL_KEY, namespace), (WORKSPACE_LABEL_KEY, name)]),
+            ..Default::default()
+        })
+        .await
+        .context("while creating workspace volume")?;
+    let mut host_config = c.host_config.unwrap_or_default();
+    host_config.mounts.get_or_insert_with(Vec::new).push(Mount {
+        target: Some(path.to_string()),
+        source: Some(volume),
+        typ: Some(MountTypeEnum::VOLUME),
+        ..Default::default()
+    });
+    Ok(Config {
+        host_config: Some(host_config),
+        ..c
+    })
+}
+
+/// Remove the workspace volume of a job, if workspaces are enabled.
+async fn remove_workspace(docker: &Docker, name: &str) -> Result<()> {
+    if WORKSPACE.get().is_none() {
+        return Ok(());
+    }
+    match docker
+        .remove_volume(
+            &workspace_volume(name),
+            Some(RemoveVolumeOptions { force: false }),
+        )
+        .await
+    {
+        Ok(_)
+        | Err(Error::DockerResponseServerError {
+            status_code: 404, ..
+        }) => Ok(()),
+        Err(e) => Err(e).context("while removing workspace volume"),
+    }
+}
+
+/// Stop the sidecars still running after their job exited.
+pub async fn stop_sidecars(namespace: &str) -> Result<()> {
+    if backend::is_alternative() {
+        return Ok(());
+    }
+    for host in hosts()? {
+        let mut filters = HashMap::new();
+        filters.insert("label", vec![SIDECAR_LABEL_KEY]);
+        filters.insert("status", vec!["running"]);
+        let running = host
+            .docker
+            .list_containers(Some(ListContainersOptions {
+                filters,
+                ..Default::default()
+            }))
+            .await?;
+        for sidecar in running {
+            let (Some(id), Some(job)) = (&sidecar.id, label(&sidecar, SIDECAR_LABEL_KEY)) else {
+                continue;
+            };
+            // sidecars of other namespaces' jobs aren't found
+            let exited = get(job, namespace)
+                .await?
+                .is_some_and(|job| matches!(job.state.as_deref(), Some("exited") | Some("dead")));
+            if exited {
+                info!("Stopping sidecar {:?} of job {:?}", sidecar.names, job);
+                host.docker
+                    .stop_container(id, None::<StopContainerOptions>)
+                    .await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the running jobs whose healthcheck hasn't passed yet. Jobs of
+/// alternative backends report no health.
+pub async fn get_starting(namespace: &str) -> Result<Vec<ContainerSummary>> {
+    if backend::is_alternative() {
+        return Ok(Vec::new());
+    }
+    let mut filters = HashMap::new();
+    filters.insert("status", vec!["running"]);
+    filters.insert("health", vec!["starting"]);
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    let options = ListContainersOptions {
+        all: true,
+        limit: None,
+        size: false,
+        filters,
+    };
+    list(options).await
+}
+
+/// Get the currently active jobs.
+pub async fn get_active(namespace: &str) -> Result<Vec<ContainerSummary>> {
+    let mut filters = HashMap::new();
+    filters.insert("status", vec!["restarting", "running"]);
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    let options = ListContainersOptions {
+        all: true,
+        limit: None,
+        size: false,
+        filters,
+    };
+    list(options).await
+}
+
+/// Get jobs by their status, in order from oldest to newest.
+pub async fn get_by_status(namespace: &str, statuses: &[&str]) -> Result<Vec<ContainerSummary>> {
+    let mut filters = HashMap::new();
+    filters.insert("status", statuses.to_vec());
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert("label", vec![label_filter.as_str()]);
+    let options = ListContainersOptions {
+        all: true,
+        limit: None,
+        size: false,
+        filters,
+    };
+    let mut containers = list(options).await?;
+    containers.sort_by_key(|container| container.created);
+    Ok(containers)
+}
+
+/// Get the not-yet-started jobs.
+pub async fn get_pending(namespace: &str) -> Result<Vec<ContainerSummary>> {
+    get_by_status(namespace, &["created"]).await
+}
+
+/// Get the exited jobs.
+pub async fn get_exited(namespace: &str) -> Result<Vec<ContainerSummary>> {
+    get_by_status(namespace, &["exited"]).await
+}
+
+/// Get the dead jobs, i.e. the ones the daemon failed to stop or
+/// remove.
+pub async fn get_dead(namespace: &str) -> Result<Vec<ContainerSummary>> {
+    get_by_status(namespace, &["dead"]).await
+}
+
+/// Get the IDs of the images used by containers, either all of them
+/// or only those of the given namespace.
+pub async fn images_in_use(namespace: Option<&str>) -> Result<HashSet<String>> {
+    let mut filters = HashMap::new();
+    let label_filter = namespace.map(|namespace| format!("{}={}", JOB_LABEL_KEY, namespace));
+    if let Some(label_filter) = &label_filter {
+        filters.insert("label", vec![label_filter.as_str()]);
+    }
+    let options = ListContainersOptions {
+        all: true,
+        limit: None,
+        size: false,
+        filters,
+    };
+    Ok(list(options)
+        .await?
+        .into_iter()
+        .filter_map(|container| container.image_id)
+        .collect())
+}
+
+/// List the images present in the docker hosts. Images present in
+/// several hosts are listed once.
+pub async fn list_images() -> Result<Vec<ImageSummary>> {
+    let options = ListImagesOptions::<String> {
+        all: false,
+        ..Default::default()
+    };
+    Ok(try_join_all(hosts()?.iter().map(|host| {
+        retry(&retry::READ, || {
+            host.docker.list_images(Some(options.clone()))
+        })
+    }))
+    .await?
+    .into_iter()
+    .flatten()
+    .unique_by(|image| image.id.clone())
+    .collect())
+}
+
+/// Remove an image from every docker host, unless it's being used by
+/// a container.
+pub async fn remove_image(id: &str) -> Result<()> {
+    try_join_all(hosts()?.iter().map(|host| async move {
+        match retry(&retry::WRITE, || {
+            host.docker.remove_image(
+                id,
+                Some(RemoveImageOptions {
+                    force: false,
+                    noprune: false,
+                }),
+                None,
+            )
+        })
+        .await
+        {
+            Ok(_)
+            | Err(Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(e),
+        }
+    }))
+    .await?;
+    Ok(())
+}
+
+/// Get the stream of the given job events. Backends without events
+/// of their own have them derived from periodic listings.
+pub fn job_events(
+    namespace: &str,
+    actions: &[&str],
+) -> Result<BoxStream<'static, Result<EventMessage>>> {
+    job_events_since(namespace, actions, None)
+}
+
+/// Get the stream of the given job events, starting with the past
+/// events since the given time, as far as the backend keeps them.
+pub fn job_events_since(
+    namespace: &str,
+    actions: &[&str],
+    since: Option<DateTime<Utc>>,
+) -> Result<BoxStream<'static, Result<EventMessage>>> {
+    let backend = backend::current();
+    let events = match backend.events(namespace, actions, since)? {
+        Some(events) => events,
+        None => backend::poll_events(backend, namespace),
+    };
+    let actions: Vec<String> = actions.iter().map(|action| action.to_string()).collect();
+    Ok(events
+        .try_filter(move |event| {
+            future::ready(
+                event
+                    .action
+                    .as_ref()
+                    .is_some_and(|action| actions.contains(action)),
+            )
+        })
+        .boxed())
+}
+
+/// Get the stream of the given job events across every docker host,
+/// starting with the past events since the given time, as far as the
+/// daemons keep them.
+fn host_events(
+    namespace: &str,
+    events: &[&str],
+    since: Option<DateTime<Utc>>,
+) -> Result<impl Stream<Item = core::result::Result<EventMessage, Error>>> {
+    let mut filters = HashMap::new();
+    filters.insert(String::from("type"), vec![String::from("container")]);
+    filters.insert(
+        String::from("event"),
+        events.iter().map(|event| event.to_string()).collect(),
+    );
+    let label_filter = format!("{}={}", JOB_LABEL_KEY, namespace);
+    filters.insert(String::from("label"), vec![label_filter]);
+    let options = EventsOptions {
+        since,
+        until: None,
+        filters,
+    };
+    Ok(stream::select_all(
+        hosts()?
+            .iter()
+            .map(|host| host.docker.events(Some(options.clone()))),
+    ))
+}
@@ -0,0 +1,746 @@
+//! Implements the creation and retrieval of jobs.
+
+use crate::api_error::APIError;
+use crate::archive;
+use crate::attempts;
+use crate::audit;
+use crate::auth;
+use crate::circuit_breaker;
+use crate::cpusets;
+use crate::dispatch_queue;
+use crate::docker;
+use crate::exits;
+use crate::gpu;
+use crate::job_store;
+use crate::jq;
+use crate::policy::{Policy, Violation};
+use crate::redact;
+use crate::reload::Live;
+use crate::secrets;
+use crate::shutdown;
+
+use actix_web::{
+    get, http::header::ContentType, routes, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use bollard::{
+    container::Config,
+    models::{ContainerSummary, HealthStatusEnum},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{debug, info, info_span, warn};
+
+/// A representation of a job.
+#[derive(Default, Serialize)]
+pub(crate) struct JobSummary {
+    id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    created: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_failure: Option<attempts::StartFailure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    health: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sidecars: Option<Vec<SidecarSummary>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<JobMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exit_code: Option<i64>,
+}
+
+impl JobSummary {
+    /// Summarize a job that no longer exists from its record.
+    fn from_record(id: String, record: job_store::Record) -> Self {
+        let status = match (record.state, record.exit_code) {
+            (job_store::State::Exited, Some(code)) => format!("Exited ({})", code),
+            (job_store::State::FailedToStart, _) => String::from("Failed to start"),
+            (job_store::State::Running, _) => String::from("Up"),
+            _ => String::from("Created"),
+        };
+        Self {
+            id,
+            created: record.created,
+            status: Some(match record.removed {
+                Some(_) => format!("{}, removed", status),
+                None => status,
+            }),
+            start_failure: record.last_error.map(|last_error| attempts::StartFailure {
+                attempts: record.attempts,
+                last_error,
+            }),
+            health: None,
+            sidecars: None,
+            metadata: Some(JobMetadata {
+                path: record.path,
+                submitter: record.submitter,
+                api_key: record.api_key,
+                client_cert: record.client_cert,
+                request_id: record.request_id,
+                dispatcher_version: None,
+            }),
+            started: record.started,
+            finished: record.finished,
+            exit_code: record.exit_code,
+        }
+    }
+}
+
+/// A representation of a job's sidecar.
+#[derive(Serialize)]
+struct SidecarSummary {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<String>,
+}
+
+/// Metadata of the request a job was created by.
+#[derive(Serialize)]
+struct JobMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    submitter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dispatcher_version: Option<String>,
+}
+
+impl JobMetadata {
+    /// Read the metadata of a job from its labels.
+    fn from_labels(job: &ContainerSummary) -> Self {
+        let label = |key| docker::label(job, key).map(String::from);
+        Self {
+            path: label(docker::PATH_LABEL_KEY),
+            submitter: label(docker::SUBMITTER_LABEL_KEY),
+            api_key: label(docker::API_KEY_LABEL_KEY),
+            client_cert: label(docker::CLIENT_CERT_LABEL_KEY),
+            request_id: label(docker::REQUEST_ID_LABEL_KEY),
+            dispatcher_version: label(docker::VERSION_LABEL_KEY),
+        }
+    }
+}
+
+/// Where the metadata of job creation requests is read from.
+pub struct Provenance {
+    /// Header holding the identity of the submitter, as set by an
+    /// authenticating proxy in front of the dispatcher.
+    pub submitter_header: String,
+}
+
+/// Get the status to report for a job, considering jobs that ran out
+/// of start attempts as failed.
+fn job_status(
+    status: Option<String>,
+    start_failure: &Option<attempts::StartFailure>,
+) -> Option<String> {
+    match start_failure {
+        Some(failure) if failure.exhausted() => Some(String::from("Failed to start")),
+        _ => status,
+    }
+}
+
+/// Additional fields from the job manifest.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateContainerOptions {
+    name: String,
+    platform: Option<String>,
+    #[serde(rename = "X-MutexGroup")]
+    mutex_group: Option<String>,
+    #[serde(rename = "X-Slots")]
+    slots: Option<u16>,
+    #[serde(rename = "X-Kind", default)]
+    kind: JobKind,
+    #[serde(rename = "X-Secrets", default)]
+    secrets: Vec<String>,
+    #[serde(rename = "X-Build")]
+    build: Option<docker::Build>,
+    #[serde(rename = "X-Sidecars", default)]
+    sidecars: Vec<docker::Sidecar>,
+    #[serde(rename = "X-CpuSet")]
+    cpuset: Option<CpuSetMode>,
+}
+
+/// The ways jobs can take a CPU set from the pool.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CpuSetMode {
+    /// A set possibly shared with other jobs.
+    Shared,
+    /// A set used by no other job.
+    Exclusive,
+}
+
+/// The kinds of jobs that can be dispatched.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JobKind {
+    /// A job that runs to completion.
+    #[default]
+    Batch,
+    /// A long-running job that's restarted whenever it exits.
+    Service,
+}
+
+/// A container for the create_job path information.
+#[derive(Deserialize)]
+struct PathInfo {
+    path: Option<String>,
+}
+
+/// Refuse requests made with an API key scoped to another namespace.
+fn check_namespace(request: &HttpRequest, namespace: &str) -> Result<(), APIError> {
+    match auth::scope(request) {
+        Some(scope) if scope.namespace != namespace => Err(APIError::forbidden(format!(
+            "The API key is restricted to namespace {:?}",
+            scope.namespace
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a job created through the given path may be seen with the
+/// API key of a request, if it's scoped.
+fn is_visible(request: &HttpRequest, path: Option<&str>) -> bool {
+    auth::scope(request).is_none_or(|scope| scope.covers(path))
+}
+
+/// Block the dangerous fields of a container configuration, apply the
+/// resource limits of the policy to it, and check it against the
+/// policy. Violating fields are reported with the given prefix, and
+/// rejections and stripped fields are audited.
+fn enforce(
+    policy: &Policy,
+    mut config: Config<String>,
+    prefix: &str,
+    rejection: impl Fn() -> audit::Entry,
+) -> Result<Config<String>> {
+    let reject = |config: &Config<String>, violation: Violation| {
+        warn!("Job manifest rejected by policy at {}{}", prefix, violation);
+        audit::record(audit::Entry {
+            action: "policy_rejection",
+            manifest_hash: audit::manifest_hash(config),
+            detail: Some(format!("{}{}", prefix, violation)),
+            ..rejection()
+        });
+        APIError::forbidden(format!(
+            "Generated manifest violates policy at {}{}",
+            prefix, violation
+        ))
+    };
+    let stripped = policy
+        .harden(&mut config)
+        .map_err(|violation| reject(&config, violation))?;
+    if !stripped.is_empty() {
+        let detail = stripped
+            .iter()
+            .map(|violation| format!("{}{}", prefix, violation))
+            .collect::<Vec<_>>()
+            .join("; ");
+        warn!("Dangerous fields stripped from job manifest: {}", detail);
+        audit::record(audit::Entry {
+            action: "fields_stripped",
+            manifest_hash: audit::manifest_hash(&config),
+            detail: Some(detail),
+            ..rejection()
+        });
+    }
+    let config = policy.apply_limits(config);
+    policy
+        .check(&config)
+        .map_err(|violation| reject(&config, violation))?;
+    Ok(config)
+}
+
+/// A job creation request, received through the API or from a
+/// message source.
+pub(crate) struct Submission {
+    /// The request body or message the filter runs on.
+    pub input: Value,
+    /// The path the job was submitted through, given to the filter as
+    /// `$PATH`.
+    pub path: String,
+    /// The claims of the request's token, given to the filter as
+    /// `$CLAIMS`.
+    pub claims: Value,
+    /// The attributes of the message, given to the filter as
+    /// `$MESSAGE`.
+    pub message: Value,
+    /// The subject the completion of the job is published to.
+    pub reply_subject: Option<String>,
+    /// Who submitted the job.
+    pub actor: audit::Actor,
+}
+
+/// What's needed to turn submissions into jobs.
+#[derive(Clone)]
+pub(crate) struct Intake {
+    pub filter: Arc<Live<jq::Filter>>,
+    pub policy: Arc<Live<Policy>>,
+    pub can_start: bool,
+    pub namespace: String,
+}
+
+/// Convert a submission to a job manifest, check it against the
+/// policy and create the job, reporting whether it was created (as
+/// opposed to pre-existing) along with its summary.
+pub(crate) async fn submit(intake: &Intake, submission: Submission) -> Result<(bool, JobSummary)> {
+    let Submission {
+        input,
+        path,
+        claims,
+        message,
+        reply_subject,
+        actor,
+    } = submission;
+    let namespace = &intake.namespace;
+    if shutdown::is_stopping() {
+        Err(APIError::service_unavailable(
+            "Shutting down; no new jobs are accepted",
+        ))?;
+    }
+    if circuit_breaker::is_open() {
+        Err(
+            APIError::service_unavailable("The docker daemon is unreachable")
+                .retry_after(circuit_breaker::PROBE_PERIOD.as_secs()),
+        )?;
+    }
+    // both are used as of the submission, even if reloaded meanwhile
+    let (filter, policy) = (intake.filter.get(), intake.policy.get());
+    debug!(
+        "Job creation request at {:?}: {}",
+        path,
+        redact::json(&input)
+    );
+    let raw_manifest = info_span!("filter")
+        .in_scope(|| jq::first_result(&filter, input, &path, claims, message))
+        .ok_or_else(|| APIError::bad_request("Filter didn't produce results"))?
+        .map_err(|e| APIError::bad_request(redact::text(&format!("Filter failed: {:?}", e))))?;
+    debug!("Job raw manifest: {}", redact::json(&raw_manifest));
+    let invalid = |e: serde_json::Error| {
+        APIError::bad_request(redact::text(&format!(
+            "Generated manifest is invalid: {:?}",
+            e
+        )))
+    };
+    let mut options: CreateContainerOptions =
+        serde_json::from_value(raw_manifest.clone()).map_err(invalid)?;
+    let mut manifest: Config<String> = serde_json::from_value(raw_manifest).map_err(invalid)?;
+    if docker::is_reserved_name(&options.name) {
+        Err(APIError::bad_request(format!(
+            "Generated manifest is invalid: job names can't hold {:?}",
+            docker::SIDECAR_INFIX
+        )))?
+    }
+    if let Some(platform) = &options.platform {
+        docker::check_platform(platform)
+            .map_err(|e| APIError::bad_request(format!("Generated manifest is invalid: {}", e)))?;
+    }
+    let rejection = || audit::Entry {
+        namespace: namespace.to_string(),
+        job: Some(options.name.clone()),
+        actor: actor.clone(),
+        ..Default::default()
+    };
+    if options.build.is_some() {
+        if policy.restricts_images() {
+            warn!("Job manifest rejected by policy at X-Build: images are restricted");
+            audit::record(audit::Entry {
+                action: "policy_rejection",
+                manifest_hash: audit::manifest_hash(&manifest),
+                detail: Some(String::from("X-Build: images are restricted")),
+                ..rejection()
+            });
+            Err(APIError::forbidden(
+                "Generated manifest violates policy at X-Build: images are restricted, so they \
+                 can't be built",
+            ))?
+        }
+        manifest.image = Some(docker::build_tag(&options.name));
+    }
+    manifest = enforce(&policy, manifest, "", rejection)?;
+    let mut sidecars = Vec::new();
+    for (index, sidecar) in std::mem::take(&mut options.sidecars)
+        .into_iter()
+        .enumerate()
+    {
+        let valid_name = !sidecar.name.is_empty()
+            && sidecar
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if !valid_name
+            || sidecars
+                .iter()
+                .any(|s: &docker::Sidecar| s.name == sidecar.name)
+        {
+            Err(APIError::bad_request(format!(
+                "Generated manifest is invalid: X-Sidecars[{}].Name must be a unique \
+                 alphanumeric name",
+                index
+            )))?
+        }
+        sidecars.push(docker::Sidecar {
+            config: enforce(
+                &policy,
+                sidecar.config,
+                &format!("X-Sidecars[{}].", index),
+                rejection,
+            )?,
+            ..sidecar
+        });
+    }
+    let metadata = JobMetadata {
+        path: Some(path.clone()),
+        submitter: actor.submitter.clone(),
+        api_key: actor.api_key.clone(),
+        client_cert: actor.client_cert.clone(),
+        request_id: Some(actor.request_id.clone().unwrap_or_else(cuid2::create_id)),
+        dispatcher_version: Some(String::from(env!("CARGO_PKG_VERSION"))),
+    };
+    for (key, value) in [
+        (docker::PATH_LABEL_KEY, &metadata.path),
+        (docker::SUBMITTER_LABEL_KEY, &metadata.submitter),
+        (docker::API_KEY_LABEL_KEY, &metadata.api_key),
+        (docker::CLIENT_CERT_LABEL_KEY, &metadata.client_cert),
+        (docker::REQUEST_ID_LABEL_KEY, &metadata.request_id),
+        (docker::VERSION_LABEL_KEY, &metadata.dispatcher_version),
+    ] {
+        if let Some(value) = value {
+            manifest = docker::insert_label(manifest, key, value);
+        }
+    }
+    if manifest
+        .host_config
+        .as_ref()
+        .and_then(|host_config| host_config.auto_remove)
+        .unwrap_or(false)
+    {
+        manifest = docker::insert_label(manifest, docker::AUTO_REMOVE_LABEL_KEY, "true");
+    }
+    if let Some(subject) = &reply_subject {
+        manifest = docker::insert_label(manifest, docker::REPLY_SUBJECT_LABEL_KEY, subject);
+    }
+    if let Some(group) = &options.mutex_group {
+        manifest = docker::insert_label(manifest, docker::MUTEX_GROUP_LABEL_KEY, group);
+    }
+    if options.kind == JobKind::Service {
+        manifest = docker::as_service(manifest);
+    }
+    if let Some(secret) = options.secrets.iter().find(|s| !secrets::is_registered(s)) {
+        Err(APIError::bad_request(format!(
+            "Generated manifest is invalid: secret {:?} is not registered",
+            secret
+        )))?
+    }
+    if !options.secrets.is_empty() {
+        manifest = docker::insert_label(
+            manifest,
+            docker::SECRETS_LABEL_KEY,
+            &options.secrets.join(","),
+        );
+    }
+    let gpus = gpu::requested(manifest.host_config.as_ref())
+        .map_err(|e| APIError::bad_request(format!("Generated manifest is invalid: {}", e)))?;
+    if gpus > 0 {
+        manifest = docker::insert_label(manifest, docker::GPUS_LABEL_KEY, &gpus.to_string());
+    }
+    let custom_cpuset = manifest
+        .host_config
+        .as_ref()
+        .and_then(|host_config| host_config.cpuset_cpus.as_deref())
+        .is_some_and(|cpuset| !cpuset.is_empty());
+    match (&options.cpuset, cpusets::is_enabled() && !custom_cpuset) {
+        (Some(_), false) => Err(APIError::bad_request(
+            "Generated manifest is invalid: X-CpuSet requires a CPU set pool, \
+             and can't be given along with HostConfig.CpusetCpus",
+        ))?,
+        (Some(CpuSetMode::Exclusive), true) => {
+            manifest = docker::insert_label(manifest, docker::CPUSET_LABEL_KEY, cpusets::EXCLUSIVE);
+        }
+        (_, true) => {
+            manifest = docker::insert_label(manifest, docker::CPUSET_LABEL_KEY, cpusets::SHARED);
+        }
+        (None, false) => (),
+    }
+    match options.slots {
+        Some(0) => Err(APIError::bad_request(
+            "Generated manifest is invalid: X-Slots must be a positive integer",
+        ))?,
+        Some(slots) => {
+            manifest = docker::insert_label(manifest, docker::SLOTS_LABEL_KEY, &slots.to_string());
+        }
+        None => (),
+    }
+    debug!(
+        "Job manifest of {:?}: {}",
+        options.name,
+        redact::json(&manifest)
+    );
+    let manifest_hash = audit::manifest_hash(&manifest);
+    let (name, platform, build) = (
+        options.name.clone(),
+        options.platform.clone(),
+        options.build,
+    );
+    let job_namespace = namespace.to_string();
+    let create = async move {
+        docker::create(name, platform, build, sidecars, manifest, &job_namespace).await
+    };
+    let job_opt = dispatch_queue::submit(create)
+        .await
+        .map_err(|e| APIError::service_unavailable(e).retry_after(1))?
+        .map_err(|e| APIError::bad_request(format!("Server rejected job manifest: {:?}", e)))?;
+    if job_opt.is_some() {
+        info!("Created job with ID {:?}", options.name);
+        job_store::record(
+            &options.name,
+            job_store::Record {
+                namespace: namespace.to_string(),
+                manifest_hash: manifest_hash.clone(),
+                path: metadata.path.clone(),
+                submitter: metadata.submitter.clone(),
+                api_key: metadata.api_key.clone(),
+                client_cert: metadata.client_cert.clone(),
+                request_id: metadata.request_id.clone(),
+                ..Default::default()
+            },
+        );
+        audit::record(audit::Entry {
+            action: "job_created",
+            namespace: namespace.to_string(),
+            job: Some(options.name.clone()),
+            manifest_hash,
+            actor,
+            ..Default::default()
+        });
+        // service jobs, jobs in a mutual exclusion group, jobs taking
+        // limited GPUs and jobs taking exclusive CPU sets are always
+        // left to the scheduler
+        if intake.can_start
+            && options.kind == JobKind::Batch
+            && options.mutex_group.is_none()
+            && (gpus == 0 || gpu::limit().is_none())
+            && options.cpuset != Some(CpuSetMode::Exclusive)
+        {
+            let name = options.name.clone();
+            let start = async move { docker::start(&name).await };
+            if let Err(e) = dispatch_queue::submit(start)
+                .await
+                .and_then(|result| result)
+            {
+                // the job was created, so the failure is reported
+                // and the start is left to the scheduler to retry
+                warn!("Couldn't start job {:?}: {:?}", options.name, e);
+            }
+        }
+        let start_failure = attempts::get(&options.name);
+        Ok((
+            true,
+            JobSummary {
+                id: options.name,
+                status: job_status(None, &start_failure),
+                start_failure,
+                metadata: Some(metadata),
+                ..Default::default()
+            },
+        ))
+    } else {
+        info!("Pre-existing job with ID {:?}", options.name);
+        let start_failure = attempts::get(&options.name);
+        Ok((
+            false,
+            JobSummary {
+                id: options.name,
+                status: job_status(None, &start_failure),
+                start_failure,
+                ..Default::default()
+            },
+        ))
+    }
+}
+
+/// Create a job by converting the request body to a job manifest.
+#[routes]
+#[post("/job")]
+#[post("/job/{path:.*}")]
+#[tracing::instrument(skip_all)]
+async fn create_job(
+    request: HttpRequest,
+    path: web::Path<PathInfo>,
+    body: web::Json<Value>,
+    filter: web::Data<Live<jq::Filter>>,
+    policy: web::Data<Live<Policy>>,
+    can_start: web::Data<bool>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let path = format!("/job/{}", path.path.clone().unwrap_or_default());
+    let path = path.strip_suffix('/').map(String::from).unwrap_or(path);
+    check_namespace(&request, &namespace)?;
+    if let Some(scope) = auth::scope(&request).filter(|scope| !scope.covers(Some(&path))) {
+        Err(APIError::forbidden(format!(
+            "The API key is restricted to paths under {:?}",
+            scope.path
+        )))?;
+    }
+    let intake = Intake {
+        filter: filter.into_inner(),
+        policy: policy.into_inner(),
+        can_start: **can_start,
+        namespace: namespace.to_string(),
+    };
+    let submission = Submission {
+        input: body.into_inner(),
+        path,
+        claims: auth::token(&request).map_or(Value::Null, |token| token.claims),
+        message: Value::Null,
+        reply_subject: None,
+        actor: audit::Actor::of(&request),
+    };
+    let (created, summary) = submit(&intake, submission).await?;
+    Ok(if created {
+        HttpResponse::Created().json(summary)
+    } else {
+        HttpResponse::Ok().json(summary)
+    })
+}
+
+/// Fetch a job by its ID.
+#[get("/job/{id}")]
+async fn get_job(
+    request: HttpRequest,
+    id: web::Path<String>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
+    let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    // jobs outside the scope of the API key are reported as missing
+    if job
+        .as_ref()
+        .is_some_and(|job| !is_visible(&request, docker::label(job, docker::PATH_LABEL_KEY)))
+    {
+        Err(APIError::not_found("The specified job doesn't exist"))?;
+    }
+    let Some(job) = job else {
+        // removed jobs are reported from their record in the job
+        // store, or else from their exit record if they were removed
+        // automatically
+        let record = job_store::get(&id).filter(|record| record.namespace == **namespace);
+        if let Some(record) = record {
+            if !is_visible(&request, record.path.as_deref()) {
+                Err(APIError::not_found("The specified job doesn't exist"))?;
+            }
+            info!("Fetched recorded job with ID {:?}", &*id);
+            return Ok(web::Json(JobSummary::from_record(id.clone(), record)));
+        }
+        let exit = exits::get(&id)
+            .filter(|_| is_visible(&request, None))
+            .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?;
+        info!("Fetched automatically removed job with ID {:?}", &*id);
+        return Ok(web::Json(JobSummary {
+            id: id.clone(),
+            created: exit.created,
+            status: Some(match exit.exit_code {
+                Some(code) => format!("Exited ({}), removed", code),
+                None => String::from("Removed"),
+            }),
+            start_failure: None,
+            health: None,
+            sidecars: None,
+            metadata: None,
+            started: exit.started,
+            finished: exit.finished,
+            exit_code: exit.exit_code,
+        }));
+    };
+    let sidecars = docker::sidecars(&*id)
+        .await
+        .map_err(APIError::bad_gateway)?
+        .into_iter()
+        .map(|sidecar| SidecarSummary {
+            name: sidecar
+                .names
+                .and_then(|names| names.into_iter().next())
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_default(),
+            status: sidecar.status,
+        })
+        .collect::<Vec<_>>();
+    // only running jobs with a healthcheck report their health
+    let health = match job.state.as_deref() {
+        Some("running") => docker::inspect(&*id)
+            .await
+            .map_err(APIError::bad_gateway)?
+            .state
+            .and_then(|state| state.health)
+            .and_then(|health| health.status)
+            .filter(|status| !matches!(status, HealthStatusEnum::EMPTY | HealthStatusEnum::NONE))
+            .map(|status| status.to_string()),
+        _ => None,
+    };
+    info!("Fetched job with ID {:?}", &*id);
+    let start_failure = attempts::get(&id);
+    let metadata = JobMetadata::from_labels(&job);
+    Ok(web::Json(JobSummary {
+        id: id.clone(),
+        created: job.created,
+        status: job_status(job.status, &start_failure),
+        start_failure,
+        health,
+        sidecars: (!sidecars.is_empty()).then_some(sidecars),
+        metadata: Some(metadata),
+        started: None,
+        finished: None,
+        exit_code: None,
+    }))
+}
+
+/// Fetch the output of a job by its ID, falling back to archived logs
+/// if the job no longer exists.
+#[get("/job/{id}/logs")]
+async fn get_job_logs(
+    request: HttpRequest,
+    id: web::Path<String>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
+    let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    let path = match &job {
+        Some(job) => docker::label(job, docker::PATH_LABEL_KEY).map(String::from),
+        None => job_store::get(&id)
+            .filter(|record| record.namespace == **namespace)
+            .and_then(|record| record.path),
+    };
+    // jobs outside the scope of the API key are reported as missing
+    if !is_visible(&request, path.as_deref()) {
+        Err(APIError::not_found("The specified job doesn't exist"))?;
+    }
+    let logs = if job.is_some() {
+        docker::logs(&*id).await.map_err(APIError::bad_gateway)?
+    } else {
+        archive::latest_logs(&id, &namespace)
+            .await
+            .map_err(APIError::internal_error)?
+            .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?
+    };
+    info!("Fetched logs of job with ID {:?}", &*id);
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(logs))
+}
@@ -0,0 +1,98 @@
+//! Validates the GPU device requests of jobs, and keeps track of the
+//! amount of GPUs each docker host exposes.
+
+use crate::docker;
+use bollard::models::{ContainerSummary, DeviceRequest, HostConfig};
+use once_cell::sync::OnceCell;
+
+/// Static amount of GPUs exposed by each docker host, if limited.
+static GPUS: OnceCell<usize> = OnceCell::new();
+
+/// Capabilities understood by the NVIDIA container runtime.
+const NVIDIA_CAPABILITIES: &[&str] = &[
+    "gpu", "compute", "utility", "graphics", "video", "display", "compat32",
+];
+
+/// Set the amount of GPUs exposed by each docker host.
+pub fn init(gpus: Option<u16>) {
+    if let Some(gpus) = gpus {
+        let _ = GPUS.set(usize::from(gpus));
+    }
+}
+
+/// Get the amount of GPUs exposed by each docker host, if limited.
+pub fn limit() -> Option<usize> {
+    GPUS.get().copied()
+}
+
+/// Validate a single device request, and get the amount of GPUs it
+/// takes. Requesting every GPU (a count of -1) takes them all.
+fn check_request(index: usize, request: &DeviceRequest) -> Result<usize, String> {
+    let field = format!("HostConfig.DeviceRequests[{}]", index);
+    if !matches!(request.driver.as_deref(), None | Some("") | Some("nvidia")) {
+        return Err(format!("{}.Driver must be nvidia", field));
+    }
+    if let Some(capability) = request
+        .capabilities
+        .iter()
+        .flatten()
+        .flatten()
+        .find(|capability| !NVIDIA_CAPABILITIES.contains(&capability.as_str()))
+    {
+        return Err(format!(
+            "{}.Capabilities includes unknown capability {:?}",
+            field, capability
+        ));
+    }
+    let device_ids = request.device_ids.as_deref().unwrap_or_default();
+    let gpus = match (request.count, device_ids.is_empty()) {
+        (Some(count), false) if count != 0 => {
+            return Err(format!("{} can't set both Count and DeviceIDs", field))
+        }
+        (Some(-1), true) => limit().unwrap_or(0),
+        (Some(count), true) if count >= 0 => count as usize,
+        (Some(_), true) => return Err(format!("{}.Count must be -1 or positive", field)),
+        (None, true) => 0,
+        (_, false) => device_ids.len(),
+    };
+    if let Some(limit) = limit() {
+        // device IDs may also be UUIDs, which can't be checked
+        if let Some(id) = device_ids
+            .iter()
+            .find(|id| id.parse::<usize>().is_ok_and(|id| id >= limit))
+        {
+            return Err(format!(
+                "{}.DeviceIDs includes {:?}, but hosts only expose {} GPUs",
+                field, id, limit
+            ));
+        }
+    }
+    Ok(gpus)
+}
+
+/// Validate the device requests of a job, and get the total amount of
+/// GPUs it takes. Jobs requesting more GPUs than a host exposes are
+/// rejected, since they would never be started.
+pub fn requested(host_config: Option<&HostConfig>) -> Result<usize, String> {
+    let gpus = host_config
+        .and_then(|host_config| host_config.device_requests.as_ref())
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .map(|(index, request)| check_request(index, request))
+        .sum::<Result<usize, String>>()?;
+    match limit() {
+        Some(limit) if gpus > limit => Err(format!(
+            "HostConfig.DeviceRequests takes {} GPUs, but hosts only expose {}",
+            gpus, limit
+        )),
+        _ => Ok(gpus),
+    }
+}
+
+/// Get the amount of GPUs taken by a listed job.
+pub fn taken(container: &ContainerSummary) -> usize {
+    docker::label(container, docker::GPUS_LABEL_KEY)
+        .and_then(|gpus| gpus.parse().ok())
+        .unwrap_or(0)
+}
@@ -0,0 +1,76 @@
+//! Implements the liveness and readiness checks.
+
+use crate::docker;
+use crate::shutdown;
+
+use actix_web::{error, get, HttpResponse, Responder, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Amount of missed cycles after which a background task is
+/// considered stuck.
+const STALE_CYCLES: u32 = 3;
+
+/// Background tasks expected to complete cycles periodically, with
+/// the time of their last completed cycle (or of their start) and
+/// their cycle period.
+static TASKS: Lazy<Mutex<HashMap<&'static str, (Instant, Duration)>>> = Lazy::new(Default::default);
+
+/// Expect the given background task to complete a cycle at least
+/// every period from now on.
+pub fn expect(task: &'static str, period: Duration) {
+    if let Ok(mut tasks) = TASKS.lock() {
+        tasks.insert(task, (Instant::now(), period));
+    }
+}
+
+/// Record a completed cycle of the given background task.
+pub fn beat(task: &'static str) {
+    if let Ok(mut tasks) = TASKS.lock() {
+        if let Some((last, _)) = tasks.get_mut(task) {
+            *last = Instant::now();
+        }
+    }
+}
+
+/// Describe the first background task that hasn't completed a cycle
+/// in a while, if any.
+fn stuck_task() -> Option<String> {
+    let tasks = TASKS.lock().ok()?;
+    tasks.iter().find_map(|(task, (last, period))| {
+        let elapsed = last.elapsed();
+        (elapsed > *period * STALE_CYCLES).then(|| {
+            format!(
+                "the {} hasn't completed a cycle in {} seconds",
+                task,
+                elapsed.as_secs()
+            )
+        })
+    })
+}
+
+/// Liveness check: if this function can execute, the process is
+/// alive.
+#[get("/health/live")]
+async fn liveness_check() -> impl Responder {
+    HttpResponse::NoContent().finish()
+}
+
+/// Readiness check: if the docker API responds, every background task
+/// completed a cycle recently and the process isn't shutting down, the
+/// process is ready to receive commands.
+#[get("/health/ready")]
+async fn readiness_check() -> Result<impl Responder> {
+    if shutdown::is_stopping() {
+        return Err(error::ErrorServiceUnavailable("shutting down"));
+    }
+    docker::ping()
+        .await
+        .map_err(error::ErrorServiceUnavailable)?;
+    if let Some(reason) = stuck_task() {
+        return Err(error::ErrorServiceUnavailable(reason));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
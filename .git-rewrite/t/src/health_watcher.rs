@@ -0,0 +1,52 @@
+//! Notifies an external service whenever a job turns unhealthy.
+
+use crate::docker;
+use anyhow::{Context, Result};
+use futures::stream::TryStreamExt;
+use serde_json::json;
+use tracing::{error, warn};
+
+/// Action of the events emitted when a container turns unhealthy.
+const UNHEALTHY_ACTION: &str = "health_status: unhealthy";
+
+/// Consume the docker events stream, and POST a notification to the
+/// webhook for each job that turns unhealthy. Notification failures
+/// are logged and otherwise ignored.
+pub async fn watch(namespace: String, webhook: String) -> Result<()> {
+    let client = reqwest::Client::new();
+    docker::job_events(&namespace, &["health_status"])?
+        .try_for_each(|event| {
+            let client = &client;
+            let namespace = &namespace;
+            let webhook = &webhook;
+            async move {
+                if event.action.as_deref() != Some(UNHEALTHY_ACTION) {
+                    return Ok(());
+                }
+                let job = event
+                    .actor
+                    .and_then(|actor| actor.attributes)
+                    .and_then(|attributes| attributes.get("name").cloned())
+                    .unwrap_or_default();
+                warn!("Job {:?} turned unhealthy", job);
+                let result = client
+                    .post(webhook)
+                    .json(&json!({
+                        "id": job,
+                        "namespace": namespace,
+                        "health": "unhealthy",
+                        "time": event.time,
+                    }))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    error!("Couldn't notify that job {:?} is unhealthy: {:?}", job, e);
+                }
+                Ok(())
+            }
+        })
+        .await
+        .context("while watching job health")?;
+    Ok(())
+}
@@ -0,0 +1,142 @@
+//! Allows or denies requests to the job and admin endpoints by the IP
+//! address of the client, for deployments where network policy alone
+//! isn't enough. Behind a reverse proxy, the client address may be
+//! read from a header set by the proxy, as long as the request comes
+//! from a trusted one.
+
+use crate::api_error::APIError;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error, HttpMessage, HttpRequest,
+};
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/// Lists of networks allowed or denied, and how to find the client
+/// address.
+#[derive(Default)]
+pub struct Filter {
+    /// Networks allowed to reach the job and admin endpoints; if
+    /// empty, every network not denied is.
+    pub allow: Vec<IpNet>,
+    /// Networks denied the job and admin endpoints.
+    pub deny: Vec<IpNet>,
+    /// Networks additionally required for the admin endpoints.
+    pub admin_allow: Vec<IpNet>,
+    /// Networks additionally denied the admin endpoints.
+    pub admin_deny: Vec<IpNet>,
+    /// Header holding the client address, set by a reverse proxy.
+    pub client_ip_header: Option<String>,
+    /// Networks of the proxies trusted to set the header.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// The client address of a request, as read from the proxy header.
+#[derive(Clone, Copy)]
+struct ClientIp(IpAddr);
+
+/// Parse a network given in CIDR notation, or a single address.
+pub fn parse_network(network: &str) -> Result<IpNet, String> {
+    let network = network.trim();
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is not an IP address or network", network))
+}
+
+/// Whether an address belongs to any of the given networks.
+fn within(networks: &[IpNet], address: &IpAddr) -> bool {
+    networks.iter().any(|network| network.contains(address))
+}
+
+/// Whether an address passes an allow and a deny list.
+fn passes(allow: &[IpNet], deny: &[IpNet], address: &IpAddr) -> bool {
+    (allow.is_empty() || within(allow, address)) && !within(deny, address)
+}
+
+impl Filter {
+    /// Whether any address is denied.
+    pub fn is_enabled(&self) -> bool {
+        !self.allow.is_empty()
+            || !self.deny.is_empty()
+            || !self.admin_allow.is_empty()
+            || !self.admin_deny.is_empty()
+    }
+
+    /// The client address given by a trusted proxy: the last one in
+    /// the header that isn't itself a trusted proxy, since earlier
+    /// ones may be forged by the client.
+    fn forwarded(&self, request: &ServiceRequest, peer: IpAddr) -> Option<IpAddr> {
+        let header = self.client_ip_header.as_ref()?;
+        if !within(&self.trusted_proxies, &peer) {
+            return None;
+        }
+        let addresses = request
+            .headers()
+            .get_all(header.as_str())
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|address| address.trim().parse::<IpAddr>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        addresses
+            .iter()
+            .rev()
+            .find(|address| !within(&self.trusted_proxies, address))
+            .or(addresses.first())
+            .copied()
+    }
+
+    /// Whether an address may reach the given path.
+    fn allows(&self, address: &IpAddr, path: &str) -> bool {
+        if path == "/job" || path.starts_with("/job/") {
+            passes(&self.allow, &self.deny, address)
+        } else if path.starts_with("/admin/") {
+            passes(&self.allow, &self.deny, address)
+                && passes(&self.admin_allow, &self.admin_deny, address)
+        } else {
+            true
+        }
+    }
+}
+
+/// Remember the client address given by a trusted proxy, and reject
+/// requests to the job and admin endpoints from addresses not
+/// allowed.
+pub async fn filter(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let filter = request.app_data::<web::Data<Filter>>().cloned();
+    let peer = request.peer_addr().map(|address| address.ip());
+    if let Some((filter, peer)) = filter.zip(peer) {
+        let address = match filter.forwarded(&request, peer) {
+            Some(address) => {
+                request.extensions_mut().insert(ClientIp(address));
+                address
+            }
+            None => peer,
+        };
+        if !filter.allows(&address, request.path()) {
+            let response = request.error_response(APIError::forbidden(format!(
+                "Requests from {} are not allowed",
+                address
+            )));
+            return Ok(response.map_into_right_body());
+        }
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// The address of the client behind a request: the one given by a
+/// trusted proxy, or else the peer address.
+pub fn client_ip(request: &HttpRequest) -> Option<IpAddr> {
+    request
+        .extensions()
+        .get::<ClientIp>()
+        .map(|address| address.0)
+        .or_else(|| request.peer_addr().map(|address| address.ip()))
+}
@@ -0,0 +1,155 @@
+//! Keeps a persistent record of dispatched jobs, which survives both
+//! dispatcher restarts and the removal of the jobs' containers.
+//!
+//! Records are created along with jobs, and then follow their
+//! lifecycle through the docker events stream. They're kept in an
+//! embedded database, keyed by job name, and are never removed.
+
+use crate::docker;
+use anyhow::{Context, Result};
+use chrono::offset::Utc;
+use futures::stream::TryStreamExt;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::{debug, warn};
+
+/// Static job store.
+static DB: OnceCell<sled::Db> = OnceCell::new();
+
+/// The last known state of a job.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    #[default]
+    Created,
+    Running,
+    Exited,
+    FailedToStart,
+}
+
+/// A record of a dispatched job. Times are given as UNIX timestamps.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Record {
+    pub namespace: String,
+    pub manifest_hash: Option<String>,
+    pub path: Option<String>,
+    pub submitter: Option<String>,
+    pub api_key: Option<String>,
+    pub client_cert: Option<String>,
+    pub request_id: Option<String>,
+    pub created: Option<i64>,
+    pub started: Option<i64>,
+    pub finished: Option<i64>,
+    pub removed: Option<i64>,
+    pub exit_code: Option<i64>,
+    pub attempts: u16,
+    pub last_error: Option<String>,
+    pub state: State,
+}
+
+/// Open the job store at the given path, creating it if it doesn't
+/// exist.
+pub fn init(path: &Path) -> Result<()> {
+    let db = sled::open(path).with_context(|| format!("while opening the job store {:?}", path))?;
+    let _ = DB.set(db);
+    Ok(())
+}
+
+/// Get the record of a job, if any.
+pub fn get(name: &str) -> Option<Record> {
+    let value = DB.get()?.get(name).ok()??;
+    serde_json::from_slice(&value).ok()
+}
+
+/// Store the record of a job.
+fn put(name: &str, record: &Record) -> Result<()> {
+    let Some(db) = DB.get() else {
+        return Ok(());
+    };
+    db.insert(name, serde_json::to_vec(record)?)?;
+    Ok(())
+}
+
+/// Record a newly created job, replacing the record of any previous
+/// job with the same name. Nothing is recorded if the store is
+/// disabled.
+pub fn record(name: &str, record: Record) {
+    let record = Record {
+        created: record.created.or(Some(Utc::now().timestamp())),
+        ..record
+    };
+    if let Err(e) = put(name, &record) {
+        warn!("Couldn't record job {:?}: {:?}", name, e);
+    }
+}
+
+/// Record a job created before the store was enabled, or while the
+/// dispatcher wasn't running, unless it's recorded already. Returns
+/// whether the job was recorded.
+pub fn adopt(name: &str, record: &Record) -> bool {
+    let Some(db) = DB.get() else {
+        return false;
+    };
+    let Ok(value) = serde_json::to_vec(record) else {
+        return false;
+    };
+    match db.compare_and_swap(name, None as Option<&[u8]>, Some(value)) {
+        Ok(result) => result.is_ok(),
+        Err(e) => {
+            warn!("Couldn't record job {:?}: {:?}", name, e);
+            false
+        }
+    }
+}
+
+/// Update the record of a job, if it exists.
+pub fn update<F: FnOnce(&mut Record)>(name: &str, f: F) {
+    let Some(mut record) = get(name) else {
+        return;
+    };
+    f(&mut record);
+    if let Err(e) = put(name, &record) {
+        warn!("Couldn't update the record of job {:?}: {:?}", name, e);
+    }
+}
+
+/// Consume the docker events stream, following the lifecycle of
+/// recorded jobs.
+pub async fn watch(namespace: String) -> Result<()> {
+    docker::job_events(&namespace, &["start", "die", "destroy"])?
+        .try_for_each(|event| async move {
+            let attributes = event
+                .actor
+                .and_then(|actor| actor.attributes)
+                .unwrap_or_default();
+            let Some(name) = attributes.get("name") else {
+                return Ok(());
+            };
+            let time = event.time;
+            match event.action.as_deref() {
+                Some("start") => update(name, |record| {
+                    record.started = time;
+                    record.finished = None;
+                    record.exit_code = None;
+                    record.state = State::Running;
+                }),
+                Some("die") => update(name, |record| {
+                    record.finished = time;
+                    record.exit_code = attributes
+                        .get("exitCode")
+                        .and_then(|code| code.parse().ok());
+                    record.state = State::Exited;
+                }),
+                Some("destroy") => {
+                    debug!("Recording the removal of job {:?}", name);
+                    update(name, |record| record.removed = time);
+                }
+                _ => (),
+            }
+            Ok(())
+        })
+        .await
+        .context("while following recorded jobs")?;
+    Ok(())
+}
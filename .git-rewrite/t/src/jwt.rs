@@ -0,0 +1,209 @@
+//! Validates JWTs issued by an OpenID Connect provider, against the
+//! keys the provider publishes in its JWKS document, so that existing
+//! SSO tokens may authorize job submission.
+
+use anyhow::{anyhow, bail, Context, Result};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Static token verifier.
+static VERIFIER: OnceCell<Verifier> = OnceCell::new();
+
+/// Minimum time between refreshes of the keys, triggered by tokens
+/// signed with unknown keys.
+const REFRESH_PERIOD: Duration = Duration::from_secs(60);
+
+/// The identity carried by a validated token.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub subject: Option<String>,
+    pub scopes: Vec<String>,
+    pub claims: Value,
+}
+
+/// Why a token was refused.
+pub enum Refusal {
+    /// The token is invalid, expired, or not signed by the issuer.
+    Invalid(String),
+    /// The token lacks the required scope.
+    Forbidden(String),
+}
+
+/// The part of the OpenID Connect discovery document used.
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+/// Settings and published keys of the token issuer.
+struct Verifier {
+    client: reqwest::Client,
+    issuer: String,
+    jwks_url: String,
+    audience: Option<String>,
+    scope: Option<String>,
+    keys: RwLock<JwkSet>,
+    refreshed: Mutex<Instant>,
+}
+
+impl Verifier {
+    /// Fetch the issuer's published keys.
+    async fn fetch(&self) -> Result<JwkSet> {
+        self.client
+            .get(&self.jwks_url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("while fetching the JWKS at {:?}", self.jwks_url))?
+            .json()
+            .await
+            .with_context(|| format!("while reading the JWKS at {:?}", self.jwks_url))
+    }
+
+    /// Find the key with the given ID, re-fetching the keys if it's
+    /// unknown (e.g. if they were rotated) and they weren't fetched
+    /// recently.
+    async fn key(&self, kid: &str) -> Option<Jwk> {
+        if let Some(key) = self.keys.read().await.find(kid) {
+            return Some(key.clone());
+        }
+        let mut refreshed = self.refreshed.lock().await;
+        if refreshed.elapsed() >= REFRESH_PERIOD {
+            *refreshed = Instant::now();
+            match self.fetch().await {
+                Ok(keys) => *self.keys.write().await = keys,
+                Err(e) => warn!("Couldn't refresh the token issuer's keys: {:?}", e),
+            }
+        }
+        self.keys.read().await.find(kid).cloned()
+    }
+}
+
+/// Read the scopes of a token, given either as a space-separated
+/// `scope` claim or as a `scp` array.
+fn scopes(claims: &Value) -> Vec<String> {
+    match (claims.get("scope"), claims.get("scp")) {
+        (Some(Value::String(scope)), _) => scope.split_whitespace().map(String::from).collect(),
+        (_, Some(Value::Array(scopes))) => scopes
+            .iter()
+            .filter_map(Value::as_str)
+            .map(String::from)
+            .collect(),
+        (_, Some(Value::String(scope))) => scope.split_whitespace().map(String::from).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Set up token validation for the given issuer, fetching its keys
+/// from the given JWKS URL, or else from the one advertised in the
+/// issuer's discovery document.
+pub async fn init(
+    issuer: String,
+    jwks_url: Option<String>,
+    audience: Option<String>,
+    scope: Option<String>,
+) -> Result<()> {
+    let client = reqwest::Client::new();
+    let jwks_url = match jwks_url {
+        Some(url) => url,
+        None => {
+            let url = format!(
+                "{}/.well-known/openid-configuration",
+                issuer.trim_end_matches('/')
+            );
+            client
+                .get(&url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| format!("while fetching the discovery document at {:?}", url))?
+                .json::<Discovery>()
+                .await
+                .with_context(|| format!("while reading the discovery document at {:?}", url))?
+                .jwks_uri
+        }
+    };
+    let mut verifier = Verifier {
+        client,
+        issuer,
+        jwks_url,
+        audience,
+        scope,
+        keys: RwLock::new(JwkSet { keys: Vec::new() }),
+        refreshed: Mutex::new(Instant::now()),
+    };
+    verifier.keys = RwLock::new(verifier.fetch().await?);
+    info!(
+        "Validating tokens issued by {:?}, with the keys at {:?}",
+        verifier.issuer, verifier.jwks_url
+    );
+    VERIFIER
+        .set(verifier)
+        .map_err(|_| anyhow!("token validation was already set up"))
+}
+
+/// Whether tokens are validated.
+pub fn is_enabled() -> bool {
+    VERIFIER.get().is_some()
+}
+
+/// Whether the given credential looks like a JWT, as opposed to an
+/// API key.
+pub fn is_token(credential: &str) -> bool {
+    decode_header(credential).is_ok()
+}
+
+/// Validate a token, and read the identity it carries.
+pub async fn verify(token: &str) -> Result<Token, Refusal> {
+    let Some(verifier) = VERIFIER.get() else {
+        return Err(Refusal::Invalid(String::from("Tokens aren't accepted")));
+    };
+    let checked = async {
+        let header = decode_header(token)?;
+        // symmetric algorithms would let the public keys sign tokens
+        if matches!(
+            header.alg,
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512
+        ) {
+            bail!("unsupported signing algorithm {:?}", header.alg);
+        }
+        let kid = header.kid.context("the token doesn't name its key")?;
+        let key = verifier
+            .key(&kid)
+            .await
+            .with_context(|| format!("unknown key {:?}", kid))?;
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&verifier.issuer]);
+        match &verifier.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(decode::<Value>(token, &DecodingKey::from_jwk(&key)?, &validation)?.claims)
+    };
+    let claims = checked
+        .await
+        .map_err(|e: anyhow::Error| Refusal::Invalid(format!("Invalid token: {}", e)))?;
+    let token = Token {
+        subject: claims.get("sub").and_then(Value::as_str).map(String::from),
+        scopes: scopes(&claims),
+        claims,
+    };
+    if let Some(scope) = &verifier.scope {
+        if !token.scopes.contains(scope) {
+            return Err(Refusal::Forbidden(format!(
+                "The token lacks the {:?} scope",
+                scope
+            )));
+        }
+    }
+    Ok(token)
+}
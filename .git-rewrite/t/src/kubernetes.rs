@@ -0,0 +1,383 @@
+//! Maps jobs to Kubernetes batch/v1 Jobs.
+
+use crate::backend::{self, Backend, Creation};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::Config,
+    models::{
+        ContainerConfig, ContainerInspectResponse, ContainerState, ContainerStateStatusEnum,
+        ContainerSummary,
+    },
+};
+use k8s_openapi::{
+    api::{
+        batch::v1::{Job, JobSpec},
+        core::v1::{Container, EnvVar, Pod, PodSpec, PodTemplateSpec, ResourceRequirements},
+    },
+    apimachinery::pkg::{api::resource::Quantity, apis::meta::v1::ObjectMeta},
+};
+use kube::{
+    api::{Api, DeleteParams, ListParams, LogParams, Patch, PatchParams, PostParams},
+    Client,
+};
+use serde_json::json;
+use std::collections::{BTreeMap, HashMap};
+
+/// Name of the single container of each job's pod.
+const CONTAINER_NAME: &str = "job";
+
+/// A backend dispatching jobs to a Kubernetes namespace.
+pub struct Kubernetes {
+    client: Client,
+    namespace: String,
+}
+
+impl Kubernetes {
+    /// Connect to the Kubernetes API, using the in-cluster
+    /// configuration or the local kubeconfig, and dispatch jobs to the
+    /// given namespace or to the configuration's default one.
+    pub async fn connect(namespace: Option<String>) -> Result<Self> {
+        let client = Client::try_default()
+            .await
+            .context("while configuring the Kubernetes client")?;
+        let namespace = namespace.unwrap_or_else(|| client.default_namespace().to_string());
+        Ok(Self { client, namespace })
+    }
+
+    /// Get the jobs API of the namespace.
+    fn jobs(&self) -> Api<Job> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// Get the pods API of the namespace.
+    fn pods(&self) -> Api<Pod> {
+        Api::namespaced(self.client.clone(), &self.namespace)
+    }
+
+    /// Get the most recent pod of a job, if any.
+    async fn pod(&self, name: &str) -> Result<Option<Pod>> {
+        Ok(self
+            .pods()
+            .list(&ListParams::default().labels(&format!("job-name={}", name)))
+            .await?
+            .items
+            .into_iter()
+            .max_by_key(|pod| pod.metadata.creation_timestamp.clone()))
+    }
+}
+
+/// Check whether a string is valid as a label value, or as a label
+/// key without prefix.
+fn is_label_syntax(value: &str) -> bool {
+    value.len() <= 63
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && value
+            .chars()
+            .next()
+            .is_none_or(|c| c.is_ascii_alphanumeric())
+        && value
+            .chars()
+            .last()
+            .is_none_or(|c| c.is_ascii_alphanumeric())
+}
+
+/// Build a batch/v1 Job out of a container configuration. Jobs are
+/// created suspended, and started by resuming them. Every container
+/// label is kept as an annotation, and also as a label if it's valid
+/// as such.
+fn job(name: String, config: Config<String>) -> Job {
+    let annotations: BTreeMap<String, String> =
+        config.labels.unwrap_or_default().into_iter().collect();
+    let labels: BTreeMap<String, String> = annotations
+        .iter()
+        .filter(|(key, value)| is_label_syntax(key) && is_label_syntax(value))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let host_config = config.host_config.unwrap_or_default();
+    let mut limits = BTreeMap::new();
+    if let Some(nano_cpus) = host_config.nano_cpus {
+        limits.insert(
+            String::from("cpu"),
+            Quantity(format!("{}m", nano_cpus / 1_000_000)),
+        );
+    }
+    if let Some(memory) = host_config.memory {
+        limits.insert(String::from("memory"), Quantity(memory.to_string()));
+    }
+    Job {
+        metadata: ObjectMeta {
+            name: Some(name),
+            labels: Some(labels.clone()),
+            annotations: Some(annotations),
+            ..Default::default()
+        },
+        spec: Some(JobSpec {
+            suspend: Some(true),
+            backoff_limit: Some(0),
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    restart_policy: Some(String::from("Never")),
+                    hostname: config.hostname,
+                    containers: vec![Container {
+                        name: String::from(CONTAINER_NAME),
+                        image: config.image,
+                        command: config.entrypoint,
+                        args: config.cmd,
+                        working_dir: config.working_dir,
+                        env: config.env.map(|env| {
+                            env.into_iter()
+                                .map(|var| match var.split_once('=') {
+                                    Some((name, value)) => EnvVar {
+                                        name: name.to_string(),
+                                        value: Some(value.to_string()),
+                                        ..Default::default()
+                                    },
+                                    None => EnvVar {
+                                        name: var,
+                                        ..Default::default()
+                                    },
+                                })
+                                .collect()
+                        }),
+                        resources: (!limits.is_empty()).then(|| ResourceRequirements {
+                            limits: Some(limits),
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Get the equivalent container state of a job.
+fn state(job: &Job) -> ContainerStateStatusEnum {
+    let status = job.status.as_ref();
+    if status.and_then(|s| s.succeeded).unwrap_or(0) > 0
+        || status.and_then(|s| s.failed).unwrap_or(0) > 0
+    {
+        ContainerStateStatusEnum::EXITED
+    } else if status.and_then(|s| s.active).unwrap_or(0) > 0 {
+        ContainerStateStatusEnum::RUNNING
+    } else {
+        ContainerStateStatusEnum::CREATED
+    }
+}
+
+/// Get the container of a job's pod template.
+fn container(job: &Job) -> Option<&Container> {
+    job.spec
+        .as_ref()?
+        .template
+        .spec
+        .as_ref()?
+        .containers
+        .first()
+}
+
+/// Get the container labels of a job, kept as annotations.
+fn labels(job: &Job) -> Option<HashMap<String, String>> {
+    job.metadata
+        .annotations
+        .as_ref()
+        .map(|annotations| annotations.clone().into_iter().collect())
+}
+
+/// Describe a job as a listed container.
+fn summary(job: Job) -> ContainerSummary {
+    let state = state(&job).to_string();
+    ContainerSummary {
+        id: job.metadata.uid.clone(),
+        names: job
+            .metadata
+            .name
+            .as_ref()
+            .map(|name| vec![format!("/{}", name)]),
+        image: container(&job).and_then(|container| container.image.clone()),
+        created: job
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .map(|created| created.0.timestamp()),
+        labels: labels(&job),
+        status: Some(state.clone()),
+        state: Some(state),
+        ..Default::default()
+    }
+}
+
+/// Describe a job as an inspected container. The exit code is taken
+/// from the job's pod when available.
+fn inspection(job: Job, pod: Option<Pod>) -> ContainerInspectResponse {
+    let state = state(&job);
+    let status = job.status.as_ref();
+    let failed = status.and_then(|s| s.failed).unwrap_or(0) > 0;
+    let terminated = pod
+        .and_then(|pod| pod.status)
+        .and_then(|status| status.container_statuses)
+        .and_then(|statuses| statuses.into_iter().find(|s| s.name == CONTAINER_NAME))
+        .and_then(|status| status.state)
+        .and_then(|state| state.terminated);
+    let exit_code = match (state == ContainerStateStatusEnum::EXITED, &terminated) {
+        (false, _) => None,
+        (true, Some(terminated)) => Some(terminated.exit_code.into()),
+        (true, None) => Some(if failed { 1 } else { 0 }),
+    };
+    let finished_at = terminated
+        .and_then(|terminated| terminated.finished_at)
+        .or_else(|| status.and_then(|s| s.completion_time.clone()));
+    let container = container(&job);
+    ContainerInspectResponse {
+        id: job.metadata.uid.clone(),
+        name: job.metadata.name.as_ref().map(|name| format!("/{}", name)),
+        created: job
+            .metadata
+            .creation_timestamp
+            .as_ref()
+            .map(|created| created.0.to_rfc3339()),
+        state: Some(ContainerState {
+            running: Some(state == ContainerStateStatusEnum::RUNNING),
+            exit_code,
+            started_at: status
+                .and_then(|s| s.start_time.as_ref())
+                .map(|started| started.0.to_rfc3339()),
+            finished_at: finished_at.map(|finished| finished.0.to_rfc3339()),
+            status: Some(state),
+            ..Default::default()
+        }),
+        config: Some(ContainerConfig {
+            image: container.and_then(|container| container.image.clone()),
+            labels: labels(&job),
+            cmd: container.and_then(|container| container.args.clone()),
+            entrypoint: container.and_then(|container| container.command.clone()),
+            working_dir: container.and_then(|container| container.working_dir.clone()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Whether an error is a Kubernetes API error with the given code.
+fn is_api_error(error: &kube::Error, code: u16) -> bool {
+    matches!(error, kube::Error::Api(response) if response.code == code)
+}
+
+#[async_trait]
+impl Backend for Kubernetes {
+    async fn ping(&self) -> Result<()> {
+        self.client.apiserver_version().await?;
+        Ok(())
+    }
+
+    async fn create(&self, creation: Creation) -> Result<Option<String>> {
+        let Creation { name, config, .. } = creation;
+        match self
+            .jobs()
+            .create(&PostParams::default(), &job(name, config))
+            .await
+        {
+            Ok(job) => Ok(Some(job.metadata.uid.unwrap_or_default())),
+            Err(e) if is_api_error(&e, 409) => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    async fn start(&self, name: &str) -> Result<()> {
+        self.jobs()
+            .patch(
+                name,
+                &PatchParams::default(),
+                &Patch::Merge(json!({"spec": {"suspend": false}})),
+            )
+            .await
+            .context("while resuming job")?;
+        Ok(())
+    }
+
+    async fn list(&self, filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>> {
+        let label_filters: Vec<(&str, &str)> = filters
+            .get("label")
+            .map(|labels| {
+                labels
+                    .iter()
+                    .filter_map(|label| label.split_once('='))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let selector = label_filters
+            .iter()
+            .filter(|(key, value)| is_label_syntax(key) && is_label_syntax(value))
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join(",");
+        let names = backend::filter_names(filters);
+        Ok(self
+            .jobs()
+            .list(&ListParams::default().labels(&selector))
+            .await?
+            .items
+            .into_iter()
+            .filter(|job| {
+                names.as_ref().is_none_or(|names| {
+                    job.metadata
+                        .name
+                        .as_deref()
+                        .is_some_and(|name| names.contains(&name))
+                })
+            })
+            .filter(|job| {
+                // labels that aren't valid as such are only kept as
+                // annotations
+                label_filters.iter().all(|(key, value)| {
+                    job.metadata
+                        .annotations
+                        .as_ref()
+                        .and_then(|annotations| annotations.get(*key))
+                        .is_some_and(|v| v == value)
+                })
+            })
+            .map(summary)
+            .filter(|container| backend::status_matches(filters, container))
+            .collect())
+    }
+
+    async fn inspect(&self, name: &str) -> Result<ContainerInspectResponse> {
+        let job = self.jobs().get(name).await?;
+        let pod = match state(&job) {
+            ContainerStateStatusEnum::EXITED => self.pod(name).await?,
+            _ => None,
+        };
+        Ok(inspection(job, pod))
+    }
+
+    async fn logs(&self, name: &str) -> Result<Vec<u8>> {
+        let Some(pod_name) = self.pod(name).await?.and_then(|pod| pod.metadata.name) else {
+            return Ok(Vec::new());
+        };
+        Ok(self
+            .pods()
+            .logs(&pod_name, &LogParams::default())
+            .await
+            .context("while fetching pod logs")?
+            .into_bytes())
+    }
+
+    async fn remove(&self, name: &str, _volumes: bool) -> Result<()> {
+        match self.jobs().delete(name, &DeleteParams::background()).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_api_error(&e, 404) => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Elects a leader among dispatcher replicas sharing a lock file, so
+//! that only one of them runs the upkeep tasks.
+//!
+//! The leader holds an exclusive lock on the file for as long as it
+//! lives, and the lock is released by the OS when it exits, so that a
+//! waiting replica takes over.
+
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use fs2::FileExt;
+use once_cell::sync::OnceCell;
+use std::fs::OpenOptions;
+use std::future::{self, Future};
+use std::io::Write;
+use std::path::PathBuf;
+use tokio::sync::watch;
+use tokio::time::{self, Duration};
+use tracing::info;
+
+/// How often replicas try to become the leader.
+const CAMPAIGN_PERIOD: Duration = Duration::from_secs(1);
+
+/// Static election result, unset if there's no election.
+static ELECTED: OnceCell<watch::Sender<bool>> = OnceCell::new();
+
+/// Wait until this replica is the leader. Without an election, every
+/// replica is.
+async fn elected() {
+    if let Some(elected) = ELECTED.get() {
+        let _ = elected.subscribe().wait_for(|elected| *elected).await;
+    }
+}
+
+/// Run an upkeep task once this replica is the leader, or not at all
+/// if it shuts down before that.
+pub async fn led(task: impl Future<Output = Result<()>>) -> Result<()> {
+    tokio::select! {
+        _ = elected() => task.await,
+        _ = shutdown::reached(Phase::Stopping) => Ok(()),
+    }
+}
+
+/// Start an election through the given lock file, returning the task
+/// that tries to become the leader until it does, and then holds the
+/// lock forever.
+pub fn campaign(path: PathBuf) -> impl Future<Output = Result<()>> {
+    let _ = ELECTED.set(watch::channel(false).0);
+    async move {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("while opening the leader lock file {:?}", path))?;
+        info!("Waiting to be elected leader through {:?}", path);
+        let mut interval = time::interval(CAMPAIGN_PERIOD);
+        loop {
+            interval.tick().await;
+            match file.try_lock_exclusive() {
+                Ok(()) => break,
+                Err(e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => (),
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("while locking the leader lock file {:?}", path))
+                }
+            }
+        }
+        info!("Elected as leader; running the upkeep tasks");
+        // leave a trace of the current leader
+        let _ = file
+            .set_len(0)
+            .and_then(|_| writeln!(file, "{}", std::process::id()));
+        if let Some(elected) = ELECTED.get() {
+            elected.send_replace(true);
+        }
+        // hold the lock for as long as the process lives
+        let _lock = file;
+        future::pending().await
+    }
+}
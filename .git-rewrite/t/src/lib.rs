@@ -0,0 +1,94 @@
+//! Job-dispatching interface acting as a docker container scheduler.
+//!
+//! Besides the `docker-job-dispatcher` binary, the dispatcher may be
+//! embedded in a larger actix-web application, alongside custom
+//! routes:
+//!
+//! ```no_run
+//! use actix_web::{middleware, web, App, HttpServer};
+//! use clap::Parser;
+//! use docker_job_dispatcher::{internal, Cli, Dispatcher};
+//!
+//! # async fn embed() -> anyhow::Result<()> {
+//! let cli = Cli::parse_from(["dispatcher", "--max-concurrent", "2"]);
+//! let dispatcher = Dispatcher::init(cli).await?;
+//! let mut tasks = dispatcher.spawn().await?;
+//! let server = HttpServer::new(move || {
+//!     App::new()
+//!         .wrap(middleware::from_fn(internal::guard))
+//!         .configure(|cfg| dispatcher.configure(cfg))
+//!         .route("/hello", web::get().to(|| async { "hello" }))
+//! })
+//! .bind(("0.0.0.0", 8000))?
+//! .run();
+//! tokio::select! {
+//!     result = server => result?,
+//!     result = tasks.wait() => result?,
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Most of the dispatcher's state is global, so only one dispatcher
+//! may be initialized per process.
+
+pub mod access_log;
+pub mod admin_service;
+mod amqp;
+pub mod api_error;
+mod app;
+mod archive;
+mod attempts;
+mod audit;
+pub mod auth;
+pub mod authorization;
+pub mod backend;
+mod circuit_breaker;
+pub mod cleaner;
+mod cpusets;
+mod dispatch_queue;
+pub mod docker;
+pub mod docker_service;
+mod error_report;
+mod exits;
+mod gpu;
+pub mod health_service;
+mod health_watcher;
+mod image_pruner;
+pub mod internal;
+pub mod ip_filter;
+mod job_store;
+pub mod jq;
+pub mod jwt;
+mod kafka;
+mod kubernetes;
+mod leader;
+pub mod metrics_service;
+mod nats;
+mod nomad;
+mod notifier;
+mod object_store;
+mod otlp_metrics;
+pub mod policy;
+mod pushgateway;
+pub mod rate_limit;
+mod redact;
+mod registry_auth;
+pub mod reload;
+pub mod request_id;
+mod retry;
+pub mod scheduler;
+mod secrets;
+pub mod shard;
+mod shutdown;
+pub mod signature;
+mod socket_activation;
+mod ssh_tunnel;
+mod startup;
+pub mod statsd;
+mod supervisor;
+mod swarm;
+mod telemetry;
+pub mod tls;
+
+pub use app::{init_tracing, internal_services, no_route, Cli, Dispatcher, LogFormat, Tasks};
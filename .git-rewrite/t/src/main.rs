@@ -0,0 +1,10 @@
+use anyhow::Result;
+use clap::Parser;
+use docker_job_dispatcher::{init_tracing, Cli, Dispatcher};
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    init_tracing(&cli)?;
+    Dispatcher::init(cli).await?.serve().await
+}
@@ -0,0 +1,729 @@
+//! Collects metrics from the docker events stream and exposes them in
+//! OpenMetrics format.
+
+use crate::docker;
+use crate::health_service;
+use crate::retry;
+use crate::shutdown::{self, Phase};
+use crate::statsd;
+
+use actix_web::{error, get, HttpResponse};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use futures::stream::TryStreamExt;
+use once_cell::sync::{Lazy, OnceCell};
+use prometheus_client::{
+    encoding::{text::encode, EncodeLabelSet},
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::Registry,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::sync::Arc;
+use tokio::fs;
+use tokio::sync::Mutex;
+use tokio::time::{self, Duration};
+use tracing::{info, warn};
+
+/// Static metrics registry.
+static REGISTRY: OnceCell<Arc<Mutex<Registry>>> = OnceCell::new();
+
+/// Static image pull counter.
+static PULLS: Lazy<Family<PullLabels, Counter>> = Lazy::new(Family::default);
+
+/// Static API request counter.
+static REQUESTS: Lazy<Family<RequestLabels, Counter>> = Lazy::new(Family::default);
+
+/// Static API request latency histogram.
+static LATENCIES: Lazy<Family<LatencyLabels, Histogram>> =
+    Lazy::new(|| Family::new_with_constructor(latency_histogram));
+
+/// Static upkeep cycle duration histogram.
+static CYCLE_DURATIONS: Lazy<Family<UpkeepLabels, Histogram>> =
+    Lazy::new(|| Family::new_with_constructor(cycle_duration_histogram));
+
+/// Static histogram of the jobs handled in each upkeep cycle.
+static CYCLE_JOBS: Lazy<Family<UpkeepLabels, Histogram>> =
+    Lazy::new(|| Family::new_with_constructor(cycle_jobs_histogram));
+
+/// Static gauge of consecutive upkeep errors.
+static UPKEEP_ERRORS: Lazy<Family<UpkeepLabels, Gauge>> = Lazy::new(Family::default);
+
+/// Static gauge of the time of the last successful upkeep cycle.
+static LAST_SUCCESS: Lazy<Family<UpkeepLabels, Gauge<f64, AtomicU64>>> = Lazy::new(Family::default);
+
+/// Static job counter.
+static JOBS: Lazy<Family<Labels, Counter>> = Lazy::new(Family::default);
+
+/// Static job failure counter.
+static FAILURES: Lazy<Family<FailureLabels, Counter>> = Lazy::new(Family::default);
+
+/// Static gauge of the jobs in each state.
+static STATE_GAUGES: Lazy<Family<StateLabels, Gauge>> = Lazy::new(Family::default);
+
+/// Static job run duration histogram.
+static DURATIONS: Lazy<Family<DurationLabels, Histogram>> =
+    Lazy::new(|| Family::new_with_constructor(duration_histogram));
+
+/// Static counter of background task restarts.
+static RESTARTS: Lazy<Family<UpkeepLabels, Counter>> = Lazy::new(Family::default);
+
+/// Static gauge of the docker calls waiting in the dispatch queue.
+static QUEUE_DEPTH: Lazy<Gauge> = Lazy::new(Gauge::default);
+
+/// Get the mutexed registry.
+fn registry() -> &'static Arc<Mutex<Registry>> {
+    REGISTRY.get_or_init(|| {
+        let mut reg = <Registry>::default();
+        reg.register("image_pulls", "Number of image pulls", PULLS.clone());
+        reg.register("http_requests", "Number of API requests", REQUESTS.clone());
+        reg.register(
+            "http_request_duration_seconds",
+            "Latency of API requests",
+            LATENCIES.clone(),
+        );
+        reg.register(
+            "upkeep_cycle_duration_seconds",
+            "Duration of upkeep cycles",
+            CYCLE_DURATIONS.clone(),
+        );
+        reg.register(
+            "upkeep_cycle_jobs",
+            "Number of jobs started or cleaned in each upkeep cycle",
+            CYCLE_JOBS.clone(),
+        );
+        reg.register(
+            "upkeep_consecutive_errors",
+            "Number of consecutive failed upkeep cycles",
+            UPKEEP_ERRORS.clone(),
+        );
+        reg.register(
+            "upkeep_last_success_timestamp_seconds",
+            "UNIX time of the last successful upkeep cycle",
+            LAST_SUCCESS.clone(),
+        );
+        reg.register("jobs", "Number of jobs", JOBS.clone());
+        reg.register(
+            "job_failures",
+            "Number of failed job runs, by class of failure",
+            FAILURES.clone(),
+        );
+        reg.register(
+            "job_states",
+            "Number of jobs in each state",
+            STATE_GAUGES.clone(),
+        );
+        reg.register(
+            "job_duration_seconds",
+            "Duration of job runs",
+            DURATIONS.clone(),
+        );
+        reg.register(
+            "task_restarts",
+            "Number of restarts of failed background tasks",
+            RESTARTS.clone(),
+        );
+        reg.register(
+            "dispatch_queue_depth",
+            "Number of docker calls waiting in the dispatch queue",
+            QUEUE_DEPTH.clone(),
+        );
+        Arc::new(Mutex::new(reg))
+    })
+}
+
+/// Metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, Serialize, Deserialize)]
+struct Labels {
+    namespace: String,
+    action: Option<String>,
+    status: Option<String>,
+}
+
+/// Job counters, mirrored so that their values can be saved.
+struct JobCounters {
+    family: Family<Labels, Counter>,
+    values: HashMap<Labels, u64>,
+    changed: bool,
+}
+
+impl JobCounters {
+    /// Start counting from scratch, dropping the values counted by a
+    /// previous run.
+    fn reset() -> Self {
+        JOBS.clear();
+        Self {
+            family: JOBS.clone(),
+            values: HashMap::new(),
+            changed: false,
+        }
+    }
+
+    fn inc_by(&mut self, labels: Labels, amount: u64) {
+        self.family.get_or_create(&labels).inc_by(amount);
+        *self.values.entry(labels).or_default() += amount;
+        self.changed = true;
+    }
+}
+
+/// Metric state saved across restarts: the job counters, and the
+/// time of the last event counted, in UNIX nanoseconds.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedState {
+    cursor: Option<i64>,
+    jobs: Vec<(Labels, u64)>,
+}
+
+/// Read the saved metric state, if any.
+async fn load_state(path: &Path) -> Result<Option<SavedState>> {
+    match fs::read(path).await {
+        Ok(contents) => Ok(Some(
+            serde_json::from_slice(&contents).context("while parsing the metrics state file")?,
+        )),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).context("while reading the metrics state file"),
+    }
+}
+
+/// Save the metric state, replacing the previous one atomically.
+async fn save_state(path: &Path, jobs: &JobCounters, cursor: Option<i64>) -> Result<()> {
+    let state = SavedState {
+        cursor,
+        jobs: jobs
+            .values
+            .iter()
+            .map(|(labels, value)| (labels.clone(), *value))
+            .collect(),
+    };
+    let partial = path.with_extension("partial");
+    fs::write(&partial, serde_json::to_vec(&state)?)
+        .await
+        .context("while writing the metrics state file")?;
+    fs::rename(&partial, path)
+        .await
+        .context("while replacing the metrics state file")?;
+    Ok(())
+}
+
+/// Job duration metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct DurationLabels {
+    namespace: String,
+    status: Option<String>,
+    image: Option<String>,
+}
+
+/// Build a job duration histogram, with buckets ranging from a second
+/// to about 18 hours.
+fn duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(1.0, 2.0, 17))
+}
+
+/// Job failure metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct FailureLabels {
+    namespace: String,
+    class: String,
+}
+
+/// Exit code of commands that timed out, following the convention of
+/// the timeout utility.
+const TIMEOUT_EXIT_CODE: &str = "124";
+
+/// Classify the failure of a job that died, if it failed. Jobs that
+/// can't be inspected any longer are classified by exit code alone.
+async fn failure_class(name: &str, exit_code: Option<&str>) -> Option<&'static str> {
+    if matches!(exit_code, None | Some("0")) {
+        return None;
+    }
+    let state = docker::inspect(name).await.ok().and_then(|job| job.state);
+    if state.as_ref().and_then(|state| state.oom_killed) == Some(true) {
+        Some("oom-killed")
+    } else if state
+        .as_ref()
+        .and_then(|state| state.error.as_deref())
+        .is_some_and(|error| !error.is_empty())
+    {
+        Some("start-failure")
+    } else if exit_code == Some(TIMEOUT_EXIT_CODE) {
+        Some("timeout")
+    } else {
+        Some("nonzero-exit")
+    }
+}
+
+/// Job state metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct StateLabels {
+    namespace: String,
+    state: String,
+}
+
+/// States reported by the job state gauges.
+const STATES: [&str; 3] = ["pending", "running", "exited"];
+
+/// Get the state of every job, as reported by the gauges.
+async fn list_states(namespace: &str) -> Result<HashMap<String, &'static str>> {
+    let (pending, active, exited) = tokio::try_join!(
+        docker::get_pending(namespace),
+        docker::get_active(namespace),
+        docker::get_exited(namespace)
+    )?;
+    Ok([
+        (pending, STATES[0]),
+        (active, STATES[1]),
+        (exited, STATES[2]),
+    ]
+    .into_iter()
+    .flat_map(|(containers, state)| {
+        containers.into_iter().filter_map(move |container| {
+            container
+                .names
+                .and_then(|names| names.into_iter().next())
+                .map(|name| (name.trim_start_matches('/').to_string(), state))
+        })
+    })
+    .collect())
+}
+
+/// Whether an event was already counted by the initial probe, which
+/// counts active jobs as created and started, and pending jobs as
+/// created.
+fn counted_by_probe(
+    probed: &HashMap<String, &'static str>,
+    name: &str,
+    action: Option<&str>,
+) -> bool {
+    matches!(
+        (probed.get(name).copied(), action),
+        (Some(_), Some("create")) | (Some("start"), Some("start"))
+    )
+}
+
+/// Set the job state gauges to the amount of jobs in each state.
+fn update_states(
+    gauges: &Family<StateLabels, Gauge>,
+    namespace: &str,
+    states: &HashMap<String, &'static str>,
+) {
+    for state in STATES {
+        let count = states.values().filter(|s| **s == state).count();
+        gauges
+            .get_or_create(&StateLabels {
+                namespace: namespace.to_string(),
+                state: state.to_string(),
+            })
+            .set(count as i64);
+        statsd::gauge(
+            "job_states",
+            count as f64,
+            &[("namespace", namespace), ("state", state)],
+        );
+    }
+}
+
+/// Image pull metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PullLabels {
+    registry: String,
+    result: String,
+}
+
+/// Count an image pull, either successful or failed.
+pub fn record_pull(registry: &str, success: bool) {
+    PULLS
+        .get_or_create(&PullLabels {
+            registry: registry.to_string(),
+            result: String::from(if success { "success" } else { "failure" }),
+        })
+        .inc();
+}
+
+/// API request metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct RequestLabels {
+    method: String,
+    endpoint: String,
+    status: u16,
+}
+
+/// API request latency metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct LatencyLabels {
+    method: String,
+    endpoint: String,
+    status_class: String,
+}
+
+/// Build an API request latency histogram, with buckets ranging from
+/// 5 milliseconds to about 10 seconds.
+fn latency_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.005, 2.0, 12))
+}
+
+/// Count an API request and record its latency. The endpoint is the
+/// matched route pattern, to keep the amount of series bounded.
+pub fn record_request(method: &str, endpoint: &str, status: u16, latency: Duration) {
+    REQUESTS
+        .get_or_create(&RequestLabels {
+            method: method.to_string(),
+            endpoint: endpoint.to_string(),
+            status,
+        })
+        .inc();
+    LATENCIES
+        .get_or_create(&LatencyLabels {
+            method: method.to_string(),
+            endpoint: endpoint.to_string(),
+            status_class: format!("{}xx", status / 100),
+        })
+        .observe(latency.as_secs_f64());
+}
+
+/// Upkeep loop metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct UpkeepLabels {
+    task: String,
+}
+
+/// Build an upkeep cycle duration histogram, with buckets ranging from
+/// 10 milliseconds to about 80 seconds.
+fn cycle_duration_histogram() -> Histogram {
+    Histogram::new(exponential_buckets(0.01, 2.0, 14))
+}
+
+/// Build a histogram of the jobs handled in each upkeep cycle, with
+/// buckets ranging from none to 256.
+fn cycle_jobs_histogram() -> Histogram {
+    Histogram::new([0.0].into_iter().chain(exponential_buckets(1.0, 2.0, 9)))
+}
+
+/// Record the amount of docker calls waiting in the dispatch queue.
+pub fn record_queue_depth(depth: usize) {
+    QUEUE_DEPTH.set(depth as i64);
+    statsd::gauge("dispatch_queue_depth", depth as f64, &[]);
+}
+
+/// Record an upkeep cycle of the given task (i.e. the scheduler or the
+/// cleaner), along with the amount of jobs it handled if successful,
+/// and the amount of consecutive errors so far.
+pub fn record_cycle(task: &str, duration: Duration, jobs: Option<usize>, errors: u8) {
+    let labels = UpkeepLabels {
+        task: task.to_string(),
+    };
+    let tags = [("task", task)];
+    CYCLE_DURATIONS
+        .get_or_create(&labels)
+        .observe(duration.as_secs_f64());
+    statsd::timing(
+        "upkeep_cycle_duration",
+        duration.as_secs_f64() * 1000.0,
+        &tags,
+    );
+    UPKEEP_ERRORS.get_or_create(&labels).set(errors.into());
+    statsd::gauge("upkeep_consecutive_errors", errors.into(), &tags);
+    if let Some(jobs) = jobs {
+        CYCLE_JOBS.get_or_create(&labels).observe(jobs as f64);
+        statsd::count("upkeep_cycle_jobs", jobs as u64, &tags);
+        LAST_SUCCESS
+            .get_or_create(&labels)
+            .set(Utc::now().timestamp_micros() as f64 / 1e6);
+    }
+}
+
+/// Record a restart of the given background task after it failed.
+pub fn record_restart(task: &str) {
+    RESTARTS
+        .get_or_create(&UpkeepLabels {
+            task: task.to_string(),
+        })
+        .inc();
+    statsd::count("task_restarts", 1, &[("task", task)]);
+}
+
+/// Build information metrics labels.
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct BuildLabels {
+    version: String,
+    commit: String,
+}
+
+/// Register the build information gauge, and a gauge for each of the
+/// given configuration settings (name, description and value). Unset
+/// settings aren't reported.
+pub async fn describe(settings: &[(&str, &str, Option<i64>)]) {
+    let mut reg = registry().lock().await;
+    let info = Family::<BuildLabels, Gauge>::default();
+    info.get_or_create(&BuildLabels {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        commit: env!("BUILD_COMMIT").to_string(),
+    })
+    .set(1);
+    reg.register("build_info", "Version of the dispatcher", info);
+    for (name, description, value) in settings {
+        if let Some(value) = value {
+            let gauge = Gauge::<i64, AtomicI64>::default();
+            gauge.set(*value);
+            reg.register(format!("config_{}", name), *description, gauge);
+        }
+    }
+}
+
+/// Encode the current metrics in OpenMetrics format.
+pub async fn encoded() -> Result<String> {
+    let mut body = String::new();
+    let reg = registry().lock().await;
+    encode(&mut body, &reg).context("while encoding metrics")?;
+    Ok(body)
+}
+
+/// Expose metrics.
+#[get("/metrics")]
+pub async fn expose() -> actix_web::Result<HttpResponse> {
+    let body = encoded()
+        .await
+        .map_err(|_| error::ErrorInternalServerError("couldn't encode metrics"))?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// Consume the docker events stream and update metrics according to
+/// the events read. Job state gauges are also reconciled with the
+/// listed jobs periodically, since events may be lost. The stream is
+/// reopened whenever it fails, replaying the events missed meanwhile,
+/// so that metrics survive daemon restarts. If a state file is given,
+/// the job counters are saved to it periodically, and restored from
+/// it on start along with the events missed meanwhile, and once more
+/// when shutting down.
+pub async fn run(
+    namespace: String,
+    reconcile_interval: u16,
+    state_file: Option<PathBuf>,
+) -> Result<()> {
+    // a restarted run counts from scratch, as a restarted process would
+    let mut jobs = JobCounters::reset();
+    let (failures, gauges, durations) = (&*FAILURES, &*STATE_GAUGES, &*DURATIONS);
+    failures.clear();
+    gauges.clear();
+    durations.clear();
+    let saved = match &state_file {
+        Some(path) => load_state(path).await?,
+        None => None,
+    };
+    let saved_cursor = saved.as_ref().and_then(|state| state.cursor);
+    // jobs found by the probe below, along with the last action
+    // they're counted for, and the time the probe finished at
+    let mut probed: HashMap<String, &'static str> = HashMap::new();
+    let mut probed_until = None;
+    let probe_started = Utc::now().timestamp_nanos_opt();
+    if let Some(state) = saved {
+        info!("Restoring job counters saved up to {:?}", state.cursor);
+        for (labels, value) in state.jobs {
+            jobs.inc_by(labels, value);
+        }
+    } else {
+        // account for already active jobs
+        let (active, created) = tokio::try_join!(
+            docker::get_active(&namespace),
+            docker::get_pending(&namespace)
+        )?;
+        probed_until = Utc::now().timestamp_nanos_opt();
+        for (containers, action) in [(&active, "start"), (&created, "create")] {
+            for name in containers
+                .iter()
+                .filter_map(|container| container.names.as_ref()?.first())
+            {
+                probed.insert(name.trim_start_matches('/').to_string(), action);
+            }
+        }
+        let active: u64 = active.len().try_into()?;
+        let created: u64 = created.len().try_into()?;
+        jobs.inc_by(
+            Labels {
+                namespace: namespace.clone(),
+                action: Some(String::from("create")),
+                status: None,
+            },
+            active + created,
+        );
+        jobs.inc_by(
+            Labels {
+                namespace: namespace.clone(),
+                action: Some(String::from("start")),
+                status: None,
+            },
+            active,
+        );
+    }
+    // listen for new events, replaying those missed since the saved
+    // cursor, or else since the probe above started; the cursor is
+    // truncated to seconds, and replayed events counted before are
+    // skipped
+    // note: runs of jobs started before the stream began aren't
+    // measured
+    let open = |cursor: Option<i64>| {
+        docker::job_events_since(
+            &namespace,
+            &["create", "die", "start", "health_status", "destroy"],
+            cursor.and_then(|cursor| DateTime::from_timestamp(cursor / 1_000_000_000, 0)),
+        )
+    };
+    let mut cursor = saved_cursor.or(probe_started);
+    let mut skip_until = saved_cursor;
+    let mut started: HashMap<String, i64> = HashMap::new();
+    let mut states = HashMap::new();
+    let reconcile_period = Duration::from_secs(reconcile_interval.into());
+    let mut reconciliation = time::interval(reconcile_period);
+    health_service::expect("metrics reconciler", reconcile_period);
+    let mut backoff = retry::Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    let mut events = Box::pin(open(cursor)?);
+    loop {
+        let event = tokio::select! {
+            event = events.try_next() => match event {
+                Ok(Some(event)) => {
+                    backoff.reset();
+                    event
+                }
+                result => {
+                    // reconnect, replaying the events missed meanwhile,
+                    // and reconcile the job state gauges right away
+                    match result {
+                        Err(e) => warn!("Lost the docker events stream: {:?}", e),
+                        _ => warn!("The docker events stream ended"),
+                    }
+                    backoff.wait().await;
+                    events = Box::pin(open(cursor)?);
+                    skip_until = cursor;
+                    reconciliation.reset_immediately();
+                    continue;
+                }
+            },
+            _ = reconciliation.tick() => {
+                match list_states(&namespace).await {
+                    Ok(listed) => {
+                        states = listed;
+                        update_states(gauges, &namespace, &states);
+                        health_service::beat("metrics reconciler");
+                    }
+                    Err(e) => warn!("Couldn't reconcile job state metrics: {:?}", e),
+                }
+                if let Some(path) = state_file.as_deref().filter(|_| jobs.changed) {
+                    match save_state(path, &jobs, cursor).await {
+                        Ok(()) => jobs.changed = false,
+                        Err(e) => warn!("Couldn't save the metrics state: {:?}", e),
+                    }
+                }
+                continue;
+            }
+            _ = shutdown::reached(Phase::Flushing) => {
+                if let Some(path) = state_file.as_deref().filter(|_| jobs.changed) {
+                    save_state(path, &jobs, cursor).await?;
+                }
+                return Ok(());
+            }
+        };
+        if let (Some(time), Some(skip_until)) = (event.time_nano, skip_until) {
+            if time <= skip_until {
+                continue;
+            }
+        }
+        cursor = event.time_nano.max(cursor);
+        let attributes = event.actor.and_then(|a| a.attributes).unwrap_or_default();
+        let status = attributes.get("exitCode").cloned();
+        let name = attributes.get("name").cloned().unwrap_or_default();
+        let during_probe = event
+            .time_nano
+            .zip(probed_until)
+            .is_some_and(|(time, until)| time <= until);
+        if during_probe && counted_by_probe(&probed, &name, event.action.as_deref()) {
+            continue;
+        }
+        match event.action.as_deref() {
+            Some("create") => {
+                states.insert(name.clone(), STATES[0]);
+            }
+            Some("start") => {
+                states.insert(name.clone(), STATES[1]);
+            }
+            Some("die") => {
+                states.insert(name.clone(), STATES[2]);
+            }
+            Some("destroy") => {
+                states.remove(&name);
+                update_states(gauges, &namespace, &states);
+                // removals aren't counted as job events
+                continue;
+            }
+            _ => (),
+        }
+        update_states(gauges, &namespace, &states);
+        if event.action.as_deref() == Some("die") {
+            if let Some(class) = failure_class(&name, status.as_deref()).await {
+                failures
+                    .get_or_create(&FailureLabels {
+                        namespace: namespace.clone(),
+                        class: class.to_string(),
+                    })
+                    .inc();
+                statsd::count(
+                    "job_failures",
+                    1,
+                    &[("namespace", &namespace), ("class", class)],
+                );
+            }
+        }
+        match (event.action.as_deref(), event.time_nano) {
+            (Some("start"), Some(time)) => {
+                started.insert(name, time);
+            }
+            (Some("die"), Some(time)) => {
+                if let Some(start) = started.remove(&name) {
+                    let seconds = (time - start).max(0) as f64 / 1e9;
+                    durations
+                        .get_or_create(&DurationLabels {
+                            namespace: namespace.clone(),
+                            status: status.clone(),
+                            image: attributes.get("image").cloned(),
+                        })
+                        .observe(seconds);
+                    statsd::timing(
+                        "job_duration",
+                        seconds * 1000.0,
+                        &[
+                            ("namespace", &namespace),
+                            ("status", status.as_deref().unwrap_or_default()),
+                            ("image", attributes.get("image").map_or("", String::as_str)),
+                        ],
+                    );
+                }
+            }
+            _ => (),
+        }
+        statsd::count(
+            "jobs",
+            1,
+            &[
+                ("namespace", &namespace),
+                ("action", event.action.as_deref().unwrap_or_default()),
+                ("status", status.as_deref().unwrap_or_default()),
+            ],
+        );
+        jobs.inc_by(
+            Labels {
+                namespace: namespace.clone(),
+                action: event.action,
+                status,
+            },
+            1,
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Consumes jobs from a NATS subject, either through a core
+//! subscription or a JetStream consumer, as an alternative to the HTTP
+//! API. Each message goes through the filter like a request body would,
+//! and the outcome and completion of its job are published back to the
+//! message's reply subject.
+
+use crate::audit;
+use crate::docker;
+use crate::docker_service::{self, Intake, Submission};
+use crate::notifier;
+use crate::retry::Backoff;
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, bail, Context, Result};
+use async_nats::jetstream::{self, consumer::pull, AckKind};
+use async_nats::{Client, ConnectOptions, Message};
+use futures::stream::{StreamExt, TryStreamExt};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Header of messages giving the subject to publish the outcome and
+/// completion of their job to, instead of the reply subject.
+pub const REPLY_HEADER: &str = "Dispatcher-Reply-To";
+
+/// Delay before JetStream redelivers a message whose job couldn't be
+/// created.
+const REDELIVERY_DELAY: Duration = Duration::from_secs(10);
+
+/// Where and how to consume messages.
+pub struct Source {
+    pub url: String,
+    pub subject: String,
+    /// Queue group core subscriptions join, so that each message is
+    /// taken by a single dispatcher.
+    pub queue_group: String,
+    /// JetStream stream to consume from, instead of a core
+    /// subscription.
+    pub stream: Option<String>,
+    /// Name of the durable JetStream consumer.
+    pub consumer: String,
+    pub credentials: Option<PathBuf>,
+}
+
+impl Source {
+    /// Connect to the NATS server.
+    async fn connect(&self) -> Result<Client> {
+        let mut options = ConnectOptions::new().name(env!("CARGO_PKG_NAME"));
+        if let Some(path) = &self.credentials {
+            options = options
+                .credentials_file(path)
+                .await
+                .with_context(|| format!("while reading the NATS credentials file {:?}", path))?;
+        }
+        options
+            .connect(self.url.as_str())
+            .await
+            .context("while connecting to the NATS server")
+    }
+}
+
+/// The outcome of turning a message into a job.
+enum Outcome {
+    /// The job was created, or already existed.
+    Created(Value),
+    /// The message will never make a job.
+    Rejected(String),
+    /// The job may be created if tried again.
+    Failed(String),
+}
+
+/// The subject to publish the outcome and completion of a message's job
+/// to. The reply subject of JetStream messages is taken by their
+/// acknowledgement, so only the header is looked at for those.
+fn reply_subject(message: &Message, jetstream: bool) -> Option<String> {
+    message
+        .headers
+        .as_ref()
+        .and_then(|headers| headers.get(REPLY_HEADER))
+        .map(|value| value.as_str().trim().to_string())
+        .filter(|subject| !subject.is_empty())
+        .or_else(|| {
+            message
+                .reply
+                .as_ref()
+                .filter(|_| !jetstream)
+                .map(|reply| reply.to_string())
+        })
+}
+
+/// Create the job of a message, made available to the filter as
+/// `$MESSAGE`.
+async fn create(intake: &Intake, message: &Message, reply_subject: Option<String>) -> Outcome {
+    let input = match serde_json::from_slice::<Value>(&message.payload) {
+        Ok(input) => input,
+        Err(e) => return Outcome::Rejected(format!("payload is not valid JSON: {}", e)),
+    };
+    let subject = message.subject.as_str();
+    let submission = Submission {
+        input,
+        path: format!("/nats/{}", subject),
+        claims: Value::Null,
+        message: json!({"subject": subject, "reply_subject": reply_subject}),
+        reply_subject,
+        actor: audit::Actor {
+            submitter: Some(format!("nats:{}", subject)),
+            ..Default::default()
+        },
+    };
+    match docker_service::submit(intake, submission).await {
+        Ok((_, summary)) => Outcome::Created(serde_json::to_value(summary).unwrap_or_default()),
+        Err(e) if e.as_response_error().status_code().is_client_error() => {
+            Outcome::Rejected(e.to_string())
+        }
+        Err(e) => Outcome::Failed(e.to_string()),
+    }
+}
+
+/// Publish an event to a reply subject, if any.
+async fn publish(client: &Client, subject: Option<&str>, event: Value) {
+    let Some(subject) = subject else {
+        return;
+    };
+    if let Err(e) = client
+        .publish(subject.to_string(), event.to_string().into())
+        .await
+    {
+        warn!(
+            "Couldn't publish to the NATS subject {:?}: {:?}",
+            subject, e
+        );
+    }
+}
+
+/// Log the outcome of a message, and publish it to its reply subject.
+async fn report(client: &Client, message: &Message, reply_subject: Option<&str>, outcome: Outcome) {
+    let event = match outcome {
+        Outcome::Created(job) => {
+            debug!("NATS message on {:?} made a job", message.subject.as_str());
+            json!({"event": "created", "job": job})
+        }
+        Outcome::Rejected(error) => {
+            warn!(
+                "Rejected a NATS message on {:?}: {}",
+                message.subject.as_str(),
+                error
+            );
+            json!({"event": "rejected", "error": error})
+        }
+        Outcome::Failed(error) => {
+            warn!(
+                "Couldn't create the job of a NATS message on {:?}; retrying: {}",
+                message.subject.as_str(),
+                error
+            );
+            return;
+        }
+    };
+    publish(client, reply_subject, event).await;
+}
+
+/// Consume messages through a core subscription. Since they can't be
+/// redelivered, creating the job of each one is retried until it
+/// succeeds.
+async fn subscribe(client: &Client, source: &Source, intake: &Intake) -> Result<()> {
+    let mut subscriber = client
+        .queue_subscribe(source.subject.clone(), source.queue_group.clone())
+        .await
+        .context("while subscribing to the NATS subject")?;
+    loop {
+        let message = tokio::select! {
+            message = subscriber.next() => message,
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        let Some(message) = message else {
+            bail!("the NATS subscription ended");
+        };
+        let reply_subject = reply_subject(&message, false);
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+        loop {
+            let outcome = create(intake, &message, reply_subject.clone()).await;
+            let retry = matches!(outcome, Outcome::Failed(_));
+            report(client, &message, reply_subject.as_deref(), outcome).await;
+            if !retry {
+                break;
+            }
+            tokio::select! {
+                _ = backoff.wait() => (),
+                _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Consume messages through a durable JetStream consumer, acknowledging
+/// each one once its job is created. Messages that will never make a
+/// job are terminated, and the rest redelivered later.
+async fn pull(client: &Client, source: &Source, stream: &str, intake: &Intake) -> Result<()> {
+    let consumer = jetstream::new(client.clone())
+        .get_stream(stream)
+        .await
+        .with_context(|| format!("while looking up the JetStream stream {:?}", stream))?
+        .get_or_create_consumer(
+            &source.consumer,
+            pull::Config {
+                durable_name: Some(source.consumer.clone()),
+                filter_subject: source.subject.clone(),
+                ..Default::default()
+            },
+        )
+        .await
+        .context("while creating the JetStream consumer")?;
+    let mut messages = consumer
+        .messages()
+        .await
+        .context("while consuming from the JetStream consumer")?;
+    loop {
+        let message = tokio::select! {
+            message = messages.next() => message,
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        let Some(message) = message else {
+            bail!("the JetStream consumer ended");
+        };
+        let message = message.context("while receiving a JetStream message")?;
+        let reply_subject = reply_subject(&message, true);
+        let outcome = create(intake, &message, reply_subject.clone()).await;
+        let acknowledgement = match outcome {
+            Outcome::Created(_) => AckKind::Ack,
+            Outcome::Rejected(_) => AckKind::Term,
+            Outcome::Failed(_) => AckKind::Nak(Some(REDELIVERY_DELAY)),
+        };
+        message
+            .ack_with(acknowledgement)
+            .await
+            .map_err(|e| anyhow!(e))
+            .context("while acknowledging a JetStream message")?;
+        report(client, &message, reply_subject.as_deref(), outcome).await;
+    }
+}
+
+/// Publish the completion of jobs to the reply subject they were
+/// labeled with.
+async fn publish_completions(client: &Client, namespace: &str) -> Result<()> {
+    let mut events = Box::pin(docker::job_events(namespace, &["die"])?);
+    while let Some(event) = events
+        .try_next()
+        .await
+        .context("while watching the completion of jobs")?
+    {
+        let attributes = event
+            .actor
+            .and_then(|actor| actor.attributes)
+            .unwrap_or_default();
+        let Some(subject) = attributes.get(docker::REPLY_SUBJECT_LABEL_KEY) else {
+            continue;
+        };
+        let exit_code = attributes
+            .get("exitCode")
+            .and_then(|code| code.parse::<i64>().ok());
+        let completion = json!({
+            "event": notifier::lifecycle_event("die", exit_code),
+            "id": attributes.get("name"),
+            "namespace": namespace,
+            "path": attributes.get(docker::PATH_LABEL_KEY),
+            "exit_code": exit_code,
+            "time": event.time,
+        });
+        publish(client, Some(subject), completion).await;
+    }
+    bail!("the docker events stream ended")
+}
+
+/// Consume messages from the subject, creating a job for each one, and
+/// publish the completion of those jobs to their reply subjects.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let client = source.connect().await?;
+    info!(
+        "Consuming jobs from the NATS subject {:?}{}",
+        source.subject,
+        source
+            .stream
+            .as_ref()
+            .map(|stream| format!(" of the JetStream stream {:?}", stream))
+            .unwrap_or_default()
+    );
+    let consuming = async {
+        match &source.stream {
+            Some(stream) => pull(&client, &source, stream, &intake).await,
+            None => subscribe(&client, &source, &intake).await,
+        }
+    };
+    tokio::select! {
+        result = consuming => result,
+        result = publish_completions(&client, &intake.namespace) => result,
+    }
+}
@@ -0,0 +1,177 @@
+//! Notifies an external service of the lifecycle of jobs (created,
+//! started, succeeded, failed and cleaned), as followed through the
+//! docker events stream, so that it doesn't have to poll the API.
+
+use crate::docker;
+use crate::retry::Backoff;
+use crate::signature;
+use anyhow::{anyhow, Context, Result};
+use futures::{future::join, stream::TryStreamExt};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Duration;
+use tracing::{debug, error, warn};
+
+/// Header holding the lifecycle event of a notification.
+const EVENT_HEADER: &str = "X-Dispatcher-Event";
+
+/// Header holding the signature of a notification.
+const SIGNATURE_HEADER: &str = "X-Dispatcher-Signature";
+
+/// Maximum number of attempts at delivering a notification.
+const ATTEMPTS: u32 = 5;
+
+/// Where notifications are sent, and how.
+pub struct Sink {
+    client: reqwest::Client,
+    url: String,
+    secret: Option<String>,
+}
+
+impl Sink {
+    /// Build a sink sending notifications to the given URL, with the
+    /// given key=value headers, and signed with the given secret.
+    pub fn new(url: String, headers: &[String], secret: Option<String>) -> Result<Self> {
+        let mut map = HeaderMap::new();
+        for header in headers.iter().filter(|header| !header.is_empty()) {
+            let (key, value) = header.split_once('=').ok_or_else(|| {
+                anyhow!(
+                    "invalid notification header {:?}; expected key=value",
+                    header
+                )
+            })?;
+            map.insert(
+                HeaderName::from_bytes(key.trim().as_bytes()).with_context(|| {
+                    format!("while parsing the notification header {:?}", header)
+                })?,
+                HeaderValue::from_str(value.trim()).with_context(|| {
+                    format!("while parsing the notification header {:?}", header)
+                })?,
+            );
+        }
+        let client = reqwest::Client::builder()
+            .default_headers(map)
+            .build()
+            .context("while building the notification client")?;
+        Ok(Self {
+            client,
+            url,
+            secret,
+        })
+    }
+
+    /// Send a notification once.
+    async fn send(&self, event: &str, body: &[u8]) -> reqwest::Result<()> {
+        let mut request = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, event);
+        if let Some(secret) = &self.secret {
+            request = request.header(
+                SIGNATURE_HEADER,
+                format!("sha256={}", signature::hmac_hex(secret, &[body])),
+            );
+        }
+        request
+            .body(body.to_vec())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+    }
+
+    /// Deliver a notification, retrying with backoff. Notifications
+    /// that can't be delivered are logged in full, so that they may be
+    /// recovered from the logs.
+    async fn deliver(&self, notification: &Value) {
+        let event = notification["event"].as_str().unwrap_or_default();
+        let body = notification.to_string();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        for attempt in 1..=ATTEMPTS {
+            match self.send(event, body.as_bytes()).await {
+                Ok(()) => return,
+                Err(e) if attempt < ATTEMPTS => {
+                    debug!(
+                        "Retrying job notification after error (attempt {}): {:?}",
+                        attempt, e
+                    );
+                    backoff.wait().await;
+                }
+                Err(e) => {
+                    error!(
+                        notification = %body,
+                        "Couldn't deliver job notification after {} attempts: {:?}",
+                        ATTEMPTS,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// The lifecycle event a docker event stands for, if any.
+pub(crate) fn lifecycle_event(action: &str, exit_code: Option<i64>) -> Option<&'static str> {
+    match (action, exit_code) {
+        ("create", _) => Some("created"),
+        ("start", _) => Some("started"),
+        ("die", Some(0)) => Some("succeeded"),
+        ("die", _) => Some("failed"),
+        ("destroy", _) => Some("cleaned"),
+        _ => None,
+    }
+}
+
+/// Consume the docker events stream, and deliver a notification to
+/// the sink for each step in the lifecycle of a job. Notifications are
+/// delivered in order, without holding up the events stream.
+pub async fn watch(namespace: String, sink: Arc<Sink>) -> Result<()> {
+    let (sender, mut receiver) = unbounded_channel::<Value>();
+    let follow = async move {
+        docker::job_events(&namespace, &["create", "start", "die", "destroy"])?
+            .try_for_each(|event| {
+                let attributes = event
+                    .actor
+                    .and_then(|actor| actor.attributes)
+                    .unwrap_or_default();
+                let exit_code = attributes
+                    .get("exitCode")
+                    .and_then(|code| code.parse::<i64>().ok());
+                let lifecycle = event
+                    .action
+                    .as_deref()
+                    .and_then(|action| lifecycle_event(action, exit_code));
+                if let (Some(lifecycle), Some(name)) = (lifecycle, attributes.get("name")) {
+                    let notification = json!({
+                        "event": lifecycle,
+                        "id": name,
+                        "namespace": namespace,
+                        "path": attributes.get(docker::PATH_LABEL_KEY),
+                        "exit_code": exit_code,
+                        "time": event.time,
+                    });
+                    if sender.send(notification).is_err() {
+                        warn!(
+                            "Couldn't queue the {} notification of job {:?}",
+                            lifecycle, name
+                        );
+                    }
+                }
+                futures::future::ready(Ok(()))
+            })
+            .await
+            .context("while watching the lifecycle of jobs")
+    };
+    let deliver = async {
+        while let Some(notification) = receiver.recv().await {
+            sink.deliver(&notification).await;
+        }
+    };
+    // pending notifications are delivered even if the events stream
+    // ends
+    let (result, ()) = join(follow, deliver).await;
+    result
+}
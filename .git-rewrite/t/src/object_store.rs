@@ -0,0 +1,85 @@
+//! Uploads objects to an S3-compatible storage backend.
+
+use anyhow::{Context, Result};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_s3::{config::Credentials, primitives::ByteStream, Client};
+use once_cell::sync::OnceCell;
+
+/// Settings for the S3-compatible storage backend.
+pub struct Settings {
+    pub bucket: String,
+    pub prefix: String,
+    pub endpoint: Option<String>,
+    pub region: Option<String>,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub force_path_style: bool,
+}
+
+/// A configured storage backend.
+struct Store {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+/// Static storage backend instance.
+static CURRENT: OnceCell<Store> = OnceCell::new();
+
+/// Initialize the global storage backend instance. Credentials not
+/// given explicitly are taken from the standard AWS sources
+/// (environment, profiles, instance metadata).
+pub async fn init(settings: Settings) {
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = settings.region {
+        loader = loader.region(Region::new(region));
+    }
+    if let (Some(access_key_id), Some(secret_access_key)) =
+        (settings.access_key_id, settings.secret_access_key)
+    {
+        loader = loader.credentials_provider(Credentials::new(
+            access_key_id,
+            secret_access_key,
+            None,
+            None,
+            env!("CARGO_PKG_NAME"),
+        ));
+    }
+    let shared_config = loader.load().await;
+    let mut config = aws_sdk_s3::config::Builder::from(&shared_config)
+        .force_path_style(settings.force_path_style);
+    if let Some(endpoint) = settings.endpoint {
+        config = config.endpoint_url(endpoint);
+    }
+    let _ = CURRENT.set(Store {
+        client: Client::from_conf(config.build()),
+        bucket: settings.bucket,
+        prefix: settings.prefix,
+    });
+}
+
+/// Whether a storage backend has been configured.
+pub fn is_enabled() -> bool {
+    CURRENT.get().is_some()
+}
+
+/// Upload an object under the configured prefix, and return its
+/// URL. Does nothing and returns None if no storage backend has been
+/// configured.
+pub async fn put(key: &str, body: Vec<u8>, content_type: &str) -> Result<Option<String>> {
+    let Some(store) = CURRENT.get() else {
+        return Ok(None);
+    };
+    let key = format!("{}{}", store.prefix, key);
+    store
+        .client
+        .put_object()
+        .bucket(&store.bucket)
+        .key(&key)
+        .content_type(content_type)
+        .body(ByteStream::from(body))
+        .send()
+        .await
+        .with_context(|| format!("while uploading object {:?}", key))?;
+    Ok(Some(format!("s3://{}/{}", store.bucket, key)))
+}
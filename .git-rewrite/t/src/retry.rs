@@ -0,0 +1,137 @@
+//! Retries docker API calls that fail for transient reasons, and
+//! paces reconnections of event streams, with jittered exponential
+//! backoff. The outcome of each call is fed to the circuit breaker.
+
+use crate::circuit_breaker;
+use bollard::errors::Error;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use tokio::time::{self, Duration, Instant};
+use tracing::debug;
+
+/// Limits on retrying a single operation.
+pub struct Budget {
+    /// Maximum number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the first retry, doubled on each subsequent one.
+    pub base_delay: Duration,
+    /// Maximum delay between attempts.
+    pub max_delay: Duration,
+    /// Maximum time spent retrying, after which the last error is
+    /// returned.
+    pub deadline: Duration,
+}
+
+/// Budget for health checks, which should fail fast.
+pub const PING: Budget = Budget {
+    attempts: 2,
+    base_delay: Duration::from_millis(100),
+    max_delay: Duration::from_millis(100),
+    deadline: Duration::from_secs(2),
+};
+
+/// Budget for operations that only read state.
+pub const READ: Budget = Budget {
+    attempts: 4,
+    base_delay: Duration::from_millis(200),
+    max_delay: Duration::from_secs(2),
+    deadline: Duration::from_secs(10),
+};
+
+/// Budget for operations that change state, but may be safely
+/// repeated.
+pub const WRITE: Budget = Budget {
+    attempts: 3,
+    base_delay: Duration::from_millis(500),
+    max_delay: Duration::from_secs(4),
+    deadline: Duration::from_secs(15),
+};
+
+/// Whether an error is likely to go away if the call is repeated.
+fn is_transient(error: &Error) -> bool {
+    match error {
+        Error::DockerResponseServerError { status_code, .. } => {
+            *status_code >= 500 && *status_code != 501
+        }
+        Error::RequestTimeoutError
+        | Error::IOError { .. }
+        | Error::HyperResponseError { .. }
+        | Error::HyperLegacyError { .. } => true,
+        _ => false,
+    }
+}
+
+/// Pick a random delay between half the given delay and the whole of
+/// it.
+fn jitter(delay: Duration) -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    delay.mul_f64(0.5 + (random % 1000) as f64 / 2000.0)
+}
+
+/// Call an operation until it succeeds, fails for a reason that isn't
+/// transient, or exhausts the budget. Calls exhausting the budget count
+/// as failures for the circuit breaker, while any other outcome means
+/// the daemon is reachable.
+pub async fn retry<T, F, Fut>(budget: &Budget, mut operation: F) -> Result<T, Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, Error>>,
+{
+    let start = Instant::now();
+    let mut delay = budget.base_delay;
+    let mut attempt = 1;
+    loop {
+        match operation().await {
+            Err(e)
+                if is_transient(&e)
+                    && attempt < budget.attempts
+                    && start.elapsed() + delay < budget.deadline =>
+            {
+                debug!(
+                    "Retrying docker API call after error (attempt {}): {:?}",
+                    attempt, e
+                );
+                time::sleep(jitter(delay)).await;
+                delay = (delay * 2).min(budget.max_delay);
+                attempt += 1;
+            }
+            result => {
+                match &result {
+                    Err(e) if is_transient(e) => circuit_breaker::record_failure(),
+                    _ => circuit_breaker::record_success(),
+                }
+                return result;
+            }
+        }
+    }
+}
+
+/// Delays between reconnections of a long-lived stream, growing
+/// exponentially while reconnections keep failing.
+pub struct Backoff {
+    base_delay: Duration,
+    max_delay: Duration,
+    delay: Duration,
+}
+
+impl Backoff {
+    pub fn new(base_delay: Duration, max_delay: Duration) -> Self {
+        Self {
+            base_delay,
+            max_delay,
+            delay: base_delay,
+        }
+    }
+
+    /// Wait before the next reconnection.
+    pub async fn wait(&mut self) {
+        time::sleep(jitter(self.delay)).await;
+        self.delay = (self.delay * 2).min(self.max_delay);
+    }
+
+    /// Start over once the stream delivers again.
+    pub fn reset(&mut self) {
+        self.delay = self.base_delay;
+    }
+}
@@ -0,0 +1,324 @@
+//! Implements the poll-based scheduling task.
+
+use crate::attempts;
+use crate::backend;
+use crate::circuit_breaker;
+use crate::cpusets;
+use crate::docker;
+use crate::error_report;
+use crate::gpu;
+use crate::health_service;
+use crate::metrics_service;
+use crate::reload::Live;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use bollard::models::ContainerSummary;
+use futures::future::join_all;
+use once_cell::sync::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::time::{self, Duration};
+use tracing::{debug, error, info, warn};
+
+/// Decides the order in which pending jobs are considered for
+/// starting, within the limits of the scheduler.
+pub trait Policy: Send + Sync {
+    /// Reorder the pending jobs, given oldest first. Jobs left out
+    /// aren't started in this cycle.
+    fn order(&self, pending: Vec<ContainerSummary>) -> Vec<ContainerSummary>;
+}
+
+/// Static scheduling policy, unset for first-come first-served.
+static POLICY: OnceCell<Box<dyn Policy>> = OnceCell::new();
+
+/// Set the policy ordering pending jobs, replacing first-come
+/// first-served.
+pub fn init(policy: Box<dyn Policy>) {
+    let _ = POLICY.set(policy);
+}
+
+/// Get the amount of quota slots taken by a job.
+fn slots(container: &ContainerSummary) -> usize {
+    docker::label(container, docker::SLOTS_LABEL_KEY)
+        .and_then(|slots| slots.parse().ok())
+        .unwrap_or(1)
+}
+
+/// Keep track of the CPU sets of active jobs, given as listed at some
+/// instant: the sets of jobs started before the dispatcher restarted
+/// are recovered, and those of jobs no longer active are released.
+pub async fn track_cpusets(active_jobs: &[ContainerSummary], listed_at: Instant) -> Result<()> {
+    let mut active = HashSet::new();
+    for container in active_jobs {
+        let Some(name) = container.names.as_ref().and_then(|ns| ns.first()) else {
+            continue;
+        };
+        let name = name.strip_prefix('/').unwrap_or(name).to_string();
+        if let Some(mode) = docker::label(container, docker::CPUSET_LABEL_KEY) {
+            if !cpusets::is_known(&name) {
+                let cpuset = docker::inspect(&name)
+                    .await
+                    .context("while recovering the CPU set of a job")?
+                    .host_config
+                    .and_then(|host_config| host_config.cpuset_cpus);
+                if let Some(cpuset) = cpuset {
+                    cpusets::recover(
+                        &name,
+                        cpusets::Assignment {
+                            host: docker::label(container, docker::HOST_LABEL_KEY)
+                                .unwrap_or_default()
+                                .to_string(),
+                            cpuset,
+                            exclusive: mode == cpusets::EXCLUSIVE,
+                            assigned_at: listed_at,
+                        },
+                    );
+                }
+            }
+        }
+        active.insert(name);
+    }
+    cpusets::release_inactive(&active, listed_at);
+    Ok(())
+}
+
+/// Check running containers, and begin starting containers if there's
+/// room for them accoring to the given quota. Each job takes as many
+/// quota slots as it declares, and pending jobs are started in order
+/// from oldest to newest until the first one that doesn't fit. A job
+/// declaring more slots than the whole quota is started only when no
+/// other job is active. Service jobs don't take quota slots, and are
+/// always started. Jobs belonging to a mutual exclusion group are only
+/// started if no other job of the same group is active. When the
+/// amount of GPUs is limited, jobs taking GPUs are only started if
+/// their docker host has enough of them left. Likewise, jobs taking a
+/// CPU set from the pool are only started if their docker host has
+/// sets not held exclusively by other jobs. Without a quota, only
+/// service jobs, jobs belonging to a mutual exclusion group, jobs
+/// taking limited GPUs, jobs taking exclusive CPU sets and jobs that
+/// previously failed to start are considered, since the rest are
+/// started immediately upon creation.
+/// Jobs that ran out of start attempts are skipped. If waiting for
+/// healthy jobs, every pending job is considered, but only service
+/// jobs are started while any running job is yet to pass its
+/// healthcheck.
+#[tracing::instrument(skip_all)]
+async fn schedule(
+    max_concurrent: Option<usize>,
+    wait_healthy: bool,
+    namespace: &str,
+) -> Result<usize> {
+    let listed_at = Instant::now();
+    let active_jobs = docker::get_active(namespace)
+        .await
+        .context("while fetching active jobs")?;
+    if cpusets::is_enabled() {
+        track_cpusets(&active_jobs, listed_at).await?;
+    }
+    let mut used: usize = active_jobs
+        .iter()
+        .filter(|container| !docker::is_service(container))
+        .map(slots)
+        .sum();
+    let starting = wait_healthy
+        && !docker::get_starting(namespace)
+            .await
+            .context("while fetching starting jobs")?
+            .is_empty();
+    let mut full = max_concurrent.is_some_and(|max| used >= max);
+    let mut busy_groups: HashSet<String> = active_jobs
+        .iter()
+        .filter_map(|container| docker::label(container, docker::MUTEX_GROUP_LABEL_KEY))
+        .map(String::from)
+        .collect();
+    let mut used_gpus: HashMap<String, usize> = HashMap::new();
+    for container in &active_jobs {
+        let host = docker::label(container, docker::HOST_LABEL_KEY).unwrap_or_default();
+        *used_gpus.entry(host.to_string()).or_default() += gpu::taken(container);
+    }
+    let mut held_cpusets: HashMap<String, usize> = HashMap::new();
+    let mut selected = Vec::new();
+    let pending = docker::get_pending(namespace)
+        .await
+        .context("while fetching pending jobs")?;
+    let pending = match POLICY.get() {
+        Some(policy) => policy.order(pending),
+        None => pending,
+    };
+    for container in pending {
+        let Some(name) = container.names.as_ref().and_then(|ns| ns.first()) else {
+            continue;
+        };
+        let name = name.strip_prefix('/').unwrap_or(name).to_string();
+        let failure = attempts::get(&name);
+        if failure.as_ref().is_some_and(|f| f.exhausted()) {
+            continue;
+        }
+        let service = docker::is_service(&container);
+        let group = docker::label(&container, docker::MUTEX_GROUP_LABEL_KEY);
+        let gpus = gpu::limit().map(|_| gpu::taken(&container)).unwrap_or(0);
+        let cpuset = docker::label(&container, docker::CPUSET_LABEL_KEY);
+        let exclusive = cpuset == Some(cpusets::EXCLUSIVE);
+        if group.is_some_and(|group| busy_groups.contains(group))
+            || (!service
+                && group.is_none()
+                && gpus == 0
+                && !exclusive
+                && max_concurrent.is_none()
+                && !wait_healthy
+                && failure.is_none())
+        {
+            continue;
+        }
+        let host = docker::label(&container, docker::HOST_LABEL_KEY).unwrap_or_default();
+        let host_gpus = used_gpus.get(host).copied().unwrap_or(0);
+        if gpu::limit().is_some_and(|limit| host_gpus + gpus > limit) {
+            continue;
+        }
+        let host_cpusets = *held_cpusets
+            .entry(host.to_string())
+            .or_insert_with(|| cpusets::held_exclusively(host));
+        if cpuset.is_some() && host_cpusets >= cpusets::size() {
+            continue;
+        }
+        if !service && starting {
+            continue;
+        }
+        if let (false, Some(max)) = (service, max_concurrent) {
+            if full {
+                continue;
+            }
+            let slots = slots(&container).min(max);
+            if used + slots > max {
+                // later jobs are not allowed to jump ahead of this one
+                full = true;
+                continue;
+            }
+            used += slots;
+        }
+        *used_gpus.entry(host.to_string()).or_default() += gpus;
+        if exclusive {
+            *held_cpusets.entry(host.to_string()).or_default() += 1;
+        }
+        if let Some(group) = group {
+            busy_groups.insert(group.to_string());
+        }
+        selected.push(name);
+    }
+    let started = join_all(selected.into_iter().map(|name| async move {
+        info!("Scheduling job {:?}", name);
+        let result = docker::start(&name).await;
+        if let Err(ref e) = result {
+            // start failures are recorded and retried later, so they
+            // don't count as scheduling errors
+            warn!("Couldn't start job {:?}: {:?}", name, e);
+        }
+        result.is_ok()
+    }))
+    .await;
+    Ok(started.into_iter().filter(|started| *started).count())
+}
+
+/// Restart the service jobs of alternative backends that have exited.
+/// Those of the docker backend are restarted by the docker daemon.
+#[tracing::instrument(skip_all)]
+async fn supervise(namespace: &str) -> Result<usize> {
+    if !backend::is_alternative() {
+        return Ok(0);
+    }
+    let restarted = join_all(
+        docker::get_exited(namespace)
+            .await
+            .context("while fetching exited jobs")?
+            .into_iter()
+            .filter(docker::is_service)
+            .filter_map(|container| {
+                container
+                    .names
+                    .and_then(|ns| ns.into_iter().next())
+                    .map(|name| name.strip_prefix('/').map(String::from).unwrap_or(name))
+            })
+            .filter(|name| !attempts::get(name).is_some_and(|f| f.exhausted()))
+            .map(|name| async move {
+                info!("Restarting service job {:?}", name);
+                let result = docker::start(&name).await;
+                if let Err(ref e) = result {
+                    warn!("Couldn't restart service job {:?}: {:?}", name, e);
+                }
+                result.is_ok()
+            }),
+    )
+    .await;
+    Ok(restarted.into_iter().filter(|restarted| *restarted).count())
+}
+
+/// Maximum amount of consecutive scheduling errors.
+const MAX_ERRORS: u8 = 5;
+
+/// Loop the schedule function endlessly.
+pub async fn cycle(
+    max_concurrent: Arc<Live<Option<u16>>>,
+    wait_healthy: bool,
+    scheduling_interval: u16,
+    namespace: String,
+) -> Result<()> {
+    let period = Duration::from_secs(scheduling_interval.into());
+    let mut interval = time::interval(period);
+    let mut errors: u8 = 0;
+    health_service::expect("scheduler", period);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        }
+        if circuit_breaker::is_open() {
+            debug!("Skipping scheduling while the docker daemon is unreachable");
+            continue;
+        }
+        let cycle_start = Instant::now();
+        let max_concurrent = max_concurrent.get().map(usize::from);
+        let result = match schedule(max_concurrent, wait_healthy, &namespace).await {
+            Ok(started) => supervise(&namespace)
+                .await
+                .map(|restarted| started + restarted),
+            e => e,
+        };
+        let result = match result {
+            Ok(started) => docker::stop_sidecars(&namespace)
+                .await
+                .context("while stopping sidecars")
+                .map(|_| started),
+            e => e,
+        };
+        if let Err(ref e) = result {
+            error!("Error while scheduling jobs: {:?}", e);
+            errors += 1;
+            if errors > 1 {
+                error_report::capture(
+                    "upkeep",
+                    format!("{:?}", e),
+                    &[
+                        ("task", "scheduler"),
+                        ("namespace", &namespace),
+                        ("consecutive_errors", &errors.to_string()),
+                    ],
+                );
+            }
+        } else {
+            errors = 0;
+        }
+        metrics_service::record_cycle(
+            "scheduler",
+            cycle_start.elapsed(),
+            result.as_ref().ok().copied(),
+            errors,
+        );
+        health_service::beat("scheduler");
+        if errors >= MAX_ERRORS {
+            return result
+                .map(|_| ())
+                .context("received 5 consecutive scheduling errors");
+        }
+    }
+}
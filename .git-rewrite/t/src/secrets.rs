@@ -0,0 +1,116 @@
+//! Resolves the secrets jobs may request, and packs them as files to
+//! be copied into job containers.
+
+use anyhow::{anyhow, bail, Context, Result};
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where the value of a secret is read from.
+enum Source {
+    File(PathBuf),
+    Env(String),
+}
+
+/// Static secret sources, indexed by secret name.
+static SECRETS: OnceCell<HashMap<String, Source>> = OnceCell::new();
+
+/// Static directory of job containers where secrets are placed.
+static SECRETS_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Parse a secret given as name=file:/path or name=env:VARIABLE.
+fn parse_secret(value: &str) -> Result<(String, Source)> {
+    let (name, source) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected name=file:path or name=env:variable"))?;
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("invalid secret name {:?}", name);
+    }
+    let source = match source.split_once(':') {
+        Some(("file", path)) => Source::File(PathBuf::from(path)),
+        Some(("env", variable)) => Source::Env(variable.to_string()),
+        _ => bail!("expected name=file:path or name=env:variable"),
+    };
+    Ok((name.to_string(), source))
+}
+
+/// Initialize the registered secrets, and the directory where they're
+/// placed in job containers.
+pub fn init(secrets: &[String], path: PathBuf) -> Result<()> {
+    let mut sources = HashMap::new();
+    for secret in secrets.iter().filter(|secret| !secret.is_empty()) {
+        let (name, source) =
+            parse_secret(secret).with_context(|| format!("while parsing secret {:?}", secret))?;
+        sources.insert(name, source);
+    }
+    if !path.is_absolute() || path.parent().is_none() {
+        bail!(
+            "the secrets path {:?} is not an absolute path below the root",
+            path
+        );
+    }
+    let _ = SECRETS.set(sources);
+    let _ = SECRETS_PATH.set(path);
+    Ok(())
+}
+
+/// Whether any secret is registered.
+pub fn is_enabled() -> bool {
+    SECRETS.get().is_some_and(|secrets| !secrets.is_empty())
+}
+
+/// Whether a secret with the given name is registered.
+pub fn is_registered(name: &str) -> bool {
+    SECRETS
+        .get()
+        .is_some_and(|secrets| secrets.contains_key(name))
+}
+
+/// The current values of the secrets read from environment variables.
+pub fn env_values() -> Vec<String> {
+    SECRETS
+        .get()
+        .into_iter()
+        .flat_map(|secrets| secrets.values())
+        .filter_map(|source| match source {
+            Source::Env(variable) => std::env::var(variable).ok(),
+            Source::File(_) => None,
+        })
+        .collect()
+}
+
+/// Read the current value of a secret.
+fn read(name: &str) -> Result<Vec<u8>> {
+    match SECRETS.get().and_then(|secrets| secrets.get(name)) {
+        Some(Source::File(path)) => std::fs::read(path)
+            .with_context(|| format!("while reading secret {:?} from {:?}", name, path)),
+        Some(Source::Env(variable)) => std::env::var(variable)
+            .map(String::into_bytes)
+            .with_context(|| format!("while reading secret {:?} from ${}", name, variable)),
+        None => bail!("secret {:?} is not registered", name),
+    }
+}
+
+/// Build a tar archive holding the current values of the given
+/// secrets, to be extracted at the container's root. The secrets
+/// directory is included, since it may not exist in the image.
+pub fn archive(names: &[&str]) -> Result<Vec<u8>> {
+    let path = SECRETS_PATH
+        .get()
+        .context("secrets have not been initialized")?;
+    let relative = path.strip_prefix("/").unwrap_or(path.as_path());
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut directory = tar::Header::new_gnu();
+    directory.set_entry_type(tar::EntryType::Directory);
+    directory.set_mode(0o755);
+    directory.set_size(0);
+    builder.append_data(&mut directory, relative, std::io::empty())?;
+    for name in names {
+        let value = read(name)?;
+        let mut file = tar::Header::new_gnu();
+        file.set_mode(0o444);
+        file.set_size(value.len() as u64);
+        builder.append_data(&mut file, Path::new(relative).join(name), value.as_slice())?;
+    }
+    Ok(builder.into_inner()?)
+}
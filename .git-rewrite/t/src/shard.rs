@@ -0,0 +1,94 @@
+//! Routes requests among several dispatcher instances sharing a
+//! docker daemon, each one serving its own namespace. Requests may
+//! name the namespace they're meant for, and those meant for another
+//! instance are refused, pointing to the instance serving it.
+
+use crate::api_error::APIError;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, LOCATION},
+    middleware::Next,
+    web, Error,
+};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// Request header naming the namespace a request is meant for.
+pub const NAMESPACE_HEADER: &str = "X-Dispatcher-Namespace";
+
+/// The namespace served by this instance, and the base URLs of the
+/// instances serving other namespaces.
+pub struct Shards {
+    pub namespace: String,
+    pub owners: HashMap<String, String>,
+}
+
+impl Shards {
+    /// Build the shard map from namespace=URL pairs.
+    pub fn new(namespace: String, owners: &[String]) -> Result<Self> {
+        let owners = owners
+            .iter()
+            .filter(|owner| !owner.is_empty())
+            .map(|owner| {
+                owner
+                    .split_once('=')
+                    .map(|(namespace, url)| {
+                        (namespace.to_string(), url.trim_end_matches('/').to_string())
+                    })
+                    .with_context(|| format!("shard {:?} is not a namespace=URL pair", owner))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Self { namespace, owners })
+    }
+}
+
+/// Whether a path belongs to an endpoint handling jobs of the
+/// namespace, as opposed to the health, metrics and docs endpoints.
+fn is_namespaced(path: &str) -> bool {
+    path == "/job" || path.starts_with("/job/") || path.starts_with("/admin/")
+}
+
+/// Refuse requests meant for a namespace other than the one served,
+/// with a `421 Misdirected Request` response and the location of the
+/// same request at the instance serving that namespace, if known.
+pub async fn route(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let misdirected = request
+        .app_data::<web::Data<Shards>>()
+        .zip(
+            request
+                .headers()
+                .get(NAMESPACE_HEADER)
+                .and_then(|value| value.to_str().ok()),
+        )
+        .filter(|(shards, wanted)| *wanted != shards.namespace && is_namespaced(request.path()))
+        .map(|(shards, wanted)| {
+            let location = shards.owners.get(wanted).map(|url| {
+                format!(
+                    "{}{}",
+                    url,
+                    request
+                        .uri()
+                        .path_and_query()
+                        .map_or(request.path(), |path| path.as_str())
+                )
+            });
+            (wanted.to_string(), location)
+        });
+    if let Some((wanted, location)) = misdirected {
+        let mut response = request.error_response(APIError::misdirected(match &location {
+            Some(location) => format!("Namespace {:?} is served at {}", wanted, location),
+            None => format!("Namespace {:?} isn't served by this instance", wanted),
+        }));
+        if let Some(location) = location.and_then(|location| HeaderValue::try_from(location).ok()) {
+            response.headers_mut().insert(LOCATION, location);
+        }
+        return Ok(response.map_into_right_body());
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
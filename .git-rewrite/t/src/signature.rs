@@ -0,0 +1,165 @@
+//! Verifies the signatures webhook providers attach to job creation
+//! requests, so that the filter only ever runs on payloads sent by
+//! whoever holds the shared secret.
+
+use crate::api_error::APIError;
+use crate::internal::equals;
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::Method,
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+use clap::ValueEnum;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum age in seconds of timestamped signatures, past which they
+/// may be replayed payloads.
+const TOLERANCE: u64 = 300;
+
+/// Maximum size in bytes of signed bodies, the same as that of JSON
+/// bodies.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// How a webhook provider signs its requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// A hex HMAC-SHA256 of the body.
+    #[default]
+    Github,
+    /// The shared secret itself, as a token.
+    Gitlab,
+    /// A timestamped HMAC-SHA256 of the body.
+    Stripe,
+}
+
+impl Style {
+    /// The header the provider puts the signature in.
+    pub fn default_header(&self) -> &'static str {
+        match self {
+            Self::Github => "X-Hub-Signature-256",
+            Self::Gitlab => "X-Gitlab-Token",
+            Self::Stripe => "Stripe-Signature",
+        }
+    }
+}
+
+/// The secret job creation requests must be signed with, and how.
+#[derive(Default)]
+pub struct Signatures {
+    pub secret: Option<String>,
+    pub style: Style,
+    pub header: String,
+}
+
+/// The hex HMAC-SHA256 of the given parts with the given secret.
+pub(crate) fn hmac_hex(secret: &str, parts: &[&[u8]]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+impl Signatures {
+    /// Whether requests must be signed.
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Check the signature given for a body, in the configured style.
+    fn verify(&self, signature: &str, body: &[u8]) -> Result<(), &'static str> {
+        let Some(secret) = &self.secret else {
+            return Ok(());
+        };
+        let valid = match self.style {
+            Style::Github => {
+                let given = signature.strip_prefix("sha256=").unwrap_or(signature);
+                equals(
+                    given.to_lowercase().as_bytes(),
+                    hmac_hex(secret, &[body]).as_bytes(),
+                )
+            }
+            Style::Gitlab => equals(signature.as_bytes(), secret.as_bytes()),
+            Style::Stripe => {
+                let fields = signature
+                    .split(',')
+                    .filter_map(|field| field.trim().split_once('='));
+                let timestamp = fields
+                    .clone()
+                    .find(|(key, _)| *key == "t")
+                    .map(|(_, value)| value)
+                    .ok_or("Webhook signature lacks a timestamp")?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                if !timestamp
+                    .parse::<u64>()
+                    .is_ok_and(|signed| now.abs_diff(signed) <= TOLERANCE)
+                {
+                    return Err("Webhook signature is expired");
+                }
+                let expected = hmac_hex(secret, &[timestamp.as_bytes(), b".", body]);
+                fields
+                    .filter(|(key, _)| *key == "v1")
+                    .any(|(_, given)| equals(given.as_bytes(), expected.as_bytes()))
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("Invalid webhook signature")
+        }
+    }
+}
+
+/// Reject job creation requests lacking a valid signature of their
+/// body, if signatures are required. The body is read in full, and
+/// handed back to the request for the filter to run on.
+pub async fn verify(
+    mut request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let signatures = request
+        .app_data::<web::Data<Signatures>>()
+        .filter(|signatures| signatures.is_enabled())
+        .filter(|_| {
+            request.method() == Method::POST
+                && (request.path() == "/job" || request.path().starts_with("/job/"))
+        })
+        .cloned();
+    if let Some(signatures) = signatures {
+        let signature = request
+            .headers()
+            .get(signatures.header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string());
+        let mut payload = request.take_payload();
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
+            if body.len() > MAX_BODY {
+                Err(PayloadError::Overflow)?;
+            }
+        }
+        let body = body.freeze();
+        let refusal = match signature {
+            Some(signature) => signatures.verify(&signature, &body).err(),
+            None => Some("Missing webhook signature"),
+        };
+        if let Some(refusal) = refusal {
+            let response = request.error_response(APIError::unauthorized(refusal));
+            return Ok(response.map_into_right_body());
+        }
+        request.set_payload(Payload::from(body));
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
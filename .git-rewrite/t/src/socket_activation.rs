@@ -0,0 +1,55 @@
+//! Accepts the listening sockets passed by systemd on socket
+//! activation, so that systemd may hold them across restarts.
+
+use anyhow::{Context, Result};
+use std::env;
+use std::net::TcpListener;
+use std::os::unix::io::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::process;
+
+/// First file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A listening socket passed by systemd.
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Take the listening sockets passed by systemd, if the process was
+/// socket-activated. This may only be done once per process.
+pub fn listeners() -> Result<Vec<Listener>> {
+    let pid = env::var("LISTEN_PID").ok();
+    let fds = env::var("LISTEN_FDS").ok();
+    // child processes mustn't take the sockets too
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid.and_then(|pid| pid.parse::<u32>().ok()) != Some(process::id()) {
+        return Ok(Vec::new());
+    }
+    let Some(fds) = fds else {
+        return Ok(Vec::new());
+    };
+    let count: RawFd = fds
+        .parse()
+        .with_context(|| format!("while parsing LISTEN_FDS {:?}", fds))?;
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // SAFETY: systemd passes this process the ownership of
+            // LISTEN_FDS open sockets, starting from LISTEN_FDS_START,
+            // and they're only taken here, once
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            // unix sockets don't have an IP address
+            let listener = if listener.local_addr().is_ok() {
+                Listener::Tcp(listener)
+            } else {
+                // SAFETY: the descriptor was just released by the TCP
+                // listener
+                Listener::Unix(unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) })
+            };
+            Ok(listener)
+        })
+        .collect()
+}
@@ -0,0 +1,133 @@
+//! Builds the TLS configuration of the API listener, optionally
+//! requiring client certificates (mTLS), and reads the identity of
+//! clients from their certificates.
+
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream, HttpRequest};
+use anyhow::{Context, Result};
+use rustls::{
+    crypto::{ring, CryptoProvider},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use rustls_pemfile::{certs, private_key};
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+use x509_cert::{
+    der::{oid::db::rfc4519::CN, Decode},
+    ext::pkix::{name::GeneralName, SubjectAltName},
+    Certificate,
+};
+
+/// The identity of a client, read from its verified certificate.
+#[derive(Clone, Debug)]
+struct ClientIdentity(String);
+
+/// Open a PEM file for reading.
+fn open(path: &Path) -> Result<BufReader<File>> {
+    Ok(BufReader::new(
+        File::open(path).with_context(|| format!("while opening {:?}", path))?,
+    ))
+}
+
+/// Build the TLS configuration out of a certificate chain and its
+/// private key, given as PEM files, and optionally a bundle of CA
+/// certificates client certificates must be signed by. Clients
+/// without a certificate are refused, unless it's optional.
+pub fn server_config(
+    cert: &Path,
+    key: &Path,
+    client_ca: Option<&Path>,
+    client_optional: bool,
+) -> Result<ServerConfig> {
+    // the provider is given explicitly, since dependencies may enable
+    // others
+    let provider = Arc::new(ring::default_provider());
+    let chain = certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("while reading the certificates in {:?}", cert))?;
+    let key = private_key(&mut open(key)?)
+        .with_context(|| format!("while reading the private key in {:?}", key))?
+        .with_context(|| format!("no private key found in {:?}", key))?;
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .context("while choosing TLS versions")?;
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for ca in certs(&mut open(client_ca)?) {
+                let ca = ca.with_context(|| {
+                    format!("while reading the certificates in {:?}", client_ca)
+                })?;
+                roots
+                    .add(ca)
+                    .with_context(|| format!("while adding a client CA from {:?}", client_ca))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                provider as Arc<CryptoProvider>,
+            );
+            let verifier = if client_optional {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            }
+            .build()
+            .context("while building the client certificate verifier")?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(chain, key)
+        .context("while loading the TLS certificate")
+}
+
+/// Read the identity of a client out of its certificate: the common
+/// name of its subject, or else its first DNS name, email address or
+/// URI.
+fn identity_of(cert: &[u8]) -> Option<String> {
+    let cert = Certificate::from_der(cert).ok()?;
+    let common_name = cert
+        .tbs_certificate
+        .subject
+        .0
+        .iter()
+        .flat_map(|rdn| rdn.0.iter())
+        .find(|attribute| attribute.oid == CN)
+        .and_then(|attribute| std::str::from_utf8(attribute.value.value()).ok())
+        .map(String::from);
+    common_name.or_else(|| {
+        let (_, SubjectAltName(names)) = cert.tbs_certificate.get::<SubjectAltName>().ok()??;
+        names.into_iter().find_map(|name| match name {
+            GeneralName::DnsName(name)
+            | GeneralName::Rfc822Name(name)
+            | GeneralName::UniformResourceIdentifier(name) => Some(name.to_string()),
+            _ => None,
+        })
+    })
+}
+
+/// Keep the identity of the client of a TLS connection, if it
+/// presented a certificate, along with the connection. Meant to be
+/// given to [`actix_web::HttpServer::on_connect`].
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let identity = connection
+        .downcast_ref::<TlsStream<TcpStream>>()
+        .and_then(|stream| stream.get_ref().1.peer_certificates()?.first())
+        .and_then(|cert| identity_of(cert));
+    if let Some(identity) = identity {
+        data.insert(ClientIdentity(identity));
+    }
+}
+
+/// The identity of the client certificate presented through a
+/// request's connection, if any.
+pub fn client_identity(request: &HttpRequest) -> Option<String> {
+    request
+        .conn_data::<ClientIdentity>()
+        .map(|identity| identity.0.clone())
+}
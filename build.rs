@@ -0,0 +1,23 @@
+//! Records the commit the dispatcher is built from, for the
+//! build_info metric.
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let commit = env::var("GIT_COMMIT").ok().or_else(|| {
+        Command::new("git")
+            .args(["rev-parse", "--short", "HEAD"])
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .map(|commit| commit.trim().to_string())
+    });
+    println!(
+        "cargo:rustc-env=BUILD_COMMIT={}",
+        commit.unwrap_or_else(|| String::from("unknown"))
+    );
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+}
@@ -0,0 +1,85 @@
+//! Logs every API request, and feeds the request metrics.
+
+use crate::auth;
+use crate::metrics_service;
+use crate::tls;
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error,
+};
+use std::time::Instant;
+use tracing::{event, Level};
+
+/// Access log settings.
+pub struct Settings {
+    pub level: Level,
+}
+
+/// Placeholder for endpoints of requests that matched no route.
+const UNMATCHED: &str = "unmatched";
+
+/// Emit an event at a level given at runtime.
+macro_rules! event_at {
+    ($level:expr, $($args:tt)+) => {
+        match $level {
+            Level::ERROR => event!(Level::ERROR, $($args)+),
+            Level::WARN => event!(Level::WARN, $($args)+),
+            Level::INFO => event!(Level::INFO, $($args)+),
+            Level::DEBUG => event!(Level::DEBUG, $($args)+),
+            Level::TRACE => event!(Level::TRACE, $($args)+),
+        }
+    };
+}
+
+/// Log a handled request at the given level, and count it in the
+/// request metrics.
+fn record<B: MessageBody>(response: &ServiceResponse<B>, started: Instant, level: Level) {
+    let latency = started.elapsed();
+    let request = response.request();
+    let status = response.status();
+    let endpoint = request
+        .match_pattern()
+        .unwrap_or_else(|| UNMATCHED.to_string());
+    metrics_service::record_request(
+        request.method().as_str(),
+        &endpoint,
+        status.as_u16(),
+        latency,
+    );
+    // streamed bodies (e.g. job logs) have no known size
+    let size = match response.response().body().size() {
+        BodySize::None => String::from("0"),
+        BodySize::Sized(size) => size.to_string(),
+        BodySize::Stream => String::from("-"),
+    };
+    event_at!(
+        level,
+        method = %request.method(),
+        path = request.path(),
+        status = status.as_u16(),
+        size = %size,
+        latency_ms = latency.as_secs_f64() * 1000.0,
+        api_key = auth::key_name(request).as_deref(),
+        client_cert = tls::client_identity(request).as_deref(),
+        "{} {} {}",
+        request.method(),
+        request.path(),
+        status.as_u16()
+    );
+}
+
+/// Log every request once handled, at the configured level.
+pub async fn log_request(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    let level = request
+        .app_data::<web::Data<Settings>>()
+        .map_or(Level::INFO, |settings| settings.level);
+    let response = next.call(request).await?;
+    record(&response, started, level);
+    Ok(response)
+}
@@ -0,0 +1,262 @@
+//! Implements administrative endpoints.
+
+use crate::api_error::APIError;
+use crate::app::Cli;
+use crate::audit;
+use crate::cleaner::{self, Purge, Removal};
+use crate::config;
+use crate::openapi;
+use crate::reload::{Live, Reloader};
+
+use actix_web::{get, post, web, HttpRequest, Responder, Result};
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+use utoipa::ToSchema;
+
+/// Preview cleaning
+///
+/// Report the jobs the cleaner would remove, and why, without removing
+/// them
+#[utoipa::path(
+    get,
+    path = "/admin/cleaner/preview",
+    tag = "admin",
+    operation_id = "previewCleaner",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(openapi::Namespace),
+    responses(
+        (status = 200, description = "jobs the cleaner would remove", body = Vec<Removal>),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to administer the dispatcher", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "preview failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
+#[get("/admin/cleaner/preview")]
+async fn preview_cleaner(
+    request: HttpRequest,
+    retention: web::Data<Live<cleaner::Retention>>,
+    cleanup: web::Data<cleaner::Cleanup>,
+    namespace: web::Data<String>,
+) -> Result<web::Json<Vec<Removal>>> {
+    let plan = cleaner::plan(&retention.get(), &cleanup, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    info!("Previewed cleaning of {} jobs", plan.len());
+    audit::record(audit::Entry {
+        action: "cleaner_previewed",
+        namespace: namespace.to_string(),
+        actor: audit::Actor::of(&request),
+        ..Default::default()
+    });
+    Ok(web::Json(plan))
+}
+
+/// Default states of jobs to purge.
+fn default_purge_states() -> Vec<String> {
+    vec![String::from("exited"), String::from("dead")]
+}
+
+/// Criteria for selecting the jobs to purge.
+#[derive(Deserialize, ToSchema)]
+pub(crate) struct PurgeRequest {
+    /// States of the jobs to purge, among created, exited and dead
+    #[serde(default = "default_purge_states")]
+    #[schema(default = default_purge_states, example = json!(["exited", "dead"]))]
+    status: Vec<String>,
+    /// Minimum age in seconds, measured from the moment the job
+    /// finished or, if it never ran, from its creation
+    #[schema(example = 3600)]
+    older_than: Option<u32>,
+    /// Glob pattern the job names must match
+    #[schema(example = "report-*")]
+    name: Option<String>,
+    /// Whether service jobs are purged as well
+    #[serde(default)]
+    #[schema(default = false, example = false)]
+    include_services: bool,
+}
+
+/// Purge jobs
+///
+/// Immediately remove the jobs matching the given criteria, reporting
+/// the ones that couldn't be removed
+#[utoipa::path(
+    post,
+    path = "/admin/purge",
+    tag = "admin",
+    operation_id = "purge",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(openapi::Namespace),
+    request_body = PurgeRequest,
+    responses(
+        (status = 200, description = "removed jobs, and jobs that couldn't be removed", body = Purge),
+        (status = 400, description = "invalid purge criteria", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to administer the dispatcher", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "purge failed while trying to list jobs from the docker daemon", body = APIError)
+    )
+)]
+#[post("/admin/purge")]
+async fn purge(
+    request: HttpRequest,
+    body: web::Json<PurgeRequest>,
+    cleanup: web::Data<cleaner::Cleanup>,
+    namespace: web::Data<String>,
+) -> Result<web::Json<Purge>> {
+    if body.status.is_empty() {
+        Err(APIError::bad_request("At least one status must be given"))?;
+    }
+    if let Some(status) = body
+        .status
+        .iter()
+        .find(|status| !cleaner::PURGEABLE_STATES.contains(&status.as_str()))
+    {
+        Err(APIError::bad_request(format!(
+            "Status {:?} can't be purged; expected one of {:?}",
+            status,
+            cleaner::PURGEABLE_STATES
+        )))?;
+    }
+    let pattern = body
+        .name
+        .as_deref()
+        .map(Pattern::new)
+        .transpose()
+        .map_err(|e| APIError::bad_request(format!("Invalid name pattern: {}", e)))?;
+    let purge = cleaner::purge(
+        &body.status,
+        body.older_than,
+        pattern.as_ref(),
+        body.include_services,
+        &cleanup,
+        &namespace,
+    )
+    .await
+    .map_err(APIError::bad_gateway)?;
+    info!(
+        "Purged {} jobs, failed to purge {}",
+        purge.removed.len(),
+        purge.failed.len()
+    );
+    let actor = audit::Actor::of(&request);
+    for removal in &purge.removed {
+        audit::record(audit::Entry {
+            action: "job_purged",
+            namespace: namespace.to_string(),
+            job: Some(removal.id.clone()),
+            detail: Some(removal.reason.clone()),
+            actor: actor.clone(),
+            ..Default::default()
+        });
+    }
+    Ok(web::Json(purge))
+}
+
+/// Outcome of a reload.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct ReloadResponse {
+    /// Applied changes, if any
+    changes: Vec<String>,
+}
+
+/// Reload settings
+///
+/// Re-read the filter file and the settings file, and apply their
+/// changes all at once
+#[utoipa::path(
+    post,
+    path = "/admin/reload",
+    tag = "admin",
+    operation_id = "reload",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(openapi::Namespace),
+    responses(
+        (status = 200, description = "applied changes, if any", body = ReloadResponse),
+        (status = 400, description = "the filter or the settings are invalid, or can't be changed without restarting; nothing was applied", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to administer the dispatcher", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+    )
+)]
+#[post("/admin/reload")]
+async fn reload(
+    request: HttpRequest,
+    reloader: web::Data<Reloader>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let changes = reloader.reload().map_err(|e| {
+        warn!(
+            "Couldn't reload; keeping the current filter and settings: {:?}",
+            e
+        );
+        APIError::bad_request(format!("Couldn't reload: {:#}", e))
+    })?;
+    if !changes.is_empty() {
+        audit::record(audit::Entry {
+            action: "settings_reloaded",
+            namespace: namespace.to_string(),
+            detail: Some(changes.join(", ")),
+            actor: audit::Actor::of(&request),
+            ..Default::default()
+        });
+    }
+    Ok(web::Json(ReloadResponse { changes }))
+}
+
+/// Show configuration
+///
+/// Report the configuration in effect: the flags given at startup,
+/// with secrets redacted, the reloadable settings currently applied, a
+/// digest of the current filter and the docker hosts connected to
+#[utoipa::path(
+    get,
+    path = "/admin/config",
+    tag = "admin",
+    operation_id = "showConfig",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(openapi::Namespace),
+    responses(
+        (status = 200, description = "configuration in effect", body = Object),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to administer the dispatcher", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+    )
+)]
+#[get("/admin/config")]
+async fn show_config(
+    request: HttpRequest,
+    cli: web::Data<Cli>,
+    reloader: web::Data<Reloader>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    audit::record(audit::Entry {
+        action: "config_viewed",
+        namespace: namespace.to_string(),
+        actor: audit::Actor::of(&request),
+        ..Default::default()
+    });
+    Ok(web::Json(config::report(&cli, &reloader)))
+}
@@ -0,0 +1,142 @@
+//! Archives the logs of jobs to disk and/or object storage before
+//! they're removed.
+
+use crate::docker;
+use crate::object_store;
+use anyhow::{bail, Context, Result};
+use bollard::models::ContainerInspectResponse;
+use chrono::offset::Utc;
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+/// Static archive directory.
+static DIRECTORY: OnceCell<PathBuf> = OnceCell::new();
+
+/// Set the directory where job logs are archived.
+pub fn init(directory: PathBuf) {
+    let _ = DIRECTORY.set(directory);
+}
+
+/// Get the archive directory, if archival is enabled.
+fn directory() -> Option<&'static Path> {
+    DIRECTORY.get().map(PathBuf::as_path)
+}
+
+/// Check whether a job name is safe to use as a path component. Valid
+/// docker container names always are.
+pub(crate) fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+/// Get the archive directory of a specific job.
+fn job_directory(directory: &Path, namespace: &str, name: &str) -> Option<PathBuf> {
+    if is_safe_name(namespace) && is_safe_name(name) {
+        Some(directory.join(namespace).join(name))
+    } else {
+        None
+    }
+}
+
+/// Build the metadata record of an exited job.
+fn metadata(name: &str, namespace: &str, container: &ContainerInspectResponse) -> Value {
+    let state = container.state.as_ref();
+    json!({
+        "id": name,
+        "namespace": namespace,
+        "exit_code": state.and_then(|state| state.exit_code),
+        "oom_killed": state.and_then(|state| state.oom_killed),
+        "created": container.created,
+        "started_at": state.and_then(|state| state.started_at.clone()),
+        "finished_at": state.and_then(|state| state.finished_at.clone()),
+        "manifest": {
+            "config": container.config,
+            "host_config": container.host_config,
+        },
+    })
+}
+
+/// Fetch the logs of an exited job and archive them, as
+/// {namespace}/{job}/{timestamp}.log, on disk and/or in object
+/// storage. A JSON metadata record is uploaded to object storage
+/// along with the logs. Does nothing if archival is disabled.
+pub async fn save(name: &str, namespace: &str, container: &ContainerInspectResponse) -> Result<()> {
+    let directory = directory();
+    if directory.is_none() && !object_store::is_enabled() {
+        return Ok(());
+    }
+    if !is_safe_name(namespace) || !is_safe_name(name) {
+        bail!("job name {:?} can't be archived", name);
+    }
+    let logs = docker::logs(name)
+        .await
+        .context("while fetching job logs")?;
+    let timestamp = Utc::now().format("%Y%m%dT%H%M%SZ");
+    if let Some(directory) = directory {
+        let job_directory = directory.join(namespace).join(name);
+        fs::create_dir_all(&job_directory)
+            .await
+            .context("while creating the log archive directory")?;
+        let file = job_directory.join(format!("{}.log", timestamp));
+        fs::write(&file, &logs)
+            .await
+            .context("while writing archived logs")?;
+        debug!("Archived logs of job {:?} at {:?}", name, file);
+    }
+    if let Some(url) = object_store::put(
+        &format!("{}/{}/{}.log", namespace, name, timestamp),
+        logs,
+        "text/plain",
+    )
+    .await?
+    {
+        debug!("Uploaded logs of job {:?} to {}", name, url);
+    }
+    if let Some(url) = object_store::put(
+        &format!("{}/{}/{}.json", namespace, name, timestamp),
+        serde_json::to_vec(&metadata(name, namespace, container))?,
+        "application/json",
+    )
+    .await?
+    {
+        debug!("Uploaded metadata of job {:?} to {}", name, url);
+    }
+    Ok(())
+}
+
+/// Read the most recently archived logs of a job, if any.
+pub async fn latest_logs(name: &str, namespace: &str) -> Result<Option<Vec<u8>>> {
+    let Some(job_directory) =
+        directory().and_then(|directory| job_directory(directory, namespace, name))
+    else {
+        return Ok(None);
+    };
+    let mut entries = match fs::read_dir(&job_directory).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => Err(e).context("while listing archived logs")?,
+    };
+    let mut latest: Option<PathBuf> = None;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == "log")
+            && latest.as_ref().is_none_or(|latest| &path > latest)
+        {
+            latest = Some(path);
+        }
+    }
+    match latest {
+        Some(path) => Ok(Some(
+            fs::read(path)
+                .await
+                .context("while reading archived logs")?,
+        )),
+        None => Ok(None),
+    }
+}
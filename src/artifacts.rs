@@ -0,0 +1,111 @@
+//! Uploads the artifacts of jobs, i.e. the contents of a path in their
+//! filesystem, to object storage as soon as they exit, and keeps track
+//! of where they were uploaded so that it's reported along with the
+//! job.
+
+use crate::archive;
+use crate::docker;
+use crate::job_store;
+use crate::notifier;
+use crate::object_store;
+use anyhow::{bail, Result};
+use futures::stream::TryStreamExt;
+use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Maximum amount of uploads kept in memory.
+const MAX_RECORDS: usize = 1000;
+
+/// Maximum amount of jobs whose artifacts are uploaded at once.
+const CONCURRENCY: usize = 4;
+
+/// Which artifacts are uploaded.
+pub struct Settings {
+    /// Path in the filesystem of jobs holding their artifacts.
+    pub path: String,
+    /// Maximum size in bytes of the archive of artifacts.
+    pub max_size: usize,
+}
+
+/// Static artifact settings.
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+
+/// Static registry of recent uploads, by job name, ordered from
+/// oldest to newest, for when the job store is disabled.
+static UPLOADS: OnceCell<Mutex<VecDeque<(String, String)>>> = OnceCell::new();
+
+/// Initialize the global artifact settings.
+pub fn init(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Get the registry of recent uploads.
+fn uploads() -> &'static Mutex<VecDeque<(String, String)>> {
+    UPLOADS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+/// Get the URL the artifacts of a job were uploaded to, if any.
+pub fn url(name: &str) -> Option<String> {
+    uploads()
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map(|(_, url)| url.clone())
+        .or_else(|| job_store::get(name).and_then(|record| record.artifacts))
+}
+
+/// Copy the artifacts out of an exited job and upload them, as
+/// {namespace}/{job}/artifacts.tar, returning their URL.
+async fn upload(settings: &Settings, name: &str, namespace: &str) -> Result<Option<String>> {
+    if !archive::is_safe_name(namespace) || !archive::is_safe_name(name) {
+        bail!("job name {:?} can't be used as an object key", name);
+    }
+    let artifacts = docker::download(name, &settings.path, settings.max_size).await?;
+    object_store::put(
+        &format!("{}/{}/artifacts.tar", namespace, name),
+        artifacts,
+        "application/x-tar",
+    )
+    .await
+}
+
+/// Consume the docker events stream, uploading the artifacts of each
+/// job that exits. Failed uploads are logged, and not retried.
+pub async fn watch(namespace: String) -> Result<()> {
+    let Some(settings) = SETTINGS.get() else {
+        return Ok(());
+    };
+    notifier::transitions(&namespace)?
+        .try_filter(|transition| {
+            futures::future::ready(matches!(transition.event, "succeeded" | "failed"))
+        })
+        .try_for_each_concurrent(CONCURRENCY, |transition| {
+            let namespace = namespace.clone();
+            async move {
+                match upload(settings, &transition.id, &namespace).await {
+                    Ok(Some(url)) => {
+                        debug!("Uploaded artifacts of job {:?} to {}", transition.id, url);
+                        job_store::update(&transition.id, |record| {
+                            record.artifacts = Some(url.clone());
+                        });
+                        let mut uploads = uploads().lock().unwrap();
+                        uploads.push_back((transition.id, url));
+                        if uploads.len() > MAX_RECORDS {
+                            uploads.pop_front();
+                        }
+                    }
+                    Ok(None) => (),
+                    Err(e) => warn!(
+                        "Couldn't upload the artifacts of job {:?}: {:?}",
+                        transition.id, e
+                    ),
+                }
+                Ok(())
+            }
+        })
+        .await
+}
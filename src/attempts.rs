@@ -0,0 +1,125 @@
+//! Keeps track of failed attempts at starting jobs.
+//!
+//! Docker doesn't allow updating the labels of an existing container,
+//! so start failures are kept in memory, keyed by job name, and in the
+//! job store, if enabled. Jobs that run out of attempts are also marked
+//! with a volume in their docker host, which every dispatcher sharing
+//! it adopts, so that they're not started again after a restart.
+//! Without the job store, the attempt counters of jobs with attempts
+//! left are reset when the dispatcher restarts.
+
+use crate::backend;
+use crate::docker;
+use crate::job_store;
+use anyhow::Result;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use utoipa::ToSchema;
+
+/// Static failure registry.
+static FAILURES: OnceCell<Mutex<HashMap<String, StartFailure>>> = OnceCell::new();
+
+/// Static maximum amount of start attempts.
+static MAX_ATTEMPTS: OnceCell<u16> = OnceCell::new();
+
+/// A record of failed attempts at starting a job.
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct StartFailure {
+    #[schema(example = 1)]
+    pub attempts: u16,
+    #[schema(example = "Docker responded with status code 500: no such image")]
+    pub last_error: String,
+}
+
+impl StartFailure {
+    /// Whether the job has run out of start attempts, and should be
+    /// considered failed.
+    pub fn exhausted(&self) -> bool {
+        self.attempts >= max_attempts()
+    }
+}
+
+/// Get the failure registry.
+fn failures() -> &'static Mutex<HashMap<String, StartFailure>> {
+    FAILURES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Set the maximum amount of start attempts for each job.
+pub fn init(max_attempts: u16) {
+    let _ = MAX_ATTEMPTS.set(max_attempts);
+}
+
+/// Get the maximum amount of start attempts for each job.
+fn max_attempts() -> u16 {
+    MAX_ATTEMPTS.get().copied().unwrap_or(1)
+}
+
+/// Record a failed attempt at starting a job.
+pub fn record_failure<S: ToString>(name: &str, error: S) -> StartFailure {
+    let mut failures = failures().lock().unwrap();
+    let failure = failures
+        .entry(name.to_string())
+        .or_insert_with(|| StartFailure {
+            attempts: 0,
+            last_error: String::new(),
+        });
+    failure.attempts = failure.attempts.saturating_add(1);
+    failure.last_error = error.to_string();
+    let failure = failure.clone();
+    drop(failures);
+    job_store::update(name, |record| {
+        record.attempts = failure.attempts;
+        record.last_error = Some(failure.last_error.clone());
+        if failure.exhausted() {
+            record.state = job_store::State::FailedToStart;
+        }
+    });
+    failure
+}
+
+/// Forget about previous failures of a job.
+pub fn clear(name: &str) {
+    failures().lock().unwrap().remove(name);
+}
+
+/// Forget about previous failures of a job once it's started, in the
+/// job store as well.
+pub fn reset(name: &str) {
+    clear(name);
+    job_store::update(name, |record| {
+        record.attempts = 0;
+        record.last_error = None;
+    });
+}
+
+/// Get the failure record of a job, if any, falling back to the one
+/// kept in the job store.
+pub fn get(name: &str) -> Option<StartFailure> {
+    if let Some(failure) = failures().lock().unwrap().get(name).cloned() {
+        return Some(failure);
+    }
+    job_store::get(name)
+        .filter(|record| record.attempts > 0)
+        .map(|record| StartFailure {
+            attempts: record.attempts,
+            last_error: record.last_error.unwrap_or_default(),
+        })
+}
+
+/// Adopt the failures of the jobs of a namespace marked as out of
+/// start attempts, by this or any other dispatcher.
+pub async fn adopt(namespace: &str) -> Result<()> {
+    if backend::is_alternative() {
+        return Ok(());
+    }
+    let exhausted = docker::exhausted(namespace).await?;
+    let mut failures = failures().lock().unwrap();
+    for (name, failure) in exhausted {
+        if !failures.get(&name).is_some_and(StartFailure::exhausted) {
+            failures.insert(name, failure);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,266 @@
+//! Enforces rules mapping the identities of clients (API keys, token
+//! subjects and client certificates) to the operations they may
+//! perform, in which namespaces and through which paths.
+
+use crate::api_error::APIError;
+use crate::audit;
+use crate::auth;
+use crate::shard;
+use crate::tls;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web, Error, HttpRequest,
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// An operation clients may be allowed to perform.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    Create,
+    Read,
+    Delete,
+    Admin,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Create => "create",
+            Self::Read => "read",
+            Self::Delete => "delete",
+            Self::Admin => "admin",
+        })
+    }
+}
+
+/// A rule allowing some identities to perform some operations,
+/// optionally only in some namespaces, and only creating jobs through
+/// some paths, or under them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    identities: Vec<String>,
+    operations: Vec<Operation>,
+    namespaces: Option<Vec<String>>,
+    paths: Option<Vec<String>>,
+}
+
+impl Rule {
+    /// Whether the rule allows any of the given identities to perform
+    /// the given operation, in the given namespace and through the
+    /// given path.
+    fn allows(
+        &self,
+        identities: &[String],
+        operation: Operation,
+        namespace: &str,
+        path: &str,
+    ) -> bool {
+        self.identities
+            .iter()
+            .any(|identity| identity == "*" || identities.contains(identity))
+            && self.operations.contains(&operation)
+            && self
+                .namespaces
+                .as_ref()
+                .is_none_or(|namespaces| namespaces.iter().any(|n| n == namespace))
+            && (operation != Operation::Create
+                || self
+                    .paths
+                    .as_ref()
+                    .is_none_or(|paths| paths.iter().any(|p| auth::is_under(path, p))))
+    }
+}
+
+/// Authorization rules, and the namespace they're enforced in unless
+/// requests select another one. With no rules, every operation is
+/// allowed.
+#[derive(Default)]
+pub struct Rules {
+    rules: Option<Vec<Rule>>,
+    namespace: String,
+}
+
+impl Rules {
+    /// Read the rules from a JSON file holding an array of them.
+    pub fn load(path: &Path, namespace: String) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("while reading the authorization rules {:?}", path))?;
+        let rules = serde_json::from_str(&contents)
+            .with_context(|| format!("while parsing the authorization rules {:?}", path))?;
+        Ok(Self {
+            rules: Some(rules),
+            namespace,
+        })
+    }
+
+    /// Whether rules are enforced.
+    pub fn is_enabled(&self) -> bool {
+        self.rules.is_some()
+    }
+
+    /// Whether any rule allows the given operation in the given
+    /// namespace.
+    fn allows(
+        &self,
+        identities: &[String],
+        operation: Operation,
+        namespace: &str,
+        path: &str,
+    ) -> bool {
+        self.rules.as_ref().is_none_or(|rules| {
+            rules
+                .iter()
+                .any(|rule| rule.allows(identities, operation, namespace, path))
+        })
+    }
+}
+
+/// The operation a request performs, if it's subject to
+/// authorization.
+fn operation(method: &Method, path: &str) -> Option<Operation> {
+    if path.starts_with("/admin/") || path.starts_with("/templates/") {
+        return Some(Operation::Admin);
+    }
+    if path == "/graphql" || path.starts_with("/jobs/") {
+        return Some(Operation::Read);
+    }
+    if path != "/job" && !path.starts_with("/job/") {
+        return None;
+    }
+    match *method {
+        Method::POST => Some(Operation::Create),
+        Method::GET => Some(Operation::Read),
+        Method::DELETE => Some(Operation::Delete),
+        _ => None,
+    }
+}
+
+/// The identities a request was authenticated with.
+fn identities(request: &HttpRequest) -> Vec<String> {
+    [
+        auth::key_name(request).map(|name| format!("key:{}", name)),
+        auth::token(request)
+            .and_then(|token| token.subject)
+            .map(|subject| format!("subject:{}", subject)),
+        tls::client_identity(request).map(|identity| format!("cert:{}", identity)),
+    ]
+    .into_iter()
+    .flatten()
+    .collect()
+}
+
+/// Reject requests performing operations their identities aren't
+/// allowed to, if rules are enforced, in the namespace selected with
+/// the X-Namespace header or else in the one served.
+pub async fn authorize(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let denied = request
+        .app_data::<web::Data<Rules>>()
+        .filter(|rules| rules.is_enabled())
+        .cloned()
+        .zip(operation(request.method(), request.path()))
+        .map(|(rules, operation)| {
+            let namespace = shard::selected(&request)
+                .unwrap_or(&rules.namespace)
+                .to_string();
+            (rules, operation, namespace)
+        })
+        .filter(|(rules, operation, namespace)| {
+            !rules.allows(
+                &identities(request.request()),
+                *operation,
+                namespace,
+                request.path(),
+            )
+        });
+    if let Some((_, operation, namespace)) = denied {
+        audit::record(audit::Entry {
+            action: "access_denied",
+            namespace: namespace.clone(),
+            detail: Some(format!("{} {}", operation, request.path())),
+            actor: audit::Actor::of(request.request()),
+            ..Default::default()
+        });
+        let response = request.error_response(APIError::forbidden(format!(
+            "Not allowed to {} in namespace {:?}",
+            match operation {
+                Operation::Create => "create jobs through this path",
+                Operation::Read => "read jobs",
+                Operation::Delete => "delete jobs",
+                Operation::Admin => "administer the dispatcher",
+            },
+            namespace
+        )));
+        return Ok(response.map_into_right_body());
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Operation, Rule};
+
+    /// A rule allowing the ci key to create jobs through a prefix.
+    fn rule(prefix: &str) -> Rule {
+        Rule {
+            identities: vec![String::from("key:ci")],
+            operations: vec![Operation::Create],
+            namespaces: None,
+            paths: Some(vec![prefix.to_string()]),
+        }
+    }
+
+    fn allows(rule: &Rule, path: &str) -> bool {
+        rule.allows(
+            &[String::from("key:ci")],
+            Operation::Create,
+            "default",
+            path,
+        )
+    }
+
+    #[test]
+    fn path_prefixes_match_whole_segments() {
+        let rule = rule("/job/team-a");
+        assert!(allows(&rule, "/job/team-a"));
+        assert!(allows(&rule, "/job/team-a/nightly"));
+        assert!(!allows(&rule, "/job/team-ab"));
+        assert!(!allows(&rule, "/job/team-ab/nightly"));
+        assert!(!allows(&rule, "/job"));
+    }
+
+    #[test]
+    fn path_prefixes_ending_in_a_slash_cover_their_segments() {
+        let rule = rule("/job/team-a/");
+        assert!(allows(&rule, "/job/team-a/nightly"));
+        assert!(!allows(&rule, "/job/team-a"));
+        assert!(!allows(&rule, "/job/team-ab"));
+    }
+
+    #[test]
+    fn other_operations_ignore_paths() {
+        let rule = Rule {
+            operations: vec![Operation::Read],
+            ..rule("/job/team-a")
+        };
+        assert!(rule.allows(
+            &[String::from("key:ci")],
+            Operation::Read,
+            "default",
+            "/job/team-ab"
+        ));
+    }
+}
@@ -0,0 +1,209 @@
+//! Alerts a Slack, Mattermost or Discord channel, through an incoming
+//! webhook, whenever a job fails (including OOM kills and timeouts) and
+//! whenever an upkeep task gives up after repeated errors.
+//!
+//! Alerts are posted by a single task, which routes them by namespace
+//! and holds back identical alerts for a while, so that a burst of
+//! failures doesn't flood the channel.
+
+use crate::metrics_service;
+use crate::notifier;
+use anyhow::{anyhow, Result};
+use clap::ValueEnum;
+use futures::stream::TryStreamExt;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error};
+
+/// Static channel to the alerting task.
+static SENDER: OnceCell<UnboundedSender<Alert>> = OnceCell::new();
+
+/// The kind of chat webhooks alerts are posted to.
+#[derive(Clone, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flavor {
+    Slack,
+    Mattermost,
+    Discord,
+}
+
+impl Flavor {
+    /// Build the body of a message with the given text.
+    fn message(&self, text: &str) -> Value {
+        match self {
+            Self::Slack | Self::Mattermost => json!({"text": text}),
+            Self::Discord => json!({"content": text}),
+        }
+    }
+}
+
+/// The webhook alerts of each namespace are posted to.
+pub struct Routes {
+    default: Option<String>,
+    namespaces: HashMap<String, String>,
+}
+
+impl Routes {
+    /// Build the routes from a default webhook, and webhooks of
+    /// specific namespaces given as namespace=url.
+    pub fn new(default: Option<String>, routes: &[String]) -> Result<Self> {
+        let namespaces = routes
+            .iter()
+            .filter(|route| !route.is_empty())
+            .map(|route| {
+                route
+                    .split_once('=')
+                    .map(|(namespace, url)| (namespace.trim().to_string(), url.trim().to_string()))
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "invalid chat webhook route {:?}; expected namespace=url",
+                            route
+                        )
+                    })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            default,
+            namespaces,
+        })
+    }
+
+    /// The webhook alerts of the given namespace are posted to, if any.
+    fn get(&self, namespace: &str) -> Option<&str> {
+        self.namespaces
+            .get(namespace)
+            .or(self.default.as_ref())
+            .map(String::as_str)
+    }
+}
+
+/// An alert, along with what makes it identical to others.
+struct Alert {
+    namespace: String,
+    key: String,
+    text: String,
+}
+
+/// Post an alert to the channel of the given namespace. Alerts with the
+/// same key are considered identical. Nothing is posted if alerting is
+/// disabled.
+fn alert(namespace: &str, key: String, text: String) {
+    let Some(sender) = SENDER.get() else {
+        return;
+    };
+    let _ = sender.send(Alert {
+        namespace: namespace.to_string(),
+        key,
+        text,
+    });
+}
+
+/// Alert of an upkeep task (e.g. the scheduler) giving up after
+/// reaching its threshold of consecutive errors.
+pub fn upkeep_failed(task: &str, namespace: &str, errors: u8, error: &anyhow::Error) {
+    alert(
+        namespace,
+        format!("upkeep:{}", task),
+        format!(
+            ":rotating_light: The {} of namespace `{}` gave up after {} consecutive errors: {}",
+            task, namespace, errors, error
+        ),
+    );
+}
+
+/// Describe the failure of a job, as classified by the metrics.
+fn describe(class: &str, exit_code: Option<i64>) -> String {
+    match class {
+        "oom-killed" => String::from("was OOM-killed"),
+        "timeout" => String::from("timed out"),
+        "start-failure" => String::from("failed to start"),
+        _ => format!(
+            "failed with exit code {}",
+            exit_code.map(|code| code.to_string()).unwrap_or_default()
+        ),
+    }
+}
+
+/// Consume the docker events stream, and alert of each job that fails.
+/// Failures of the same kind of the same path are considered identical.
+pub async fn watch(namespace: String) -> Result<()> {
+    notifier::transitions(&namespace)?
+        .try_filter(|transition| futures::future::ready(transition.event == "failed"))
+        .try_for_each(|transition| {
+            let namespace = namespace.clone();
+            async move {
+                let exit_code = transition.exit_code.map(|code| code.to_string());
+                let Some(class) =
+                    metrics_service::failure_class(&transition.id, exit_code.as_deref()).await
+                else {
+                    return Ok(());
+                };
+                let path = transition.path.unwrap_or_default();
+                alert(
+                    &namespace,
+                    format!("job:{}:{}", class, path),
+                    format!(
+                        ":x: Job `{}` of namespace `{}` {} (path `{}`)",
+                        transition.id,
+                        namespace,
+                        describe(class, transition.exit_code),
+                        path
+                    ),
+                );
+                Ok(())
+            }
+        })
+        .await
+}
+
+/// Start alerting through the given webhooks, returning the task that
+/// posts alerts. Identical alerts are posted once per interval, the
+/// next one mentioning how many were held back.
+pub fn init(
+    routes: Routes,
+    flavor: Flavor,
+    interval: Duration,
+) -> impl Future<Output = Result<()>> {
+    let (sender, mut receiver) = unbounded_channel::<Alert>();
+    let _ = SENDER.set(sender);
+    async move {
+        let client = reqwest::Client::new();
+        let mut posted: HashMap<(String, String), (Instant, u32)> = HashMap::new();
+        while let Some(alert) = receiver.recv().await {
+            let Some(webhook) = routes.get(&alert.namespace) else {
+                debug!("No chat webhook for the namespace {:?}", alert.namespace);
+                continue;
+            };
+            let now = Instant::now();
+            let held_back = match posted.get_mut(&(alert.namespace.clone(), alert.key.clone())) {
+                Some((last, held_back)) if now.duration_since(*last) < interval => {
+                    *held_back += 1;
+                    continue;
+                }
+                Some((_, held_back)) => *held_back,
+                None => 0,
+            };
+            posted.insert((alert.namespace, alert.key), (now, 0));
+            let text = if held_back > 0 {
+                format!("{} ({} identical alerts held back)", alert.text, held_back)
+            } else {
+                alert.text
+            };
+            let result = client
+                .post(webhook)
+                .json(&flavor.message(&text))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                error!("Couldn't post chat alert {:?}: {:?}", text, e);
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,67 +1,552 @@
 //! Implements the poll-based cleaning task.
 
+use crate::archive;
+use crate::audit;
+use crate::chat;
+use crate::circuit_breaker;
 use crate::docker;
-use anyhow::{anyhow, Context, Result};
-use chrono::{offset::Utc, DateTime, Duration as ChronoDuration};
-use futures::future::join_all;
+use crate::error_report;
+use crate::health_service;
+use crate::metrics_service;
+use crate::reload::Live;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use bollard::models::ContainerInspectResponse;
+use chrono::{offset::Utc, DateTime};
+use clap::ValueEnum;
+use futures::{
+    future::join_all,
+    stream::{self, StreamExt, TryStreamExt},
+};
+use glob::Pattern;
+use itertools::Itertools;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::time::{self, Duration};
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
 
-/// Check exited containers, and remove them if they're old enough
-/// according to maximum age.
-async fn clean(max_age: u32, namespace: &str) -> Result<()> {
+/// The creation time and name of the last job inspected in a batch,
+/// which the next batch picks up after.
+type Cursor = (i64, String);
+
+/// Last job inspected by the cleaner in each namespace when inspection
+/// is limited to a batch, so that the next cycle picks up after it.
+static CURSORS: Lazy<Mutex<HashMap<String, Cursor>>> = Lazy::new(Default::default);
+
+/// Which of the exited jobs are inspected when planning removals.
+enum Inspection<'a> {
+    /// Every exited job, however many.
+    All,
+    /// A batch of jobs, following the given one if any.
+    Batch(Option<&'a Cursor>),
+}
+
+/// Retention settings for exited jobs.
+#[derive(Clone, Debug)]
+pub struct Retention {
+    /// Interval in seconds to keep an exited job.
+    pub keep_exited_for: Option<u32>,
+    /// Interval in seconds to keep a failed job, overriding
+    /// keep_exited_for for failed jobs.
+    pub keep_failed_for: Option<u32>,
+    /// Amount of most recent exited jobs to keep regardless of age,
+    /// removing the rest regardless of age.
+    pub keep_last: Option<usize>,
+    /// Grouping criteria for the keep_last policy.
+    pub keep_last_by: KeepLastBy,
+    /// Interval in seconds to keep a job that was never started.
+    pub keep_created_for: Option<u32>,
+}
+
+impl Retention {
+    /// Whether the retention settings require a cleaner at all.
+    pub fn is_enabled(&self) -> bool {
+        self.keep_exited_for.is_some()
+            || self.keep_failed_for.is_some()
+            || self.keep_last.is_some()
+            || self.keep_created_for.is_some()
+    }
+}
+
+/// Resources removed along with exited jobs.
+#[derive(Clone, Debug)]
+pub struct Cleanup {
+    /// Remove the anonymous volumes of removed jobs.
+    pub volumes: bool,
+    /// Remove the networks created for the namespace once they're no
+    /// longer in use.
+    pub networks: bool,
+    /// Maximum amount of jobs to inspect and remove in each cleaning
+    /// cycle.
+    pub batch_size: Option<usize>,
+    /// Only report the jobs that would be removed.
+    pub dry_run: bool,
+}
+
+/// A way of grouping exited jobs for the keep-last retention policy.
+#[derive(Clone, Debug, Default, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum KeepLastBy {
+    /// A single group for the whole namespace.
+    #[default]
+    Namespace,
+    /// A group for each creation path.
+    Path,
+    /// A group for each container image.
+    Image,
+}
+
+impl KeepLastBy {
+    /// Get the group key of an inspected job.
+    fn key(&self, container: &ContainerInspectResponse) -> Option<String> {
+        match self {
+            Self::Namespace => None,
+            Self::Path => container
+                .config
+                .as_ref()
+                .and_then(|config| config.labels.as_ref())
+                .and_then(|labels| labels.get(docker::PATH_LABEL_KEY))
+                .cloned(),
+            Self::Image => container
+                .config
+                .as_ref()
+                .and_then(|config| config.image.clone()),
+        }
+    }
+}
+
+/// A job selected for removal by the cleaner.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Removal {
+    /// The name of the job.
+    #[schema(min_length = 1, example = "job-id")]
+    pub id: String,
+    /// The retention policy that selected the job.
+    #[schema(
+        example = "exited 7200 seconds ago, longer than the retention interval of 3600 seconds"
+    )]
+    pub reason: String,
+    /// The inspected job, if it ever ran.
+    #[serde(skip)]
+    container: Option<ContainerInspectResponse>,
+    /// Whether the job is dead, in which case removal may fail and is
+    /// retried in later cycles.
+    #[serde(skip)]
+    dead: bool,
+}
+
+/// Check exited containers, and select them for removal if they're
+/// old enough according to maximum age, which depends on whether the
+/// job failed or not. If a keep-last policy is given, the most recent
+/// jobs of each group are kept and the rest selected, regardless of
+/// age. Service jobs are never selected. Jobs that were never started
+/// are selected as well, if they're old enough. If a batch size is
+/// given, at most that many jobs are inspected concurrently. Dead jobs
+/// are always selected. Every exited job is inspected, regardless of
+/// the batch size, and the cleaner's progress through batches is left
+/// as it is.
+pub async fn plan(
+    retention: &Retention,
+    cleanup: &Cleanup,
+    namespace: &str,
+) -> Result<Vec<Removal>> {
+    Ok(select(retention, cleanup, namespace, Inspection::All)
+        .await?
+        .0)
+}
+
+/// Select the jobs to remove as the plan function does, inspecting
+/// only a batch of the exited jobs if so requested. Return the
+/// selected jobs along with the last one inspected, if the batch
+/// didn't cover every exited job.
+async fn select(
+    retention: &Retention,
+    cleanup: &Cleanup,
+    namespace: &str,
+    inspection: Inspection<'_>,
+) -> Result<(Vec<Removal>, Option<Cursor>)> {
     // the /containers/prune API could be useful here if it did have a
     // filter for finished_at timestamps, but it doesn't (there's a
     // filter for created_at timestamps though, but that's not what
     // determines age here)
     // thus this fetch -> filter(old-enough) -> map(remove) scheme
-    let finished_at_threshold = Utc::now()
-        .checked_sub_signed(ChronoDuration::seconds(max_age.into()))
-        .ok_or_else(|| anyhow!("can't calculate exited age threshold"))?
-        .timestamp();
-    let containers: Vec<_> = join_all(
-        docker::get_exited(namespace)
+    if !retention.is_enabled() {
+        return Ok((Vec::new(), None));
+    }
+    let now = Utc::now().timestamp();
+    let exited_max_age = retention.keep_exited_for;
+    let failed_max_age = retention.keep_failed_for.or(retention.keep_exited_for);
+    // without a keep-last policy, only a batch of jobs is inspected,
+    // since inspection is needed only to determine their age
+    let inspection_limit = match (retention.keep_last, &inspection) {
+        (Some(_), _) => usize::MAX,
+        (None, _) if exited_max_age.is_none() && failed_max_age.is_none() => 0,
+        (None, Inspection::All) => usize::MAX,
+        (None, Inspection::Batch(_)) => cleanup.batch_size.unwrap_or(usize::MAX),
+    };
+    let mut candidates: Vec<(i64, String)> = docker::get_exited(namespace)
+        .await
+        .context("while fetching exited jobs")?
+        .into_iter()
+        .filter(|container| !docker::is_service(container))
+        .filter_map(|container| {
+            let name = container.names?.into_iter().next()?;
+            let name = name.strip_prefix('/').map(String::from).unwrap_or(name);
+            Some((container.created.unwrap_or_default(), name))
+        })
+        .sorted()
+        .collect();
+    let mut next = None;
+    if candidates.len() > inspection_limit {
+        // batches go through the jobs from oldest to newest, resuming
+        // after the last one inspected, since jobs still too young to
+        // be removed would otherwise fill every batch
+        let start = match inspection {
+            Inspection::Batch(Some(cursor)) => {
+                candidates.partition_point(|candidate| candidate <= cursor)
+            }
+            _ => 0,
+        };
+        candidates.rotate_left(start);
+        candidates.truncate(inspection_limit);
+        next = candidates.last().cloned();
+    }
+    let containers: Vec<_> = stream::iter(
+        candidates
+            .into_iter()
+            .map(|(_, name)| docker::inspect(name)),
+    )
+    .buffer_unordered(cleanup.batch_size.unwrap_or(usize::MAX))
+    .try_collect()
+    .await?;
+    let finished = containers.into_iter().filter_map(|container| {
+        let name = container.name.as_ref()?;
+        let name = name.strip_prefix('/').unwrap_or(name).to_string();
+        let state = container.state.as_ref()?;
+        let finished_at = DateTime::parse_from_rfc3339(state.finished_at.as_ref()?)
+            .ok()?
+            .timestamp();
+        let failed = state.exit_code != Some(0);
+        Some((name, container, finished_at, failed))
+    });
+    let mut removals: Vec<_> = if let Some(keep_last) = retention.keep_last {
+        // keep the most recent jobs of each group, and remove the rest
+        finished
+            .into_group_map_by(|(_, container, _, _)| retention.keep_last_by.key(container))
+            .into_iter()
+            .flat_map(|(key, group)| {
+                group
+                    .into_iter()
+                    .sorted_by_key(|(_, _, finished_at, _)| Reverse(*finished_at))
+                    .skip(keep_last)
+                    .map(move |(id, container, _, _)| Removal {
+                        id,
+                        reason: match &key {
+                            Some(key) => format!(
+                                "not among the {keep_last} most recently exited jobs of group {:?}",
+                                key
+                            ),
+                            None => {
+                                format!("not among the {keep_last} most recently exited jobs")
+                            }
+                        },
+                        container: Some(container),
+                        dead: false,
+                    })
+            })
+            .collect()
+    } else {
+        finished
+            .filter_map(|(id, container, finished_at, failed)| {
+                let max_age = if failed {
+                    failed_max_age
+                } else {
+                    exited_max_age
+                }?;
+                let age = now - finished_at;
+                (age > max_age.into()).then(|| Removal {
+                    id,
+                    reason: format!(
+                        "{} {age} seconds ago, longer than the retention interval of {max_age} seconds",
+                        if failed { "failed" } else { "exited" }
+                    ),
+                    container: Some(container),
+                    dead: false,
+                })
+            })
+            .collect()
+    };
+    if let Some(max_age) = retention.keep_created_for {
+        removals.extend(
+            docker::get_pending(namespace)
+                .await
+                .context("while fetching pending jobs")?
+                .into_iter()
+                .filter_map(|container| {
+                    let age = now - container.created?;
+                    let name = container.names?.into_iter().next()?;
+                    let id = name.strip_prefix('/').map(String::from).unwrap_or(name);
+                    (age > max_age.into()).then(|| Removal {
+                        id,
+                        reason: format!(
+                            "created {age} seconds ago and never started, longer than \
+                             the retention interval of {max_age} seconds"
+                        ),
+                        container: None,
+                        dead: false,
+                    })
+                }),
+        );
+    }
+    // dead jobs are always removed, since they're leftovers of daemon
+    // failures
+    removals.extend(
+        docker::get_dead(namespace)
             .await
-            .context("while fetching exited jobs")?
+            .context("while fetching dead jobs")?
             .into_iter()
             .filter_map(|container| {
-                container
-                    .names
-                    .and_then(|ns| ns.into_iter().next())
-                    .map(|name| {
-                        let name = name.strip_prefix('/').map(String::from).unwrap_or(name);
-                        docker::inspect(name)
-                    })
+                let name = container.names?.into_iter().next()?;
+                let id = name.strip_prefix('/').map(String::from).unwrap_or(name);
+                Some(Removal {
+                    id,
+                    reason: String::from("dead"),
+                    container: None,
+                    dead: true,
+                })
             }),
+    );
+    Ok((removals, next))
+}
+
+/// Remove the jobs selected by the plan function, archiving the ones
+/// that ran. Volumes and networks are removed along with jobs
+/// according to the cleanup settings. If a batch size is given, each
+/// cycle inspects the batch of exited jobs following the previous
+/// one, and removes at most that many jobs, with removals spread
+/// evenly across the cleaning interval. In dry-run mode, removals are
+/// only reported. Return the amount of jobs removed.
+#[tracing::instrument(skip_all)]
+async fn clean(
+    retention: &Retention,
+    cleanup: &Cleanup,
+    interval: Duration,
+    namespace: &str,
+) -> Result<usize> {
+    let cursor = CURSORS.lock().unwrap().get(namespace).cloned();
+    let (plan, next) = select(
+        retention,
+        cleanup,
+        namespace,
+        Inspection::Batch(cursor.as_ref()),
     )
-    .await
-    .into_iter()
-    .collect::<Result<_>>()?;
-    join_all(
-        containers
+    .await?;
+    if let Some(next) = next {
+        CURSORS.lock().unwrap().insert(namespace.to_string(), next);
+    }
+    if cleanup.dry_run {
+        for removal in plan {
+            info!("Would clean job {:?}: {}", removal.id, removal.reason);
+        }
+        return Ok(0);
+    }
+    let removals = plan.into_iter().map(|removal| async move {
+        if let Some(container) = &removal.container {
+            archive::save(&removal.id, namespace, container)
+                .await
+                .with_context(|| format!("while archiving job {:?}", removal.id))?;
+        }
+        info!("Cleaning job {:?}: {}", removal.id, removal.reason);
+        match docker::remove(&removal.id, cleanup.volumes).await {
+            Ok(()) => {
+                audit::record(audit::Entry {
+                    action: "job_removed",
+                    namespace: namespace.to_string(),
+                    job: Some(removal.id.clone()),
+                    detail: Some(removal.reason.clone()),
+                    ..Default::default()
+                });
+                Ok(true)
+            }
+            Err(e) if removal.dead => {
+                warn!(
+                    "Couldn't remove dead job {:?}, will retry: {:?}",
+                    removal.id, e
+                );
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    });
+    let mut removed = 0;
+    if let Some(batch_size) = cleanup.batch_size {
+        // spread removals evenly across the cleaning interval
+        let spacing = interval / u32::try_from(batch_size).unwrap_or(u32::MAX);
+        for (index, removal) in removals.take(batch_size).enumerate() {
+            if index > 0 {
+                time::sleep(spacing).await;
+            }
+            if removal.await? {
+                removed += 1;
+            }
+        }
+    } else {
+        for result in join_all(removals).await {
+            if result? {
+                removed += 1;
+            }
+        }
+    }
+    if cleanup.networks {
+        for network in docker::prune_networks(namespace)
+            .await
+            .context("while pruning networks")?
+        {
+            info!("Removed unused network {:?}", network);
+        }
+    }
+    if let Some(network) = docker::remove_unused_network(namespace)
+        .await
+        .context("while removing the namespace network")?
+    {
+        info!("Removed unused network {:?}", network);
+    }
+    Ok(removed)
+}
+
+/// A job a purge couldn't remove.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PurgeFailure {
+    /// The name of the job.
+    #[schema(min_length = 1, example = "job-id")]
+    pub id: String,
+    /// Why the job couldn't be removed.
+    #[schema(example = "while archiving job \"job-id\"")]
+    pub error: String,
+}
+
+/// Outcome of a purge.
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct Purge {
+    /// The removed jobs.
+    pub removed: Vec<Removal>,
+    /// The jobs that matched the criteria but couldn't be removed.
+    pub failed: Vec<PurgeFailure>,
+}
+
+/// Container states that can be purged.
+pub const PURGEABLE_STATES: &[&str] = &["created", "exited", "dead"];
+
+/// Get the age in seconds of an inspected job, measured from the
+/// moment it finished or, if it never ran, from the moment it was
+/// created.
+fn age(container: &ContainerInspectResponse, now: i64) -> Option<i64> {
+    container
+        .state
+        .as_ref()
+        .and_then(|state| state.finished_at.as_ref())
+        .and_then(|finished_at| DateTime::parse_from_rfc3339(finished_at).ok())
+        .filter(|finished_at| finished_at.timestamp() > 0)
+        .or_else(|| {
+            container
+                .created
+                .as_ref()
+                .and_then(|created| DateTime::parse_from_rfc3339(created).ok())
+        })
+        .map(|reference| now - reference.timestamp())
+}
+
+/// Immediately remove the jobs in the given states, optionally
+/// restricted to the ones older than the given age and with names
+/// matching the given pattern, and report the removed jobs along with
+/// the ones that couldn't be removed. Exited jobs are archived before
+/// removal. Service jobs are left alone unless included explicitly.
+pub async fn purge(
+    states: &[String],
+    older_than: Option<u32>,
+    pattern: Option<&Pattern>,
+    services: bool,
+    cleanup: &Cleanup,
+    namespace: &str,
+) -> Result<Purge> {
+    let now = Utc::now().timestamp();
+    let states: Vec<_> = states.iter().map(String::as_str).collect();
+    let inspections: Vec<_> = stream::iter(
+        docker::get_by_status(namespace, &states)
+            .await
+            .context("while fetching jobs to purge")?
             .into_iter()
+            .filter(|container| services || !docker::is_service(container))
             .filter_map(|container| {
-                container.state.clone().and_then(|state| {
-                    state.finished_at.and_then(|finished_at| {
-                        DateTime::parse_from_rfc3339(&finished_at)
-                            .ok()
-                            .map(|dt| (container, dt.timestamp()))
-                    })
-                })
+                let name = container.names?.into_iter().next()?;
+                Some(name.strip_prefix('/').map(String::from).unwrap_or(name))
             })
-            .filter(|(_, dt)| dt < &finished_at_threshold)
-            .filter_map(|(container, _)| {
-                container.name.map(|name| {
-                    let name = name.strip_prefix('/').map(String::from).unwrap_or(name);
-                    info!("Cleaning job {:?}", name);
-                    docker::remove(name)
-                })
+            .filter(|name| pattern.is_none_or(|pattern| pattern.matches(name)))
+            .map(|name| async move {
+                let inspection = docker::inspect(&name).await;
+                (name, inspection)
             }),
     )
-    .await
-    .into_iter()
-    .collect::<Result<_>>()?;
-    Ok(())
+    .buffer_unordered(cleanup.batch_size.unwrap_or(usize::MAX))
+    .collect()
+    .await;
+    let mut purge = Purge::default();
+    for (id, inspection) in inspections {
+        let container = match inspection {
+            Ok(container) => container,
+            Err(e) => {
+                warn!("Couldn't inspect job {:?} to purge: {:?}", id, e);
+                purge.failed.push(PurgeFailure {
+                    id,
+                    error: format!("{:#}", e),
+                });
+                continue;
+            }
+        };
+        let Some(age) = age(&container, now) else {
+            continue;
+        };
+        if older_than.is_some_and(|older_than| age <= older_than.into()) {
+            continue;
+        }
+        let state = container
+            .state
+            .as_ref()
+            .and_then(|state| state.status)
+            .map(|status| status.to_string())
+            .unwrap_or_default();
+        info!("Purging job {:?}", id);
+        let result = async {
+            if state == "exited" {
+                archive::save(&id, namespace, &container)
+                    .await
+                    .with_context(|| format!("while archiving job {:?}", id))?;
+            }
+            docker::remove(&id, cleanup.volumes).await
+        }
+        .await;
+        match result {
+            Ok(()) => purge.removed.push(Removal {
+                id,
+                reason: format!("purged while {state}, {age} seconds old"),
+                container: None,
+                dead: false,
+            }),
+            Err(e) => {
+                warn!("Couldn't purge job {:?}: {:?}", id, e);
+                purge.failed.push(PurgeFailure {
+                    id,
+                    error: format!("{:#}", e),
+                });
+            }
+        }
+    }
+    Ok(purge)
 }
 
 /// Maximum amount of consecutive cleaning errors.
@@ -69,23 +554,57 @@ const MAX_ERRORS: u8 = 5;
 
 /// Loop the clean function endlessly.
 pub async fn cycle(
-    keep_exited_for: u32,
+    retention: Arc<Live<Retention>>,
+    cleanup: Cleanup,
     scheduling_interval: u16,
     namespace: String,
 ) -> Result<()> {
-    let mut interval = time::interval(Duration::from_secs(scheduling_interval.into()));
+    let period = Duration::from_secs(scheduling_interval.into());
+    let mut interval = time::interval(period);
     let mut errors: u8 = 0;
+    health_service::expect("cleaner", period);
     loop {
-        interval.tick().await;
-        let result = clean(keep_exited_for, &namespace).await;
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        }
+        if circuit_breaker::is_open() {
+            debug!("Skipping cleaning while the docker daemon is unreachable");
+            continue;
+        }
+        let cycle_start = Instant::now();
+        let result = clean(&retention.get(), &cleanup, period, &namespace).await;
         if let Err(ref e) = result {
             error!("Error while cleaning jobs: {:?}", e);
             errors += 1;
+            if errors > 1 {
+                error_report::capture(
+                    "upkeep",
+                    format!("{:?}", e),
+                    &[
+                        ("task", "cleaner"),
+                        ("namespace", &namespace),
+                        ("consecutive_errors", &errors.to_string()),
+                    ],
+                );
+            }
             if errors >= MAX_ERRORS {
-                return result.context("received 5 consecutive cleaning errors");
+                chat::upkeep_failed("cleaner", &namespace, errors, e);
             }
         } else {
             errors = 0;
         }
+        metrics_service::record_cycle(
+            "cleaner",
+            cycle_start.elapsed(),
+            result.as_ref().ok().copied(),
+            errors,
+        );
+        health_service::beat("cleaner");
+        if errors >= MAX_ERRORS {
+            return result
+                .map(|_| ())
+                .context("received 5 consecutive cleaning errors");
+        }
     }
 }
@@ -0,0 +1,173 @@
+//! Emits the lifecycle of jobs as CloudEvents, either to an HTTP sink
+//! or onto a NATS subject, so that event-driven systems downstream can
+//! react to jobs the same way they react to anything else. Each event
+//! has the job's id as subject, and the lifecycle step in its type.
+
+use crate::nats;
+use crate::notifier::{self, Transition};
+use crate::retry::Backoff;
+use anyhow::{Context, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures::{future::join, stream::TryStreamExt};
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::time::Duration;
+use tracing::{debug, error, warn};
+
+/// Prefix of the type of events, followed by the lifecycle step.
+pub const TYPE_PREFIX: &str = "io.github.kklingenberg.docker-job-dispatcher.job.";
+
+/// Media type of events in structured mode.
+const MEDIA_TYPE: &str = "application/cloudevents+json";
+
+/// Maximum number of attempts at emitting an event.
+const ATTEMPTS: u32 = 5;
+
+/// Where events are emitted.
+pub enum Sink {
+    /// An HTTP endpoint, POSTed to in structured mode.
+    Http(String),
+    /// A subject of a NATS server.
+    Nats {
+        url: String,
+        credentials: Option<PathBuf>,
+        subject: String,
+    },
+}
+
+/// A sink ready to take events.
+enum Emitter {
+    Http(reqwest::Client, String),
+    Nats(async_nats::Client, String),
+}
+
+impl Emitter {
+    /// Get ready to emit events to the sink.
+    async fn new(sink: &Sink) -> Result<Self> {
+        Ok(match sink {
+            Sink::Http(url) => Self::Http(reqwest::Client::new(), url.clone()),
+            Sink::Nats {
+                url,
+                credentials,
+                subject,
+            } => Self::Nats(
+                nats::connect(url, credentials.as_deref()).await?,
+                subject.clone(),
+            ),
+        })
+    }
+
+    /// Emit an event once.
+    async fn send(&self, body: String) -> Result<()> {
+        match self {
+            Self::Http(client, url) => client
+                .post(url)
+                .header(CONTENT_TYPE, MEDIA_TYPE)
+                .body(body)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .map(|_| ())
+                .context("while posting a CloudEvent"),
+            Self::Nats(client, subject) => {
+                let mut headers = async_nats::HeaderMap::new();
+                headers.insert("content-type", MEDIA_TYPE);
+                client
+                    .publish_with_headers(subject.clone(), headers, body.into())
+                    .await
+                    .context("while publishing a CloudEvent")
+            }
+        }
+    }
+
+    /// Emit an event, retrying with backoff. Events that can't be
+    /// emitted are logged in full, so that they may be recovered from
+    /// the logs.
+    async fn emit(&self, event: &Value) {
+        let body = event.to_string();
+        let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(30));
+        for attempt in 1..=ATTEMPTS {
+            match self.send(body.clone()).await {
+                Ok(()) => return,
+                Err(e) if attempt < ATTEMPTS => {
+                    debug!(
+                        "Retrying CloudEvent after error (attempt {}): {:?}",
+                        attempt, e
+                    );
+                    backoff.wait().await;
+                }
+                Err(e) => {
+                    error!(
+                        event = %body,
+                        "Couldn't emit CloudEvent after {} attempts: {:?}",
+                        ATTEMPTS,
+                        e
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Build the CloudEvent of a step in the lifecycle of a job, or of
+/// its progress.
+fn cloudevent(source: &str, namespace: &str, transition: &Transition) -> Value {
+    let time = transition
+        .time_nano
+        .map(DateTime::<Utc>::from_timestamp_nanos)
+        .unwrap_or_else(Utc::now);
+    let mut data = json!({
+        "namespace": namespace,
+        "path": transition.path,
+        "exit_code": transition.exit_code,
+    });
+    if let Some(progress) = &transition.progress {
+        data["progress"] = json!(progress);
+    }
+    json!({
+        "specversion": "1.0",
+        "id": cuid2::create_id(),
+        "source": source,
+        "type": format!("{}{}", TYPE_PREFIX, transition.event),
+        "subject": transition.id,
+        "time": time.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        "datacontenttype": "application/json",
+        "data": data,
+    })
+}
+
+/// Consume the docker events stream, and emit a CloudEvent to the sink
+/// for each step in the lifecycle of a job, with the given event
+/// source. Events are emitted in order, without holding up the events
+/// stream.
+pub async fn watch(namespace: String, source: String, sink: Arc<Sink>) -> Result<()> {
+    let emitter = Emitter::new(&sink).await?;
+    let (sender, mut receiver) = unbounded_channel::<Value>();
+    let follow = async move {
+        notifier::transitions(&namespace)?
+            .try_for_each(|transition| {
+                if sender
+                    .send(cloudevent(&source, &namespace, &transition))
+                    .is_err()
+                {
+                    warn!(
+                        "Couldn't queue the {} CloudEvent of job {:?}",
+                        transition.event, transition.id
+                    );
+                }
+                futures::future::ready(Ok(()))
+            })
+            .await
+    };
+    let emit = async {
+        while let Some(event) = receiver.recv().await {
+            emitter.emit(&event).await;
+        }
+    };
+    // pending events are emitted even if the events stream ends
+    let (result, ()) = join(follow, emit).await;
+    result
+}
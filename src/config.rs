@@ -0,0 +1,98 @@
+//! Reports the configuration in effect, with the secrets among the
+//! flags redacted, for operators to check what a running instance was
+//! actually given.
+
+use crate::app::Cli;
+use crate::{docker, reload::Reloader};
+use serde::Serializer;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fmt::Display;
+
+/// Replacement of redacted values.
+const REDACTED: &str = "[REDACTED]";
+
+/// Serialize a secret value as a placeholder, if given.
+pub fn secret<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(_) => serializer.serialize_some(REDACTED),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serialize a list of secret values as placeholders.
+pub fn secrets<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|_| REDACTED))
+}
+
+/// Serialize a list of name=value pairs keeping only the names.
+pub fn pairs<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|pair| match pair.split_once('=') {
+        Some((name, _)) => format!("{}={}", name, REDACTED),
+        None => REDACTED.to_string(),
+    }))
+}
+
+/// Remove the credentials and the query, which may hold tokens, from
+/// a URL. Values that don't parse as URLs are kept as they are.
+fn strip(value: &str) -> String {
+    match reqwest::Url::parse(value) {
+        Ok(mut url)
+            if !url.username().is_empty() || url.password().is_some() || url.query().is_some() =>
+        {
+            let _ = url.set_username("");
+            let _ = url.set_password(None);
+            if url.query().is_some() {
+                url.set_query(Some(REDACTED));
+            }
+            url.to_string()
+        }
+        _ => value.to_string(),
+    }
+}
+
+/// Serialize a URL without its credentials, if given.
+pub fn url<S: Serializer>(value: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    match value {
+        Some(value) => serializer.serialize_some(&strip(value)),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serialize a list of URLs without their credentials.
+pub fn urls<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|value| strip(value)))
+}
+
+/// Serialize a list of name=URL pairs without the URLs' credentials.
+pub fn routes<S: Serializer>(values: &[String], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(values.iter().map(|pair| match pair.split_once('=') {
+        Some((name, url)) => format!("{}={}", name, strip(url)),
+        None => strip(pair),
+    }))
+}
+
+/// Serialize a value through its textual representation.
+pub fn display<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+/// Build the report of the configuration in effect: the flags given
+/// at startup, the reloadable settings currently applied, a digest of
+/// the current filter and the docker hosts connected to.
+pub fn report(cli: &Cli, reloader: &Reloader) -> Value {
+    let source = reloader.filter_source();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "filter": {
+            "file": cli.from_file,
+            "sha256": format!("{:x}", Sha256::digest(source.as_bytes())),
+        },
+        "flags": cli,
+        "settings": reloader.current(),
+        "docker": docker::platforms()
+            .into_iter()
+            .map(|(host, platform)| json!({"host": strip(&host), "platform": platform}))
+            .collect::<Vec<_>>(),
+    })
+}
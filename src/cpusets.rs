@@ -0,0 +1,160 @@
+//! Assigns CPU sets from a configured pool to jobs as they're started.
+//!
+//! Docker doesn't allow updating the labels of an existing container,
+//! so assignments are kept in memory, keyed by job name. Assignments
+//! of running jobs are recovered from their configuration when the
+//! dispatcher restarts.
+
+use anyhow::{bail, Result};
+use once_cell::sync::OnceCell;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Static pool of CPU sets, the same for every docker host.
+static POOL: OnceCell<Vec<String>> = OnceCell::new();
+
+/// Counter used for round-robin assignment of shared CPU sets.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// Static assignment registry.
+static ASSIGNMENTS: OnceCell<Mutex<HashMap<String, Assignment>>> = OnceCell::new();
+
+/// Label value of jobs taking a CPU set on their own.
+pub const EXCLUSIVE: &str = "exclusive";
+
+/// Label value of jobs sharing a CPU set with others.
+pub const SHARED: &str = "shared";
+
+/// A CPU set given to a job.
+#[derive(Clone, Debug)]
+pub struct Assignment {
+    pub host: String,
+    pub cpuset: String,
+    pub exclusive: bool,
+    pub assigned_at: Instant,
+}
+
+/// Get the assignment registry.
+fn assignments() -> &'static Mutex<HashMap<String, Assignment>> {
+    ASSIGNMENTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Whether a CPU set is given in docker's format, e.g. 0-3,8.
+fn is_valid(cpuset: &str) -> bool {
+    !cpuset.is_empty()
+        && cpuset.split(',').all(|part| match part.split_once('-') {
+            Some((start, end)) => start
+                .parse::<u16>()
+                .ok()
+                .zip(end.parse::<u16>().ok())
+                .is_some_and(|(start, end)| start <= end),
+            None => part.parse::<u16>().is_ok(),
+        })
+}
+
+/// Set the pool of CPU sets assigned to jobs.
+pub fn init(pool: &[String]) -> Result<()> {
+    let pool: Vec<String> = pool
+        .iter()
+        .map(|cpuset| cpuset.trim().to_string())
+        .filter(|cpuset| !cpuset.is_empty())
+        .collect();
+    if let Some(cpuset) = pool.iter().find(|cpuset| !is_valid(cpuset)) {
+        bail!("invalid CPU set {:?}; expected e.g. 0-3,8", cpuset);
+    }
+    if pool.iter().collect::<HashSet<_>>().len() < pool.len() {
+        bail!("the CPU set pool has duplicate entries");
+    }
+    let _ = POOL.set(pool);
+    Ok(())
+}
+
+/// Get the pool of CPU sets, which is empty if disabled.
+fn pool() -> &'static [String] {
+    POOL.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Whether CPU sets are assigned to jobs.
+pub fn is_enabled() -> bool {
+    !pool().is_empty()
+}
+
+/// Get the amount of CPU sets in the pool.
+pub fn size() -> usize {
+    pool().len()
+}
+
+/// Get the amount of CPU sets held exclusively in the given host.
+pub fn held_exclusively(host: &str) -> usize {
+    assignments()
+        .lock()
+        .unwrap()
+        .values()
+        .filter(|a| a.exclusive && a.host == host)
+        .count()
+}
+
+/// Whether the assignment of a job is known.
+pub fn is_known(name: &str) -> bool {
+    assignments().lock().unwrap().contains_key(name)
+}
+
+/// Record the assignment of a job started earlier, found in its
+/// configuration.
+pub fn recover(name: &str, assignment: Assignment) {
+    if pool().contains(&assignment.cpuset) {
+        assignments()
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), assignment);
+    }
+}
+
+/// Assign a CPU set to a job about to start. Sets held exclusively
+/// are never assigned to other jobs. Exclusive jobs prefer sets no
+/// job is using, and shared jobs are spread round-robin. If no set is
+/// left, nothing is assigned.
+pub fn assign(name: &str, host: &str, exclusive: bool) -> Option<String> {
+    let mut assignments = assignments().lock().unwrap();
+    assignments.remove(name);
+    let in_use: Vec<&Assignment> = assignments.values().filter(|a| a.host == host).collect();
+    let available: Vec<&String> = pool()
+        .iter()
+        .filter(|cpuset| !in_use.iter().any(|a| a.exclusive && &a.cpuset == *cpuset))
+        .collect();
+    let cpuset = if exclusive {
+        available
+            .iter()
+            .min_by_key(|cpuset| in_use.iter().filter(|a| &a.cpuset == **cpuset).count())
+            .copied()
+    } else if available.is_empty() {
+        None
+    } else {
+        available
+            .get(NEXT.fetch_add(1, Ordering::Relaxed) % available.len())
+            .copied()
+    }?
+    .clone();
+    assignments.insert(
+        name.to_string(),
+        Assignment {
+            host: host.to_string(),
+            cpuset: cpuset.clone(),
+            exclusive,
+            assigned_at: Instant::now(),
+        },
+    );
+    Some(cpuset)
+}
+
+/// Release the CPU sets of jobs that are no longer active, given the
+/// active jobs as listed at some instant. Sets assigned after that
+/// are kept, since their jobs may have started after the listing.
+pub fn release_inactive(active: &HashSet<String>, listed_at: Instant) {
+    assignments()
+        .lock()
+        .unwrap()
+        .retain(|name, a| active.contains(name) || a.assigned_at >= listed_at);
+}
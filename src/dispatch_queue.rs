@@ -0,0 +1,76 @@
+//! Decouples job creations from the latency of the docker daemon.
+//!
+//! The docker calls of job creations are queued and made by a fixed
+//! pool of workers, so that bursts of requests wait in the queue
+//! instead of piling up on the daemon, and requests are refused right
+//! away once the queue is full.
+
+use crate::metrics_service;
+use anyhow::{anyhow, Context, Result};
+use futures::future::{join_all, BoxFuture};
+use once_cell::sync::OnceCell;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Mutex};
+
+/// A queued docker call.
+type Call = BoxFuture<'static, ()>;
+
+/// Static queue of docker calls, unset if calls aren't queued.
+static QUEUE: OnceCell<mpsc::Sender<Call>> = OnceCell::new();
+
+/// Record the amount of calls waiting in the queue.
+fn record_depth() {
+    if let Some(queue) = QUEUE.get() {
+        metrics_service::record_queue_depth(queue.max_capacity() - queue.capacity());
+    }
+}
+
+/// Make queued calls, one at a time, until the queue is closed.
+async fn work(calls: Arc<Mutex<mpsc::Receiver<Call>>>) {
+    loop {
+        let call = calls.lock().await.recv().await;
+        record_depth();
+        match call {
+            Some(call) => call.await,
+            None => break,
+        }
+    }
+}
+
+/// Start queueing docker calls up to the given capacity, returning the
+/// task running the given amount of workers.
+pub fn init(workers: u16, capacity: usize) -> impl Future<Output = Result<()>> {
+    let (sender, receiver) = mpsc::channel(capacity);
+    let _ = QUEUE.set(sender);
+    let receiver = Arc::new(Mutex::new(receiver));
+    async move {
+        for result in join_all((0..workers).map(|_| tokio::spawn(work(receiver.clone())))).await {
+            result.context("while making queued docker calls")?;
+        }
+        Ok(())
+    }
+}
+
+/// Make a docker call through the queue and wait for its result, or
+/// make it right away if calls aren't queued. Fails if the queue is
+/// full.
+pub async fn submit<T, F>(call: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: Future<Output = T> + Send + 'static,
+{
+    let Some(queue) = QUEUE.get() else {
+        return Ok(call.await);
+    };
+    let (sender, receiver) = oneshot::channel();
+    queue
+        .try_send(Box::pin(async move {
+            let _ = sender.send(call.await);
+        }))
+        .map_err(|_| anyhow!("Too many jobs are being created; try again later"))?;
+    record_depth();
+    receiver
+        .await
+        .context("The job creation was interrupted; try again later")
+}
@@ -1,23 +1,378 @@
 //! Implements the creation and retrieval of jobs.
 
 use crate::api_error::APIError;
+use crate::archive;
+use crate::artifacts;
+use crate::attempts::{self, StartFailure};
+use crate::audit;
+use crate::auth;
+use crate::circuit_breaker;
+use crate::cpusets;
+use crate::dispatch_queue;
 use crate::docker;
+use crate::exits;
+use crate::export;
+use crate::gpu;
+use crate::hold;
+use crate::job_store;
 use crate::jq;
+use crate::openapi;
+use crate::policy::{Policy, Violation};
+use crate::progress::{self, Progress};
+use crate::quiet_hours;
+use crate::redact;
+use crate::reload::Live;
+use crate::report::{self, JobGroup};
+use crate::results;
+use crate::secrets;
+use crate::shutdown;
+use crate::usage::{self, Usage};
 
-use actix_web::{get, routes, web, HttpResponse, Responder, Result};
-use bollard::container::Config;
+use actix_web::{
+    get,
+    http::header::{ContentType, AUTHORIZATION, CONTENT_DISPOSITION},
+    post, put, routes, web, HttpRequest, HttpResponse, Responder, Result,
+};
+use bollard::{
+    container::Config,
+    models::{ContainerSummary, HealthStatusEnum},
+};
+use clap::ValueEnum;
+use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use tracing::{debug, info};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::{debug, info, info_span, warn};
+use utoipa::{IntoParams, ToSchema};
 
 /// A representation of a job.
-#[derive(Serialize)]
-struct JobSummary {
+#[derive(Default, Serialize, ToSchema)]
+pub(crate) struct JobSummary {
+    #[schema(min_length = 1, example = "job-id")]
     id: String,
+    /// ID of the job's container, unless it's been removed and wasn't
+    /// recorded
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "4f66ad9a0b2b4a61d5b1e33b0e5f3a9c8f5f8b1c2a6e7d9f0b3c4d5e6f7a8b9c")]
+    container_id: Option<String>,
+    /// Image the job's container was created from, unless it's been
+    /// removed and wasn't recorded
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "debian:stable-slim")]
+    image: Option<String>,
+    /// Namespace of the dispatcher the job belongs to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "default")]
+    namespace: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1702598995)]
     created: Option<i64>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(min_length = 1, example = "Exited(0)")]
+    status: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    start_failure: Option<StartFailure>,
+    /// One of starting, healthy and unhealthy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "healthy")]
+    health: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sidecars: Option<Vec<SidecarSummary>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<JobMetadata>,
+    /// Only reported for jobs removed automatically
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1702598996)]
+    started: Option<i64>,
+    /// Only reported for jobs removed automatically
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 1702599012)]
+    finished: Option<i64>,
+    /// Only reported for jobs removed automatically
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = 0)]
+    exit_code: Option<i64>,
+    /// Structured result of the job, if captured
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = json!({"rows": 1024}))]
+    result: Option<Value>,
+    /// Resources the job consumed, as of its latest sample, if
+    /// accounted for
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<Usage>,
+    /// Latest progress reported by the job, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    progress: Option<Progress>,
+    /// Whether the job was created on hold and awaits its release
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = false)]
+    held: Option<bool>,
+    /// Only reported when creating jobs: whether a job with the
+    /// generated name already existed, so none was created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = false)]
+    deduplicated: Option<bool>,
+}
+
+impl JobSummary {
+    /// Summarize a job that no longer exists from its record.
+    fn from_record(id: String, record: job_store::Record) -> Self {
+        let status = match (record.state, record.exit_code) {
+            (job_store::State::Exited, Some(code)) => format!("Exited ({})", code),
+            (job_store::State::FailedToStart, _) => String::from("Failed to start"),
+            (job_store::State::Running, _) => String::from("Up"),
+            _ => String::from("Created"),
+        };
+        let reported = progress::get(&id);
+        Self {
+            id,
+            container_id: record.container_id,
+            image: record.image,
+            namespace: Some(record.namespace),
+            created: record.created,
+            status: Some(match record.removed {
+                Some(_) => format!("{}, removed", status),
+                None => status,
+            }),
+            start_failure: record.last_error.map(|last_error| attempts::StartFailure {
+                attempts: record.attempts,
+                last_error,
+            }),
+            health: None,
+            sidecars: None,
+            metadata: Some(JobMetadata {
+                path: record.path,
+                submitter: record.submitter,
+                api_key: record.api_key,
+                client_cert: record.client_cert,
+                request_id: record.request_id,
+                dispatcher_version: None,
+                artifacts: record.artifacts,
+            }),
+            started: record.started,
+            finished: record.finished,
+            exit_code: record.exit_code,
+            result: record.result,
+            usage: record.usage,
+            progress: reported,
+            held: None,
+            deduplicated: None,
+        }
+    }
+}
+
+/// A representation of a job's sidecar.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct SidecarSummary {
+    #[schema(min_length = 1, example = "job-id-proxy")]
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(min_length = 1, example = "Up 5 seconds")]
+    status: Option<String>,
+}
+
+/// Metadata of the request a job was created by.
+#[derive(Serialize, ToSchema)]
+pub(crate) struct JobMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "/job/report")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "alice")]
+    submitter: Option<String>,
+    /// Name of the API key the job was submitted with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "ci")]
+    api_key: Option<String>,
+    /// Identity of the client certificate the job was submitted with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "billing-service")]
+    client_cert: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "f3kz1e0q2ahmv1d9c8wo7r5n")]
+    request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "0.4.1")]
+    dispatcher_version: Option<String>,
+    /// URL the job's artifacts were uploaded to, once it exited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "s3://job-archive/default/job-id/artifacts.tar")]
+    artifacts: Option<String>,
+}
+
+impl JobMetadata {
+    /// Read the metadata of a job from its labels.
+    fn from_labels(job: &ContainerSummary) -> Self {
+        let label = |key| docker::label(job, key).map(String::from);
+        Self {
+            path: label(docker::PATH_LABEL_KEY),
+            submitter: label(docker::SUBMITTER_LABEL_KEY),
+            api_key: label(docker::API_KEY_LABEL_KEY),
+            client_cert: label(docker::CLIENT_CERT_LABEL_KEY),
+            request_id: label(docker::REQUEST_ID_LABEL_KEY),
+            dispatcher_version: label(docker::VERSION_LABEL_KEY),
+            artifacts: None,
+        }
+    }
+}
+
+/// Whether jobs whose manifest lacks a name are named after a hash of
+/// their manifest.
+static CONTENT_ADDRESSED_NAMES: OnceCell<bool> = OnceCell::new();
+
+/// Prefix of the names derived from the hash of a manifest.
+const CONTENT_ADDRESSED_PREFIX: &str = "job-";
+
+/// Amount of hexadecimal digits of the hash kept in derived names.
+const CONTENT_ADDRESSED_DIGITS: usize = 32;
+
+/// What's done when a job is submitted with the name of an existing
+/// one.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum, ToSchema,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnDuplicate {
+    /// Respond with the existing job
+    #[default]
+    Existing,
+    /// Refuse the submission
+    Conflict,
+    /// Remove the existing job, if it's finished, and create the new
+    /// one
+    Replace,
+}
+
+/// What's done by default when a job is submitted with the name of an
+/// existing one.
+static ON_DUPLICATE: OnceCell<OnDuplicate> = OnceCell::new();
+
+/// Initialize the global naming settings.
+pub fn init(content_addressed_names: bool, on_duplicate: OnDuplicate) {
+    let _ = CONTENT_ADDRESSED_NAMES.set(content_addressed_names);
+    let _ = ON_DUPLICATE.set(on_duplicate);
+}
+
+/// Maximum amount of jobs kept in the namespace, if limited.
+static MAX_TOTAL: OnceCell<u32> = OnceCell::new();
+
+/// Initialize the limit of jobs kept in the namespace.
+pub fn init_max_total(max_total: u32) {
+    let _ = MAX_TOTAL.set(max_total);
+}
+
+/// Refuse to create a job if the namespace is already holding as many
+/// jobs as allowed, unless the job already exists, since then nothing
+/// would be created.
+async fn check_total(name: &str, namespace: &str) -> Result<(), APIError> {
+    let Some(max_total) = MAX_TOTAL.get() else {
+        return Ok(());
+    };
+    // jobs are counted as they are, not as they were cached
+    docker::invalidate(namespace);
+    let total = docker::get_by_status(
+        namespace,
+        &[
+            "created",
+            "restarting",
+            "running",
+            "paused",
+            "exited",
+            "dead",
+        ],
+    )
+    .await
+    .map_err(APIError::bad_gateway)?
+    .len();
+    if total >= *max_total as usize
+        && docker::get(name, namespace)
+            .await
+            .map_err(APIError::bad_gateway)?
+            .is_none()
+    {
+        Err(APIError::too_many_requests(format!(
+            "The namespace holds {} jobs, the most allowed; try again once some are removed",
+            total
+        )))?;
+    }
+    Ok(())
+}
+
+/// Remove the finished job with the given name, if any, to make way
+/// for its replacement, reporting whether there was one. Jobs that
+/// haven't finished can't be replaced.
+async fn make_way(name: &str, namespace: &str) -> Result<bool, APIError> {
+    docker::invalidate(namespace);
+    let Some(existing) = docker::get(name, namespace)
+        .await
+        .map_err(APIError::bad_gateway)?
+    else {
+        return Ok(false);
+    };
+    match existing.state.as_deref() {
+        Some("exited") => {
+            let inspection = docker::inspect(name).await.map_err(APIError::bad_gateway)?;
+            archive::save(name, namespace, &inspection)
+                .await
+                .map_err(APIError::internal_error)?;
+        }
+        Some("dead") => (),
+        _ => Err(APIError::conflict(format!(
+            "Job {:?} already exists and hasn't finished, so it can't be replaced",
+            name
+        )))?,
+    }
+    docker::remove(name, true)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    info!("Removed job {:?} to replace it", name);
+    Ok(true)
+}
+
+/// Name a manifest lacking one after a hash of its contents, so that
+/// identical manifests get the same name. Object keys are serialized
+/// in order, so the hash doesn't depend on the filter's field order.
+fn name_by_content(manifest: &mut Value) {
+    let Value::Object(fields) = manifest else {
+        return;
+    };
+    match fields.get("Name") {
+        None | Some(Value::Null) => (),
+        Some(Value::String(name)) if name.is_empty() => (),
+        Some(_) => return,
+    }
+    fields.remove("Name");
+    let Ok(contents) = serde_json::to_vec(&fields) else {
+        return;
+    };
+    let hash = format!("{:x}", Sha256::digest(contents));
+    let name = format!(
+        "{}{}",
+        CONTENT_ADDRESSED_PREFIX,
+        &hash[..CONTENT_ADDRESSED_DIGITS]
+    );
+    fields.insert(String::from("Name"), Value::from(name));
+}
+
+/// Where the metadata of job creation requests is read from.
+pub struct Provenance {
+    /// Header holding the identity of the submitter, as set by an
+    /// authenticating proxy in front of the dispatcher.
+    pub submitter_header: String,
+}
+
+/// Get the status to report for a job, considering jobs that ran out
+/// of start attempts as failed.
+fn job_status(
     status: Option<String>,
+    start_failure: &Option<attempts::StartFailure>,
+) -> Option<String> {
+    match start_failure {
+        Some(failure) if failure.exhausted() => Some(String::from("Failed to start")),
+        _ => status,
+    }
 }
 
 /// Additional fields from the job manifest.
@@ -26,6 +381,41 @@ struct JobSummary {
 struct CreateContainerOptions {
     name: String,
     platform: Option<String>,
+    #[serde(rename = "X-MutexGroup")]
+    mutex_group: Option<String>,
+    #[serde(rename = "X-Slots")]
+    slots: Option<u16>,
+    #[serde(rename = "X-Kind", default)]
+    kind: JobKind,
+    #[serde(rename = "X-Secrets", default)]
+    secrets: Vec<String>,
+    #[serde(rename = "X-Build")]
+    build: Option<docker::Build>,
+    #[serde(rename = "X-Sidecars", default)]
+    sidecars: Vec<docker::Sidecar>,
+    #[serde(rename = "X-CpuSet")]
+    cpuset: Option<CpuSetMode>,
+}
+
+/// The ways jobs can take a CPU set from the pool.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CpuSetMode {
+    /// A set possibly shared with other jobs.
+    Shared,
+    /// A set used by no other job.
+    Exclusive,
+}
+
+/// The kinds of jobs that can be dispatched.
+#[derive(Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum JobKind {
+    /// A job that runs to completion.
+    #[default]
+    Batch,
+    /// A long-running job that's restarted whenever it exits.
+    Service,
 }
 
 /// A container for the create_job path information.
@@ -34,70 +424,1315 @@ struct PathInfo {
     path: Option<String>,
 }
 
-/// Create a job by converting the request body to a job manifest.
+/// Refuse requests made with an API key scoped to another namespace.
+pub(crate) fn check_namespace(request: &HttpRequest, namespace: &str) -> Result<(), APIError> {
+    match auth::scope(request) {
+        Some(scope) if scope.namespace != namespace => Err(APIError::forbidden(format!(
+            "The API key is restricted to namespace {:?}",
+            scope.namespace
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a job created through the given path may be seen with the
+/// API key of a request, if it's scoped.
+fn is_visible(request: &HttpRequest, path: Option<&str>) -> bool {
+    auth::scope(request).is_none_or(|scope| scope.covers(path))
+}
+
+/// Block the dangerous fields of a container configuration, apply the
+/// resource limits of the policy to it, and check it against the
+/// policy. Violating fields are reported with the given prefix, and
+/// rejections and stripped fields are audited.
+fn enforce(
+    policy: &Policy,
+    mut config: Config<String>,
+    prefix: &str,
+    rejection: impl Fn() -> audit::Entry,
+) -> Result<Config<String>> {
+    let reject = |config: &Config<String>, violation: Violation| {
+        warn!("Job manifest rejected by policy at {}{}", prefix, violation);
+        audit::record(audit::Entry {
+            action: "policy_rejection",
+            manifest_hash: audit::manifest_hash(config),
+            detail: Some(format!("{}{}", prefix, violation)),
+            ..rejection()
+        });
+        APIError::forbidden(format!(
+            "Generated manifest violates policy at {}{}",
+            prefix, violation
+        ))
+    };
+    let stripped = policy
+        .harden(&mut config)
+        .map_err(|violation| reject(&config, violation))?;
+    if !stripped.is_empty() {
+        let detail = stripped
+            .iter()
+            .map(|violation| format!("{}{}", prefix, violation))
+            .collect::<Vec<_>>()
+            .join("; ");
+        warn!("Dangerous fields stripped from job manifest: {}", detail);
+        audit::record(audit::Entry {
+            action: "fields_stripped",
+            manifest_hash: audit::manifest_hash(&config),
+            detail: Some(detail),
+            ..rejection()
+        });
+    }
+    let config = policy.apply_limits(config);
+    policy
+        .check(&config)
+        .map_err(|violation| reject(&config, violation))?;
+    Ok(config)
+}
+
+/// A job creation request, received through the API or from a
+/// message source.
+pub(crate) struct Submission {
+    /// The request body or message the filter runs on.
+    pub input: Value,
+    /// The path the job was submitted through, given to the filter as
+    /// `$PATH`.
+    pub path: String,
+    /// The claims of the request's token, given to the filter as
+    /// `$CLAIMS`.
+    pub claims: Value,
+    /// The attributes of the message, given to the filter as
+    /// `$MESSAGE`.
+    pub message: Value,
+    /// The subject the completion of the job is published to.
+    pub reply_subject: Option<String>,
+    /// Who submitted the job.
+    pub actor: audit::Actor,
+    /// Whether the job is created on hold, to be started only once
+    /// released.
+    pub hold: bool,
+    /// Labels given by the client, added to the job's container under
+    /// a reserved prefix.
+    pub labels: BTreeMap<String, String>,
+    /// What's done if a job with the same name exists, overriding the
+    /// deployment's default.
+    pub on_duplicate: Option<OnDuplicate>,
+}
+
+/// What's needed to turn submissions into jobs.
+#[derive(Clone)]
+pub(crate) struct Intake {
+    pub filter: Arc<Live<jq::Filter>>,
+    pub policy: Arc<Live<Policy>>,
+    pub can_start: bool,
+    pub namespace: String,
+}
+
+/// A job manifest produced by the filter, checked against the policy.
+pub(crate) struct Rendered {
+    options: CreateContainerOptions,
+    manifest: Config<String>,
+    sidecars: Vec<docker::Sidecar>,
+    /// The manifest as produced by the filter, with the changes made
+    /// by the policy.
+    pub json: Value,
+}
+
+/// Replace the fields of a container configuration within a manifest
+/// with the ones it was changed to.
+fn overlay(json: &mut Value, before: &Config<String>, after: &Config<String>) {
+    let (Value::Object(fields), Ok(Value::Object(before)), Ok(Value::Object(after))) = (
+        json,
+        serde_json::to_value(before),
+        serde_json::to_value(after),
+    ) else {
+        return;
+    };
+    for key in before.keys() {
+        fields.remove(key);
+    }
+    fields.extend(after);
+}
+
+/// Convert a submission to a job manifest through the filter, and
+/// check it against the policy. Checks depending on the state of the
+/// dispatcher (e.g. registered secrets) are left to the submission.
+pub(crate) fn render(
+    filter: &jq::Filter,
+    policy: &Policy,
+    namespace: &str,
+    submission: &Submission,
+) -> Result<Rendered> {
+    let mut raw_manifest = info_span!("filter")
+        .in_scope(|| {
+            jq::first_result(
+                filter,
+                submission.input.clone(),
+                &submission.path,
+                submission.claims.clone(),
+                submission.message.clone(),
+            )
+        })
+        .ok_or_else(|| APIError::bad_request("Filter didn't produce results"))?
+        .map_err(|e| APIError::bad_request(redact::text(&format!("Filter failed: {:?}", e))))?;
+    if CONTENT_ADDRESSED_NAMES.get().copied().unwrap_or(false) {
+        name_by_content(&mut raw_manifest);
+    }
+    debug!("Job raw manifest: {}", redact::json(&raw_manifest));
+    let invalid = |e: serde_json::Error| {
+        APIError::bad_request(redact::text(&format!(
+            "Generated manifest is invalid: {:?}",
+            e
+        )))
+    };
+    let mut options: CreateContainerOptions =
+        serde_json::from_value(raw_manifest.clone()).map_err(invalid)?;
+    let mut manifest: Config<String> =
+        serde_json::from_value(raw_manifest.clone()).map_err(invalid)?;
+    if docker::is_reserved_name(&options.name) {
+        Err(APIError::bad_request(format!(
+            "Generated manifest is invalid: job names can't hold {:?}",
+            docker::SIDECAR_INFIX
+        )))?
+    }
+    if let Some(platform) = &options.platform {
+        docker::check_platform(platform)
+            .map_err(|e| APIError::bad_request(format!("Generated manifest is invalid: {}", e)))?;
+    }
+    let rejection = || audit::Entry {
+        namespace: namespace.to_string(),
+        job: Some(options.name.clone()),
+        actor: submission.actor.clone(),
+        ..Default::default()
+    };
+    if options.build.is_some() {
+        if policy.restricts_images() {
+            warn!("Job manifest rejected by policy at X-Build: images are restricted");
+            audit::record(audit::Entry {
+                action: "policy_rejection",
+                manifest_hash: audit::manifest_hash(&manifest),
+                detail: Some(String::from("X-Build: images are restricted")),
+                ..rejection()
+            });
+            Err(APIError::forbidden(
+                "Generated manifest violates policy at X-Build: images are restricted, so they \
+                 can't be built",
+            ))?
+        }
+        manifest.image = Some(docker::build_tag(&options.name));
+    }
+    let mut json = raw_manifest;
+    let before = manifest.clone();
+    manifest = docker::with_default_env(enforce(policy, manifest, "", rejection)?);
+    overlay(&mut json, &before, &manifest);
+    let mut sidecars = Vec::new();
+    for (index, sidecar) in std::mem::take(&mut options.sidecars)
+        .into_iter()
+        .enumerate()
+    {
+        let valid_name = !sidecar.name.is_empty()
+            && sidecar
+                .name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-');
+        if !valid_name
+            || sidecars
+                .iter()
+                .any(|s: &docker::Sidecar| s.name == sidecar.name)
+        {
+            Err(APIError::bad_request(format!(
+                "Generated manifest is invalid: X-Sidecars[{}].Name must be a unique \
+                 alphanumeric name",
+                index
+            )))?
+        }
+        let before = sidecar.config.clone();
+        let sidecar = docker::Sidecar {
+            config: docker::with_default_env(enforce(
+                policy,
+                sidecar.config,
+                &format!("X-Sidecars[{}].", index),
+                rejection,
+            )?),
+            ..sidecar
+        };
+        if let Some(json) = json.pointer_mut(&format!("/X-Sidecars/{}", index)) {
+            overlay(json, &before, &sidecar.config);
+        }
+        sidecars.push(sidecar);
+    }
+    Ok(Rendered {
+        options,
+        manifest,
+        sidecars,
+        json,
+    })
+}
+
+/// Why a submission didn't make a job.
+#[derive(Debug)]
+pub(crate) enum SubmitError {
+    /// The submission itself is at fault (e.g. the filter or the
+    /// policy rejected it), so it would be rejected again if retried.
+    Rejected(actix_web::Error),
+    /// The job couldn't be created for now (e.g. the docker daemon is
+    /// unreachable, or the namespace holds as many jobs as allowed),
+    /// but may be if the submission is retried later.
+    Failed(actix_web::Error),
+}
+
+impl SubmitError {
+    fn rejected(e: impl Into<actix_web::Error>) -> Self {
+        Self::Rejected(e.into())
+    }
+
+    fn failed(e: impl Into<actix_web::Error>) -> Self {
+        Self::Failed(e.into())
+    }
+
+    /// Whether the submission would be rejected again if retried, so
+    /// that message intakes may drop it instead.
+    pub fn is_permanent(&self) -> bool {
+        matches!(self, Self::Rejected(_))
+    }
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected(e) | Self::Failed(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<SubmitError> for actix_web::Error {
+    fn from(e: SubmitError) -> Self {
+        match e {
+            SubmitError::Rejected(e) | SubmitError::Failed(e) => e,
+        }
+    }
+}
+
+/// Whether docker refused to create a job because of its manifest, as
+/// opposed to failing to create it for now.
+fn is_manifest_rejection(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<bollard::errors::Error>(),
+            Some(bollard::errors::Error::DockerResponseServerError {
+                status_code: 400,
+                ..
+            })
+        )
+    })
+}
+
+/// Convert a submission to a job manifest, check it against the
+/// policy and create the job, reporting whether it was created (as
+/// opposed to pre-existing) along with its summary.
+pub(crate) async fn submit(
+    intake: &Intake,
+    submission: Submission,
+) -> Result<(bool, JobSummary), SubmitError> {
+    let namespace = &intake.namespace;
+    if shutdown::is_stopping() {
+        Err(SubmitError::failed(APIError::service_unavailable(
+            "Shutting down; no new jobs are accepted",
+        )))?;
+    }
+    if circuit_breaker::is_open() {
+        Err(SubmitError::failed(
+            APIError::service_unavailable("The docker daemon is unreachable")
+                .retry_after(circuit_breaker::PROBE_PERIOD.as_secs()),
+        ))?;
+    }
+    // both are used as of the submission, even if reloaded meanwhile
+    let (filter, policy) = (intake.filter.get(), intake.policy.get());
+    debug!(
+        "Job creation request at {:?}: {}",
+        submission.path,
+        redact::json(&submission.input)
+    );
+    let Rendered {
+        options,
+        mut manifest,
+        sidecars,
+        ..
+    } = render(&filter, &policy, namespace, &submission).map_err(SubmitError::Rejected)?;
+    let Submission {
+        path,
+        reply_subject,
+        actor,
+        hold,
+        labels,
+        on_duplicate,
+        ..
+    } = submission;
+    let on_duplicate =
+        on_duplicate.unwrap_or_else(|| ON_DUPLICATE.get().copied().unwrap_or_default());
+    let metadata = JobMetadata {
+        path: Some(path.clone()),
+        submitter: actor.submitter.clone(),
+        api_key: actor.api_key.clone(),
+        client_cert: actor.client_cert.clone(),
+        request_id: Some(actor.request_id.clone().unwrap_or_else(cuid2::create_id)),
+        dispatcher_version: Some(String::from(env!("CARGO_PKG_VERSION"))),
+        artifacts: None,
+    };
+    for (key, value) in [
+        (docker::PATH_LABEL_KEY, &metadata.path),
+        (docker::SUBMITTER_LABEL_KEY, &metadata.submitter),
+        (docker::API_KEY_LABEL_KEY, &metadata.api_key),
+        (docker::CLIENT_CERT_LABEL_KEY, &metadata.client_cert),
+        (docker::REQUEST_ID_LABEL_KEY, &metadata.request_id),
+        (docker::VERSION_LABEL_KEY, &metadata.dispatcher_version),
+    ] {
+        if let Some(value) = value {
+            manifest = docker::insert_label(manifest, key, value);
+        }
+    }
+    for (key, value) in &labels {
+        manifest = docker::insert_label(
+            manifest,
+            &format!("{}{}", docker::CLIENT_LABEL_PREFIX, key),
+            value,
+        );
+    }
+    if manifest
+        .host_config
+        .as_ref()
+        .and_then(|host_config| host_config.auto_remove)
+        .unwrap_or(false)
+    {
+        manifest = docker::insert_label(manifest, docker::AUTO_REMOVE_LABEL_KEY, "true");
+    }
+    if let Some(subject) = &reply_subject {
+        manifest = docker::insert_label(manifest, docker::REPLY_SUBJECT_LABEL_KEY, subject);
+    }
+    if hold {
+        manifest = docker::insert_label(manifest, docker::HELD_LABEL_KEY, "true");
+    }
+    if let Some(group) = &options.mutex_group {
+        manifest = docker::insert_label(manifest, docker::MUTEX_GROUP_LABEL_KEY, group);
+    }
+    if options.kind == JobKind::Service {
+        manifest = docker::as_service(manifest);
+    }
+    if let Some(secret) = options.secrets.iter().find(|s| !secrets::is_registered(s)) {
+        Err(SubmitError::rejected(APIError::bad_request(format!(
+            "Generated manifest is invalid: secret {:?} is not registered",
+            secret
+        ))))?
+    }
+    if !options.secrets.is_empty() {
+        manifest = docker::insert_label(
+            manifest,
+            docker::SECRETS_LABEL_KEY,
+            &options.secrets.join(","),
+        );
+    }
+    let gpus = gpu::requested(manifest.host_config.as_ref()).map_err(|e| {
+        SubmitError::rejected(APIError::bad_request(format!(
+            "Generated manifest is invalid: {}",
+            e
+        )))
+    })?;
+    if gpus > 0 {
+        manifest = docker::insert_label(manifest, docker::GPUS_LABEL_KEY, &gpus.to_string());
+    }
+    let custom_cpuset = manifest
+        .host_config
+        .as_ref()
+        .and_then(|host_config| host_config.cpuset_cpus.as_deref())
+        .is_some_and(|cpuset| !cpuset.is_empty());
+    match (&options.cpuset, cpusets::is_enabled() && !custom_cpuset) {
+        (Some(_), false) => Err(SubmitError::rejected(APIError::bad_request(
+            "Generated manifest is invalid: X-CpuSet requires a CPU set pool, \
+             and can't be given along with HostConfig.CpusetCpus",
+        )))?,
+        (Some(CpuSetMode::Exclusive), true) => {
+            manifest = docker::insert_label(manifest, docker::CPUSET_LABEL_KEY, cpusets::EXCLUSIVE);
+        }
+        (_, true) => {
+            manifest = docker::insert_label(manifest, docker::CPUSET_LABEL_KEY, cpusets::SHARED);
+        }
+        (None, false) => (),
+    }
+    match options.slots {
+        Some(0) => Err(SubmitError::rejected(APIError::bad_request(
+            "Generated manifest is invalid: X-Slots must be a positive integer",
+        )))?,
+        Some(slots) => {
+            manifest = docker::insert_label(manifest, docker::SLOTS_LABEL_KEY, &slots.to_string());
+        }
+        None => (),
+    }
+    debug!(
+        "Job manifest of {:?}: {}",
+        options.name,
+        redact::json(&manifest)
+    );
+    let manifest_hash = audit::manifest_hash(&manifest);
+    let image = manifest.image.clone();
+    // jobs that haven't finished may be replaced once they do
+    if on_duplicate == OnDuplicate::Replace
+        && make_way(&options.name, namespace)
+            .await
+            .map_err(SubmitError::failed)?
+    {
+        audit::record(audit::Entry {
+            action: "job_replaced",
+            namespace: namespace.to_string(),
+            job: Some(options.name.clone()),
+            actor: actor.clone(),
+            ..Default::default()
+        });
+    }
+    // the namespace may make room for the job later
+    check_total(&options.name, namespace)
+        .await
+        .map_err(SubmitError::failed)?;
+    let manifest = progress::with_env(manifest, &options.name, namespace);
+    let (name, platform, build) = (
+        options.name.clone(),
+        options.platform.clone(),
+        options.build,
+    );
+    let job_namespace = namespace.to_string();
+    let create = async move {
+        docker::create(name, platform, build, sidecars, manifest, &job_namespace).await
+    };
+    let job_opt = dispatch_queue::submit(create)
+        .await
+        .map_err(|e| SubmitError::failed(APIError::service_unavailable(e).retry_after(1)))?
+        .map_err(|e| {
+            if is_manifest_rejection(&e) {
+                SubmitError::rejected(APIError::bad_request(format!(
+                    "Server rejected job manifest: {:?}",
+                    e
+                )))
+            } else {
+                SubmitError::failed(APIError::bad_gateway(format!(
+                    "Couldn't create the job: {:?}",
+                    e
+                )))
+            }
+        })?;
+    if let Some(container_id) = job_opt {
+        info!("Created job with ID {:?}", options.name);
+        job_store::record(
+            &options.name,
+            job_store::Record {
+                namespace: namespace.to_string(),
+                manifest_hash: manifest_hash.clone(),
+                path: metadata.path.clone(),
+                submitter: metadata.submitter.clone(),
+                api_key: metadata.api_key.clone(),
+                client_cert: metadata.client_cert.clone(),
+                request_id: metadata.request_id.clone(),
+                container_id: Some(container_id.clone()),
+                image: image.clone(),
+                labels: labels.clone(),
+                ..Default::default()
+            },
+        );
+        audit::record(audit::Entry {
+            action: "job_created",
+            namespace: namespace.to_string(),
+            job: Some(options.name.clone()),
+            manifest_hash,
+            actor,
+            ..Default::default()
+        });
+        // service jobs, jobs in a mutual exclusion group, jobs taking
+        // limited GPUs and jobs taking exclusive CPU sets are always
+        // left to the scheduler, as are jobs created during quiet
+        // hours, and held jobs to their release
+        if intake.can_start
+            && !hold
+            && !quiet_hours::is_quiet()
+            && options.kind == JobKind::Batch
+            && options.mutex_group.is_none()
+            && (gpus == 0 || gpu::limit().is_none())
+            && options.cpuset != Some(CpuSetMode::Exclusive)
+        {
+            start_now(&options.name).await;
+        }
+        let start_failure = attempts::get(&options.name);
+        Ok((
+            true,
+            JobSummary {
+                id: options.name,
+                container_id: Some(container_id),
+                image,
+                namespace: Some(namespace.to_string()),
+                status: job_status(None, &start_failure),
+                start_failure,
+                metadata: Some(metadata),
+                held: Some(hold),
+                deduplicated: Some(false),
+                ..Default::default()
+            },
+        ))
+    } else if on_duplicate != OnDuplicate::Existing {
+        Err(SubmitError::rejected(APIError::conflict(format!(
+            "Job {:?} already exists",
+            options.name
+        ))))?
+    } else {
+        info!("Pre-existing job with ID {:?}", options.name);
+        let start_failure = attempts::get(&options.name);
+        // the existing job is reported as it is, which may differ from
+        // the submitted one
+        let existing = docker::get(&options.name, namespace).await.ok().flatten();
+        let held = match &existing {
+            Some(job) => Some(hold::is_held(job).await),
+            None => None,
+        };
+        Ok((
+            false,
+            JobSummary {
+                id: options.name,
+                container_id: existing.as_ref().and_then(|job| job.id.clone()),
+                image: existing.and_then(|job| job.image),
+                namespace: Some(namespace.to_string()),
+                status: job_status(None, &start_failure),
+                start_failure,
+                held,
+                deduplicated: Some(true),
+                ..Default::default()
+            },
+        ))
+    }
+}
+
+/// Start a job right after it's created or released. Failures are
+/// reported and left to the scheduler to retry, since the job exists.
+async fn start_now(name: &str) {
+    let job = name.to_string();
+    let start = async move { docker::start(&job).await };
+    if let Err(e) = dispatch_queue::submit(start)
+        .await
+        .and_then(|result| result)
+    {
+        warn!("Couldn't start job {:?}: {:?}", name, e);
+    }
+}
+
+/// Header requesting a job to be created on hold.
+const HOLD_HEADER: &str = "X-Hold";
+
+/// Options of job creation requests.
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct CreateQuery {
+    /// Whether the job is started as soon as possible; if false, the
+    /// job is created on hold, not to be started until released
+    #[param(example = false)]
+    start: Option<bool>,
+    /// What's done if a job with the same name exists, overriding the
+    /// deployment's default: existing, conflict or replace
+    #[param(inline)]
+    on_duplicate: Option<OnDuplicate>,
+}
+
+/// Read the options of a job creation request.
+fn create_query(request: &HttpRequest) -> Result<CreateQuery, APIError> {
+    web::Query::<CreateQuery>::from_query(request.query_string())
+        .map(web::Query::into_inner)
+        .map_err(|e| APIError::bad_request(format!("Invalid query: {}", e)))
+}
+
+/// What a request asks to be done if a job with the same name exists,
+/// if anything.
+pub(crate) fn duplicate_handling(request: &HttpRequest) -> Result<Option<OnDuplicate>, APIError> {
+    Ok(create_query(request)?.on_duplicate)
+}
+
+/// Whether a request asks for the job to be created on hold, either
+/// with `start=false` in its query or with a true `X-Hold` header,
+/// which alternative backends only allow with the job store enabled.
+pub(crate) fn holds(request: &HttpRequest) -> Result<bool, APIError> {
+    let query = create_query(request)?;
+    let header = request
+        .headers()
+        .get(HOLD_HEADER)
+        .map(|value| value.to_str().unwrap_or_default().trim().to_lowercase());
+    let header = match header.as_deref() {
+        None | Some("false" | "0") => false,
+        Some("true" | "1") => true,
+        Some(_) => Err(APIError::bad_request(format!(
+            "The {} header must be true or false",
+            HOLD_HEADER
+        )))?,
+    };
+    let hold = query.start == Some(false) || header;
+    if hold && !hold::is_supported() {
+        Err(APIError::bad_request(
+            "Jobs can't be created on hold with this backend unless the job store is enabled",
+        ))?
+    }
+    Ok(hold)
+}
+
+/// Prefix of the headers giving labels for the job.
+const LABEL_HEADER_PREFIX: &str = "x-job-label-";
+
+/// Maximum amount of labels a request may give.
+const MAX_CLIENT_LABELS: usize = 32;
+
+/// Maximum length of the values of labels given by requests.
+const MAX_CLIENT_LABEL_LENGTH: usize = 1024;
+
+/// Read the labels a request gives for the job through `X-Job-Label-*`
+/// headers, keyed by the rest of the header name, in lowercase.
+pub(crate) fn client_labels(request: &HttpRequest) -> Result<BTreeMap<String, String>, APIError> {
+    let mut labels = BTreeMap::new();
+    for (name, value) in request.headers() {
+        let Some(key) = name.as_str().strip_prefix(LABEL_HEADER_PREFIX) else {
+            continue;
+        };
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+        {
+            Err(APIError::bad_request(format!(
+                "Invalid label header {:?}; label keys must be alphanumeric",
+                name.as_str()
+            )))?;
+        }
+        let value = value
+            .to_str()
+            .ok()
+            .filter(|value| value.len() <= MAX_CLIENT_LABEL_LENGTH)
+            .ok_or_else(|| {
+                APIError::bad_request(format!(
+                    "The {:?} header must hold text of at most {} bytes",
+                    name.as_str(),
+                    MAX_CLIENT_LABEL_LENGTH
+                ))
+            })?;
+        labels.insert(key.to_string(), value.trim().to_string());
+    }
+    if labels.len() > MAX_CLIENT_LABELS {
+        Err(APIError::bad_request(format!(
+            "At most {} labels may be given",
+            MAX_CLIENT_LABELS
+        )))?;
+    }
+    Ok(labels)
+}
+
+/// Create a job
+///
+/// Create a job as a docker container, by converting the request body
+/// to a job manifest
+#[utoipa::path(
+    post,
+    path = "/job",
+    tag = "job",
+    operation_id = "createJob",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(CreateQuery, openapi::Hold, openapi::Namespace),
+    request_body(content = Value, example = json!({"args": ["Hello", "world!"]})),
+    responses(
+        (status = 200, description = "job with the generated name already exists", body = JobSummary),
+        (status = 201, description = "job was created", body = JobSummary),
+        (status = 400, description = "job generation failed because of an invalid job manifest", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "job generation failed because the job manifest violates the security policy, or the client isn't allowed to create jobs through this path", body = APIError),
+        (status = 409, description = "a job with the same name exists, and duplicates are refused or the existing job hasn't finished and can't be replaced", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (
+            status = 429,
+            description = "the client exceeded its rate of job creation requests, or the namespace holds as many jobs as allowed",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying"))
+        ),
+        (status = 502, description = "job creation failed while trying to communicate with the docker daemon, or the daemon couldn't create the job (e.g. its image couldn't be pulled or built)", body = APIError),
+        (
+            status = 503,
+            description = "jobs aren't being accepted, because the dispatcher is shutting down, the docker daemon is unreachable or the dispatch queue is full",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying, when the docker daemon is unreachable"))
+        )
+    )
+)]
 #[routes]
 #[post("/job")]
 #[post("/job/{path:.*}")]
+#[tracing::instrument(skip_all)]
 async fn create_job(
+    request: HttpRequest,
     path: web::Path<PathInfo>,
     body: web::Json<Value>,
-    filter: web::Data<jq::Filter>,
+    filter: web::Data<Live<jq::Filter>>,
+    policy: web::Data<Live<Policy>>,
     can_start: web::Data<bool>,
     namespace: web::Data<String>,
 ) -> Result<impl Responder> {
     let path = format!("/job/{}", path.path.clone().unwrap_or_default());
     let path = path.strip_suffix('/').map(String::from).unwrap_or(path);
-    debug!("Job creation request at {:?}: {:?}", path, body);
-    let raw_manifest = jq::first_result(&filter, body.into_inner(), &path)
-        .ok_or_else(|| APIError::bad_request("Filter didn't produce results"))?
-        .map_err(|e| APIError::bad_request(format!("Filter failed: {:?}", e)))?;
-    debug!("Job raw manifest: {:?}", raw_manifest);
-    let options: CreateContainerOptions = serde_json::from_value(raw_manifest.clone())
-        .map_err(|e| APIError::bad_request(format!("Generated manifest is invalid: {:?}", e)))?;
-    let manifest: Config<String> = serde_json::from_value(raw_manifest)
-        .map_err(|e| APIError::bad_request(format!("Generated manifest is invalid: {:?}", e)))?;
-    debug!("Job manifest: {:?} {:?}", options, manifest);
-    let job_opt = docker::create(
-        options.name.clone(),
-        options.platform.clone(),
-        manifest,
-        &namespace,
-    )
-    .await
-    .map_err(|e| APIError::bad_request(format!("Server rejected job manifest: {:?}", e)))?;
-    if job_opt.is_some() {
-        info!("Created job with ID {:?}", options.name);
-        if **can_start {
-            docker::start(&options.name)
-                .await
-                .map_err(APIError::bad_gateway)?;
-        }
-        Ok(HttpResponse::Created().json(JobSummary {
-            id: options.name,
-            created: None,
-            status: None,
-        }))
-    } else {
-        info!("Pre-existing job with ID {:?}", options.name);
-        Ok(HttpResponse::Ok().json(JobSummary {
-            id: options.name,
-            created: None,
-            status: None,
-        }))
+    check_namespace(&request, &namespace)?;
+    if let Some(scope) = auth::scope(&request).filter(|scope| !scope.covers(Some(&path))) {
+        Err(APIError::forbidden(format!(
+            "The API key is restricted to paths under {:?}",
+            scope.path
+        )))?;
     }
+    let intake = Intake {
+        filter: filter.into_inner(),
+        policy: policy.into_inner(),
+        can_start: **can_start,
+        namespace: namespace.to_string(),
+    };
+    let submission = Submission {
+        input: body.into_inner(),
+        path,
+        claims: auth::token(&request).map_or(Value::Null, |token| token.claims),
+        message: Value::Null,
+        reply_subject: None,
+        actor: audit::Actor::of(&request),
+        hold: holds(&request)?,
+        labels: client_labels(&request)?,
+        on_duplicate: duplicate_handling(&request)?,
+    };
+    let (created, summary) = submit(&intake, submission).await?;
+    Ok(if created {
+        HttpResponse::Created().json(summary)
+    } else {
+        HttpResponse::Ok().json(summary)
+    })
 }
 
-/// Fetch a job by its ID.
+/// Create a job through a path
+///
+/// Create a job as a docker container, by converting the request body
+/// to a job manifest; the path is given to the filter. Served by
+/// `create_job`, and declared apart only to be documented.
+#[utoipa::path(
+    post,
+    path = "/job/{path}",
+    tag = "job",
+    operation_id = "createJobWithPath",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("path" = String, Path, description = "Path information to provide for the job"),
+        CreateQuery,
+        openapi::Hold,
+        openapi::Namespace
+    ),
+    request_body(content = Value, example = json!({"args": ["Hello", "world!"]})),
+    responses(
+        (status = 200, description = "job with the generated name already exists", body = JobSummary),
+        (status = 201, description = "job was created", body = JobSummary),
+        (status = 400, description = "job generation failed because of an invalid job manifest", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "job generation failed because the job manifest violates the security policy, or the client isn't allowed to create jobs through this path", body = APIError),
+        (status = 409, description = "a job with the same name exists, and duplicates are refused or the existing job hasn't finished and can't be replaced", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (
+            status = 429,
+            description = "the client exceeded its rate of job creation requests, or the namespace holds as many jobs as allowed",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying"))
+        ),
+        (status = 502, description = "job creation failed while trying to communicate with the docker daemon, or the daemon couldn't create the job (e.g. its image couldn't be pulled or built)", body = APIError),
+        (
+            status = 503,
+            description = "jobs aren't being accepted, because the dispatcher is shutting down, the docker daemon is unreachable or the dispatch queue is full",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying, when the docker daemon is unreachable"))
+        )
+    )
+)]
+#[allow(dead_code)]
+fn create_job_with_path() {}
+
+/// Fetch a job
+///
+/// Fetch a job by its ID
+#[utoipa::path(
+    get,
+    path = "/job/{id}",
+    tag = "job",
+    operation_id = "fetchJob",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("id" = String, Path, description = "ID of the job to fetch"),
+        openapi::Namespace
+    ),
+    responses(
+        (status = 200, description = "job matching the given ID", body = JobSummary),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to read jobs", body = APIError),
+        (status = 404, description = "job doesn't exist", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "job fetching failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
 #[get("/job/{id}")]
-async fn get_job(id: web::Path<String>, namespace: web::Data<String>) -> Result<impl Responder> {
+async fn get_job(
+    request: HttpRequest,
+    id: web::Path<String>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
     let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    // jobs outside the scope of the API key are reported as missing
+    if job
+        .as_ref()
+        .is_some_and(|job| !is_visible(&request, docker::label(job, docker::PATH_LABEL_KEY)))
+    {
+        Err(APIError::not_found("The specified job doesn't exist"))?;
+    }
+    let Some(job) = job else {
+        // removed jobs are reported from their record in the job
+        // store, or else from their exit record if they were removed
+        // automatically
+        let record = job_store::get(&id).filter(|record| record.namespace == **namespace);
+        if let Some(record) = record {
+            if !is_visible(&request, record.path.as_deref()) {
+                Err(APIError::not_found("The specified job doesn't exist"))?;
+            }
+            info!("Fetched recorded job with ID {:?}", &*id);
+            return Ok(web::Json(JobSummary::from_record(id.clone(), record)));
+        }
+        let exit = exits::get(&id)
+            .filter(|exit| exit.namespace.as_deref() == Some(namespace.as_str()))
+            .filter(|exit| is_visible(&request, exit.path.as_deref()))
+            .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?;
+        info!("Fetched automatically removed job with ID {:?}", &*id);
+        return Ok(web::Json(JobSummary {
+            id: id.clone(),
+            container_id: None,
+            image: None,
+            namespace: Some(namespace.to_string()),
+            created: exit.created,
+            status: Some(match exit.exit_code {
+                Some(code) => format!("Exited ({}), removed", code),
+                None => String::from("Removed"),
+            }),
+            start_failure: None,
+            health: None,
+            sidecars: None,
+            metadata: None,
+            started: exit.started,
+            finished: exit.finished,
+            exit_code: exit.exit_code,
+            result: results::get(&id),
+            usage: usage::get(&id),
+            progress: progress::get(&id),
+            held: None,
+            deduplicated: None,
+        }));
+    };
+    let sidecars = docker::sidecars(&*id)
         .await
         .map_err(APIError::bad_gateway)?
-        .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?;
+        .into_iter()
+        .map(|sidecar| SidecarSummary {
+            name: sidecar
+                .names
+                .and_then(|names| names.into_iter().next())
+                .map(|name| name.trim_start_matches('/').to_string())
+                .unwrap_or_default(),
+            status: sidecar.status,
+        })
+        .collect::<Vec<_>>();
+    // only running jobs with a healthcheck report their health
+    let health = match job.state.as_deref() {
+        Some("running") => docker::inspect(&*id)
+            .await
+            .map_err(APIError::bad_gateway)?
+            .state
+            .and_then(|state| state.health)
+            .and_then(|health| health.status)
+            .filter(|status| !matches!(status, HealthStatusEnum::EMPTY | HealthStatusEnum::NONE))
+            .map(|status| status.to_string()),
+        _ => None,
+    };
     info!("Fetched job with ID {:?}", &*id);
+    let start_failure = attempts::get(&id);
+    let held = hold::is_held(&job).await;
+    let metadata = JobMetadata {
+        artifacts: artifacts::url(&id),
+        ..JobMetadata::from_labels(&job)
+    };
     Ok(web::Json(JobSummary {
         id: id.clone(),
+        container_id: job.id.clone(),
+        image: job.image.clone(),
+        namespace: Some(namespace.to_string()),
         created: job.created,
-        status: job.status,
+        status: job_status(job.status, &start_failure),
+        start_failure,
+        health,
+        sidecars: (!sidecars.is_empty()).then_some(sidecars),
+        metadata: Some(metadata),
+        started: None,
+        finished: None,
+        exit_code: None,
+        result: results::get(&id),
+        usage: usage::get(&id),
+        progress: progress::get(&id),
+        held: Some(held),
+        deduplicated: None,
     }))
 }
+
+/// Release a job
+///
+/// Release a job created on hold, letting it be started; jobs that
+/// would've been started upon creation are started right away, and
+/// the rest are left to the scheduler
+#[utoipa::path(
+    post,
+    path = "/job/{id}/release",
+    tag = "job",
+    operation_id = "releaseJob",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("id" = String, Path, description = "ID of the job to release"),
+        openapi::Namespace
+    ),
+    responses(
+        (status = 200, description = "job was released", body = JobSummary),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to create jobs", body = APIError),
+        (status = 404, description = "job doesn't exist", body = APIError),
+        (status = 409, description = "job wasn't created on hold, or was already released", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "job release failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
+#[post("/job/{id}/release")]
+async fn release_job(
+    request: HttpRequest,
+    id: web::Path<String>,
+    can_start: web::Data<bool>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
+    let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?
+        // jobs outside the scope of the API key are reported as missing
+        .filter(|job| is_visible(&request, docker::label(job, docker::PATH_LABEL_KEY)))
+        .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?;
+    if !hold::is_held(&job).await || job.state.as_deref() != Some("created") {
+        Err(APIError::conflict("The specified job isn't on hold"))?;
+    }
+    hold::release(&id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    info!("Released job with ID {:?}", &*id);
+    audit::record(audit::Entry {
+        action: "job_released",
+        namespace: namespace.to_string(),
+        job: Some(id.clone()),
+        actor: audit::Actor::of(&request),
+        ..Default::default()
+    });
+    // the same jobs started upon creation are started upon release
+    if **can_start
+        && !quiet_hours::is_quiet()
+        && !docker::is_service(&job)
+        && docker::label(&job, docker::MUTEX_GROUP_LABEL_KEY).is_none()
+        && (gpu::taken(&job) == 0 || gpu::limit().is_none())
+        && docker::label(&job, docker::CPUSET_LABEL_KEY) != Some(cpusets::EXCLUSIVE)
+    {
+        start_now(&id).await;
+    }
+    let start_failure = attempts::get(&id);
+    Ok(web::Json(JobSummary {
+        id: id.clone(),
+        container_id: job.id.clone(),
+        image: job.image.clone(),
+        namespace: Some(namespace.to_string()),
+        created: job.created,
+        status: job_status(job.status.clone(), &start_failure),
+        start_failure,
+        health: None,
+        sidecars: None,
+        metadata: Some(JobMetadata::from_labels(&job)),
+        started: None,
+        finished: None,
+        exit_code: None,
+        result: None,
+        usage: None,
+        progress: progress::get(&id),
+        held: Some(false),
+        deduplicated: None,
+    }))
+}
+
+/// Report a job's progress
+///
+/// Report the progress of a running job, meant to be called by the job
+/// itself with the URL and token given in its DISPATCHER_PROGRESS_URL
+/// and DISPATCHER_PROGRESS_TOKEN environment variables; each report
+/// replaces the previous one
+#[utoipa::path(
+    put,
+    path = "/job/{id}/progress",
+    tag = "job",
+    operation_id = "reportJobProgress",
+    security(("bearerAuth" = [])),
+    params(
+        ("id" = String, Path, description = "ID of the job reporting its progress"),
+        openapi::Namespace
+    ),
+    request_body(content = Progress, description = "Progress of the job", content_type = "application/json"),
+    responses(
+        (status = 200, description = "progress was recorded", body = Progress),
+        (status = 400, description = "reported progress is invalid", body = APIError),
+        (status = 401, description = "the request lacks the job's progress token", body = APIError),
+        (status = 404, description = "job doesn't exist, or progress reports are disabled", body = APIError),
+        (status = 409, description = "job isn't running", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "progress reporting failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
+#[put("/job/{id}/progress")]
+async fn report_progress(
+    request: HttpRequest,
+    id: web::Path<String>,
+    body: web::Json<Progress>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
+    if !progress::is_enabled() {
+        Err(APIError::not_found("Progress reports are disabled"))?;
+    }
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    if !token.is_some_and(|token| progress::is_valid(token, &id, &namespace)) {
+        Err(APIError::unauthorized("Missing or invalid progress token"))?;
+    }
+    let report = body.into_inner();
+    report.validate().map_err(APIError::bad_request)?;
+    let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?
+        .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?;
+    if job.state.as_deref() != Some("running") {
+        Err(APIError::conflict("The specified job isn't running"))?;
+    }
+    debug!("Job with ID {:?} reported its progress", &*id);
+    Ok(web::Json(progress::report(
+        &id,
+        &namespace,
+        docker::label(&job, docker::PATH_LABEL_KEY),
+        report,
+    )))
+}
+
+/// Fetch a job's logs
+///
+/// Fetch the output of a job by its ID, or its archived output if the
+/// job has been removed
+#[utoipa::path(
+    get,
+    path = "/job/{id}/logs",
+    tag = "job",
+    operation_id = "fetchJobLogs",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("id" = String, Path, description = "ID of the job to fetch logs from"),
+        openapi::Namespace
+    ),
+    responses(
+        (status = 200, description = "output of the job matching the given ID", body = String, content_type = "text/plain"),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to read jobs", body = APIError),
+        (status = 404, description = "job doesn't exist and has no archived logs", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "log fetching failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
+#[get("/job/{id}/logs")]
+async fn get_job_logs(
+    request: HttpRequest,
+    id: web::Path<String>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    check_namespace(&request, &namespace)?;
+    let job = docker::get(&*id, &namespace)
+        .await
+        .map_err(APIError::bad_gateway)?;
+    let path = match &job {
+        Some(job) => docker::label(job, docker::PATH_LABEL_KEY).map(String::from),
+        None => job_store::get(&id)
+            .filter(|record| record.namespace == **namespace)
+            .and_then(|record| record.path),
+    };
+    // jobs outside the scope of the API key are reported as missing
+    if !is_visible(&request, path.as_deref()) {
+        Err(APIError::not_found("The specified job doesn't exist"))?;
+    }
+    let logs = if job.is_some() {
+        docker::logs(&*id).await.map_err(APIError::bad_gateway)?
+    } else {
+        archive::latest_logs(&id, &namespace)
+            .await
+            .map_err(APIError::internal_error)?
+            .ok_or_else(|| APIError::not_found("The specified job doesn't exist"))?
+    };
+    info!("Fetched logs of job with ID {:?}", &*id);
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::plaintext())
+        .body(logs))
+}
+
+/// Criteria for summarizing jobs.
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct SummaryQuery {
+    /// What jobs are grouped by: path, image or label:KEY
+    #[param(example = "label:com.example.team")]
+    group_by: Option<String>,
+}
+
+/// Summarize jobs
+///
+/// Count the retained jobs of the namespace by state, and their
+/// success rate, per path, image or label value
+#[utoipa::path(
+    get,
+    path = "/jobs/summary",
+    tag = "job",
+    operation_id = "summarizeJobs",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(SummaryQuery, openapi::Namespace),
+    responses(
+        (status = 200, description = "counts of jobs per group, ordered by group", body = Vec<JobGroup>),
+        (status = 400, description = "the grouping is invalid", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to read jobs", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (status = 502, description = "summarizing failed while trying to communicate with the docker daemon", body = APIError)
+    )
+)]
+#[get("/jobs/summary")]
+async fn summarize_jobs(
+    request: HttpRequest,
+    query: web::Query<SummaryQuery>,
+    namespace: web::Data<String>,
+) -> Result<web::Json<Vec<JobGroup>>> {
+    check_namespace(&request, &namespace)?;
+    let group_by = report::GroupBy::parse(query.group_by.as_deref().unwrap_or("path"))
+        .map_err(APIError::bad_request)?;
+    // jobs outside the scope of the API key are left out
+    let groups = report::summarize(&namespace, &group_by, |job| {
+        is_visible(&request, docker::label(job, docker::PATH_LABEL_KEY))
+    })
+    .await
+    .map_err(APIError::bad_gateway)?;
+    info!("Summarized jobs in {} groups", groups.len());
+    Ok(web::Json(groups))
+}
+
+/// Criteria for exporting jobs.
+#[derive(Deserialize, IntoParams)]
+pub(crate) struct ExportQuery {
+    /// Format of the export
+    #[param(inline)]
+    format: Option<export::Format>,
+    /// UNIX time before which created jobs are left out
+    #[param(example = 1702598995)]
+    since: Option<i64>,
+}
+
+/// Export the job history
+///
+/// Stream the records of the jobs of the namespace kept in the job
+/// store, with their timings, exit codes and labels, one per line
+#[utoipa::path(
+    get,
+    path = "/jobs/export",
+    tag = "job",
+    operation_id = "exportJobs",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(ExportQuery, openapi::Namespace),
+    responses(
+        (status = 200, description = "records of the jobs, ordered by job ID", body = String, content_type = ["application/x-ndjson", "text/csv"]),
+        (status = 400, description = "the format or time is invalid", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to read jobs", body = APIError),
+        (status = 404, description = "the job store is disabled", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        )
+    )
+)]
+#[get("/jobs/export")]
+async fn export_jobs(
+    request: HttpRequest,
+    query: web::Query<ExportQuery>,
+    namespace: web::Data<String>,
+) -> Result<HttpResponse> {
+    check_namespace(&request, &namespace)?;
+    if !job_store::is_enabled() {
+        Err(APIError::not_found("The job store is disabled"))?;
+    }
+    let format = query.format.unwrap_or_default();
+    // jobs outside the scope of the API key are left out
+    let scope = auth::scope(&request);
+    let rows = export::stream(&namespace, query.since, format, move |path| {
+        scope.as_ref().is_none_or(|scope| scope.covers(path))
+    });
+    info!("Exporting the job history of namespace {:?}", &**namespace);
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((
+            CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"jobs-{}.{}\"",
+                &**namespace,
+                format.extension()
+            ),
+        ))
+        .streaming(rows))
+}
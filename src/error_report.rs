@@ -0,0 +1,229 @@
+//! Reports panics, repeated upkeep failures and server error
+//! responses to Sentry, or to a generic webhook, along with the
+//! context they happened in.
+//!
+//! Reports are sent by a single task, so that reporting never blocks
+//! the code that fails. Failures to send them are logged.
+
+use crate::request_id;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, Error,
+};
+use anyhow::{anyhow, Context, Result};
+use chrono::{offset::Utc, SecondsFormat};
+use once_cell::sync::OnceCell;
+use reqwest::Url;
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::panic;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::{self, Duration};
+use tracing::error;
+
+/// Static channel to the reporting task.
+static SENDER: OnceCell<UnboundedSender<Report>> = OnceCell::new();
+
+/// Amount of reports yet to be sent.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+/// A reported error.
+#[derive(Debug, Serialize)]
+struct Report {
+    kind: &'static str,
+    message: String,
+    time: String,
+    tags: BTreeMap<&'static str, String>,
+}
+
+/// A Sentry project, as given by its DSN.
+struct Sentry {
+    url: Url,
+    auth: String,
+}
+
+impl Sentry {
+    /// Parse a DSN, e.g. `https://public-key@sentry.example.com/42`.
+    fn parse(dsn: &str) -> Result<Self> {
+        let mut url = Url::parse(dsn).context("while parsing the Sentry DSN")?;
+        let key = url.username().to_string();
+        if key.is_empty() {
+            return Err(anyhow!("Sentry DSN {:?} is missing the public key", dsn));
+        }
+        let project = url
+            .path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .filter(|project| !project.is_empty())
+            .map(String::from)
+            .ok_or_else(|| anyhow!("Sentry DSN {:?} is missing the project ID", dsn))?;
+        url.set_username("")
+            .and_then(|_| url.set_password(None))
+            .map_err(|_| anyhow!("invalid Sentry DSN {:?}", dsn))?;
+        url.path_segments_mut()
+            .map_err(|_| anyhow!("invalid Sentry DSN {:?}", dsn))?
+            .pop()
+            .extend(["api", &project, "store", ""]);
+        Ok(Self {
+            url,
+            auth: format!(
+                "Sentry sentry_version=7, sentry_key={}, sentry_client={}/{}",
+                key,
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            ),
+        })
+    }
+
+    /// Send a report as a Sentry event.
+    async fn send(&self, client: &reqwest::Client, report: &Report) -> reqwest::Result<()> {
+        client
+            .post(self.url.clone())
+            .header("X-Sentry-Auth", &self.auth)
+            .json(&json!({
+                "timestamp": report.time,
+                "level": if report.kind == "panic" { "fatal" } else { "error" },
+                "logger": report.kind,
+                "platform": "other",
+                "release": concat!(env!("CARGO_PKG_NAME"), "@", env!("CARGO_PKG_VERSION")),
+                "message": { "formatted": report.message },
+                "tags": report.tags,
+            }))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+    }
+}
+
+/// Report an error of the given kind (e.g. `upkeep`), with the given
+/// context. Nothing is reported if reporting is disabled.
+pub fn capture(kind: &'static str, message: String, tags: &[(&'static str, &str)]) {
+    let Some(sender) = SENDER.get() else {
+        return;
+    };
+    let report = Report {
+        kind,
+        message,
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+        tags: tags
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| (*key, value.to_string()))
+            .collect(),
+    };
+    PENDING.fetch_add(1, Ordering::SeqCst);
+    if sender.send(report).is_err() {
+        PENDING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Report server error responses, along with the request that caused
+/// them.
+pub async fn capture_responses(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let response = next.call(request).await?;
+    if SENDER.get().is_none() || !response.status().is_server_error() {
+        return Ok(response);
+    }
+    let request = response.request();
+    let message = response
+        .response()
+        .error()
+        .map(|e| e.to_string())
+        .unwrap_or_else(|| String::from("server error"));
+    let status = response.status().as_u16().to_string();
+    let endpoint = request.match_pattern().unwrap_or_default();
+    let namespace = request
+        .app_data::<web::Data<String>>()
+        .map(|namespace| namespace.to_string())
+        .unwrap_or_default();
+    let request_id = request_id::get(request).unwrap_or_default();
+    capture(
+        "response",
+        message,
+        &[
+            ("method", request.method().as_str()),
+            ("endpoint", &endpoint),
+            ("status", &status),
+            ("namespace", &namespace),
+            ("job", request.match_info().get("id").unwrap_or_default()),
+            ("request_id", &request_id),
+        ],
+    );
+    Ok(response)
+}
+
+/// Wait for pending reports to be sent, for up to the given time.
+pub async fn flush(timeout: Duration) {
+    let _ = time::timeout(timeout, async {
+        while PENDING.load(Ordering::SeqCst) > 0 {
+            time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await;
+}
+
+/// Start reporting errors to the given Sentry DSN and webhook,
+/// returning the task that sends them. Panics are reported from now
+/// on, in addition to being handled as before.
+pub fn init(
+    sentry_dsn: Option<&str>,
+    webhook: Option<String>,
+) -> Result<impl Future<Output = Result<()>>> {
+    let sentry = sentry_dsn.map(Sentry::parse).transpose()?;
+    let (sender, mut receiver) = unbounded_channel::<Report>();
+    let _ = SENDER.set(sender);
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let location = info
+            .location()
+            .map(|location| location.to_string())
+            .unwrap_or_default();
+        let message = info
+            .payload()
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| String::from("panic"));
+        let thread = std::thread::current();
+        capture(
+            "panic",
+            message,
+            &[
+                ("location", &location),
+                ("thread", thread.name().unwrap_or_default()),
+            ],
+        );
+        previous_hook(info);
+    }));
+    Ok(async move {
+        let client = reqwest::Client::new();
+        while let Some(report) = receiver.recv().await {
+            if let Some(sentry) = &sentry {
+                if let Err(e) = sentry.send(&client, &report).await {
+                    error!("Couldn't report error to Sentry {:?}: {:?}", report, e);
+                }
+            }
+            if let Some(webhook) = &webhook {
+                let result = client
+                    .post(webhook)
+                    .json(&report)
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status());
+                if let Err(e) = result {
+                    error!("Couldn't report error to the webhook {:?}: {:?}", report, e);
+                }
+            }
+            PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,115 @@
+//! Keeps track of the outcome of jobs removed automatically by docker
+//! as soon as they exit.
+//!
+//! Such jobs vanish before they can be fetched or cleaned, so their
+//! lifecycle is followed through the docker events stream instead.
+//! Records are kept in memory, which means they're lost when the
+//! dispatcher restarts, and only the most recent ones are kept.
+
+use crate::docker;
+use anyhow::{Context, Result};
+use futures::stream::TryStreamExt;
+use once_cell::sync::OnceCell;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tracing::debug;
+
+/// Maximum amount of records kept.
+const MAX_RECORDS: usize = 1000;
+
+/// Static exit registry, ordered from oldest to newest.
+static RECORDS: OnceCell<Mutex<VecDeque<(String, Exit)>>> = OnceCell::new();
+
+/// A record of the lifecycle of an automatically removed job, along
+/// with the namespace and path it was created in, as given by its
+/// labels. Times are given as UNIX timestamps.
+#[derive(Clone, Debug, Default)]
+pub struct Exit {
+    pub namespace: Option<String>,
+    pub path: Option<String>,
+    pub created: Option<i64>,
+    pub started: Option<i64>,
+    pub finished: Option<i64>,
+    pub exit_code: Option<i64>,
+    pub removed: bool,
+}
+
+/// Get the exit registry.
+fn records() -> &'static Mutex<VecDeque<(String, Exit)>> {
+    RECORDS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+/// Update the record of a job, creating it if it's new.
+fn update<F: FnOnce(&mut Exit)>(name: &str, f: F) {
+    let mut records = records().lock().unwrap();
+    match records.iter_mut().find(|(n, _)| n == name) {
+        Some((_, exit)) => f(exit),
+        None => {
+            let mut exit = Exit::default();
+            f(&mut exit);
+            records.push_back((name.to_string(), exit));
+            if records.len() > MAX_RECORDS {
+                records.pop_front();
+            }
+        }
+    }
+}
+
+/// Get the record of a job that was removed automatically, if any.
+pub fn get(name: &str) -> Option<Exit> {
+    records()
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|(n, exit)| n == name && exit.removed)
+        .map(|(_, exit)| exit.clone())
+}
+
+/// Consume the docker events stream, recording the lifecycle of jobs
+/// labelled for automatic removal.
+pub async fn watch(namespace: String) -> Result<()> {
+    docker::job_events(&namespace, &["create", "start", "die", "destroy"])?
+        .try_for_each(|event| async move {
+            let attributes = event
+                .actor
+                .and_then(|actor| actor.attributes)
+                .unwrap_or_default();
+            if !attributes.contains_key(docker::AUTO_REMOVE_LABEL_KEY) {
+                return Ok(());
+            }
+            let Some(name) = attributes.get("name") else {
+                return Ok(());
+            };
+            let time = event.time;
+            match event.action.as_deref() {
+                Some("create") => {
+                    // a job created again under the same name starts
+                    // a new record
+                    records().lock().unwrap().retain(|(n, _)| n != name);
+                    update(name, |exit| exit.created = time);
+                }
+                Some("start") => update(name, |exit| exit.started = time),
+                Some("die") => update(name, |exit| {
+                    exit.finished = time;
+                    exit.exit_code = attributes
+                        .get("exitCode")
+                        .and_then(|code| code.parse().ok());
+                }),
+                Some("destroy") => {
+                    debug!("Job {:?} was removed automatically", name);
+                    update(name, |exit| exit.removed = true);
+                }
+                _ => (),
+            }
+            // every event carries the labels, should the creation of
+            // the job have been missed
+            update(name, |exit| {
+                exit.namespace = attributes.get(docker::JOB_LABEL_KEY).cloned();
+                exit.path = attributes.get(docker::PATH_LABEL_KEY).cloned();
+            });
+            Ok(())
+        })
+        .await
+        .context("while watching automatically removed jobs")?;
+    Ok(())
+}
@@ -0,0 +1,189 @@
+//! Exports the history of jobs kept in the job store, for offline
+//! analysis and archival before the cleaner removes their containers.
+//!
+//! Records are read lazily and written one per line, so that exports
+//! of long histories are streamed rather than held in memory.
+
+use crate::job_store::{self, Record};
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// Columns of the CSV export, in order.
+const COLUMNS: [&str; 20] = [
+    "id",
+    "namespace",
+    "state",
+    "path",
+    "submitter",
+    "api_key",
+    "client_cert",
+    "request_id",
+    "container_id",
+    "image",
+    "created",
+    "started",
+    "finished",
+    "removed",
+    "released",
+    "duration",
+    "exit_code",
+    "attempts",
+    "last_error",
+    "labels",
+];
+
+/// Format of an export.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+    /// Comma-separated values, with a header line and labels given as
+    /// a JSON object
+    Csv,
+}
+
+impl Format {
+    /// The content type of an export in this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Jsonl => "application/x-ndjson",
+            Self::Csv => "text/csv",
+        }
+    }
+
+    /// The file extension of an export in this format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jsonl => "jsonl",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// An exported job. Times are given as UNIX timestamps.
+#[derive(Serialize)]
+struct Row {
+    id: String,
+    namespace: String,
+    state: job_store::State,
+    path: Option<String>,
+    submitter: Option<String>,
+    api_key: Option<String>,
+    client_cert: Option<String>,
+    request_id: Option<String>,
+    container_id: Option<String>,
+    image: Option<String>,
+    created: Option<i64>,
+    started: Option<i64>,
+    finished: Option<i64>,
+    removed: Option<i64>,
+    released: Option<i64>,
+    /// Seconds the job ran for, if it finished.
+    duration: Option<i64>,
+    exit_code: Option<i64>,
+    attempts: u16,
+    last_error: Option<String>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Row {
+    /// Build the row of a recorded job.
+    fn of(id: String, record: Record) -> Self {
+        Self {
+            id,
+            namespace: record.namespace,
+            state: record.state,
+            path: record.path,
+            submitter: record.submitter,
+            api_key: record.api_key,
+            client_cert: record.client_cert,
+            request_id: record.request_id,
+            container_id: record.container_id,
+            image: record.image,
+            created: record.created,
+            started: record.started,
+            finished: record.finished,
+            removed: record.removed,
+            released: record.released,
+            duration: record
+                .started
+                .zip(record.finished)
+                .map(|(started, finished)| (finished - started).max(0)),
+            exit_code: record.exit_code,
+            attempts: record.attempts,
+            last_error: record.last_error,
+            labels: record.labels,
+        }
+    }
+
+    /// Write the row as a line in the given format.
+    fn line(&self, format: Format) -> Result<String, serde_json::Error> {
+        match format {
+            Format::Jsonl => Ok(serde_json::to_string(self)? + "\n"),
+            Format::Csv => {
+                let text = |value: &Option<String>| value.as_deref().map(quote).unwrap_or_default();
+                let number = |value: Option<i64>| value.map(|n| n.to_string()).unwrap_or_default();
+                let state = serde_json::to_value(self.state)?;
+                let fields = [
+                    quote(&self.id),
+                    quote(&self.namespace),
+                    state.as_str().map(quote).unwrap_or_default(),
+                    text(&self.path),
+                    text(&self.submitter),
+                    text(&self.api_key),
+                    text(&self.client_cert),
+                    text(&self.request_id),
+                    text(&self.container_id),
+                    text(&self.image),
+                    number(self.created),
+                    number(self.started),
+                    number(self.finished),
+                    number(self.removed),
+                    number(self.released),
+                    number(self.duration),
+                    number(self.exit_code),
+                    self.attempts.to_string(),
+                    text(&self.last_error),
+                    quote(&serde_json::to_string(&self.labels)?),
+                ];
+                Ok(fields.join(",") + "\r\n")
+            }
+        }
+    }
+}
+
+/// Quote a CSV field if it holds a delimiter, a quote or a line break.
+fn quote(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Stream the recorded jobs of a namespace created since the given
+/// time, if any, and accepted by the given filter, which is given the
+/// path each job was created through.
+pub fn stream<F>(
+    namespace: &str,
+    since: Option<i64>,
+    format: Format,
+    filter: F,
+) -> impl Stream<Item = Result<Bytes, serde_json::Error>>
+where
+    F: Fn(Option<&str>) -> bool + 'static,
+{
+    let header = (format == Format::Csv).then(|| Ok(Bytes::from(COLUMNS.join(",") + "\r\n")));
+    let rows = job_store::iter(namespace)
+        .filter(move |(_, record)| {
+            since.is_none_or(|since| record.created.is_some_and(|created| created >= since))
+        })
+        .filter(move |(_, record)| filter(record.path.as_deref()))
+        .map(move |(id, record)| Row::of(id, record).line(format).map(Bytes::from));
+    stream::iter(header.into_iter().chain(rows))
+}
@@ -0,0 +1,304 @@
+//! Implements an optional GraphQL endpoint to query jobs, their
+//! states, lifecycle events and output, so that dashboards may fetch
+//! exactly the fields they need in a single request.
+
+use crate::api_error::APIError;
+use crate::archive;
+use crate::auth::{self, Scope};
+use crate::docker;
+use crate::docker_service;
+use crate::job_store;
+use crate::openapi;
+use actix_web::{post, web, HttpRequest, Responder, Result};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, EmptySubscription, Enum, Object, Schema, SimpleObject,
+};
+use bollard::models::ContainerSummary;
+use std::cmp::Reverse;
+use tracing::info;
+
+/// Maximum depth of queries.
+const MAX_DEPTH: usize = 8;
+
+/// Maximum number of jobs fetched at once.
+const MAX_PAGE_SIZE: usize = 500;
+
+/// The schema of the endpoint.
+pub type JobSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+/// Build the schema of the endpoint.
+pub fn schema() -> JobSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+}
+
+/// Who's querying, and which jobs they may see.
+struct Viewer {
+    namespace: String,
+    scope: Option<Scope>,
+}
+
+impl Viewer {
+    /// Whether a job created through the given path may be seen.
+    fn sees(&self, path: Option<&str>) -> bool {
+        self.scope.as_ref().is_none_or(|scope| scope.covers(path))
+    }
+}
+
+/// The normalized state of a job.
+#[derive(Enum, Clone, Copy, PartialEq, Eq)]
+enum JobState {
+    /// Created, but not yet started.
+    Pending,
+    Running,
+    Exited,
+    /// Removed, and known only from its record.
+    Removed,
+}
+
+impl JobState {
+    /// Normalize the state of a container.
+    fn of(container: &ContainerSummary) -> Self {
+        match container.state.as_deref() {
+            Some("created") => Self::Pending,
+            Some("running" | "restarting" | "paused") => Self::Running,
+            _ => Self::Exited,
+        }
+    }
+}
+
+/// A step in the lifecycle of a job, as recorded in the job store.
+#[derive(SimpleObject)]
+struct JobEvent {
+    /// One of created, started, finished and removed.
+    event: &'static str,
+    /// UNIX timestamp of the step.
+    time: i64,
+}
+
+/// A job, either existing or removed.
+#[derive(SimpleObject)]
+#[graphql(complex)]
+struct Job {
+    id: String,
+    state: JobState,
+    /// Status as reported by the docker daemon.
+    status: Option<String>,
+    created: Option<i64>,
+    path: Option<String>,
+    submitter: Option<String>,
+    exit_code: Option<i64>,
+    #[graphql(skip)]
+    namespace: String,
+}
+
+impl Job {
+    /// Describe an existing job.
+    fn from_container(container: &ContainerSummary, namespace: &str) -> Option<Self> {
+        let id = container
+            .names
+            .as_ref()
+            .and_then(|names| names.first())
+            .map(|name| name.trim_start_matches('/').to_string())?;
+        let exit_code = job_store::get(&id).and_then(|record| record.exit_code);
+        Some(Self {
+            id,
+            state: JobState::of(container),
+            status: container.status.clone(),
+            created: container.created,
+            path: docker::label(container, docker::PATH_LABEL_KEY).map(String::from),
+            submitter: docker::label(container, docker::SUBMITTER_LABEL_KEY).map(String::from),
+            exit_code,
+            namespace: namespace.to_string(),
+        })
+    }
+
+    /// Describe a removed job from its record.
+    fn from_record(id: String, record: job_store::Record) -> Self {
+        Self {
+            id,
+            state: JobState::Removed,
+            status: None,
+            created: record.created,
+            path: record.path,
+            submitter: record.submitter,
+            exit_code: record.exit_code,
+            namespace: record.namespace,
+        }
+    }
+}
+
+#[ComplexObject]
+impl Job {
+    /// The recorded steps in the lifecycle of the job, oldest first;
+    /// empty if the job store is disabled.
+    async fn events(&self) -> Vec<JobEvent> {
+        let Some(record) = job_store::get(&self.id) else {
+            return Vec::new();
+        };
+        [
+            ("created", record.created),
+            ("started", record.started),
+            ("finished", record.finished),
+            ("removed", record.removed),
+        ]
+        .into_iter()
+        .filter_map(|(event, time)| time.map(|time| JobEvent { event, time }))
+        .collect()
+    }
+
+    /// The last lines of the job's output, from its archived logs if
+    /// it was removed.
+    async fn logs_tail(
+        &self,
+        #[graphql(default = 100)] lines: usize,
+    ) -> async_graphql::Result<Option<String>> {
+        let logs = if self.state == JobState::Removed {
+            archive::latest_logs(&self.id, &self.namespace).await?
+        } else {
+            Some(docker::logs(&self.id).await?)
+        };
+        Ok(logs.map(|logs| {
+            let logs = String::from_utf8_lossy(&logs);
+            let mut tail = logs.lines().rev().take(lines).collect::<Vec<_>>();
+            tail.reverse();
+            tail.join("\n")
+        }))
+    }
+}
+
+/// A page of jobs.
+#[derive(SimpleObject)]
+struct JobPage {
+    nodes: Vec<Job>,
+    /// Total number of jobs matching the query.
+    total_count: usize,
+    /// Cursor to give as `after` to fetch the next page.
+    end_cursor: Option<String>,
+    has_next_page: bool,
+}
+
+/// The root of queries.
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Fetch a job by its ID.
+    async fn job(&self, context: &Context<'_>, id: String) -> async_graphql::Result<Option<Job>> {
+        let viewer = context.data::<Viewer>()?;
+        let job = match docker::get(&id, &viewer.namespace).await? {
+            Some(container) => Job::from_container(&container, &viewer.namespace),
+            None => job_store::get(&id)
+                .filter(|record| record.namespace == viewer.namespace)
+                .map(|record| Job::from_record(id, record)),
+        };
+        Ok(job.filter(|job| viewer.sees(job.path.as_deref())))
+    }
+
+    /// List jobs, newest first, optionally only those in a state or
+    /// created through paths with a prefix. Removed jobs are listed
+    /// only if the job store is enabled.
+    async fn jobs(
+        &self,
+        context: &Context<'_>,
+        state: Option<JobState>,
+        path_prefix: Option<String>,
+        #[graphql(default = 50)] first: usize,
+        after: Option<String>,
+    ) -> async_graphql::Result<JobPage> {
+        let viewer = context.data::<Viewer>()?;
+        let containers = docker::get_by_status(
+            &viewer.namespace,
+            &[
+                "created",
+                "restarting",
+                "running",
+                "paused",
+                "exited",
+                "dead",
+            ],
+        )
+        .await?;
+        let mut jobs = containers
+            .iter()
+            .filter_map(|container| Job::from_container(container, &viewer.namespace))
+            .collect::<Vec<_>>();
+        let removed = job_store::list(&viewer.namespace)
+            .into_iter()
+            .filter(|(id, _)| !jobs.iter().any(|job| &job.id == id))
+            .map(|(id, record)| Job::from_record(id, record))
+            .collect::<Vec<_>>();
+        jobs.extend(removed);
+        jobs.retain(|job| {
+            viewer.sees(job.path.as_deref())
+                && state.is_none_or(|state| job.state == state)
+                && path_prefix.as_ref().is_none_or(|prefix| {
+                    job.path
+                        .as_deref()
+                        .is_some_and(|path| path.starts_with(prefix.as_str()))
+                })
+        });
+        jobs.sort_by(|a, b| (Reverse(a.created), &a.id).cmp(&(Reverse(b.created), &b.id)));
+        let total_count = jobs.len();
+        let start = after
+            .and_then(|after| jobs.iter().position(|job| job.id == after))
+            .map_or(0, |position| position + 1);
+        let nodes = jobs
+            .into_iter()
+            .skip(start)
+            .take(first.min(MAX_PAGE_SIZE))
+            .collect::<Vec<_>>();
+        let has_next_page = start + nodes.len() < total_count;
+        Ok(JobPage {
+            end_cursor: nodes.last().map(|job| job.id.clone()),
+            nodes,
+            total_count,
+            has_next_page,
+        })
+    }
+}
+
+/// Query jobs through GraphQL
+///
+/// Run a GraphQL query over the jobs of the namespace, their states,
+/// lifecycle events and output, as far as the API key of the request
+/// may see, if the dispatcher was started with --graphql
+#[utoipa::path(
+    post,
+    path = "/graphql",
+    tag = "job",
+    operation_id = "queryJobs",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(openapi::Namespace),
+    request_body(
+        content = Object,
+        description = "GraphQL request, holding the query and, optionally, the operationName and variables"
+    ),
+    responses(
+        (status = 200, description = "result of the query, holding its data and any errors in it", body = Object),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to read jobs", body = APIError),
+        (status = 404, description = "the GraphQL endpoint is disabled", body = APIError)
+    )
+)]
+#[post("/graphql")]
+async fn query(
+    request: HttpRequest,
+    schema: web::Data<Option<JobSchema>>,
+    namespace: web::Data<String>,
+    body: web::Json<async_graphql::Request>,
+) -> Result<impl Responder> {
+    let schema = schema
+        .as_ref()
+        .as_ref()
+        .ok_or_else(|| APIError::not_found("The GraphQL endpoint is disabled"))?;
+    docker_service::check_namespace(&request, &namespace)?;
+    let viewer = Viewer {
+        namespace: namespace.to_string(),
+        scope: auth::scope(&request),
+    };
+    let response = schema.execute(body.into_inner().data(viewer)).await;
+    info!("Ran a GraphQL query");
+    Ok(web::Json(response))
+}
@@ -0,0 +1,57 @@
+//! Keeps jobs created on hold from being started, until they're
+//! released. Since container labels can't be changed, held jobs are
+//! labeled as such when created, and their release is recorded apart
+//! where every dispatcher sees it: as a volume in the job's host, or
+//! in the job store with alternative backends, which require it for
+//! holds.
+
+use crate::backend;
+use crate::docker;
+use crate::job_store;
+use anyhow::Result;
+use bollard::models::ContainerSummary;
+use chrono::Utc;
+use tracing::warn;
+
+/// Whether held jobs may be created, which alternative backends only
+/// allow with the job store enabled.
+pub fn is_supported() -> bool {
+    !backend::is_alternative() || job_store::is_enabled()
+}
+
+/// Whether a job was created on hold and hasn't been released yet.
+/// Jobs whose release can't be looked up are considered held.
+pub async fn is_held(container: &ContainerSummary) -> bool {
+    if docker::label(container, docker::HELD_LABEL_KEY) != Some("true") {
+        return false;
+    }
+    let Some(name) = container
+        .names
+        .as_ref()
+        .and_then(|names| names.first())
+        .map(|name| name.trim_start_matches('/'))
+    else {
+        return true;
+    };
+    if backend::is_alternative() {
+        return job_store::get(name).is_none_or(|record| record.released.is_none());
+    }
+    match docker::is_released(name).await {
+        Ok(released) => !released,
+        Err(e) => {
+            warn!("Couldn't tell whether job {:?} was released: {:#}", name, e);
+            true
+        }
+    }
+}
+
+/// Release a held job, so that it may be started.
+pub async fn release(name: &str, namespace: &str) -> Result<()> {
+    if !backend::is_alternative() {
+        docker::mark_released(name, namespace).await?;
+    }
+    job_store::update(name, |record| {
+        record.released = Some(Utc::now().timestamp());
+    });
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Implements the poll-based image pruning task.
+
+use crate::circuit_breaker;
+use crate::docker;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+/// Check whether an image reference is matched by an allowlist
+/// entry. Entries match either a full reference (`repository:tag`) or
+/// every tag of a repository (`repository`).
+fn is_allowed(reference: &str, allowlist: &[String]) -> bool {
+    let repository = reference
+        .rsplit_once(':')
+        .filter(|(_, tag)| !tag.contains('/'))
+        .map_or(reference, |(repository, _)| repository);
+    allowlist
+        .iter()
+        .any(|entry| entry == reference || entry == repository)
+}
+
+/// Record the images used by jobs, and remove the ones that only jobs
+/// used and that have been unused for longer than the given
+/// age. Images used by other containers or matched by the allowlist
+/// are never removed.
+#[tracing::instrument(skip_all)]
+async fn prune(
+    last_used: &mut HashMap<String, Instant>,
+    max_age: Duration,
+    allowlist: &[String],
+    namespace: &str,
+) -> Result<()> {
+    let now = Instant::now();
+    for image in docker::images_in_use(Some(namespace))
+        .await
+        .context("while fetching images used by jobs")?
+    {
+        last_used.insert(image, now);
+    }
+    let in_use = docker::images_in_use(None)
+        .await
+        .context("while fetching images in use")?;
+    // images used by other containers are no longer tracked
+    last_used.retain(|image, used| *used == now || !in_use.contains(image));
+    for image in docker::list_images()
+        .await
+        .context("while fetching images")?
+    {
+        let Some(used) = last_used.get(&image.id) else {
+            continue;
+        };
+        if now.duration_since(*used) < max_age
+            || image
+                .repo_tags
+                .iter()
+                .any(|reference| is_allowed(reference, allowlist))
+        {
+            continue;
+        }
+        info!("Pruning image {:?} ({:?})", image.id, image.repo_tags);
+        match docker::remove_image(&image.id).await {
+            Ok(_) => {
+                last_used.remove(&image.id);
+            }
+            Err(e) => warn!("Couldn't prune image {:?}: {:?}", image.id, e),
+        }
+    }
+    Ok(())
+}
+
+/// Maximum amount of consecutive pruning errors.
+const MAX_ERRORS: u8 = 5;
+
+/// Loop the prune function endlessly.
+pub async fn cycle(
+    unused_for: u32,
+    allowlist: Vec<String>,
+    pruning_interval: u16,
+    namespace: String,
+) -> Result<()> {
+    let mut interval = time::interval(Duration::from_secs(pruning_interval.into()));
+    let max_age = Duration::from_secs(unused_for.into());
+    let mut last_used = HashMap::new();
+    let mut errors: u8 = 0;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => (),
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        }
+        if circuit_breaker::is_open() {
+            debug!("Skipping pruning while the docker daemon is unreachable");
+            continue;
+        }
+        let result = prune(&mut last_used, max_age, &allowlist, &namespace).await;
+        if let Err(ref e) = result {
+            error!("Error while pruning images: {:?}", e);
+            errors += 1;
+            if errors >= MAX_ERRORS {
+                return result.context("received 5 consecutive pruning errors");
+            }
+        } else {
+            errors = 0;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Guards the internal endpoints, i.e. metrics and API docs, which
+//! expose scheduler internals and aren't meant for job submitters.
+
+use crate::api_error::APIError;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE},
+    middleware::Next,
+    web, Error,
+};
+
+/// Access settings of the internal endpoints.
+pub struct Access {
+    pub token: Option<String>,
+}
+
+/// Whether a path belongs to an internal endpoint.
+fn is_internal(path: &str) -> bool {
+    path == "/metrics" || path == "/openapi.json" || path == "/docs" || path.starts_with("/docs/")
+}
+
+/// Compare secrets in time independent of where they differ.
+pub(crate) fn equals(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Whether a request carries the given bearer token.
+fn is_authorized(request: &ServiceRequest, token: &str) -> bool {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|given| equals(given.trim().as_bytes(), token.as_bytes()))
+}
+
+/// Reject requests to internal endpoints lacking the configured
+/// bearer token, if any.
+pub async fn guard(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let token = request
+        .app_data::<web::Data<Access>>()
+        .and_then(|access| access.token.clone());
+    if let Some(token) = token {
+        if is_internal(request.path()) && !is_authorized(&request, &token) {
+            let mut response =
+                request.error_response(APIError::unauthorized("Missing or invalid bearer token"));
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            return Ok(response.map_into_right_body());
+        }
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
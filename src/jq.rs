@@ -45,7 +45,12 @@ fn jq_extensions() -> impl Iterator<Item = (String, usize, Native)> {
 
 /// Compile a filter.
 pub fn compile(filter: &str) -> Result<Filter> {
-    let mut defs = ParseCtx::new(vec!["ENV".to_string(), "PATH".to_string()]);
+    let mut defs = ParseCtx::new(vec![
+        "ENV".to_string(),
+        "PATH".to_string(),
+        "CLAIMS".to_string(),
+        "MESSAGE".to_string(),
+    ]);
     defs.insert_natives(jaq_core::core());
     defs.insert_natives(jq_extensions());
     defs.insert_defs(jaq_std::std());
@@ -61,12 +66,29 @@ pub fn compile(filter: &str) -> Result<Filter> {
 }
 
 /// Execute a compiled filter against an input, and produce the first
-/// serde_json value.
-pub fn first_result(filter: &Filter, input: Value, path: &str) -> Option<Result<Value>> {
+/// serde_json value. The filter is given the request path as `$PATH`,
+/// the claims of the request's token (or null) as `$CLAIMS`, and the
+/// attributes of the message the input was read from (or null) as
+/// `$MESSAGE`.
+pub fn first_result(
+    filter: &Filter,
+    input: Value,
+    path: &str,
+    claims: Value,
+    message: Value,
+) -> Option<Result<Value>> {
     let inputs = RcIter::new(core::iter::empty());
     let mut outputs = filter
         .run((
-            Ctx::new([jq_env(), Val::str(path.to_string())], &inputs),
+            Ctx::new(
+                [
+                    jq_env(),
+                    Val::str(path.to_string()),
+                    Val::from(claims),
+                    Val::from(message),
+                ],
+                &inputs,
+            ),
             Val::from(input),
         ))
         .map(|r| r.map(Value::from).map_err(|e| anyhow!(e.to_string())));
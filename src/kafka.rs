@@ -0,0 +1,172 @@
+//! Consumes jobs from Kafka topics, as an alternative to the HTTP API
+//! for producers already publishing events. Each message goes through
+//! the filter like a request body would, and its offset is committed
+//! only once the job is created.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::retry::Backoff;
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, Context, Result};
+use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+use rdkafka::message::BorrowedMessage;
+use rdkafka::{ClientConfig, Message};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Where and how to consume messages.
+pub struct Source {
+    pub brokers: String,
+    pub topics: Vec<String>,
+    pub group: String,
+    /// Additional consumer options, given as key=value.
+    pub options: Vec<String>,
+}
+
+impl Source {
+    /// Build a consumer subscribed to the topics. Offsets are never
+    /// committed automatically.
+    fn subscribe(&self) -> Result<StreamConsumer> {
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", &self.group)
+            .set("auto.offset.reset", "earliest");
+        for option in self.options.iter().filter(|option| !option.is_empty()) {
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                anyhow!(
+                    "invalid Kafka consumer option {:?}; expected key=value",
+                    option
+                )
+            })?;
+            config.set(key.trim(), value.trim());
+        }
+        config.set("enable.auto.commit", "false");
+        let consumer: StreamConsumer = config
+            .create()
+            .context("while creating the Kafka consumer")?;
+        let topics = self.topics.iter().map(String::as_str).collect::<Vec<_>>();
+        consumer
+            .subscribe(&topics)
+            .context("while subscribing to the Kafka topics")?;
+        Ok(consumer)
+    }
+}
+
+/// Turn a message into a job submission, made available to the filter
+/// as `$MESSAGE`.
+fn submission(message: &BorrowedMessage<'_>) -> Result<Submission, String> {
+    let payload = message.payload().unwrap_or_default();
+    let input = serde_json::from_slice::<Value>(payload)
+        .map_err(|e| format!("payload is not valid JSON: {}", e))?;
+    let topic = message.topic();
+    Ok(Submission {
+        input,
+        path: format!("/kafka/{}", topic),
+        claims: Value::Null,
+        message: json!({
+            "topic": topic,
+            "partition": message.partition(),
+            "offset": message.offset(),
+            "key": message.key().map(String::from_utf8_lossy),
+            "timestamp": message.timestamp().to_millis(),
+        }),
+        reply_subject: None,
+        actor: audit::Actor {
+            submitter: Some(format!("kafka:{}", topic)),
+            ..Default::default()
+        },
+        hold: false,
+        labels: Default::default(),
+        on_duplicate: None,
+    })
+}
+
+/// Create the job of a message, retrying for as long as the failure
+/// isn't the message's fault. Returns once the message may be
+/// committed, or once shutting down.
+async fn process(intake: &Intake, message: &BorrowedMessage<'_>) -> bool {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let submission = match submission(message) {
+            Ok(submission) => submission,
+            Err(e) => {
+                warn!(
+                    "Skipping Kafka message at {}/{}/{}: {}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    e
+                );
+                return true;
+            }
+        };
+        // the error is rendered right away, since it can't be held
+        // across the wait
+        let failure = match docker_service::submit(intake, submission).await {
+            Ok((created, _)) => {
+                debug!(
+                    "Kafka message at {}/{}/{} {} a job",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    if created { "created" } else { "matched" }
+                );
+                return true;
+            }
+            Err(e) => (e.is_permanent(), e.to_string()),
+        };
+        match failure {
+            (true, e) => {
+                warn!(
+                    "Skipping Kafka message at {}/{}/{}: {}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    e
+                );
+                return true;
+            }
+            (false, e) => {
+                warn!(
+                    "Couldn't create the job of the Kafka message at {}/{}/{}; \
+                     retrying: {}",
+                    message.topic(),
+                    message.partition(),
+                    message.offset(),
+                    e
+                );
+                tokio::select! {
+                    _ = backoff.wait() => (),
+                    _ = shutdown::reached(Phase::Stopping) => return false,
+                }
+            }
+        }
+    }
+}
+
+/// Consume messages from the topics, creating a job for each one and
+/// committing its offset once created. Messages the filter or the
+/// policy reject are committed as well, so that they don't block the
+/// partition.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let consumer = source.subscribe()?;
+    info!(
+        "Consuming jobs from the Kafka topics {:?} as group {:?}",
+        source.topics, source.group
+    );
+    loop {
+        let message = tokio::select! {
+            message = consumer.recv() => message.context("while receiving a Kafka message")?,
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        if !process(&intake, &message).await {
+            return Ok(());
+        }
+        consumer
+            .commit_message(&message, CommitMode::Sync)
+            .context("while committing a Kafka offset")?;
+    }
+}
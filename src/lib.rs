@@ -0,0 +1,120 @@
+//! Job-dispatching interface acting as a docker container scheduler.
+//!
+//! Besides the `docker-job-dispatcher` binary, the dispatcher may be
+//! embedded in a larger actix-web application, alongside custom
+//! routes:
+//!
+//! ```no_run
+//! use actix_web::{web, App, HttpServer};
+//! use clap::Parser;
+//! use docker_job_dispatcher::{Cli, Dispatcher};
+//!
+//! # async fn embed() -> anyhow::Result<()> {
+//! let cli = Cli::parse_from(["dispatcher", "--max-concurrent", "2"]);
+//! let dispatcher = Dispatcher::init(cli).await?;
+//! let mut tasks = dispatcher.spawn().await?;
+//! let server = HttpServer::new(move || {
+//!     Dispatcher::wrap(App::new())
+//!         .configure(|cfg| dispatcher.configure(cfg))
+//!         .route("/hello", web::get().to(|| async { "hello" }))
+//! })
+//! .bind(("0.0.0.0", 8000))?
+//! .run();
+//! tokio::select! {
+//!     result = server => result?,
+//!     result = tasks.wait() => result?,
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Dispatcher::wrap`] installs the middleware enforcing the
+//! configured signatures, API keys, tokens, authorization rules, IP
+//! filter and rate limits; custom routes are subject to it as well.
+//!
+//! Most of the dispatcher's state is global, so only one dispatcher
+//! may be initialized per process.
+
+pub mod access_log;
+pub mod admin_service;
+mod amqp;
+pub mod api_error;
+mod app;
+mod archive;
+mod artifacts;
+mod attempts;
+mod audit;
+pub mod auth;
+pub mod authorization;
+pub mod backend;
+mod chat;
+mod circuit_breaker;
+pub mod cleaner;
+mod cloudevents;
+mod config;
+mod cpusets;
+mod dispatch_queue;
+pub mod docker;
+pub mod docker_service;
+mod email;
+mod error_report;
+mod exits;
+mod export;
+mod gpu;
+mod graphql;
+pub mod health_service;
+mod health_watcher;
+mod hold;
+mod image_pruner;
+pub mod internal;
+pub mod ip_filter;
+mod job_store;
+pub mod jq;
+pub mod jwt;
+mod kafka;
+mod kubernetes;
+mod leader;
+pub mod metrics_service;
+mod mqtt;
+mod nats;
+mod nomad;
+mod notifier;
+mod object_store;
+mod openapi;
+mod otlp_metrics;
+pub mod policy;
+mod progress;
+mod pubsub;
+mod pushgateway;
+mod quiet_hours;
+pub mod rate_limit;
+mod redact;
+mod redis_queue;
+mod registry_auth;
+pub mod reload;
+mod report;
+pub mod request_id;
+mod results;
+mod retry;
+pub mod scheduler;
+mod secrets;
+pub mod shard;
+mod shutdown;
+pub mod signature;
+mod socket_activation;
+mod sqs;
+mod ssh_tunnel;
+mod startup;
+pub mod statsd;
+mod supervisor;
+mod swarm;
+mod telemetry;
+pub mod template_service;
+mod templates;
+pub mod tls;
+mod usage;
+
+pub use app::{
+    completions, init_tracing, internal_services, man, no_route, render, Cli, Command, Dispatcher,
+    LogFormat, RenderArgs, Tasks,
+};
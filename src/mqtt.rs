@@ -0,0 +1,170 @@
+//! Subscribes to MQTT topics for jobs, as an alternative to the HTTP
+//! API for IoT-style deployments, where devices publish work requests
+//! to a broker. Each message goes through the filter like a request
+//! body would, and is acknowledged once its job is created.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::retry::Backoff;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::mpsc::channel;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Capacity of the queue of received messages, past which the broker
+/// connection stops taking more.
+const CAPACITY: usize = 16;
+
+/// Where and how to subscribe.
+pub struct Source {
+    /// Broker URL, as `mqtt://host:port` or `mqtts://host:port`.
+    pub url: String,
+    pub topics: Vec<String>,
+    pub qos: u8,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Source {
+    /// Build the connection options. Sessions are kept by the broker
+    /// across reconnections, and messages are acknowledged manually.
+    fn options(&self) -> Result<MqttOptions> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        let mut options = MqttOptions::parse_url(format!(
+            "{}{}client_id={}",
+            self.url, separator, self.client_id
+        ))
+        .context("invalid MQTT broker URL")?;
+        options
+            .set_keep_alive(Duration::from_secs(30))
+            .set_clean_session(false)
+            .set_manual_acks(true);
+        if let Some(username) = &self.username {
+            options.set_credentials(username, self.password.clone().unwrap_or_default());
+        }
+        Ok(options)
+    }
+}
+
+/// Create the job of a message, made available to the filter as
+/// `$MESSAGE`, retrying for as long as the failure isn't the message's
+/// fault. Returns once the message may be acknowledged, or once
+/// shutting down.
+async fn process(intake: &Intake, publish: &Publish) -> bool {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let input = match serde_json::from_slice::<Value>(&publish.payload) {
+            Ok(input) => input,
+            Err(e) => {
+                warn!(
+                    "Rejected an MQTT message on {:?}: payload is not valid JSON: {}",
+                    publish.topic, e
+                );
+                return true;
+            }
+        };
+        let submission = Submission {
+            input,
+            path: format!("/mqtt/{}", publish.topic),
+            claims: Value::Null,
+            message: json!({
+                "topic": publish.topic,
+                "qos": publish.qos as u8,
+                "retain": publish.retain,
+                "dup": publish.dup,
+            }),
+            reply_subject: None,
+            actor: audit::Actor {
+                submitter: Some(format!("mqtt:{}", publish.topic)),
+                ..Default::default()
+            },
+            hold: false,
+            labels: Default::default(),
+            on_duplicate: None,
+        };
+        let failure = match docker_service::submit(intake, submission).await {
+            Ok(_) => {
+                debug!("MQTT message on {:?} made a job", publish.topic);
+                return true;
+            }
+            Err(e) => (e.is_permanent(), e.to_string()),
+        };
+        match failure {
+            (true, e) => {
+                warn!("Rejected an MQTT message on {:?}: {}", publish.topic, e);
+                return true;
+            }
+            (false, e) => {
+                warn!(
+                    "Couldn't create the job of an MQTT message on {:?}; retrying: {}",
+                    publish.topic, e
+                );
+                tokio::select! {
+                    _ = backoff.wait() => (),
+                    _ = shutdown::reached(Phase::Stopping) => return false,
+                }
+            }
+        }
+    }
+}
+
+/// Subscribe to the topics, creating a job for each message received.
+/// The broker connection is polled apart from job creation, so that it
+/// stays alive while jobs are being created.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let qos = rumqttc::qos(source.qos).context("invalid MQTT QoS")?;
+    let (client, mut eventloop) = AsyncClient::new(source.options()?, CAPACITY);
+    for topic in &source.topics {
+        client
+            .subscribe(topic, qos)
+            .await
+            .with_context(|| format!("while subscribing to the MQTT topic {:?}", topic))?;
+    }
+    info!(
+        "Consuming jobs from the MQTT topics {:?} with QoS {}",
+        source.topics, source.qos
+    );
+    let (sender, mut receiver) = channel::<Publish>(CAPACITY);
+    let connection = async move {
+        loop {
+            if let Event::Incoming(Packet::Publish(publish)) = eventloop
+                .poll()
+                .await
+                .context("while polling the MQTT broker")?
+            {
+                if sender.send(publish).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    let dispatch = async {
+        loop {
+            let publish = tokio::select! {
+                publish = receiver.recv() => publish,
+                _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+            };
+            let Some(publish) = publish else {
+                return Ok(());
+            };
+            if !process(&intake, &publish).await {
+                return Ok(());
+            }
+            if publish.qos != QoS::AtMostOnce {
+                client
+                    .ack(&publish)
+                    .await
+                    .context("while acknowledging an MQTT message")?;
+            }
+        }
+    };
+    tokio::select! {
+        result = connection => result,
+        result = dispatch => result,
+    }
+}
@@ -0,0 +1,503 @@
+//! Maps jobs to parameterized HashiCorp Nomad batch jobs.
+
+use crate::backend::{self, Backend, Creation};
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::Config,
+    models::{
+        ContainerConfig, ContainerInspectResponse, ContainerState, ContainerStateStatusEnum,
+        ContainerSummary,
+    },
+};
+use chrono::{DateTime, Utc};
+use reqwest::{Client, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Name of the single task group, and task, of each job.
+const TASK_NAME: &str = "job";
+
+/// Prefix of the errors given by Nomad when a registration enforcing
+/// the job modify index fails.
+const ENFORCE_INDEX_ERROR_PREFIX: &str = "Enforcing job modify index";
+
+/// Settings for connecting to the Nomad API.
+pub struct Settings {
+    pub address: String,
+    pub token: Option<String>,
+    pub namespace: Option<String>,
+    pub datacenters: Vec<String>,
+}
+
+/// A backend dispatching jobs to a Nomad cluster.
+pub struct Nomad {
+    client: Client,
+    settings: Settings,
+}
+
+/// The relevant part of a job listing entry.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JobStub {
+    #[serde(rename = "ID")]
+    id: String,
+    #[serde(rename = "ParentID", default)]
+    parent_id: String,
+    #[serde(default)]
+    parameterized_job: bool,
+    status: String,
+    submit_time: Option<i64>,
+    meta: Option<HashMap<String, String>>,
+    job_summary: Option<JobSummary>,
+}
+
+/// The summary of the allocations of a job.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct JobSummary {
+    summary: HashMap<String, TaskGroupSummary>,
+}
+
+/// The allocation counts of a task group.
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskGroupSummary {
+    #[serde(default)]
+    complete: u64,
+    #[serde(default)]
+    failed: u64,
+    #[serde(default)]
+    lost: u64,
+}
+
+/// The relevant part of an allocation.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Allocation {
+    #[serde(rename = "ID")]
+    id: String,
+    create_time: i64,
+    task_states: Option<HashMap<String, TaskState>>,
+}
+
+/// The state of a task within an allocation.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskState {
+    started_at: Option<DateTime<Utc>>,
+    finished_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    events: Vec<TaskEvent>,
+}
+
+/// An event of a task.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct TaskEvent {
+    #[serde(default)]
+    details: HashMap<String, String>,
+}
+
+/// Convert a Nomad timestamp, in nanoseconds, to a date.
+fn timestamp(nanos: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_nanos(nanos)
+}
+
+impl Nomad {
+    /// Prepare a client for the Nomad API, and check that it's
+    /// reachable.
+    pub async fn connect(settings: Settings) -> Result<Self> {
+        let nomad = Self {
+            client: Client::new(),
+            settings,
+        };
+        nomad.ping().await.context("while connecting to Nomad")?;
+        Ok(nomad)
+    }
+
+    /// Build a request to the Nomad API, including the token and
+    /// namespace.
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut request = self.client.request(
+            method,
+            format!("{}/v1{}", self.settings.address.trim_end_matches('/'), path),
+        );
+        if let Some(token) = &self.settings.token {
+            request = request.header("X-Nomad-Token", token);
+        }
+        if let Some(namespace) = &self.settings.namespace {
+            request = request.query(&[("namespace", namespace)]);
+        }
+        request
+    }
+
+    /// List jobs, including their metadata, optionally restricted to
+    /// the ones with the given ID prefix.
+    async fn list_jobs(&self, prefix: Option<&str>) -> Result<Vec<JobStub>> {
+        let mut request = self
+            .request(reqwest::Method::GET, "/jobs")
+            .query(&[("meta", "true")]);
+        if let Some(prefix) = prefix {
+            request = request.query(&[("prefix", prefix)]);
+        }
+        request
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("while listing Nomad jobs")
+    }
+
+    /// Get the most recent allocation of a job's dispatched children.
+    async fn allocation(&self, children: &[&JobStub]) -> Result<Option<Allocation>> {
+        let mut latest: Option<Allocation> = None;
+        for child in children {
+            let allocations: Vec<Allocation> = self
+                .request(
+                    reqwest::Method::GET,
+                    &format!("/job/{}/allocations", urlencode(&child.id)),
+                )
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await
+                .context("while listing Nomad allocations")?;
+            for allocation in allocations {
+                if latest
+                    .as_ref()
+                    .is_none_or(|latest| allocation.create_time > latest.create_time)
+                {
+                    latest = Some(allocation);
+                }
+            }
+        }
+        Ok(latest)
+    }
+
+    /// Fetch a log stream of a task.
+    async fn task_log(&self, allocation: &str, kind: &str) -> Result<Vec<u8>> {
+        Ok(self
+            .request(
+                reqwest::Method::GET,
+                &format!("/client/fs/logs/{}", allocation),
+            )
+            .query(&[
+                ("task", TASK_NAME),
+                ("type", kind),
+                ("origin", "start"),
+                ("plain", "true"),
+            ])
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?
+            .to_vec())
+    }
+}
+
+/// Percent-encode a job ID for use in a URL path, since the IDs of
+/// dispatched jobs contain slashes.
+fn urlencode(id: &str) -> String {
+    id.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+/// Build a parameterized batch job out of a container configuration,
+/// running it with the docker driver. Container labels are kept as job
+/// metadata. The job is registered only if it doesn't exist yet, by
+/// enforcing a modify index of zero.
+fn job(name: &str, config: Config<String>, datacenters: &[String]) -> Value {
+    let env: HashMap<String, String> = config
+        .env
+        .unwrap_or_default()
+        .into_iter()
+        .map(|var| match var.split_once('=') {
+            Some((name, value)) => (name.to_string(), value.to_string()),
+            None => (var, String::new()),
+        })
+        .collect();
+    let mut driver_config = json!({ "image": config.image });
+    if let Some(entrypoint) = config.entrypoint {
+        driver_config["entrypoint"] = json!(entrypoint);
+    }
+    if let Some(cmd) = config.cmd {
+        driver_config["args"] = json!(cmd);
+    }
+    if let Some(working_dir) = config.working_dir {
+        driver_config["work_dir"] = json!(working_dir);
+    }
+    if let Some(hostname) = config.hostname {
+        driver_config["hostname"] = json!(hostname);
+    }
+    let mut resources = json!({});
+    if let Some(memory) = config
+        .host_config
+        .and_then(|host_config| host_config.memory)
+    {
+        resources["MemoryMB"] = json!((memory / (1024 * 1024)).max(1));
+    }
+    json!({
+        "Job": {
+            "ID": name,
+            "Name": name,
+            "Type": "batch",
+            "Datacenters": datacenters,
+            "Meta": config.labels.unwrap_or_default(),
+            "ParameterizedJob": {},
+            "TaskGroups": [{
+                "Name": TASK_NAME,
+                "Count": 1,
+                "RestartPolicy": { "Attempts": 0, "Mode": "fail" },
+                "ReschedulePolicy": { "Attempts": 0, "Unlimited": false },
+                "Tasks": [{
+                    "Name": TASK_NAME,
+                    "Driver": "docker",
+                    "Config": driver_config,
+                    "Env": env,
+                    "Resources": resources,
+                }],
+            }],
+        },
+        "EnforceIndex": true,
+        "JobModifyIndex": 0,
+    })
+}
+
+/// Get the equivalent container state of a job, given its dispatched
+/// children.
+fn state(children: &[&JobStub]) -> ContainerStateStatusEnum {
+    let Some(child) = children.iter().max_by_key(|child| child.submit_time) else {
+        return ContainerStateStatusEnum::CREATED;
+    };
+    if child.status == "dead" {
+        ContainerStateStatusEnum::EXITED
+    } else {
+        ContainerStateStatusEnum::RUNNING
+    }
+}
+
+/// Whether the most recent dispatched child of a job failed.
+fn failed(children: &[&JobStub]) -> bool {
+    children
+        .iter()
+        .max_by_key(|child| child.submit_time)
+        .and_then(|child| child.job_summary.as_ref())
+        .and_then(|summary| summary.summary.get(TASK_NAME))
+        .is_some_and(|summary| summary.failed + summary.lost > 0 || summary.complete == 0)
+}
+
+/// Describe a parameterized job as a listed container.
+fn summary(job: &JobStub, children: &[&JobStub]) -> ContainerSummary {
+    let state = state(children).to_string();
+    ContainerSummary {
+        id: Some(job.id.clone()),
+        names: Some(vec![format!("/{}", job.id)]),
+        created: job.submit_time.map(|nanos| timestamp(nanos).timestamp()),
+        labels: job.meta.clone(),
+        status: Some(state.clone()),
+        state: Some(state),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl Backend for Nomad {
+    async fn ping(&self) -> Result<()> {
+        self.request(reqwest::Method::GET, "/status/leader")
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn create(&self, creation: Creation) -> Result<Option<String>> {
+        let Creation { name, config, .. } = creation;
+        let existing = self
+            .request(reqwest::Method::GET, &format!("/job/{}", urlencode(&name)))
+            .send()
+            .await?;
+        if existing.status() != StatusCode::NOT_FOUND {
+            existing.error_for_status()?;
+            return Ok(None);
+        }
+        let response = self
+            .request(reqwest::Method::PUT, "/jobs")
+            .json(&job(&name, config, &self.settings.datacenters))
+            .send()
+            .await?;
+        if let Some(e) = response.error_for_status_ref().err() {
+            // another dispatcher registered the job in the meantime
+            let body = response.text().await.unwrap_or_default();
+            if body.starts_with(ENFORCE_INDEX_ERROR_PREFIX) {
+                return Ok(None);
+            }
+            return Err(anyhow::Error::new(e)
+                .context(body)
+                .context("while registering Nomad job"));
+        }
+        Ok(Some(name))
+    }
+
+    async fn start(&self, name: &str) -> Result<()> {
+        self.request(
+            reqwest::Method::POST,
+            &format!("/job/{}/dispatch", urlencode(name)),
+        )
+        .json(&json!({}))
+        .send()
+        .await?
+        .error_for_status()
+        .context("while dispatching Nomad job")?;
+        Ok(())
+    }
+
+    async fn list(&self, filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>> {
+        let label_filters: Vec<(&str, &str)> = filters
+            .get("label")
+            .map(|labels| {
+                labels
+                    .iter()
+                    .filter_map(|label| label.split_once('='))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let names = backend::filter_names(filters);
+        let jobs = self.list_jobs(None).await?;
+        Ok(jobs
+            .iter()
+            .filter(|job| job.parameterized_job && job.parent_id.is_empty())
+            .filter(|job| {
+                names
+                    .as_ref()
+                    .is_none_or(|names| names.contains(&job.id.as_str()))
+            })
+            .filter(|job| {
+                label_filters.iter().all(|(key, value)| {
+                    job.meta
+                        .as_ref()
+                        .and_then(|meta| meta.get(*key))
+                        .is_some_and(|v| v == value)
+                })
+            })
+            .map(|job| {
+                let children: Vec<&JobStub> = jobs
+                    .iter()
+                    .filter(|child| child.parent_id == job.id)
+                    .collect();
+                summary(job, &children)
+            })
+            .filter(|container| backend::status_matches(filters, container))
+            .collect())
+    }
+
+    async fn inspect(&self, name: &str) -> Result<ContainerInspectResponse> {
+        let response = self
+            .request(reqwest::Method::GET, &format!("/job/{}", urlencode(name)))
+            .send()
+            .await?
+            .error_for_status()
+            .with_context(|| format!("while fetching Nomad job {:?}", name))?;
+        let job: Value = response.json().await?;
+        let jobs = self.list_jobs(Some(name)).await?;
+        let children: Vec<&JobStub> = jobs.iter().filter(|job| job.parent_id == name).collect();
+        let state = state(&children);
+        let exited = state == ContainerStateStatusEnum::EXITED;
+        let task_state = match state {
+            ContainerStateStatusEnum::CREATED => None,
+            _ => self
+                .allocation(&children)
+                .await?
+                .and_then(|allocation| allocation.task_states)
+                .and_then(|mut states| states.remove(TASK_NAME)),
+        };
+        let exit_code = task_state
+            .as_ref()
+            .and_then(|state| {
+                state
+                    .events
+                    .iter()
+                    .rev()
+                    .find_map(|event| event.details.get("exit_code"))
+            })
+            .and_then(|code| code.parse().ok())
+            .or_else(|| exited.then(|| if failed(&children) { 1 } else { 0 }));
+        let task = &job["TaskGroups"][0]["Tasks"][0];
+        let strings =
+            |value: &Value| -> Option<Vec<String>> { serde_json::from_value(value.clone()).ok() };
+        Ok(ContainerInspectResponse {
+            id: Some(name.to_string()),
+            name: Some(format!("/{}", name)),
+            created: job["SubmitTime"]
+                .as_i64()
+                .map(|nanos| timestamp(nanos).to_rfc3339()),
+            state: Some(ContainerState {
+                running: Some(state == ContainerStateStatusEnum::RUNNING),
+                exit_code: exit_code.filter(|_| exited),
+                started_at: task_state
+                    .as_ref()
+                    .and_then(|state| state.started_at)
+                    .map(|started| started.to_rfc3339()),
+                finished_at: task_state
+                    .as_ref()
+                    .and_then(|state| state.finished_at)
+                    .filter(|_| exited)
+                    .map(|finished| finished.to_rfc3339()),
+                status: Some(state),
+                ..Default::default()
+            }),
+            config: Some(ContainerConfig {
+                image: task["Config"]["image"].as_str().map(String::from),
+                labels: serde_json::from_value(job["Meta"].clone()).ok(),
+                cmd: strings(&task["Config"]["args"]),
+                entrypoint: strings(&task["Config"]["entrypoint"]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+    }
+
+    async fn logs(&self, name: &str) -> Result<Vec<u8>> {
+        let jobs = self.list_jobs(Some(name)).await?;
+        let children: Vec<&JobStub> = jobs.iter().filter(|job| job.parent_id == name).collect();
+        let Some(allocation) = self.allocation(&children).await? else {
+            return Ok(Vec::new());
+        };
+        let mut output = self.task_log(&allocation.id, "stdout").await?;
+        output.extend(self.task_log(&allocation.id, "stderr").await?);
+        Ok(output)
+    }
+
+    /// Dispatched children are deregistered along with the job.
+    async fn remove(&self, name: &str, _volumes: bool) -> Result<()> {
+        let jobs = self.list_jobs(Some(name)).await?;
+        for id in jobs
+            .iter()
+            .filter(|job| job.parent_id == name)
+            .map(|job| job.id.as_str())
+            .chain(std::iter::once(name))
+        {
+            let response = self
+                .request(reqwest::Method::DELETE, &format!("/job/{}", urlencode(id)))
+                .query(&[("purge", "true")])
+                .send()
+                .await?;
+            if response.status() != StatusCode::NOT_FOUND {
+                response
+                    .error_for_status()
+                    .with_context(|| format!("while deregistering Nomad job {:?}", id))?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//! Generates the OpenAPI document of the API out of the annotations on
+//! its handlers and the types they exchange, so that the served
+//! document can't drift from the implementation.
+
+use crate::{
+    admin_service, api_error, attempts, cleaner, docker_service, graphql, health_service, progress,
+    report, template_service, usage,
+};
+use once_cell::sync::Lazy;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{IntoParams, Modify, OpenApi};
+
+/// The headers naming the namespace a request is meant for.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Header)]
+#[allow(dead_code)]
+pub(crate) struct Namespace {
+    /// Namespace the request is meant for; requests meant for a
+    /// namespace other than the one served are refused
+    #[param(rename = "X-Dispatcher-Namespace")]
+    dispatcher_namespace: Option<String>,
+    /// Namespace the request is meant for, among the one served and
+    /// those of the known shards; requests selecting another served
+    /// namespace are forwarded to the instance serving it, and requests
+    /// also giving X-Dispatcher-Namespace must name the same namespace
+    /// in each
+    #[param(rename = "X-Namespace")]
+    namespace: Option<String>,
+}
+
+/// The header asking for a job to be created on hold.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Header)]
+#[allow(dead_code)]
+pub(crate) struct Hold {
+    /// Whether the job is created on hold, not to be started until
+    /// released; either true or false
+    #[param(rename = "X-Hold")]
+    hold: Option<bool>,
+}
+
+/// Adds the ways requests may be authenticated.
+struct Authentication;
+
+impl Modify for Authentication {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearerAuth",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "API key or JWT given as a bearer token, when either is required",
+                    ))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "apiKey",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "X-Api-Key",
+                "API key, when API keys are required",
+            ))),
+        );
+    }
+}
+
+/// The OpenAPI document of the API.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Docker job dispatcher",
+        description = "This is a simple facade over the docker API that converts requests into containers to operate as jobs."
+    ),
+    external_docs(
+        url = "https://github.com/kklingenberg/docker-job-dispatcher",
+        description = "Github repository"
+    ),
+    tags(
+        (name = "job", description = "Create and fetch jobs"),
+        (name = "health", description = "Diagnose the API"),
+        (name = "admin", description = "Administer the dispatcher")
+    ),
+    paths(
+        docker_service::create_job,
+        docker_service::create_job_with_path,
+        docker_service::get_job,
+        docker_service::release_job,
+        docker_service::report_progress,
+        docker_service::get_job_logs,
+        docker_service::summarize_jobs,
+        docker_service::export_jobs,
+        template_service::create_job_from_template,
+        graphql::query,
+        health_service::liveness_check,
+        health_service::readiness_check,
+        admin_service::preview_cleaner,
+        admin_service::purge,
+        admin_service::reload,
+        admin_service::show_config,
+        template_service::put_template
+    ),
+    components(schemas(
+        docker_service::JobSummary,
+        docker_service::JobMetadata,
+        docker_service::SidecarSummary,
+        report::JobGroup,
+        attempts::StartFailure,
+        usage::Usage,
+        progress::Progress,
+        cleaner::Removal,
+        cleaner::Purge,
+        cleaner::PurgeFailure,
+        admin_service::PurgeRequest,
+        admin_service::ReloadResponse,
+        api_error::APIError
+    )),
+    modifiers(&Authentication)
+)]
+pub struct ApiDoc;
+
+/// The OpenAPI document, serialized once.
+static DOCUMENT: Lazy<String> = Lazy::new(|| {
+    let mut document = ApiDoc::openapi();
+    // the crate declares no license, which would be reported as an
+    // empty one
+    document.info.license = None;
+    document.to_pretty_json().unwrap()
+});
+
+/// Get the OpenAPI document as JSON.
+pub fn json() -> &'static str {
+    &DOCUMENT
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ApiDoc;
+    use crate::app;
+    use actix_web::{http::Method, http::StatusCode, test, web, App, HttpRequest, HttpResponse};
+    use std::collections::BTreeSet;
+    use utoipa::OpenApi;
+
+    /// Routes registered outside of the API, on the internal services.
+    const INTERNAL_ROUTES: [&str; 3] = ["get /metrics", "get /openapi.json", "get /docs"];
+
+    /// Methods the routes are probed with.
+    const METHODS: [Method; 5] = [
+        Method::GET,
+        Method::POST,
+        Method::PUT,
+        Method::PATCH,
+        Method::DELETE,
+    ];
+
+    /// Status given by the test application to requests matching no
+    /// route.
+    const UNROUTED: StatusCode = StatusCode::IM_A_TEAPOT;
+
+    /// Convert a resource pattern to OpenAPI's syntax, i.e. drop the
+    /// patterns of its parameters, as in {path:.*}.
+    fn normalize(pattern: &str) -> String {
+        let mut normalized = String::new();
+        let mut in_pattern = false;
+        for c in pattern.chars() {
+            match c {
+                ':' if normalized.rfind('{') > normalized.rfind('}') => in_pattern = true,
+                '}' => {
+                    in_pattern = false;
+                    normalized.push(c);
+                }
+                _ if !in_pattern => normalized.push(c),
+                _ => (),
+            }
+        }
+        normalized
+    }
+
+    /// A path matching a resource pattern, with a sample value for
+    /// each parameter; parameters matching anything get several
+    /// segments, so that they don't match single-segment parameters.
+    fn sample(pattern: &str) -> String {
+        let mut sample = String::new();
+        let mut parameter = None;
+        for c in pattern.chars() {
+            match (c, parameter.as_mut()) {
+                ('{', None) => parameter = Some(String::new()),
+                ('}', Some(name)) => {
+                    sample.push_str(if name.contains(':') { "x/y/z" } else { "x" });
+                    parameter = None;
+                }
+                (_, Some(name)) => name.push(c),
+                (_, None) => sample.push(c),
+            }
+        }
+        sample
+    }
+
+    /// Collect the routes of the application, as "method path" with
+    /// the path in OpenAPI's syntax. The resource patterns are taken
+    /// from the application's resource map, and each of them is probed
+    /// with every method, keeping the pattern of the resource that
+    /// handled the request.
+    async fn registered_routes() -> BTreeSet<String> {
+        let application = test::init_service(
+            App::new()
+                .configure(app::services)
+                .configure(app::internal_services)
+                .default_service(web::to(|request: HttpRequest| async move {
+                    HttpResponse::build(UNROUTED).body(format!("{:?}", request.resource_map()))
+                })),
+        )
+        .await;
+        let response =
+            test::call_service(&application, test::TestRequest::get().uri("/").to_request()).await;
+        let resource_map = String::from_utf8(test::read_body(response).await.to_vec()).unwrap();
+        let patterns: BTreeSet<String> = resource_map
+            .split("patterns: Single(\"")
+            .skip(1)
+            .filter_map(|rest| rest.split_once("\")").map(|(pattern, _)| pattern))
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect();
+        assert!(patterns.contains("/job/{path:.*}"));
+        let mut routes = BTreeSet::new();
+        for pattern in &patterns {
+            for method in METHODS {
+                let request = test::TestRequest::default()
+                    .method(method.clone())
+                    .uri(&sample(pattern))
+                    .to_request();
+                let response = test::call_service(&application, request).await;
+                if matches!(response.status(), UNROUTED | StatusCode::METHOD_NOT_ALLOWED) {
+                    continue;
+                }
+                if let Some(matched) = response.request().match_pattern() {
+                    routes.insert(format!(
+                        "{} {}",
+                        method.as_str().to_lowercase(),
+                        normalize(&matched)
+                    ));
+                }
+            }
+        }
+        routes
+    }
+
+    /// Collect the routes in the OpenAPI document, as "method path".
+    fn documented_routes() -> BTreeSet<String> {
+        let document = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        document["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, item)| {
+                item.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| format!("{} {}", method, path))
+            })
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn every_registered_route_is_documented() {
+        let registered = registered_routes().await;
+        assert!(registered.contains("post /job/{path}"));
+        for route in INTERNAL_ROUTES {
+            assert!(registered.contains(route), "missing internal route {route}");
+        }
+        let documented = documented_routes();
+        let undocumented = registered
+            .iter()
+            .filter(|route| !INTERNAL_ROUTES.contains(&route.as_str()))
+            .filter(|route| !documented.contains(*route))
+            .collect::<Vec<_>>();
+        assert!(
+            undocumented.is_empty(),
+            "undocumented routes: {:?}",
+            undocumented
+        );
+        let unregistered = documented.difference(&registered).collect::<Vec<_>>();
+        assert!(
+            unregistered.is_empty(),
+            "documented routes that don't exist: {:?}",
+            unregistered
+        );
+    }
+}
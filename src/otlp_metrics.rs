@@ -0,0 +1,303 @@
+//! Pushes the metrics exposed in `/metrics` to an OpenTelemetry
+//! collector, using the JSON encoding of OTLP over HTTP.
+//!
+//! Metrics are collected in OpenMetrics format, so they're converted
+//! from their text encoding: counters are sent as monotonic cumulative
+//! sums, gauges as gauges and histograms as cumulative histograms.
+
+use crate::metrics_service;
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, Context, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{self, Duration};
+use tracing::{debug, error};
+
+/// OTLP aggregation temporality of cumulative metrics.
+const CUMULATIVE: u8 = 2;
+
+/// Label set of a sample.
+type Labels = BTreeMap<String, String>;
+
+/// A sample of the text encoding.
+struct Sample {
+    name: String,
+    labels: Labels,
+    value: f64,
+}
+
+/// A metric family of the text encoding.
+#[derive(Default)]
+struct Metric {
+    name: String,
+    kind: String,
+    help: String,
+    samples: Vec<Sample>,
+}
+
+/// The series of a histogram with a given label set.
+#[derive(Default)]
+struct Series {
+    buckets: Vec<(f64, f64)>,
+    sum: f64,
+    count: f64,
+}
+
+/// Parse a sample line, e.g. `jobs_total{namespace="default"} 3`.
+fn parse_sample(line: &str) -> Option<Sample> {
+    let (name, mut rest) = line.split_at(line.find(['{', ' '])?);
+    let mut labels = Labels::new();
+    if let Some(body) = rest.strip_prefix('{') {
+        let mut chars = body.char_indices();
+        let mut key = String::new();
+        loop {
+            match chars.next()? {
+                (i, '}') => {
+                    rest = &body[i + 1..];
+                    break;
+                }
+                (_, ',') => (),
+                (_, '=') => {
+                    if chars.next()?.1 != '"' {
+                        return None;
+                    }
+                    let mut value = String::new();
+                    loop {
+                        match chars.next()?.1 {
+                            '"' => break,
+                            '\\' => match chars.next()?.1 {
+                                'n' => value.push('\n'),
+                                c => value.push(c),
+                            },
+                            c => value.push(c),
+                        }
+                    }
+                    labels.insert(std::mem::take(&mut key), value);
+                }
+                (_, c) => key.push(c),
+            }
+        }
+    }
+    let value = rest.split_whitespace().next()?.parse().ok()?;
+    Some(Sample {
+        name: name.to_string(),
+        labels,
+        value,
+    })
+}
+
+/// Parse the text encoding into metric families.
+fn parse(text: &str) -> Vec<Metric> {
+    let mut metrics: Vec<Metric> = Vec::new();
+    for line in text.lines() {
+        if let Some(comment) = line.strip_prefix("# ") {
+            let mut parts = comment.splitn(3, ' ');
+            let (Some(keyword), Some(name)) = (parts.next(), parts.next()) else {
+                continue;
+            };
+            if !matches!(metrics.last(), Some(metric) if metric.name == name) {
+                metrics.push(Metric {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+            }
+            let text = parts.next().unwrap_or_default().to_string();
+            if let Some(metric) = metrics.last_mut() {
+                match keyword {
+                    "HELP" => metric.help = text,
+                    "TYPE" => metric.kind = text,
+                    _ => (),
+                }
+            }
+        } else if let Some(sample) = parse_sample(line) {
+            if let Some(metric) = metrics.last_mut() {
+                metric.samples.push(sample);
+            }
+        }
+    }
+    metrics
+}
+
+/// Convert a label set to OTLP attributes.
+fn attributes(labels: &Labels) -> Vec<Value> {
+    labels
+        .iter()
+        .map(|(key, value)| json!({"key": key, "value": {"stringValue": value}}))
+        .collect()
+}
+
+/// Build the OTLP data points of a histogram.
+fn histogram_points(metric: &Metric, start: &str, now: &str) -> Vec<Value> {
+    let mut series: BTreeMap<Labels, Series> = BTreeMap::new();
+    for sample in &metric.samples {
+        let mut labels = sample.labels.clone();
+        let bound = labels.remove("le").and_then(|le| le.parse().ok());
+        let entry = series.entry(labels).or_default();
+        match sample.name.strip_prefix(&metric.name) {
+            Some("_bucket") => {
+                if let Some(bound) = bound {
+                    entry.buckets.push((bound, sample.value));
+                }
+            }
+            Some("_sum") => entry.sum = sample.value,
+            Some("_count") => entry.count = sample.value,
+            _ => (),
+        }
+    }
+    series
+        .into_iter()
+        .map(|(labels, mut series)| {
+            series.buckets.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let bounds: Vec<f64> = series
+                .buckets
+                .iter()
+                .map(|(bound, _)| *bound)
+                .filter(|bound| bound.is_finite())
+                .collect();
+            // buckets are cumulative in the text encoding, but not in OTLP
+            let mut previous = 0.0;
+            let mut counts: Vec<String> = series
+                .buckets
+                .iter()
+                .map(|(_, count)| {
+                    let bucket = count - previous;
+                    previous = *count;
+                    (bucket as u64).to_string()
+                })
+                .collect();
+            if counts.len() == bounds.len() {
+                counts.push(((series.count - previous) as u64).to_string());
+            }
+            json!({
+                "attributes": attributes(&labels),
+                "startTimeUnixNano": start,
+                "timeUnixNano": now,
+                "count": (series.count as u64).to_string(),
+                "sum": series.sum,
+                "bucketCounts": counts,
+                "explicitBounds": bounds,
+            })
+        })
+        .collect()
+}
+
+/// Convert a metric family to an OTLP metric, if its type is
+/// supported.
+fn convert(metric: &Metric, start: &str, now: &str) -> Option<Value> {
+    let points = |suffix: &str| -> Vec<Value> {
+        metric
+            .samples
+            .iter()
+            .filter(|sample| sample.name.strip_prefix(&metric.name) == Some(suffix))
+            .map(|sample| {
+                json!({
+                    "attributes": attributes(&sample.labels),
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": now,
+                    "asDouble": sample.value,
+                })
+            })
+            .collect()
+    };
+    let (key, data) = match metric.kind.as_str() {
+        "counter" => (
+            "sum",
+            json!({
+                "aggregationTemporality": CUMULATIVE,
+                "isMonotonic": true,
+                "dataPoints": points("_total"),
+            }),
+        ),
+        "gauge" => ("gauge", json!({"dataPoints": points("")})),
+        "histogram" => (
+            "histogram",
+            json!({
+                "aggregationTemporality": CUMULATIVE,
+                "dataPoints": histogram_points(metric, start, now),
+            }),
+        ),
+        _ => return None,
+    };
+    let mut converted = json!({"name": metric.name, "description": metric.help});
+    converted[key] = data;
+    Some(converted)
+}
+
+/// Get the given time as UNIX nanoseconds, encoded as OTLP expects.
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+/// Build the HTTP client used to push metrics, given extra headers
+/// as key=value.
+pub fn client(headers: &[String]) -> Result<reqwest::Client> {
+    let mut map = HeaderMap::new();
+    for header in headers {
+        let (key, value) = header
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid OTLP header {:?}; expected key=value", header))?;
+        map.insert(
+            HeaderName::from_bytes(key.trim().as_bytes())
+                .with_context(|| format!("while parsing the OTLP header {:?}", header))?,
+            HeaderValue::from_str(value.trim())
+                .with_context(|| format!("while parsing the OTLP header {:?}", header))?,
+        );
+    }
+    reqwest::Client::builder()
+        .default_headers(map)
+        .build()
+        .context("while building the OTLP metrics client")
+}
+
+/// Push the current metrics once.
+async fn push(client: &reqwest::Client, endpoint: &str, start: &str) -> Result<()> {
+    let now = unix_nanos(SystemTime::now());
+    let metrics: Vec<Value> = parse(&metrics_service::encoded().await?)
+        .iter()
+        .filter_map(|metric| convert(metric, start, &now))
+        .collect();
+    debug!("Pushing {} metrics over OTLP", metrics.len());
+    client
+        .post(endpoint)
+        .json(&json!({
+            "resourceMetrics": [{
+                "resource": {"attributes": [
+                    {"key": "service.name", "value": {"stringValue": env!("CARGO_PKG_NAME")}},
+                    {"key": "service.version", "value": {"stringValue": env!("CARGO_PKG_VERSION")}},
+                ]},
+                "scopeMetrics": [{
+                    "scope": {"name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION")},
+                    "metrics": metrics,
+                }],
+            }],
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("while pushing metrics")?;
+    Ok(())
+}
+
+/// Push metrics to the endpoint endlessly, and once more when shutting
+/// down. Push failures are logged and otherwise ignored.
+pub async fn cycle(client: reqwest::Client, endpoint: String, push_interval: u16) -> Result<()> {
+    let start = unix_nanos(SystemTime::now());
+    let mut interval = time::interval(Duration::from_secs(push_interval.into()));
+    loop {
+        let flushing = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown::reached(Phase::Flushing) => true,
+        };
+        if let Err(e) = push(&client, &endpoint, &start).await {
+            error!("Couldn't push metrics over OTLP: {:?}", e);
+        }
+        if flushing {
+            return Ok(());
+        }
+    }
+}
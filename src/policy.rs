@@ -0,0 +1,511 @@
+//! Enforces a security policy on the job manifests produced by the
+//! filter.
+
+use anyhow::{Context, Result};
+use bollard::{
+    container::Config,
+    models::{HostConfig, Mount, MountTypeEnum},
+};
+use clap::ValueEnum;
+use glob::Pattern;
+use serde::Serialize;
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+
+/// How strictly job images must be pinned to a specific version.
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pinning {
+    Any,
+    Tag,
+    Digest,
+}
+
+/// What hardening does to manifests with dangerous fields.
+#[derive(Clone, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Hardening {
+    Reject,
+    Strip,
+}
+
+/// Paths of the docker daemon socket, access to which equals root on
+/// the host.
+const DOCKER_SOCKETS: [&str; 2] = ["/var/run/docker.sock", "/run/docker.sock"];
+
+/// Rules job manifests must abide by.
+#[derive(Clone, Debug)]
+pub struct Policy {
+    /// Patterns images must match, if any is given.
+    pub allow_images: Vec<Pattern>,
+    /// Patterns images must not match.
+    pub deny_images: Vec<Pattern>,
+    /// Pinning required of images.
+    pub pinning: Pinning,
+    /// Whether privileged containers, shared namespaces, host devices,
+    /// other containers' volumes, unconfined security options and bind
+    /// mounts are forbidden.
+    pub restrict_host_access: bool,
+    /// Host paths that may be bind-mounted despite the restriction.
+    pub allow_binds: Vec<PathBuf>,
+    /// What to do with dangerous fields regardless of the rest of the
+    /// policy, if anything.
+    pub hardening: Option<Hardening>,
+    /// Maximum resource limits.
+    pub max: Limits,
+    /// Whether resource limits above the maximum are lowered to it
+    /// instead of rejected.
+    pub clamp: bool,
+    /// Resource limits given to jobs that don't set their own.
+    pub default: Limits,
+}
+
+/// Resource limits of a job.
+#[derive(Clone, Debug, Default)]
+pub struct Limits {
+    /// Memory limit in bytes.
+    pub memory: Option<i64>,
+    /// CPU quota in units of 10^-9 CPUs.
+    pub nano_cpus: Option<i64>,
+    /// Maximum number of processes.
+    pub pids: Option<i64>,
+}
+
+/// A manifest field breaking the policy.
+#[derive(Debug)]
+pub struct Violation {
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.reason)
+    }
+}
+
+/// Build a violation of the given field.
+fn violation<F: ToString, R: ToString>(field: F, reason: R) -> Violation {
+    Violation {
+        field: field.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Compile the image patterns given as arguments.
+pub fn compile_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Pattern::new(pattern).with_context(|| format!("invalid image pattern {:?}", pattern))
+        })
+        .collect()
+}
+
+/// Split an image reference into its name, tag and digest.
+fn parse_image(image: &str) -> (&str, Option<&str>, Option<&str>) {
+    let (image, digest) = match image.split_once('@') {
+        Some((image, digest)) => (image, Some(digest)),
+        None => (image, None),
+    };
+    // a colon before the last slash separates a registry port
+    let name_start = image.rfind('/').map(|i| i + 1).unwrap_or(0);
+    match image[name_start..].rfind(':') {
+        Some(i) => (
+            &image[..name_start + i],
+            Some(&image[name_start + i + 1..]),
+            digest,
+        ),
+        None => (image, None, digest),
+    }
+}
+
+/// Whether a bind-mounted host path is within the allowed paths. Paths
+/// going up the hierarchy are never allowed.
+fn is_bind_allowed(source: &str, allowed: &[PathBuf]) -> bool {
+    let path = Path::new(source);
+    !path.components().any(|c| c == Component::ParentDir)
+        && allowed.iter().any(|prefix| path.starts_with(prefix))
+}
+
+/// Get the host path a mount exposes, along with the field holding it:
+/// the source of bind mounts, or the device of volumes of the local
+/// driver given bind options, which mount it just the same.
+fn bind_source(mount: &Mount) -> Option<(&'static str, &str)> {
+    match mount.typ {
+        Some(MountTypeEnum::BIND) => Some(("Source", mount.source.as_deref().unwrap_or_default())),
+        Some(MountTypeEnum::VOLUME) => {
+            let driver = mount.volume_options.as_ref()?.driver_config.as_ref()?;
+            let options = driver.options.as_ref()?;
+            let binds = driver.name.as_deref().unwrap_or("local") == "local"
+                && options
+                    .get("o")
+                    .is_some_and(|o| o.split(',').any(|o| o == "bind" || o == "rbind"));
+            binds
+                .then(|| options.get("device"))
+                .flatten()
+                .map(|device| ("VolumeOptions.DriverConfig.Options.device", device.as_str()))
+        }
+        _ => None,
+    }
+}
+
+/// Whether a namespace mode shares the namespace of the host or of
+/// another container.
+fn is_shared_namespace(mode: Option<&str>) -> bool {
+    mode.is_some_and(|mode| mode == "host" || mode.starts_with("container:"))
+}
+
+/// Whether a security option disables a confinement mechanism, e.g.
+/// seccomp=unconfined or label=disable.
+fn is_unconfined(option: &str) -> bool {
+    option.contains("unconfined") || option == "label=disable" || option == "label:disable"
+}
+
+/// Whether a bind-mounted host path exposes the docker socket, either
+/// directly or through a directory holding it. Paths going up the
+/// hierarchy are assumed to.
+fn exposes_docker_socket(source: &str) -> bool {
+    let path = Path::new(source);
+    source.starts_with('/')
+        && (path.components().any(|c| c == Component::ParentDir)
+            || path.file_name().is_some_and(|name| name == "docker.sock")
+            || DOCKER_SOCKETS
+                .iter()
+                .any(|socket| Path::new(socket).starts_with(path)))
+}
+
+/// Find the dangerous fields of a host configuration, i.e. those
+/// granting jobs control of the host, and unset them if stripping.
+fn dangerous_fields(host_config: &mut HostConfig, strip: bool) -> Vec<Violation> {
+    let mut found = Vec::new();
+    if host_config.privileged.unwrap_or(false) {
+        found.push(violation(
+            "HostConfig.Privileged",
+            "privileged jobs are blocked",
+        ));
+        if strip {
+            host_config.privileged = None;
+        }
+    }
+    if host_config
+        .cap_add
+        .as_ref()
+        .is_some_and(|caps| !caps.is_empty())
+    {
+        found.push(violation(
+            "HostConfig.CapAdd",
+            "added capabilities are blocked",
+        ));
+        if strip {
+            host_config.cap_add = None;
+        }
+    }
+    for (field, mode) in [
+        ("HostConfig.NetworkMode", &mut host_config.network_mode),
+        ("HostConfig.PidMode", &mut host_config.pid_mode),
+        ("HostConfig.IpcMode", &mut host_config.ipc_mode),
+    ] {
+        if is_shared_namespace(mode.as_deref()) {
+            found.push(violation(
+                field,
+                "namespaces of the host or of other containers are blocked",
+            ));
+            if strip {
+                *mode = None;
+            }
+        }
+    }
+    for (field, set) in [
+        (
+            "HostConfig.Devices",
+            host_config
+                .devices
+                .as_ref()
+                .is_some_and(|devices| !devices.is_empty()),
+        ),
+        (
+            "HostConfig.DeviceCgroupRules",
+            host_config
+                .device_cgroup_rules
+                .as_ref()
+                .is_some_and(|rules| !rules.is_empty()),
+        ),
+        (
+            "HostConfig.VolumesFrom",
+            host_config
+                .volumes_from
+                .as_ref()
+                .is_some_and(|containers| !containers.is_empty()),
+        ),
+    ] {
+        if set {
+            found.push(violation(
+                field,
+                "host devices and volumes of other containers are blocked",
+            ));
+        }
+    }
+    if strip {
+        host_config.devices = None;
+        host_config.device_cgroup_rules = None;
+        host_config.volumes_from = None;
+    }
+    for (i, option) in host_config.security_opt.iter().flatten().enumerate() {
+        if is_unconfined(option) {
+            found.push(violation(
+                format!("HostConfig.SecurityOpt[{}]", i),
+                format!("{:?} is blocked", option),
+            ));
+        }
+    }
+    if strip {
+        if let Some(options) = &mut host_config.security_opt {
+            options.retain(|option| !is_unconfined(option));
+        }
+    }
+    for (i, bind) in host_config.binds.iter().flatten().enumerate() {
+        let source = bind.split(':').next().unwrap_or_default();
+        if exposes_docker_socket(source) {
+            found.push(violation(
+                format!("HostConfig.Binds[{}]", i),
+                format!("bind mounts of {:?} are blocked", source),
+            ));
+        }
+    }
+    for (i, mount) in host_config.mounts.iter().flatten().enumerate() {
+        if let Some((field, source)) =
+            bind_source(mount).filter(|(_, source)| exposes_docker_socket(source))
+        {
+            found.push(violation(
+                format!("HostConfig.Mounts[{}].{}", i, field),
+                format!("bind mounts of {:?} are blocked", source),
+            ));
+        }
+    }
+    if strip {
+        if let Some(binds) = &mut host_config.binds {
+            binds.retain(|bind| !exposes_docker_socket(bind.split(':').next().unwrap_or_default()));
+        }
+        if let Some(mounts) = &mut host_config.mounts {
+            mounts.retain(|mount| {
+                !bind_source(mount).is_some_and(|(_, source)| exposes_docker_socket(source))
+            });
+        }
+    }
+    found
+}
+
+/// Fill in a resource field if it's unset, and lower it to the
+/// maximum if clamping.
+fn adjust_limit(
+    value: Option<i64>,
+    default: Option<i64>,
+    max: Option<i64>,
+    clamp: bool,
+) -> Option<i64> {
+    // zero or negative values mean unlimited
+    let value = value.filter(|value| *value > 0).or(default);
+    match (value, max) {
+        (Some(value), Some(max)) if clamp => Some(value.min(max)),
+        (None, Some(max)) if clamp => Some(max),
+        _ => value,
+    }
+}
+
+/// Check that a resource field is capped.
+fn check_cap(field: &str, value: Option<i64>, max: Option<i64>) -> Result<(), Violation> {
+    match (value, max) {
+        (_, None) => Ok(()),
+        (Some(value), Some(max)) if value > 0 && value <= max => Ok(()),
+        (_, Some(max)) => Err(violation(field, format!("must be set to at most {}", max))),
+    }
+}
+
+impl Policy {
+    /// Whether the policy restricts the images jobs may use. Such a
+    /// policy forbids image builds, since the base images they pull
+    /// can't be checked.
+    pub fn restricts_images(&self) -> bool {
+        !self.allow_images.is_empty()
+            || !self.deny_images.is_empty()
+            || self.pinning != Pinning::Any
+    }
+
+    /// Check the image of a manifest.
+    fn check_image(&self, image: &str) -> Result<(), Violation> {
+        if !self.allow_images.is_empty()
+            && !self
+                .allow_images
+                .iter()
+                .any(|pattern| pattern.matches(image))
+        {
+            return Err(violation("Image", format!("{:?} is not allowed", image)));
+        }
+        if let Some(pattern) = self.deny_images.iter().find(|p| p.matches(image)) {
+            return Err(violation(
+                "Image",
+                format!("{:?} is denied by pattern {:?}", image, pattern.as_str()),
+            ));
+        }
+        let (_, tag, digest) = parse_image(image);
+        match self.pinning {
+            Pinning::Any => Ok(()),
+            Pinning::Tag if digest.is_some() || tag.is_some_and(|tag| tag != "latest") => Ok(()),
+            Pinning::Tag => Err(violation(
+                "Image",
+                "must be pinned to a tag other than latest, or to a digest",
+            )),
+            Pinning::Digest if digest.is_some() => Ok(()),
+            Pinning::Digest => Err(violation("Image", "must be pinned to a digest")),
+        }
+    }
+
+    /// Block the dangerous fields of a manifest, if hardened: either
+    /// reject it over the first one found, or strip them all and
+    /// report the ones stripped.
+    pub fn harden(&self, manifest: &mut Config<String>) -> Result<Vec<Violation>, Violation> {
+        let Some(hardening) = &self.hardening else {
+            return Ok(Vec::new());
+        };
+        let Some(host_config) = &mut manifest.host_config else {
+            return Ok(Vec::new());
+        };
+        match hardening {
+            Hardening::Reject => match dangerous_fields(host_config, false).into_iter().next() {
+                Some(found) => Err(found),
+                None => Ok(Vec::new()),
+            },
+            Hardening::Strip => Ok(dangerous_fields(host_config, true)),
+        }
+    }
+
+    /// Apply the default resource limits to a manifest, and clamp its
+    /// limits if configured to do so.
+    pub fn apply_limits(&self, mut manifest: Config<String>) -> Config<String> {
+        let mut host_config = manifest.host_config.unwrap_or_default();
+        host_config.memory = adjust_limit(
+            host_config.memory,
+            self.default.memory,
+            self.max.memory,
+            self.clamp,
+        );
+        host_config.nano_cpus = adjust_limit(
+            host_config.nano_cpus,
+            self.default.nano_cpus,
+            self.max.nano_cpus,
+            self.clamp,
+        );
+        host_config.pids_limit = adjust_limit(
+            host_config.pids_limit,
+            self.default.pids,
+            self.max.pids,
+            self.clamp,
+        );
+        manifest.host_config = Some(host_config);
+        manifest
+    }
+
+    /// Check a manifest against the policy, reporting the first
+    /// offending field.
+    pub fn check(&self, manifest: &Config<String>) -> Result<(), Violation> {
+        self.check_image(manifest.image.as_deref().unwrap_or_default())?;
+        let host_config = manifest.host_config.clone().unwrap_or_default();
+        if self.restrict_host_access {
+            if host_config.privileged.unwrap_or(false) {
+                return Err(violation(
+                    "HostConfig.Privileged",
+                    "privileged jobs are forbidden",
+                ));
+            }
+            for (field, mode) in [
+                ("HostConfig.NetworkMode", &host_config.network_mode),
+                ("HostConfig.PidMode", &host_config.pid_mode),
+                ("HostConfig.IpcMode", &host_config.ipc_mode),
+                ("HostConfig.UTSMode", &host_config.uts_mode),
+                ("HostConfig.UsernsMode", &host_config.userns_mode),
+            ] {
+                if is_shared_namespace(mode.as_deref()) {
+                    return Err(violation(
+                        field,
+                        "namespaces of the host or of other containers are forbidden",
+                    ));
+                }
+            }
+            if host_config
+                .devices
+                .as_ref()
+                .is_some_and(|devices| !devices.is_empty())
+            {
+                return Err(violation(
+                    "HostConfig.Devices",
+                    "host devices are forbidden",
+                ));
+            }
+            if host_config
+                .device_cgroup_rules
+                .as_ref()
+                .is_some_and(|rules| !rules.is_empty())
+            {
+                return Err(violation(
+                    "HostConfig.DeviceCgroupRules",
+                    "host devices are forbidden",
+                ));
+            }
+            if host_config
+                .volumes_from
+                .as_ref()
+                .is_some_and(|containers| !containers.is_empty())
+            {
+                return Err(violation(
+                    "HostConfig.VolumesFrom",
+                    "volumes of other containers are forbidden",
+                ));
+            }
+            if let Some((i, option)) = host_config
+                .security_opt
+                .iter()
+                .flatten()
+                .enumerate()
+                .find(|(_, option)| is_unconfined(option))
+            {
+                return Err(violation(
+                    format!("HostConfig.SecurityOpt[{}]", i),
+                    format!("{:?} is forbidden", option),
+                ));
+            }
+            for (i, bind) in host_config.binds.iter().flatten().enumerate() {
+                let source = bind.split(':').next().unwrap_or_default();
+                // sources that aren't paths are named volumes
+                if source.starts_with('/') && !is_bind_allowed(source, &self.allow_binds) {
+                    return Err(violation(
+                        format!("HostConfig.Binds[{}]", i),
+                        format!("bind mounts of {:?} are forbidden", source),
+                    ));
+                }
+            }
+            for (i, mount) in host_config.mounts.iter().flatten().enumerate() {
+                if let Some((field, source)) = bind_source(mount)
+                    .filter(|(_, source)| !is_bind_allowed(source, &self.allow_binds))
+                {
+                    return Err(violation(
+                        format!("HostConfig.Mounts[{}].{}", i, field),
+                        format!("bind mounts of {:?} are forbidden", source),
+                    ));
+                }
+            }
+        }
+        check_cap("HostConfig.Memory", host_config.memory, self.max.memory)?;
+        check_cap(
+            "HostConfig.NanoCpus",
+            host_config.nano_cpus,
+            self.max.nano_cpus,
+        )?;
+        check_cap(
+            "HostConfig.PidsLimit",
+            host_config.pids_limit,
+            self.max.pids,
+        )?;
+        Ok(())
+    }
+}
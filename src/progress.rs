@@ -0,0 +1,255 @@
+//! Lets running jobs report their progress, as a percentage and a
+//! phase or message, for clients to follow.
+//!
+//! Each job is given the URL to report to and a token valid only for
+//! itself in its environment, so that reports need no API key. Reports
+//! are kept in memory, which means they're lost when the dispatcher
+//! restarts, and only those of the most recent jobs are kept. Reports
+//! are also published as they're received, to be followed along with
+//! the lifecycle of jobs.
+
+use crate::internal::equals;
+use crate::signature;
+use actix_web::http::Method;
+use bollard::container::Config;
+use chrono::Utc;
+use futures::stream::{self, Stream, StreamExt};
+use once_cell::sync::{Lazy, OnceCell};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+use utoipa::ToSchema;
+
+/// Environment variable holding the URL jobs report their progress to.
+pub const URL_VARIABLE: &str = "DISPATCHER_PROGRESS_URL";
+
+/// Environment variable holding the token jobs report their progress
+/// with.
+pub const TOKEN_VARIABLE: &str = "DISPATCHER_PROGRESS_TOKEN";
+
+/// Maximum amount of reports kept.
+const MAX_RECORDS: usize = 1000;
+
+/// Maximum length of a reported phase.
+pub const MAX_PHASE_LENGTH: usize = 256;
+
+/// Maximum length of a reported message.
+pub const MAX_MESSAGE_LENGTH: usize = 1024;
+
+/// Maximum amount of reports published but not yet consumed by a
+/// follower, beyond which it misses the oldest ones.
+const CHANNEL_CAPACITY: usize = 256;
+
+/// How jobs report their progress.
+struct Settings {
+    url: String,
+    secret: String,
+}
+
+/// Static progress settings.
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+
+/// Static progress registry, ordered from least to most recently
+/// updated.
+static RECORDS: OnceCell<Mutex<VecDeque<(String, Progress)>>> = OnceCell::new();
+
+/// Channel reports are published on as they're received.
+static UPDATES: Lazy<broadcast::Sender<Update>> =
+    Lazy::new(|| broadcast::channel(CHANNEL_CAPACITY).0);
+
+/// A report published as it's received.
+#[derive(Clone, Debug)]
+pub struct Update {
+    /// The job's id.
+    pub name: String,
+    pub namespace: String,
+    pub path: Option<String>,
+    pub progress: Progress,
+}
+
+/// The latest progress reported by a job.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Progress {
+    /// Percentage of the work done, between 0 and 100
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(minimum = 0, maximum = 100, example = 42.5)]
+    pub percent: Option<f64>,
+    /// Name of the stage the job is in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(max_length = 256, example = "uploading")]
+    pub phase: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(max_length = 1024, example = "12 of 28 files uploaded")]
+    pub message: Option<String>,
+    /// UNIX time of the report, set by the dispatcher
+    #[serde(default)]
+    #[schema(read_only, example = 1702599010)]
+    pub updated: i64,
+}
+
+impl Progress {
+    /// Check the reported values, telling what's wrong with them if
+    /// anything.
+    pub fn validate(&self) -> Result<(), String> {
+        if self
+            .percent
+            .is_some_and(|percent| !(0.0..=100.0).contains(&percent))
+        {
+            return Err(String::from("percent must be between 0 and 100"));
+        }
+        if self
+            .phase
+            .as_ref()
+            .is_some_and(|phase| phase.len() > MAX_PHASE_LENGTH)
+        {
+            return Err(format!(
+                "phase must be at most {} bytes long",
+                MAX_PHASE_LENGTH
+            ));
+        }
+        if self
+            .message
+            .as_ref()
+            .is_some_and(|message| message.len() > MAX_MESSAGE_LENGTH)
+        {
+            return Err(format!(
+                "message must be at most {} bytes long",
+                MAX_MESSAGE_LENGTH
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Enable progress reports, given the base URL jobs reach the
+/// dispatcher at, and the secret tokens are derived from. Without a
+/// secret, a random one is used, which invalidates the tokens of
+/// existing jobs when the dispatcher restarts.
+pub fn init(url: &str, secret: Option<&str>) {
+    let secret = secret
+        .map(String::from)
+        .unwrap_or_else(|| format!("{}{}", cuid2::create_id(), cuid2::create_id()));
+    let _ = SETTINGS.set(Settings {
+        url: url.trim_end_matches('/').to_string(),
+        secret,
+    });
+}
+
+/// Whether jobs may report their progress.
+pub fn is_enabled() -> bool {
+    SETTINGS.get().is_some()
+}
+
+/// The token a job reports its progress with.
+fn token(settings: &Settings, name: &str, namespace: &str) -> String {
+    signature::hmac_hex(
+        &settings.secret,
+        &[namespace.as_bytes(), b"/", name.as_bytes()],
+    )
+}
+
+/// Whether a token is the one given to a job.
+pub fn is_valid(given: &str, name: &str, namespace: &str) -> bool {
+    SETTINGS.get().is_some_and(|settings| {
+        equals(
+            given.as_bytes(),
+            token(settings, name, namespace).as_bytes(),
+        )
+    })
+}
+
+/// Whether a request is a progress report, which is authenticated
+/// with the job's token instead of an API key.
+pub fn is_report(method: &Method, path: &str) -> bool {
+    is_enabled()
+        && *method == Method::PUT
+        && path
+            .strip_prefix("/job/")
+            .and_then(|rest| rest.strip_suffix("/progress"))
+            .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+}
+
+/// Give a job the URL and token to report its progress with, unless
+/// progress reports are disabled.
+pub fn with_env(c: Config<String>, name: &str, namespace: &str) -> Config<String> {
+    let Some(settings) = SETTINGS.get() else {
+        return c;
+    };
+    let mut env = c.env.unwrap_or_default();
+    env.retain(|variable| {
+        let key = variable.split('=').next().unwrap_or_default();
+        key != URL_VARIABLE && key != TOKEN_VARIABLE
+    });
+    env.push(format!(
+        "{}={}/job/{}/progress",
+        URL_VARIABLE, settings.url, name
+    ));
+    env.push(format!(
+        "{}={}",
+        TOKEN_VARIABLE,
+        token(settings, name, namespace)
+    ));
+    Config {
+        env: Some(env),
+        ..c
+    }
+}
+
+/// Get the registry of reports.
+fn records() -> &'static Mutex<VecDeque<(String, Progress)>> {
+    RECORDS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+/// Record the progress reported by a job of the given namespace,
+/// created through the given path, replacing its previous report, and
+/// publish it.
+pub fn report(name: &str, namespace: &str, path: Option<&str>, mut progress: Progress) -> Progress {
+    progress.updated = Utc::now().timestamp();
+    {
+        let mut records = records().lock().unwrap();
+        records.retain(|(n, _)| n != name);
+        records.push_back((name.to_string(), progress.clone()));
+        if records.len() > MAX_RECORDS {
+            records.pop_front();
+        }
+    }
+    // reports published while nobody follows them are dropped
+    let _ = UPDATES.send(Update {
+        name: name.to_string(),
+        namespace: namespace.to_string(),
+        path: path.map(String::from),
+        progress: progress.clone(),
+    });
+    progress
+}
+
+/// Get the stream of the reports of the jobs of a namespace, as
+/// they're received from now on.
+pub fn updates(namespace: &str) -> impl Stream<Item = Update> {
+    let namespace = namespace.to_string();
+    stream::unfold(UPDATES.subscribe(), |mut receiver| async move {
+        loop {
+            match receiver.recv().await {
+                Ok(update) => return Some((update, receiver)),
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Missed {} progress reports while following them", missed);
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+    .filter(move |update| futures::future::ready(update.namespace == namespace))
+}
+
+/// Get the latest progress reported by a job, if any.
+pub fn get(name: &str) -> Option<Progress> {
+    records()
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map(|(_, progress)| progress.clone())
+}
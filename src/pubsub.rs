@@ -0,0 +1,248 @@
+//! Pulls jobs from a Google Cloud Pub/Sub subscription, as an
+//! alternative to the HTTP API. Each message goes through the filter
+//! like a request body would, and is acknowledged once its job is
+//! created, its ack deadline being extended meanwhile.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::future::join_all;
+use google_cloud_auth::{project::Config, token::DefaultTokenSourceProvider};
+use google_cloud_token::{TokenSource, TokenSourceProvider};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{interval_at, Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// The Pub/Sub API endpoint.
+const ENDPOINT: &str = "https://pubsub.googleapis.com/v1";
+
+/// The OAuth scope of the Pub/Sub API.
+const SCOPE: &str = "https://www.googleapis.com/auth/pubsub";
+
+/// Where and how to pull messages.
+pub struct Source {
+    /// Full name of the subscription, as
+    /// `projects/<project>/subscriptions/<subscription>`.
+    pub subscription: String,
+    /// Maximum number of messages pulled at once, processed
+    /// concurrently.
+    pub concurrency: u16,
+    /// Seconds the ack deadline of messages is extended by while
+    /// their job is being created.
+    pub ack_deadline: u16,
+}
+
+/// A pulled message.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReceivedMessage {
+    ack_id: String,
+    message: PubsubMessage,
+    delivery_attempt: Option<u32>,
+}
+
+/// The contents of a pulled message.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PubsubMessage {
+    #[serde(default)]
+    data: String,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+    message_id: String,
+    publish_time: Option<String>,
+    ordering_key: Option<String>,
+}
+
+/// The response to a pull request.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PullResponse {
+    #[serde(default)]
+    received_messages: Vec<ReceivedMessage>,
+}
+
+/// An authenticated client of the subscription.
+struct Subscriber {
+    client: reqwest::Client,
+    tokens: Arc<dyn TokenSource>,
+    subscription: String,
+}
+
+impl Subscriber {
+    /// Call a method of the subscription.
+    async fn call(&self, method: &str, body: Value) -> Result<reqwest::Response> {
+        let token = self
+            .tokens
+            .token()
+            .await
+            .map_err(|e| anyhow!(e))
+            .context("while getting a Pub/Sub access token")?;
+        self.client
+            .post(format!("{}/{}:{}", ENDPOINT, self.subscription, method))
+            .header(reqwest::header::AUTHORIZATION, token)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| format!("while calling the Pub/Sub {} method", method))
+    }
+
+    /// Pull up to the given amount of messages, waiting for some to
+    /// arrive.
+    async fn pull(&self, max_messages: u16) -> Result<Vec<ReceivedMessage>> {
+        let response = self
+            .call("pull", json!({"maxMessages": max_messages}))
+            .await?
+            .json::<PullResponse>()
+            .await
+            .context("while reading pulled Pub/Sub messages")?;
+        Ok(response.received_messages)
+    }
+
+    /// Set the ack deadline of a message, relative to now; a deadline
+    /// of zero makes it available for redelivery right away.
+    async fn modify_ack_deadline(&self, ack_id: &str, seconds: u16) -> Result<()> {
+        self.call(
+            "modifyAckDeadline",
+            json!({"ackIds": [ack_id], "ackDeadlineSeconds": seconds}),
+        )
+        .await
+        .map(|_| ())
+    }
+
+    /// Acknowledge a message.
+    async fn acknowledge(&self, ack_id: &str) -> Result<()> {
+        self.call("acknowledge", json!({"ackIds": [ack_id]}))
+            .await
+            .map(|_| ())
+    }
+}
+
+/// Create the job of a message, made available to the filter as
+/// `$MESSAGE`, extending its ack deadline meanwhile, and acknowledge
+/// it once created. Messages that will never make a job are
+/// acknowledged as well, and the rest are nacked to be redelivered
+/// according to the subscription's retry policy.
+async fn process(
+    subscriber: &Subscriber,
+    source: &Source,
+    intake: &Intake,
+    received: ReceivedMessage,
+) -> Result<()> {
+    let message = &received.message;
+    let input = STANDARD
+        .decode(&message.data)
+        .map_err(|e| format!("data is not valid base64: {}", e))
+        .and_then(|data| {
+            serde_json::from_slice::<Value>(&data)
+                .map_err(|e| format!("data is not valid JSON: {}", e))
+        });
+    let outcome = match input {
+        Ok(input) => {
+            let submission = Submission {
+                input,
+                path: format!("/pubsub/{}", source.subscription),
+                claims: Value::Null,
+                message: json!({
+                    "subscription": source.subscription,
+                    "message_id": message.message_id,
+                    "attributes": message.attributes,
+                    "publish_time": message.publish_time,
+                    "ordering_key": message.ordering_key,
+                    "delivery_attempt": received.delivery_attempt,
+                }),
+                reply_subject: None,
+                actor: audit::Actor {
+                    submitter: Some(format!("pubsub:{}", source.subscription)),
+                    ..Default::default()
+                },
+                hold: false,
+                labels: Default::default(),
+                on_duplicate: None,
+            };
+            let creating = async {
+                docker_service::submit(intake, submission)
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| (e.is_permanent(), e.to_string()))
+            };
+            tokio::pin!(creating);
+            let period = Duration::from_secs(u64::from(source.ack_deadline) / 2);
+            let mut extensions = interval_at(Instant::now() + period, period);
+            loop {
+                tokio::select! {
+                    outcome = &mut creating => break outcome,
+                    _ = extensions.tick() => {
+                        if let Err(e) = subscriber
+                            .modify_ack_deadline(&received.ack_id, source.ack_deadline)
+                            .await
+                        {
+                            warn!(
+                                "Couldn't extend the ack deadline of the Pub/Sub message {:?}: {:?}",
+                                message.message_id, e
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        Err(e) => Err((true, e)),
+    };
+    match outcome {
+        Ok(()) => {
+            debug!("Pub/Sub message {:?} made a job", message.message_id);
+            subscriber.acknowledge(&received.ack_id).await
+        }
+        Err((true, e)) => {
+            warn!(
+                "Rejected the Pub/Sub message {:?}: {}",
+                message.message_id, e
+            );
+            subscriber.acknowledge(&received.ack_id).await
+        }
+        Err((false, e)) => {
+            warn!(
+                "Couldn't create the job of the Pub/Sub message {:?}; nacking it: {}",
+                message.message_id, e
+            );
+            subscriber.modify_ack_deadline(&received.ack_id, 0).await
+        }
+    }
+}
+
+/// Pull messages from the subscription, creating a job for each one.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let tokens = DefaultTokenSourceProvider::new(Config::default().with_scopes(&[SCOPE]))
+        .await
+        .context("while looking up Google Cloud credentials")?
+        .token_source();
+    let subscriber = Subscriber {
+        client: reqwest::Client::new(),
+        tokens,
+        subscription: source.subscription.clone(),
+    };
+    info!(
+        "Consuming jobs from the Pub/Sub subscription {:?}, {} at a time",
+        source.subscription, source.concurrency
+    );
+    loop {
+        let received = tokio::select! {
+            received = subscriber.pull(source.concurrency) => received?,
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        join_all(
+            received
+                .into_iter()
+                .map(|received| process(&subscriber, &source, &intake, received)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    }
+}
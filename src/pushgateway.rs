@@ -0,0 +1,81 @@
+//! Pushes the metrics exposed in `/metrics` to a Prometheus
+//! Pushgateway, for deployments where scraping isn't possible.
+
+use crate::metrics_service;
+use crate::shutdown::{self, Phase};
+use anyhow::{anyhow, Context, Result};
+use reqwest::Url;
+use std::collections::HashSet;
+use tokio::time::{self, Duration};
+use tracing::{debug, error};
+
+/// Convert metrics from OpenMetrics to the Prometheus text format
+/// expected by the Pushgateway, which names counter families after
+/// their samples.
+fn to_text_format(metrics: &str) -> String {
+    let counters: HashSet<&str> = metrics
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect();
+    metrics
+        .lines()
+        .filter(|line| *line != "# EOF")
+        .map(|line| {
+            let mut parts = line.splitn(4, ' ');
+            match (parts.next(), parts.next(), parts.next(), parts.next()) {
+                (Some("#"), Some(keyword @ ("HELP" | "TYPE")), Some(name), rest)
+                    if counters.contains(name) =>
+                {
+                    let rest = rest.map(|rest| format!(" {}", rest)).unwrap_or_default();
+                    format!("# {} {}_total{}\n", keyword, name, rest)
+                }
+                _ => format!("{}\n", line),
+            }
+        })
+        .collect()
+}
+
+/// Build the URL of the metrics group of the given job.
+pub fn group_url(base: &str, job: &str) -> Result<Url> {
+    let mut url = Url::parse(base).context("while parsing the Pushgateway URL")?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("invalid Pushgateway URL {:?}", base))?
+        .pop_if_empty()
+        .extend(["metrics", "job", job]);
+    Ok(url)
+}
+
+/// Push the current metrics once, replacing the previous ones.
+async fn push(client: &reqwest::Client, url: &Url) -> Result<()> {
+    let body = to_text_format(&metrics_service::encoded().await?);
+    debug!("Pushing metrics to the Pushgateway");
+    client
+        .put(url.clone())
+        .header("Content-Type", "text/plain; version=0.0.4")
+        .body(body)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .context("while pushing metrics")?;
+    Ok(())
+}
+
+/// Push metrics to the Pushgateway endlessly, and once more when
+/// shutting down. Push failures are logged and otherwise ignored.
+pub async fn cycle(url: Url, push_interval: u16) -> Result<()> {
+    let client = reqwest::Client::new();
+    let mut interval = time::interval(Duration::from_secs(push_interval.into()));
+    loop {
+        let flushing = tokio::select! {
+            _ = interval.tick() => false,
+            _ = shutdown::reached(Phase::Flushing) => true,
+        };
+        if let Err(e) = push(&client, &url).await {
+            error!("Couldn't push metrics to the Pushgateway: {:?}", e);
+        }
+        if flushing {
+            return Ok(());
+        }
+    }
+}
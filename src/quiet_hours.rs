@@ -0,0 +1,261 @@
+//! Keeps jobs from being started during configured time windows, e.g.
+//! while the docker host is backed up or maintained. Jobs are still
+//! accepted meanwhile, and started once the window is over.
+//!
+//! Windows are given as cron expressions matching the minutes during
+//! which no job is started, optionally preceded by the time zone they
+//! refer to, e.g. `TZ=Europe/Berlin * 2-3 * * *` for 02:00 to 03:59
+//! Berlin time. The time zone is UTC by default.
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use chrono_tz::Tz;
+use once_cell::sync::OnceCell;
+
+/// Static list of quiet windows.
+static WINDOWS: OnceCell<Vec<Window>> = OnceCell::new();
+
+/// Names of the months, in cron order.
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// Names of the days of the week, in cron order.
+const DAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// The values a cron field matches, as a bit set, along with whether
+/// it was given as `*`.
+#[derive(Debug)]
+struct Field {
+    values: u64,
+    any: bool,
+}
+
+impl Field {
+    /// Parse a field of values between the given bounds, which may be
+    /// given by name, counting from the lower bound.
+    fn parse(field: &str, min: u32, max: u32, names: &[&str]) -> Result<Self> {
+        let value = |value: &str| -> Result<u32> {
+            let lowercase = value.to_lowercase();
+            let value = match names.iter().position(|name| *name == lowercase) {
+                Some(index) => index as u32 + min,
+                None => value
+                    .parse()
+                    .with_context(|| format!("invalid value {:?}", value))?,
+            };
+            if value < min || value > max {
+                bail!("value {} is out of range {}-{}", value, min, max);
+            }
+            Ok(value)
+        };
+        let mut values = 0;
+        for part in field.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (
+                    range,
+                    step.parse::<u32>()
+                        .ok()
+                        .filter(|step| *step > 0)
+                        .with_context(|| format!("invalid step {:?}", step))?,
+                ),
+                None => (part, 1),
+            };
+            let (start, end) = match range.split_once('-') {
+                _ if range == "*" => (min, max),
+                Some((start, end)) => (value(start)?, value(end)?),
+                None if step > 1 => (value(range)?, max),
+                None => (value(range)?, value(range)?),
+            };
+            if start > end {
+                bail!("range {:?} is reversed", range);
+            }
+            for value in (start..=end).step_by(step as usize) {
+                values |= 1 << value;
+            }
+        }
+        Ok(Self {
+            values,
+            any: field == "*",
+        })
+    }
+
+    /// Whether the field matches a value.
+    fn matches(&self, value: u32) -> bool {
+        self.values & (1 << value) != 0
+    }
+}
+
+/// A quiet window.
+#[derive(Debug)]
+struct Window {
+    zone: Tz,
+    minute: Field,
+    hour: Field,
+    day: Field,
+    month: Field,
+    weekday: Field,
+}
+
+impl Window {
+    /// Parse a window given as a cron expression, optionally preceded
+    /// by `TZ=` and a time zone.
+    fn parse(expression: &str) -> Result<Self> {
+        let mut fields = expression.split_whitespace().peekable();
+        let zone = match fields.next_if(|field| field.starts_with("TZ=")) {
+            Some(zone) => zone[3..]
+                .parse::<Tz>()
+                .map_err(|e| anyhow!("invalid time zone: {}", e))?,
+            None => Tz::UTC,
+        };
+        let fields: Vec<&str> = fields.collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            bail!("expected five fields: minute, hour, day of month, month and day of week");
+        };
+        let mut weekday = Field::parse(weekday, 0, 7, &DAYS)?;
+        // both 0 and 7 stand for Sunday
+        if weekday.matches(7) {
+            weekday.values |= 1;
+        }
+        Ok(Self {
+            zone,
+            minute: Field::parse(minute, 0, 59, &[])?,
+            hour: Field::parse(hour, 0, 23, &[])?,
+            day: Field::parse(day, 1, 31, &[])?,
+            month: Field::parse(month, 1, 12, &MONTHS)?,
+            weekday,
+        })
+    }
+
+    /// Whether the window covers an instant. As in cron, when both the
+    /// day of the month and the day of the week are restricted, either
+    /// of them matching is enough.
+    fn covers(&self, instant: DateTime<Utc>) -> bool {
+        let local = instant.with_timezone(&self.zone);
+        let day = self.day.matches(local.day());
+        let weekday = self.weekday.matches(local.weekday().num_days_from_sunday());
+        self.minute.matches(local.minute())
+            && self.hour.matches(local.hour())
+            && self.month.matches(local.month())
+            && match (self.day.any, self.weekday.any) {
+                (false, false) => day || weekday,
+                _ => day && weekday,
+            }
+    }
+}
+
+/// Set the quiet windows.
+pub fn init(expressions: &[String]) -> Result<()> {
+    let windows = expressions
+        .iter()
+        .filter(|expression| !expression.trim().is_empty())
+        .map(|expression| {
+            Window::parse(expression)
+                .with_context(|| format!("while parsing the quiet hours {:?}", expression))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let _ = WINDOWS.set(windows);
+    Ok(())
+}
+
+/// Whether any quiet window is configured.
+pub fn is_enabled() -> bool {
+    WINDOWS.get().is_some_and(|windows| !windows.is_empty())
+}
+
+/// Whether jobs are currently kept from being started.
+pub fn is_quiet() -> bool {
+    let now = Utc::now();
+    WINDOWS
+        .get()
+        .is_some_and(|windows| windows.iter().any(|window| window.covers(now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Field, Window, DAYS};
+    use chrono::{DateTime, TimeZone, Utc};
+
+    /// The values a field matches, among those between the given
+    /// bounds.
+    fn values(field: &Field, min: u32, max: u32) -> Vec<u32> {
+        (min..=max).filter(|value| field.matches(*value)).collect()
+    }
+
+    /// An instant given in UTC.
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn steps_start_at_the_given_value() {
+        let every_quarter = Field::parse("*/15", 0, 59, &[]).unwrap();
+        assert_eq!(values(&every_quarter, 0, 59), vec![0, 15, 30, 45]);
+        assert!(!every_quarter.any);
+        let from_five = Field::parse("5/10", 0, 59, &[]).unwrap();
+        assert_eq!(values(&from_five, 0, 59), vec![5, 15, 25, 35, 45, 55]);
+        let stepped_range = Field::parse("10-20/5,30", 0, 59, &[]).unwrap();
+        assert_eq!(values(&stepped_range, 0, 59), vec![10, 15, 20, 30]);
+    }
+
+    #[test]
+    fn names_count_from_the_lower_bound() {
+        let weekdays = Field::parse("mon-fri", 0, 7, &DAYS).unwrap();
+        assert_eq!(values(&weekdays, 0, 7), vec![1, 2, 3, 4, 5]);
+        let weekend = Field::parse("SAT,sun", 0, 7, &DAYS).unwrap();
+        assert_eq!(values(&weekend, 0, 7), vec![0, 6]);
+    }
+
+    #[test]
+    fn invalid_fields_are_refused() {
+        assert!(Field::parse("5-1", 0, 59, &[]).is_err());
+        assert!(Field::parse("fri-mon", 0, 7, &DAYS).is_err());
+        assert!(Field::parse("60", 0, 59, &[]).is_err());
+        assert!(Field::parse("0", 1, 31, &[]).is_err());
+        assert!(Field::parse("*/0", 0, 59, &[]).is_err());
+        assert!(Field::parse("*/x", 0, 59, &[]).is_err());
+        assert!(Field::parse("noon", 0, 23, &[]).is_err());
+        assert!(Window::parse("* * * *").is_err());
+        assert!(Window::parse("TZ=Mars/Olympus * * * * *").is_err());
+    }
+
+    #[test]
+    fn seven_stands_for_sunday() {
+        let window = Window::parse("* * * * 7").unwrap();
+        // 2024-06-02 was a Sunday
+        assert!(window.covers(utc(2024, 6, 2, 12, 0)));
+        assert!(!window.covers(utc(2024, 6, 3, 12, 0)));
+        let window = Window::parse("* * * * 0").unwrap();
+        assert!(window.covers(utc(2024, 6, 2, 12, 0)));
+    }
+
+    #[test]
+    fn either_day_matches_when_both_are_restricted() {
+        // the 1st of the month, or any Monday
+        let window = Window::parse("* * 1 * mon").unwrap();
+        assert!(window.covers(utc(2024, 6, 1, 12, 0)));
+        assert!(window.covers(utc(2024, 6, 3, 12, 0)));
+        assert!(!window.covers(utc(2024, 6, 4, 12, 0)));
+        // the 1st of the month, any day of the week
+        let window = Window::parse("* * 1 * *").unwrap();
+        assert!(window.covers(utc(2024, 6, 1, 12, 0)));
+        assert!(!window.covers(utc(2024, 6, 3, 12, 0)));
+    }
+
+    #[test]
+    fn windows_follow_their_time_zone_across_transitions() {
+        let window = Window::parse("TZ=Europe/Berlin * 2 * * *").unwrap();
+        // in summer, 02:00 in Berlin is 00:00 UTC
+        assert!(window.covers(utc(2024, 6, 3, 0, 30)));
+        assert!(!window.covers(utc(2024, 6, 3, 2, 30)));
+        // on 2024-03-31 Berlin skipped from 02:00 to 03:00, at 01:00 UTC
+        assert!(!window.covers(utc(2024, 3, 31, 0, 59)));
+        assert!(!window.covers(utc(2024, 3, 31, 1, 0)));
+        let after = Window::parse("TZ=Europe/Berlin * 3 * * *").unwrap();
+        assert!(after.covers(utc(2024, 3, 31, 1, 0)));
+        // on 2024-10-27 Berlin went through 02:00 to 02:59 twice
+        assert!(window.covers(utc(2024, 10, 27, 0, 30)));
+        assert!(window.covers(utc(2024, 10, 27, 1, 30)));
+        assert!(!window.covers(utc(2024, 10, 27, 2, 0)));
+    }
+}
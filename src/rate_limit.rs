@@ -0,0 +1,114 @@
+//! Limits the rate of job creation requests of each client, so that
+//! a misbehaving producer can't hammer the docker daemon.
+
+use crate::api_error::APIError;
+use crate::auth;
+use crate::ip_filter;
+use crate::tls;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::Method,
+    middleware::Next,
+    web, Error, HttpRequest,
+};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Amount of clients tracked after which the ones with a full bucket
+/// are forgotten.
+const MAX_TRACKED: usize = 10000;
+
+/// A token bucket per client, refilled at a steady rate up to the
+/// burst size, each job creation request taking a token.
+#[derive(Default)]
+pub struct Limiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl Limiter {
+    /// Allow each client the given amount of job creation requests per
+    /// minute, in bursts of up to the given size.
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            per_second: f64::from(per_minute) / 60.0,
+            burst: f64::from(burst),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether requests are limited.
+    pub fn is_enabled(&self) -> bool {
+        self.per_second > 0.0
+    }
+
+    /// Take a token from the client's bucket, or tell how many
+    /// seconds to wait for the next one.
+    fn take(&self, client: &str) -> Result<(), u64> {
+        let Ok(mut buckets) = self.buckets.lock() else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if buckets.len() >= MAX_TRACKED && !buckets.contains_key(client) {
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, (tokens, updated)| {
+                *tokens + now.duration_since(*updated).as_secs_f64() * per_second < burst
+            });
+        }
+        let (tokens, updated) = buckets
+            .entry(client.to_string())
+            .or_insert((self.burst, now));
+        *tokens = (*tokens + now.duration_since(*updated).as_secs_f64() * self.per_second)
+            .min(self.burst);
+        *updated = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - *tokens) / self.per_second).ceil() as u64)
+        }
+    }
+}
+
+/// The client a request is accounted to: the API key, token subject
+/// or client certificate it was authenticated with, or else its
+/// client address.
+fn client(request: &HttpRequest) -> String {
+    auth::key_name(request)
+        .map(|name| format!("key:{}", name))
+        .or_else(|| {
+            auth::token(request)
+                .and_then(|token| token.subject)
+                .map(|subject| format!("subject:{}", subject))
+        })
+        .or_else(|| tls::client_identity(request).map(|identity| format!("cert:{}", identity)))
+        .or_else(|| ip_filter::client_ip(request).map(|address| format!("ip:{}", address)))
+        .unwrap_or_default()
+}
+
+/// Refuse job creation requests of clients exceeding their rate,
+/// with a `429 Too Many Requests` response telling when to retry.
+pub async fn limit(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let creates = request.method() == Method::POST
+        && (request.path() == "/job" || request.path().starts_with("/job/"));
+    let limited = request
+        .app_data::<web::Data<Limiter>>()
+        .filter(|limiter| creates && limiter.is_enabled())
+        .and_then(|limiter| limiter.take(&client(request.request())).err());
+    if let Some(retry_after) = limited {
+        let response = request.error_response(
+            APIError::too_many_requests("Too many job creation requests; try again later")
+                .retry_after(retry_after),
+        );
+        return Ok(response.map_into_right_body());
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
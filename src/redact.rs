@@ -0,0 +1,87 @@
+//! Redacts sensitive values, such as passwords, tokens and the values
+//! of secrets, from request bodies and manifests before they're logged
+//! or returned in error messages.
+
+use crate::secrets;
+use anyhow::{Context, Result};
+use glob::Pattern;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::Value;
+
+/// Static patterns of the keys holding sensitive values.
+static PATTERNS: OnceCell<Vec<Pattern>> = OnceCell::new();
+
+/// Replacement of redacted values.
+const REDACTED: &str = "[REDACTED]";
+
+/// Minimum length of the secret values redacted wherever they appear,
+/// so that short values don't garble everything else.
+const MIN_SECRET_LENGTH: usize = 4;
+
+/// Compile the patterns of the keys holding sensitive values, matched
+/// case-insensitively.
+pub fn init(patterns: &[String]) -> Result<()> {
+    let patterns = patterns
+        .iter()
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            Pattern::new(&pattern.to_lowercase())
+                .with_context(|| format!("invalid redaction pattern {:?}", pattern))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let _ = PATTERNS.set(patterns);
+    Ok(())
+}
+
+/// Whether a key holds a sensitive value.
+fn is_sensitive(key: &str) -> bool {
+    let key = key.to_lowercase();
+    PATTERNS
+        .get()
+        .is_some_and(|patterns| patterns.iter().any(|pattern| pattern.matches(&key)))
+}
+
+/// Redact the values of secrets from a text.
+pub fn text(text: &str) -> String {
+    secrets::env_values()
+        .iter()
+        .filter(|secret| secret.len() >= MIN_SECRET_LENGTH)
+        .fold(text.to_string(), |text, secret| {
+            text.replace(secret.as_str(), REDACTED)
+        })
+}
+
+/// Redact the values of sensitive keys from a JSON value, including
+/// those of environment variables given as NAME=value, and the values
+/// of secrets from every string.
+fn value(value: &Value) -> Value {
+    match value {
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, field)| {
+                    let field = if is_sensitive(key) {
+                        Value::String(String::from(REDACTED))
+                    } else {
+                        self::value(field)
+                    };
+                    (key.clone(), field)
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(items.iter().map(self::value).collect()),
+        Value::String(string) => Value::String(match string.split_once('=') {
+            Some((name, _)) if is_sensitive(name) => format!("{}={}", name, REDACTED),
+            _ => text(string),
+        }),
+        other => other.clone(),
+    }
+}
+
+/// Serialize a value as JSON, with its sensitive values redacted.
+pub fn json<T: Serialize>(data: &T) -> String {
+    serde_json::to_value(data)
+        .map(|data| value(&data).to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,230 @@
+//! Pulls jobs from a Redis list or stream, as an alternative to the
+//! HTTP API for the many lightweight producers already enqueueing
+//! into Redis. Each payload goes through the filter like a request body
+//! would, and payloads that can't make a job are pushed to a
+//! dead-letter list.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::retry::Backoff;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{StreamReadOptions, StreamReadReply};
+use redis::AsyncCommands;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Field of stream entries holding the payload.
+pub const PAYLOAD_FIELD: &str = "payload";
+
+/// Maximum number of stream entries read at once.
+const BATCH: usize = 16;
+
+/// Where to pull payloads from.
+pub enum Key {
+    /// A list, popped with BLPOP.
+    List(String),
+    /// A stream, read as part of a consumer group.
+    Stream {
+        key: String,
+        group: String,
+        consumer: String,
+    },
+}
+
+/// Where and how to pull payloads.
+pub struct Source {
+    pub url: String,
+    pub key: Key,
+    /// List failed payloads are pushed to.
+    pub dead_letter: Option<String>,
+}
+
+impl Source {
+    /// The key payloads are pulled from.
+    fn key(&self) -> &str {
+        match &self.key {
+            Key::List(key) | Key::Stream { key, .. } => key,
+        }
+    }
+}
+
+/// Create the job of a payload, made available to the filter as
+/// `$MESSAGE`, retrying for as long as the failure isn't the payload's
+/// fault. Payloads that will never make a job are pushed to the
+/// dead-letter list. Returns once the payload is done with, or once
+/// shutting down.
+async fn process(
+    connection: &mut MultiplexedConnection,
+    source: &Source,
+    intake: &Intake,
+    id: Option<&str>,
+    payload: Option<String>,
+) -> Result<bool> {
+    let key = source.key();
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    let error = loop {
+        let Some(payload) = &payload else {
+            break String::from("entry lacks a payload field");
+        };
+        let input = match serde_json::from_str::<Value>(payload) {
+            Ok(input) => input,
+            Err(e) => break format!("payload is not valid JSON: {}", e),
+        };
+        let submission = Submission {
+            input,
+            path: format!("/redis/{}", key),
+            claims: Value::Null,
+            message: json!({"key": key, "id": id}),
+            reply_subject: None,
+            actor: audit::Actor {
+                submitter: Some(format!("redis:{}", key)),
+                ..Default::default()
+            },
+            hold: false,
+            labels: Default::default(),
+            on_duplicate: None,
+        };
+        let failure = match docker_service::submit(intake, submission).await {
+            Ok(_) => {
+                debug!("Redis payload from {:?} made a job", key);
+                return Ok(true);
+            }
+            Err(e) => (e.is_permanent(), e.to_string()),
+        };
+        match failure {
+            (true, e) => break e,
+            (false, e) => {
+                warn!(
+                    "Couldn't create the job of a Redis payload from {:?}; retrying: {}",
+                    key, e
+                );
+                tokio::select! {
+                    _ = backoff.wait() => (),
+                    _ = shutdown::reached(Phase::Stopping) => return Ok(false),
+                }
+            }
+        }
+    };
+    warn!("Rejected a Redis payload from {:?}: {}", key, error);
+    if let Some(dead_letter) = &source.dead_letter {
+        let letter = json!({"key": key, "id": id, "payload": payload, "error": error});
+        connection
+            .rpush::<_, _, ()>(dead_letter, letter.to_string())
+            .await
+            .context("while pushing a payload to the Redis dead-letter list")?;
+    }
+    Ok(true)
+}
+
+/// Pop payloads from a list. Popped payloads are gone from the list,
+/// so creating their jobs is retried until it succeeds.
+async fn pop(
+    connection: &mut MultiplexedConnection,
+    source: &Source,
+    intake: &Intake,
+    key: &str,
+) -> Result<()> {
+    loop {
+        let popped = tokio::select! {
+            popped = connection.blpop::<_, Option<(String, String)>>(key, 0.0) => {
+                popped.context("while popping from the Redis list")?
+            }
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        let Some((_, payload)) = popped else {
+            continue;
+        };
+        if !process(connection, source, intake, None, Some(payload)).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Read entries from a stream as part of a consumer group, starting
+/// with the ones left pending by a previous run of the same consumer.
+/// Entries are acknowledged once their job is created or they're
+/// dead-lettered.
+async fn read(
+    connection: &mut MultiplexedConnection,
+    source: &Source,
+    intake: &Intake,
+    key: &str,
+    group: &str,
+    consumer: &str,
+) -> Result<()> {
+    if let Err(e) = connection
+        .xgroup_create_mkstream::<_, _, _, ()>(key, group, "$")
+        .await
+    {
+        if e.code() != Some("BUSYGROUP") {
+            return Err(e).context("while creating the Redis consumer group");
+        }
+    }
+    let mut pending = true;
+    loop {
+        let options = StreamReadOptions::default()
+            .group(group, consumer)
+            .count(BATCH);
+        let (id, options) = if pending {
+            ("0", options)
+        } else {
+            (">", options.block(0))
+        };
+        let (keys, ids) = ([key], [id]);
+        let reply = tokio::select! {
+            reply = connection.xread_options::<_, _, Option<StreamReadReply>>(&keys, &ids, &options) => {
+                reply.context("while reading from the Redis stream")?
+            }
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        let entries = reply
+            .into_iter()
+            .flat_map(|reply| reply.keys)
+            .flat_map(|stream| stream.ids)
+            .collect::<Vec<_>>();
+        if entries.is_empty() {
+            pending = false;
+            continue;
+        }
+        for entry in entries {
+            let payload = entry.get::<String>(PAYLOAD_FIELD);
+            if !process(connection, source, intake, Some(&entry.id), payload).await? {
+                return Ok(());
+            }
+            connection
+                .xack::<_, _, _, ()>(key, group, &[&entry.id])
+                .await
+                .context("while acknowledging a Redis stream entry")?;
+        }
+    }
+}
+
+/// Pull payloads from the list or stream, creating a job for each one.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let mut connection = redis::Client::open(source.url.as_str())
+        .context("invalid Redis URL")?
+        .get_multiplexed_async_connection()
+        .await
+        .context("while connecting to Redis")?;
+    match &source.key {
+        Key::List(key) => {
+            info!("Consuming jobs from the Redis list {:?}", key);
+            pop(&mut connection, &source, &intake, key).await
+        }
+        Key::Stream {
+            key,
+            group,
+            consumer,
+        } => {
+            info!(
+                "Consuming jobs from the Redis stream {:?} as {:?} of group {:?}",
+                key, consumer, group
+            );
+            read(&mut connection, &source, &intake, key, group, consumer).await
+        }
+    }
+}
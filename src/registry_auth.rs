@@ -0,0 +1,162 @@
+//! Resolves the credentials used to pull images from registries.
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bollard::auth::DockerCredentials;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Registry assumed for images that don't name one.
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+/// Static credentials, indexed by registry host.
+static CREDENTIALS: OnceCell<HashMap<String, DockerCredentials>> = OnceCell::new();
+
+/// A single entry of the `auths` section of a docker config file.
+#[derive(Deserialize)]
+struct AuthEntry {
+    auth: Option<String>,
+    username: Option<String>,
+    password: Option<String>,
+    identitytoken: Option<String>,
+}
+
+/// The relevant part of a docker config file.
+#[derive(Deserialize)]
+struct DockerConfig {
+    #[serde(default)]
+    auths: HashMap<String, AuthEntry>,
+}
+
+/// Normalize a registry address as found in docker config files
+/// (e.g. https://index.docker.io/v1/) into a registry host.
+fn normalize_registry(address: &str) -> String {
+    let host = address
+        .strip_prefix("https://")
+        .or_else(|| address.strip_prefix("http://"))
+        .unwrap_or(address);
+    let host = host.split('/').next().unwrap_or(host);
+    match host {
+        "index.docker.io" | "registry-1.docker.io" => String::from(DEFAULT_REGISTRY),
+        _ => host.to_string(),
+    }
+}
+
+/// Split a base64-encoded `username:password` pair.
+fn decode_auth(auth: &str) -> Result<(String, String)> {
+    let decoded = String::from_utf8(STANDARD.decode(auth)?)?;
+    let (username, password) = decoded
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected username:password"))?;
+    Ok((username.to_string(), password.to_string()))
+}
+
+/// Read the credentials stored in a docker config file.
+fn read_config(path: &Path) -> Result<HashMap<String, DockerCredentials>> {
+    let config: DockerConfig = serde_json::from_str(
+        &std::fs::read_to_string(path).context("while reading the docker config file")?,
+    )
+    .context("while parsing the docker config file")?;
+    config
+        .auths
+        .into_iter()
+        .map(|(address, entry)| {
+            let (username, password) = match entry.auth {
+                Some(auth) if !auth.is_empty() => decode_auth(&auth)
+                    .map(|(username, password)| (Some(username), Some(password)))
+                    .with_context(|| format!("while decoding credentials for {:?}", address))?,
+                _ => (entry.username, entry.password),
+            };
+            let registry = normalize_registry(&address);
+            Ok((
+                registry.clone(),
+                DockerCredentials {
+                    username,
+                    password,
+                    identitytoken: entry.identitytoken,
+                    serveraddress: Some(registry),
+                    ..Default::default()
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Parse credentials given as `registry=username:password`.
+fn parse_credentials(value: &str) -> Result<(String, DockerCredentials)> {
+    let (registry, pair) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected registry=username:password"))?;
+    let (username, password) = pair
+        .split_once(':')
+        .ok_or_else(|| anyhow!("expected registry=username:password"))?;
+    let registry = normalize_registry(registry);
+    Ok((
+        registry.clone(),
+        DockerCredentials {
+            username: Some(username.to_string()),
+            password: Some(password.to_string()),
+            serveraddress: Some(registry),
+            ..Default::default()
+        },
+    ))
+}
+
+/// Initialize the registry credentials from an optional docker config
+/// file and a list of explicit credentials, which take precedence.
+pub fn init(config_file: Option<&Path>, credentials: &[String]) -> Result<()> {
+    let mut registries = match config_file {
+        Some(path) => read_config(path)?,
+        None => HashMap::new(),
+    };
+    for value in credentials {
+        let (registry, credentials) =
+            parse_credentials(value).context("while parsing registry credentials")?;
+        registries.insert(registry, credentials);
+    }
+    let _ = CREDENTIALS.set(registries);
+    Ok(())
+}
+
+/// Get the registry host of an image reference, following docker's
+/// rules: the first component names a registry only if it looks like
+/// a host.
+pub fn registry_of(image: &str) -> &str {
+    match image.split_once('/') {
+        Some((first, _)) if first.contains('.') || first.contains(':') || first == "localhost" => {
+            first
+        }
+        _ => DEFAULT_REGISTRY,
+    }
+}
+
+/// Get the credentials to use when pulling the given image, if any
+/// were configured for its registry.
+pub fn credentials_for(image: &str) -> Option<DockerCredentials> {
+    CREDENTIALS
+        .get()
+        .and_then(|registries| registries.get(registry_of(image)))
+        .cloned()
+}
+
+/// Get every configured credential, keyed as expected by the docker
+/// build API, so that base images may be pulled during builds.
+pub fn build_credentials() -> HashMap<String, DockerCredentials> {
+    CREDENTIALS
+        .get()
+        .map(|registries| {
+            registries
+                .iter()
+                .map(|(registry, credentials)| match registry.as_str() {
+                    DEFAULT_REGISTRY => (
+                        String::from("https://index.docker.io/v1/"),
+                        credentials.clone(),
+                    ),
+                    _ => (registry.clone(), credentials.clone()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,351 @@
+//! Applies changes to the filter and to the reloadable settings while
+//! running, on SIGHUP or through the admin API, without dropping
+//! connections.
+//!
+//! Reloadable settings are given as flags like every other setting,
+//! and may be overridden by a settings file holding more flags, which
+//! is re-read along with the filter file.
+
+use crate::{cleaner, jq, policy};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{value_parser, ArgAction, Args, Command, FromArgMatches};
+use serde::Serialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::signal::unix::{self, SignalKind};
+use tracing::{error, info};
+
+/// A value that may be replaced while running.
+pub struct Live<T>(RwLock<Arc<T>>);
+
+impl<T> Live<T> {
+    /// Wrap the initial value.
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(Arc::new(value)))
+    }
+
+    /// Get the current value.
+    pub fn get(&self) -> Arc<T> {
+        self.0.read().unwrap().clone()
+    }
+
+    /// Replace the current value.
+    fn set(&self, value: T) {
+        *self.0.write().unwrap() = Arc::new(value);
+    }
+}
+
+/// Settings that may be changed without restarting.
+#[derive(Args, Clone, Serialize)]
+#[command(next_help_heading = "Reloadable settings")]
+#[serde(rename_all = "kebab-case")]
+pub struct Tunables {
+    /// Maximum number of concurrently-running containers; default is
+    /// unlimited; set to 0 to never start jobs
+    #[arg(short, long, env)]
+    pub max_concurrent: Option<u16>,
+
+    /// Interval in seconds to keep an exited job; default is to keep
+    /// them forever
+    #[arg(short, long, env)]
+    pub keep_exited_for: Option<u32>,
+
+    /// Interval in seconds to keep a failed job (one that exited with
+    /// a non-zero exit code); default is to use the same interval as
+    /// for other exited jobs
+    #[arg(long, env)]
+    pub keep_failed_for: Option<u32>,
+
+    /// Number of most recent exited jobs to always keep, regardless of
+    /// age; older exited jobs beyond this number are removed
+    /// regardless of age
+    #[arg(long, env)]
+    pub keep_last: Option<usize>,
+
+    /// Interval in seconds to keep a job that was never started;
+    /// default is to keep them forever
+    #[arg(long, env)]
+    pub keep_created_for: Option<u32>,
+
+    /// Grouping of exited jobs for the --keep-last policy
+    #[arg(long, env, value_enum, default_value_t = cleaner::KeepLastBy::Namespace)]
+    pub keep_last_by: cleaner::KeepLastBy,
+
+    /// Image pattern (e.g. registry.example.com/*) jobs must match;
+    /// default is to allow every image
+    #[arg(long, env, value_delimiter = ',')]
+    pub allow_image: Vec<String>,
+
+    /// Image pattern jobs must not match
+    #[arg(long, env, value_delimiter = ',')]
+    pub deny_image: Vec<String>,
+
+    /// Pinning required of job images: tag forbids untagged and latest
+    /// images, digest requires images given by digest
+    #[arg(long, env, value_enum, default_value_t = policy::Pinning::Any)]
+    pub image_pinning: policy::Pinning,
+
+    /// Reject jobs that are privileged, use the network, PID, IPC, UTS
+    /// or user namespaces of the host or of other containers, take host
+    /// devices or other containers' volumes, disable their confinement,
+    /// or bind-mount host paths not allowed with --allow-bind
+    #[arg(long, env)]
+    pub restrict_host_access: bool,
+
+    /// Host path that jobs may bind-mount, along with its contents,
+    /// under --restrict-host-access
+    #[arg(long, env, value_delimiter = ',')]
+    pub allow_bind: Vec<PathBuf>,
+
+    /// Block privileged jobs, added capabilities, the network, PID and
+    /// IPC namespaces of the host or of other containers, host devices,
+    /// other containers' volumes, unconfined security options, and bind
+    /// mounts of the docker socket, regardless of the rest of the policy
+    #[arg(long, env)]
+    pub hardened: bool,
+
+    /// What --hardened does to manifests with dangerous fields: reject
+    /// them, or strip the fields
+    #[arg(long, env, value_enum, default_value_t = policy::Hardening::Reject)]
+    pub hardening: policy::Hardening,
+
+    /// Memory limit in bytes given to jobs that don't set one
+    #[arg(long, env, value_parser = value_parser!(i64).range(1..))]
+    pub default_memory: Option<i64>,
+
+    /// Number of CPUs given to jobs that don't set a CPU limit
+    #[arg(long, env)]
+    pub default_cpus: Option<f64>,
+
+    /// Maximum number of processes given to jobs that don't set a PIDs
+    /// limit
+    #[arg(long, env, value_parser = value_parser!(i64).range(1..))]
+    pub default_pids_limit: Option<i64>,
+
+    /// Maximum memory limit in bytes jobs may set; jobs without a
+    /// memory limit are rejected
+    #[arg(long, env, value_parser = value_parser!(i64).range(1..))]
+    pub max_memory: Option<i64>,
+
+    /// Maximum number of CPUs jobs may set; jobs without a CPU limit
+    /// are rejected
+    #[arg(long, env)]
+    pub max_cpus: Option<f64>,
+
+    /// Maximum number of processes jobs may set; jobs without a PIDs
+    /// limit are rejected
+    #[arg(long, env, value_parser = value_parser!(i64).range(1..))]
+    pub max_pids: Option<i64>,
+
+    /// Lower resource limits above --max-memory, --max-cpus and
+    /// --max-pids to the maximum (and set missing ones to it) instead
+    /// of rejecting jobs
+    #[arg(long, env)]
+    pub clamp_limits: bool,
+}
+
+impl Tunables {
+    /// Override these settings with the flags found in a settings
+    /// file, given one or more per line. Empty lines and lines starting
+    /// with # are ignored.
+    fn overridden(&self, path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("while reading the settings file {:?}", path))?;
+        let args = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .flat_map(str::split_whitespace);
+        // only the flags present in the file may override the current
+        // settings, so neither defaults nor environment variables apply,
+        // and switches take an optional value so that they may be
+        // turned off
+        let matches = Self::augment_args(Command::new("settings-file").no_binary_name(true))
+            .mut_args(|arg| match arg.get_action() {
+                ArgAction::SetTrue => arg
+                    .action(ArgAction::Set)
+                    .num_args(0..=1)
+                    .default_missing_value("true")
+                    .value_parser(value_parser!(bool))
+                    .env(None),
+                _ => arg.default_value(None).env(None),
+            })
+            .try_get_matches_from(args)
+            .map_err(|e| {
+                // keep only the message, without usage hints
+                let message = e.to_string();
+                anyhow!(message.lines().next().unwrap_or_default().to_string())
+            })
+            .with_context(|| format!("while parsing the settings file {:?}", path))?;
+        let mut tunables = self.clone();
+        tunables
+            .update_from_arg_matches(&matches)
+            .with_context(|| format!("while parsing the settings file {:?}", path))?;
+        Ok(tunables)
+    }
+
+    /// Build the rules job manifests must abide by.
+    pub fn policy(&self) -> Result<policy::Policy> {
+        Ok(policy::Policy {
+            allow_images: policy::compile_patterns(&self.allow_image)?,
+            deny_images: policy::compile_patterns(&self.deny_image)?,
+            pinning: self.image_pinning.clone(),
+            restrict_host_access: self.restrict_host_access,
+            allow_binds: self.allow_bind.clone(),
+            hardening: self.hardened.then(|| self.hardening.clone()),
+            max: policy::Limits {
+                memory: self.max_memory,
+                nano_cpus: self.max_cpus.map(|cpus| (cpus * 1e9) as i64),
+                pids: self.max_pids,
+            },
+            clamp: self.clamp_limits,
+            default: policy::Limits {
+                memory: self.default_memory,
+                nano_cpus: self.default_cpus.map(|cpus| (cpus * 1e9) as i64),
+                pids: self.default_pids_limit,
+            },
+        })
+    }
+
+    /// Build the retention settings for exited jobs.
+    pub fn retention(&self) -> cleaner::Retention {
+        cleaner::Retention {
+            keep_exited_for: self.keep_exited_for,
+            keep_failed_for: self.keep_failed_for,
+            keep_last: self.keep_last,
+            keep_last_by: self.keep_last_by.clone(),
+            keep_created_for: self.keep_created_for,
+        }
+    }
+}
+
+/// Describe the settings that differ, as flags with their old and new
+/// values.
+fn changes(old: &Tunables, new: &Tunables) -> Result<Vec<String>> {
+    let (Value::Object(old), Value::Object(new)) =
+        (serde_json::to_value(old)?, serde_json::to_value(new)?)
+    else {
+        bail!("settings aren't serialized as objects");
+    };
+    Ok(old
+        .iter()
+        .filter_map(|(flag, value)| {
+            let new_value = new.get(flag).unwrap_or(&Value::Null);
+            (value != new_value).then(|| format!("--{}: {} -> {}", flag, value, new_value))
+        })
+        .collect())
+}
+
+/// The sources of the filter and the reloadable settings, and the
+/// state built from them.
+pub struct Reloader {
+    filter_file: Option<PathBuf>,
+    settings_file: Option<PathBuf>,
+    base: Tunables,
+    current: Mutex<(String, Tunables)>,
+    pub filter: Arc<Live<jq::Filter>>,
+    pub policy: Arc<Live<policy::Policy>>,
+    pub retention: Arc<Live<cleaner::Retention>>,
+    pub max_concurrent: Arc<Live<Option<u16>>>,
+}
+
+impl Reloader {
+    /// Build the initial state out of the given filter and settings,
+    /// overridden by the settings file, if any. Only a filter given as
+    /// a file may be reloaded.
+    pub fn new(
+        filter_source: String,
+        filter_file: Option<PathBuf>,
+        base: Tunables,
+        settings_file: Option<PathBuf>,
+    ) -> Result<Self> {
+        let tunables = match &settings_file {
+            Some(path) => base.overridden(path)?,
+            None => base.clone(),
+        };
+        Ok(Self {
+            filter: Arc::new(Live::new(jq::compile(&filter_source)?)),
+            policy: Arc::new(Live::new(tunables.policy()?)),
+            retention: Arc::new(Live::new(tunables.retention())),
+            max_concurrent: Arc::new(Live::new(tunables.max_concurrent)),
+            filter_file,
+            settings_file,
+            base,
+            current: Mutex::new((filter_source, tunables)),
+        })
+    }
+
+    /// Get the settings in effect.
+    pub fn current(&self) -> Tunables {
+        self.current.lock().unwrap().1.clone()
+    }
+
+    /// Get the source of the filter in effect.
+    pub fn filter_source(&self) -> String {
+        self.current.lock().unwrap().0.clone()
+    }
+
+    /// Re-read the filter file and the settings file, and apply the
+    /// changes all at once, or none of them if any is invalid. Returns
+    /// the applied changes.
+    pub fn reload(&self) -> Result<Vec<String>> {
+        let mut current = self.current.lock().unwrap();
+        let (current_source, current_tunables) = &*current;
+        let source = match &self.filter_file {
+            Some(path) => std::fs::read_to_string(path)
+                .with_context(|| format!("while reading the filter file {:?}", path))?,
+            None => current_source.clone(),
+        };
+        let tunables = match &self.settings_file {
+            Some(path) => self.base.overridden(path)?,
+            None => self.base.clone(),
+        };
+        let mut changes = changes(current_tunables, &tunables)?;
+        if source != *current_source {
+            changes.insert(0, String::from("the filter"));
+        }
+        if changes.is_empty() {
+            info!("Reloaded the filter and settings; nothing changed");
+            return Ok(changes);
+        }
+        let limit_kind = |max_concurrent: Option<u16>| max_concurrent.map(|max| max > 0);
+        if limit_kind(current_tunables.max_concurrent) != limit_kind(tunables.max_concurrent) {
+            bail!("adding, removing or zeroing the concurrency limit requires a restart");
+        }
+        if current_tunables.retention().is_enabled() != tunables.retention().is_enabled() {
+            bail!("enabling or disabling the cleaner requires a restart");
+        }
+        let filter = (source != *current_source)
+            .then(|| jq::compile(&source))
+            .transpose()
+            .context("while compiling the filter")?;
+        let policy = tunables.policy()?;
+        if let Some(filter) = filter {
+            self.filter.set(filter);
+        }
+        self.policy.set(policy);
+        self.retention.set(tunables.retention());
+        self.max_concurrent.set(tunables.max_concurrent);
+        for change in &changes {
+            info!("Reloaded {}", change);
+        }
+        *current = (source, tunables);
+        Ok(changes)
+    }
+}
+
+/// Reload on every SIGHUP, endlessly.
+pub async fn watch(reloader: Arc<Reloader>) -> Result<()> {
+    let mut hangup = unix::signal(SignalKind::hangup()).context("while listening for SIGHUP")?;
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP; reloading the filter and settings");
+        if let Err(e) = reloader.reload() {
+            error!(
+                "Couldn't reload; keeping the current filter and settings: {:?}",
+                e
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Summarizes the retained jobs of a namespace by group, e.g. by path
+//! or image, to tell which kinds of jobs are failing without exporting
+//! metrics elsewhere.
+
+use crate::docker;
+use anyhow::{bail, Result};
+use bollard::models::ContainerSummary;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// Maximum amount of exited jobs inspected at once.
+const CONCURRENCY: usize = 8;
+
+/// What jobs are grouped by.
+pub enum GroupBy {
+    Path,
+    Image,
+    Label(String),
+}
+
+impl GroupBy {
+    /// Parse a grouping given as path, image or label:KEY.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "path" => Ok(Self::Path),
+            "image" => Ok(Self::Image),
+            _ => match value.strip_prefix("label:") {
+                Some(key) if !key.is_empty() => Ok(Self::Label(key.to_string())),
+                _ => bail!("jobs may be grouped by path, image or label:KEY"),
+            },
+        }
+    }
+
+    /// Get the group of a job.
+    fn key(&self, container: &ContainerSummary) -> Option<String> {
+        match self {
+            Self::Path => docker::label(container, docker::PATH_LABEL_KEY).map(String::from),
+            Self::Image => container.image.clone(),
+            Self::Label(key) => docker::label(container, key).map(String::from),
+        }
+    }
+}
+
+/// Counts of the jobs of a group, by state.
+#[derive(Default, Serialize, ToSchema)]
+pub(crate) struct JobGroup {
+    /// Path, image or label value the jobs share; null for the jobs
+    /// lacking one
+    #[schema(example = "/job/reports")]
+    key: Option<String>,
+    #[schema(example = 12)]
+    total: usize,
+    #[schema(example = 1)]
+    pending: usize,
+    #[schema(example = 1)]
+    running: usize,
+    /// Exited jobs with a zero exit code
+    #[schema(example = 8)]
+    succeeded: usize,
+    /// Exited jobs with a non-zero exit code, and dead jobs
+    #[schema(example = 2)]
+    failed: usize,
+    /// Share of succeeded jobs among the finished ones; null if none
+    /// finished
+    #[schema(example = 0.8)]
+    success_rate: Option<f64>,
+}
+
+/// Get the name of a container.
+fn name_of(container: &ContainerSummary) -> Option<&str> {
+    container
+        .names
+        .as_ref()
+        .and_then(|names| names.first())
+        .map(|name| name.trim_start_matches('/'))
+}
+
+/// Summarize the retained jobs of a namespace the given filter lets
+/// through, by group. Exited jobs are inspected for their exit codes.
+pub(crate) async fn summarize(
+    namespace: &str,
+    group_by: &GroupBy,
+    visible: impl Fn(&ContainerSummary) -> bool,
+) -> Result<Vec<JobGroup>> {
+    let containers = docker::get_by_status(
+        namespace,
+        &[
+            "created",
+            "restarting",
+            "running",
+            "paused",
+            "exited",
+            "dead",
+        ],
+    )
+    .await?
+    .into_iter()
+    .filter(|container| visible(container))
+    .collect::<Vec<_>>();
+    let exit_codes: Vec<Option<i64>> =
+        stream::iter(containers.iter().map(|container| async move {
+            match (container.state.as_deref(), name_of(container)) {
+                (Some("exited"), Some(name)) => docker::inspect(name)
+                    .await
+                    .map(|inspection| inspection.state.and_then(|state| state.exit_code)),
+                _ => Ok(None),
+            }
+        }))
+        .buffered(CONCURRENCY)
+        .try_collect()
+        .await?;
+    let mut groups: BTreeMap<Option<String>, JobGroup> = BTreeMap::new();
+    for (container, exit_code) in containers.iter().zip(exit_codes) {
+        let key = group_by.key(container);
+        let group = groups.entry(key.clone()).or_insert_with(|| JobGroup {
+            key,
+            ..Default::default()
+        });
+        group.total += 1;
+        match (container.state.as_deref(), exit_code) {
+            (Some("created"), _) => group.pending += 1,
+            (Some("exited"), Some(0)) => group.succeeded += 1,
+            (Some("exited" | "dead"), _) => group.failed += 1,
+            _ => group.running += 1,
+        }
+    }
+    Ok(groups
+        .into_values()
+        .map(|group| {
+            let finished = group.succeeded + group.failed;
+            JobGroup {
+                success_rate: (finished > 0).then(|| group.succeeded as f64 / finished as f64),
+                ..group
+            }
+        })
+        .collect())
+}
@@ -0,0 +1,70 @@
+//! Correlates API requests with the logs and jobs they produce, by
+//! giving each request an ID.
+
+use crate::telemetry;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    Error, HttpMessage, HttpRequest,
+};
+use tracing::{info_span, Instrument};
+
+/// Header holding the ID of a request, generated if missing.
+const HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Maximum length of request IDs given by clients.
+const MAX_LENGTH: usize = 128;
+
+/// The ID of a request, kept in its extensions.
+#[derive(Clone)]
+struct RequestId(String);
+
+/// Whether a request ID given by a client may be used as is, in logs,
+/// headers and labels.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_.:".contains(c))
+}
+
+/// Get the ID of a request, if it was given one.
+pub fn get(request: &HttpRequest) -> Option<String> {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .map(|id| id.0.clone())
+}
+
+/// Give each request an ID, honoring the one sent by the client if
+/// valid, and handle the request within a span holding the ID. The ID
+/// is sent back in the response.
+pub async fn correlate(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = request
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::trim)
+        .filter(|id| is_valid(id))
+        .map(String::from)
+        .unwrap_or_else(cuid2::create_id);
+    request.extensions_mut().insert(RequestId(id.clone()));
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = request.path(),
+        request_id = %id
+    );
+    telemetry::set_parent(&span, request.headers());
+    let mut response = next.call(request).instrument(span).await?;
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(HEADER, value);
+    }
+    Ok(response)
+}
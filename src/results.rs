@@ -0,0 +1,152 @@
+//! Captures the structured results of jobs as they exit, so that
+//! callers get a job's answer without parsing its output. A result is
+//! a JSON value written by the job to a well-known file, or else
+//! printed as the last line of its standard output.
+//!
+//! Results are kept in the job store if it's enabled, and in memory
+//! otherwise, in which case only the most recent ones are kept.
+
+use crate::docker;
+use crate::job_store;
+use crate::notifier;
+use anyhow::{bail, Context, Result};
+use futures::stream::TryStreamExt;
+use once_cell::sync::OnceCell;
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::io::Read;
+use std::sync::Mutex;
+use tracing::{debug, warn};
+
+/// Maximum amount of results kept in memory.
+const MAX_RECORDS: usize = 1000;
+
+/// Maximum amount of jobs whose results are captured at once.
+const CONCURRENCY: usize = 4;
+
+/// Room for the headers of the tar archive the result file is copied
+/// out in.
+const ARCHIVE_OVERHEAD: usize = 4096;
+
+/// Where results are captured from.
+pub struct Settings {
+    /// Path in the filesystem of jobs of the result file.
+    pub file: String,
+    /// Maximum size in bytes of a result.
+    pub max_size: usize,
+}
+
+/// Static result settings.
+static SETTINGS: OnceCell<Settings> = OnceCell::new();
+
+/// Static registry of recent results, by job name, ordered from
+/// oldest to newest.
+static RESULTS: OnceCell<Mutex<VecDeque<(String, Value)>>> = OnceCell::new();
+
+/// Initialize the global result settings.
+pub fn init(settings: Settings) {
+    let _ = SETTINGS.set(settings);
+}
+
+/// Get the registry of recent results.
+fn results() -> &'static Mutex<VecDeque<(String, Value)>> {
+    RESULTS.get_or_init(|| Mutex::new(VecDeque::new()))
+}
+
+/// Get the captured result of a job, if any.
+pub fn get(name: &str) -> Option<Value> {
+    results()
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map(|(_, result)| result.clone())
+        .or_else(|| job_store::get(name).and_then(|record| record.result))
+}
+
+/// Read the result file of a job, if it wrote one.
+async fn read_file(settings: &Settings, name: &str) -> Result<Option<Vec<u8>>> {
+    let archive = docker::download(name, &settings.file, settings.max_size + ARCHIVE_OVERHEAD)
+        .await
+        .ok();
+    let Some(archive) = archive else {
+        return Ok(None);
+    };
+    let mut archive = tar::Archive::new(archive.as_slice());
+    let Some(entry) = archive
+        .entries()
+        .context("while reading the result archive")?
+        .next()
+    else {
+        return Ok(None);
+    };
+    let entry = entry.context("while reading the result archive")?;
+    if !entry.header().entry_type().is_file() {
+        bail!("{:?} is not a regular file", settings.file);
+    }
+    let mut contents = Vec::new();
+    entry
+        .take(settings.max_size as u64 + 1)
+        .read_to_end(&mut contents)
+        .context("while reading the result file")?;
+    Ok(Some(contents))
+}
+
+/// Capture the result of an exited job, from its result file, or else
+/// from the last line of its output if it's valid JSON.
+async fn capture(settings: &Settings, name: &str) -> Result<Option<Value>> {
+    if let Some(contents) = read_file(settings, name).await? {
+        if contents.len() > settings.max_size {
+            bail!("result file exceeds {} bytes", settings.max_size);
+        }
+        return serde_json::from_slice(&contents)
+            .map(Some)
+            .context("result file is not valid JSON");
+    }
+    let Some(line) = docker::last_output_line(name).await? else {
+        return Ok(None);
+    };
+    if line.len() > settings.max_size {
+        debug!(
+            "Last output line of job {:?} is too long for a result",
+            name
+        );
+        return Ok(None);
+    }
+    Ok(serde_json::from_str::<Value>(&line).ok())
+}
+
+/// Consume the docker events stream, capturing the result of each job
+/// that exits.
+pub async fn watch(namespace: String) -> Result<()> {
+    let Some(settings) = SETTINGS.get() else {
+        return Ok(());
+    };
+    notifier::transitions(&namespace)?
+        .try_filter(|transition| {
+            futures::future::ready(matches!(transition.event, "succeeded" | "failed"))
+        })
+        .try_for_each_concurrent(CONCURRENCY, |transition| async move {
+            match capture(settings, &transition.id).await {
+                Ok(Some(result)) => {
+                    debug!("Captured the result of job {:?}", transition.id);
+                    job_store::update(&transition.id, |record| {
+                        record.result = Some(result.clone());
+                    });
+                    let mut results = results().lock().unwrap();
+                    results.push_back((transition.id, result));
+                    if results.len() > MAX_RECORDS {
+                        results.pop_front();
+                    }
+                }
+                Ok(None) => debug!("Job {:?} left no result", transition.id),
+                Err(e) => warn!(
+                    "Couldn't capture the result of job {:?}: {:?}",
+                    transition.id, e
+                ),
+            }
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,204 @@
+//! Resolves the secrets jobs may request, and packs them as files to
+//! be copied into the secrets volume mounted in job containers, along
+//! with the environment variables of the dispatcher passed on to jobs.
+
+use anyhow::{anyhow, bail, Context, Result};
+use glob::Pattern;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+/// Where the value of a secret is read from.
+enum Source {
+    File(PathBuf),
+    Env(String),
+}
+
+/// Static secret sources, indexed by secret name.
+static SECRETS: OnceCell<HashMap<String, Source>> = OnceCell::new();
+
+/// Static directory of job containers where secrets are placed.
+static SECRETS_PATH: OnceCell<PathBuf> = OnceCell::new();
+
+/// Static patterns of the names of environment variables passed on to
+/// jobs.
+static PASS_ENV: OnceCell<Vec<Pattern>> = OnceCell::new();
+
+/// Subdirectory of the secrets directory holding the environment
+/// variables passed on to jobs, one file each.
+pub const ENV_DIRECTORY: &str = "env";
+
+/// Parse a secret given as name=file:/path or name=env:VARIABLE.
+fn parse_secret(value: &str) -> Result<(String, Source)> {
+    let (name, source) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected name=file:path or name=env:variable"))?;
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("invalid secret name {:?}", name);
+    }
+    if name == ENV_DIRECTORY {
+        bail!(
+            "the secret name {:?} is reserved for passed environment variables",
+            name
+        );
+    }
+    let source = match source.split_once(':') {
+        Some(("file", path)) => Source::File(PathBuf::from(path)),
+        Some(("env", variable)) => Source::Env(variable.to_string()),
+        _ => bail!("expected name=file:path or name=env:variable"),
+    };
+    Ok((name.to_string(), source))
+}
+
+/// Initialize the registered secrets, and the directory where they're
+/// placed in job containers.
+pub fn init(secrets: &[String], path: PathBuf) -> Result<()> {
+    let mut sources = HashMap::new();
+    for secret in secrets.iter().filter(|secret| !secret.is_empty()) {
+        let (name, source) =
+            parse_secret(secret).with_context(|| format!("while parsing secret {:?}", secret))?;
+        sources.insert(name, source);
+    }
+    if !path.is_absolute() || path.parent().is_none() {
+        bail!(
+            "the secrets path {:?} is not an absolute path below the root",
+            path
+        );
+    }
+    let _ = SECRETS.set(sources);
+    let _ = SECRETS_PATH.set(path);
+    Ok(())
+}
+
+/// Whether any secret is registered.
+pub fn is_enabled() -> bool {
+    SECRETS.get().is_some_and(|secrets| !secrets.is_empty())
+}
+
+/// Whether a secret with the given name is registered.
+pub fn is_registered(name: &str) -> bool {
+    SECRETS
+        .get()
+        .is_some_and(|secrets| secrets.contains_key(name))
+}
+
+/// Compile the patterns (e.g. AWS_*) of the names of environment
+/// variables passed on to jobs.
+pub fn init_pass_env(patterns: &[String]) -> Result<()> {
+    let patterns = patterns
+        .iter()
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            Pattern::new(pattern)
+                .with_context(|| format!("invalid environment variable pattern {:?}", pattern))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let _ = PASS_ENV.set(patterns);
+    Ok(())
+}
+
+/// Whether any environment variable is passed on to jobs.
+pub fn passes_env() -> bool {
+    PASS_ENV.get().is_some_and(|patterns| !patterns.is_empty())
+}
+
+/// The current environment variables passed on to jobs, as name and
+/// value.
+fn passed_env() -> Vec<(String, String)> {
+    let Some(patterns) = PASS_ENV.get().filter(|patterns| !patterns.is_empty()) else {
+        return Vec::new();
+    };
+    std::env::vars()
+        .filter(|(key, _)| patterns.iter().any(|pattern| pattern.matches(key)))
+        .collect()
+}
+
+/// The current values of the secrets read from environment variables,
+/// and of the environment variables passed on to jobs.
+pub fn env_values() -> Vec<String> {
+    SECRETS
+        .get()
+        .into_iter()
+        .flat_map(|secrets| secrets.values())
+        .filter_map(|source| match source {
+            Source::Env(variable) => std::env::var(variable).ok(),
+            Source::File(_) => None,
+        })
+        .chain(passed_env().into_iter().map(|(_, value)| value))
+        .collect()
+}
+
+/// The directory of job containers where secrets are placed, if
+/// initialized.
+pub fn path() -> Option<&'static Path> {
+    SECRETS_PATH.get().map(PathBuf::as_path)
+}
+
+/// Read the current value of a secret.
+fn read(name: &str) -> Result<Vec<u8>> {
+    match SECRETS.get().and_then(|secrets| secrets.get(name)) {
+        Some(Source::File(path)) => std::fs::read(path)
+            .with_context(|| format!("while reading secret {:?} from {:?}", name, path)),
+        Some(Source::Env(variable)) => std::env::var(variable)
+            .map(String::into_bytes)
+            .with_context(|| format!("while reading secret {:?} from ${}", name, variable)),
+        None => bail!("secret {:?} is not registered", name),
+    }
+}
+
+/// A tar header for an entry of the given type, mode, owner and size.
+fn header(
+    entry_type: tar::EntryType,
+    mode: u32,
+    (uid, gid): (u64, u64),
+    size: usize,
+) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(entry_type);
+    header.set_mode(mode);
+    header.set_uid(uid);
+    header.set_gid(gid);
+    header.set_size(size as u64);
+    header
+}
+
+/// Build a tar archive holding the current values of the given
+/// secrets, and of the environment variables passed on to jobs in the
+/// env subdirectory, to be extracted at the container's root, as
+/// files only the given user and group (as numeric IDs) may read. The
+/// secrets directory is included, since it may not exist in the image.
+pub fn archive(names: &[&str], owner: (u64, u64)) -> Result<Vec<u8>> {
+    let path = SECRETS_PATH
+        .get()
+        .context("secrets have not been initialized")?;
+    let relative = path.strip_prefix("/").unwrap_or(path.as_path());
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut directory = header(tar::EntryType::Directory, 0o755, (0, 0), 0);
+    builder.append_data(&mut directory, relative, std::io::empty())?;
+    for name in names {
+        let value = read(name)?;
+        let mut file = header(tar::EntryType::Regular, 0o400, owner, value.len());
+        builder.append_data(&mut file, relative.join(name), value.as_slice())?;
+    }
+    let variables: Vec<(String, String)> = passed_env()
+        .into_iter()
+        .filter(|(key, _)| !key.contains('/') && key != "." && key != "..")
+        .collect();
+    if !variables.is_empty() {
+        let mut directory = header(tar::EntryType::Directory, 0o500, owner, 0);
+        builder.append_data(
+            &mut directory,
+            relative.join(ENV_DIRECTORY),
+            std::io::empty(),
+        )?;
+    }
+    for (key, value) in variables {
+        let mut file = header(tar::EntryType::Regular, 0o400, owner, value.len());
+        builder.append_data(
+            &mut file,
+            relative.join(ENV_DIRECTORY).join(key),
+            value.as_bytes(),
+        )?;
+    }
+    Ok(builder.into_inner()?)
+}
@@ -0,0 +1,255 @@
+//! Routes requests among several dispatcher instances sharing a
+//! docker daemon, each one serving its own namespace. Requests may
+//! name the namespace they're meant for, and those meant for another
+//! instance are refused, pointing to the instance serving it. Requests
+//! may also select the namespace they're meant for, so that producers
+//! whose URLs can't be changed may still reach any of them, and those
+//! selecting another namespace are forwarded to the instance serving
+//! it, once authenticated and authorized here.
+
+use crate::api_error::APIError;
+use crate::docker_service;
+use crate::ip_filter;
+use actix_web::{
+    body::{BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{HeaderName, HeaderValue, LOCATION},
+        StatusCode,
+    },
+    middleware::Next,
+    web, Error, FromRequest, HttpResponse, ResponseError,
+};
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+/// Request header naming the namespace a request is meant for.
+pub const NAMESPACE_HEADER: &str = "X-Dispatcher-Namespace";
+
+/// Request header selecting the namespace a request is meant for,
+/// among those served by this instance and the known shards.
+pub const NAMESPACE_SELECTION_HEADER: &str = "X-Namespace";
+
+/// Request header telling the instance a request is forwarded to the
+/// address of the client behind it.
+const FORWARDED_FOR_HEADER: &str = "X-Forwarded-For";
+
+/// Static client used to forward requests to other instances.
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+
+/// The namespace served by this instance, and the base URLs of the
+/// instances serving other namespaces.
+pub struct Shards {
+    pub namespace: String,
+    pub owners: HashMap<String, String>,
+}
+
+impl Shards {
+    /// Build the shard map from namespace=URL pairs.
+    pub fn new(namespace: String, owners: &[String]) -> Result<Self> {
+        let owners = owners
+            .iter()
+            .filter(|owner| !owner.is_empty())
+            .map(|owner| {
+                owner
+                    .split_once('=')
+                    .map(|(namespace, url)| {
+                        (namespace.to_string(), url.trim_end_matches('/').to_string())
+                    })
+                    .with_context(|| format!("shard {:?} is not a namespace=URL pair", owner))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Self { namespace, owners })
+    }
+}
+
+/// Whether a path belongs to an endpoint handling jobs of the
+/// namespace, as opposed to the health, metrics and docs endpoints.
+fn is_namespaced(path: &str) -> bool {
+    path == "/job"
+        || path.starts_with("/job/")
+        || path.starts_with("/jobs/")
+        || path.starts_with("/admin/")
+        || path.starts_with("/templates/")
+        || path == "/graphql"
+}
+
+/// The namespace a request selects with the X-Namespace header, if
+/// any.
+pub fn selected(request: &ServiceRequest) -> Option<&str> {
+    header(request, NAMESPACE_SELECTION_HEADER)
+}
+
+/// Get the value of a header of a request.
+fn header<'a>(request: &'a ServiceRequest, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// Whether a header applies only to a single connection, and isn't
+/// forwarded.
+fn is_hop_by_hop(name: &str) -> bool {
+    matches!(
+        name.to_ascii_lowercase().as_str(),
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailer"
+            | "transfer-encoding"
+            | "upgrade"
+            | "host"
+            | "content-length"
+    )
+}
+
+/// Forward a request to the instance at the given URL, naming the
+/// namespace it's meant for so that it isn't forwarded again, and
+/// relay the instance's response. The instance authenticates and
+/// authorizes the request again, with the caller's credentials, and
+/// is given the client's address in X-Forwarded-For, replacing any
+/// given by the client, for it to honor if this instance is among its
+/// trusted proxies.
+async fn forward(
+    request: ServiceRequest,
+    namespace: &str,
+    url: &str,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let (request, mut payload) = request.into_parts();
+    let body = web::Bytes::from_request(&request, &mut payload).await?;
+    let target = format!(
+        "{}{}",
+        url,
+        request
+            .uri()
+            .path_and_query()
+            .map_or(request.path(), |path| path.as_str())
+    );
+    let method = reqwest::Method::from_bytes(request.method().as_str().as_bytes())
+        .map_err(APIError::bad_request)?;
+    let mut forwarded = CLIENT.request(method, &target).body(body);
+    for (name, value) in request.headers() {
+        if !is_hop_by_hop(name.as_str())
+            && !name
+                .as_str()
+                .eq_ignore_ascii_case(NAMESPACE_SELECTION_HEADER)
+            && !name.as_str().eq_ignore_ascii_case(FORWARDED_FOR_HEADER)
+        {
+            forwarded = forwarded.header(name.as_str(), value.as_bytes());
+        }
+    }
+    if let Some(client) = ip_filter::client_ip(&request) {
+        forwarded = forwarded.header(FORWARDED_FOR_HEADER, client.to_string());
+    }
+    let response = match forwarded.header(NAMESPACE_HEADER, namespace).send().await {
+        Ok(response) => response,
+        Err(e) => {
+            let error = APIError::bad_gateway(format!(
+                "Namespace {:?} is served at {}, which couldn't be reached: {}",
+                namespace, url, e
+            ));
+            return Ok(ServiceResponse::from_err(error, request));
+        }
+    };
+    let mut relayed = HttpResponse::build(
+        StatusCode::from_u16(response.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
+    );
+    for (name, value) in response.headers() {
+        if !is_hop_by_hop(name.as_str()) {
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name.as_str()),
+                HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                relayed.append_header((name, value));
+            }
+        }
+    }
+    let relayed = match response.bytes().await {
+        Ok(body) => relayed.body(body),
+        Err(e) => APIError::bad_gateway(format!(
+            "while reading the response of the instance serving namespace {:?}: {}",
+            namespace, e
+        ))
+        .error_response(),
+    };
+    Ok(ServiceResponse::new(request, relayed))
+}
+
+/// Refuse requests meant for a namespace other than the one served,
+/// with a `421 Misdirected Request` response and the location of the
+/// same request at the instance serving that namespace, if known.
+/// Requests selecting another namespace with the X-Namespace header
+/// are forwarded to the instance serving it instead, and refused if
+/// it isn't known or the caller's API key is restricted to another
+/// namespace. This runs after authentication and authorization, so
+/// that only requests allowed here are forwarded.
+pub async fn route(
+    request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let named = header(&request, NAMESPACE_HEADER);
+    let selected = header(&request, NAMESPACE_SELECTION_HEADER);
+    if named
+        .zip(selected)
+        .is_some_and(|(named, selected)| named != selected)
+    {
+        let error = APIError::bad_request(format!(
+            "The {} and {} headers name different namespaces",
+            NAMESPACE_HEADER, NAMESPACE_SELECTION_HEADER
+        ));
+        return Ok(request.error_response(error).map_into_right_body());
+    }
+    let shards = request.app_data::<web::Data<Shards>>().cloned();
+    if let Some((shards, selected)) = shards.as_ref().zip(selected).filter(|(shards, selected)| {
+        named.is_none() && *selected != shards.namespace && is_namespaced(request.path())
+    }) {
+        let Some(url) = shards.owners.get(selected) else {
+            let error = APIError::misdirected(format!(
+                "Namespace {:?} isn't served by this instance or any known one",
+                selected
+            ));
+            return Ok(request.error_response(error).map_into_right_body());
+        };
+        if let Err(error) = docker_service::check_namespace(request.request(), selected) {
+            return Ok(request.error_response(error).map_into_right_body());
+        }
+        let (selected, url) = (selected.to_string(), url.clone());
+        return forward(request, &selected, &url)
+            .await
+            .map(ServiceResponse::map_into_right_body);
+    }
+    let misdirected = request
+        .app_data::<web::Data<Shards>>()
+        .zip(named)
+        .filter(|(shards, wanted)| *wanted != shards.namespace && is_namespaced(request.path()))
+        .map(|(shards, wanted)| {
+            let location = shards.owners.get(wanted).map(|url| {
+                format!(
+                    "{}{}",
+                    url,
+                    request
+                        .uri()
+                        .path_and_query()
+                        .map_or(request.path(), |path| path.as_str())
+                )
+            });
+            (wanted.to_string(), location)
+        });
+    if let Some((wanted, location)) = misdirected {
+        let mut response = request.error_response(APIError::misdirected(match &location {
+            Some(location) => format!("Namespace {:?} is served at {}", wanted, location),
+            None => format!("Namespace {:?} isn't served by this instance", wanted),
+        }));
+        if let Some(location) = location.and_then(|location| HeaderValue::try_from(location).ok()) {
+            response.headers_mut().insert(LOCATION, location);
+        }
+        return Ok(response.map_into_right_body());
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
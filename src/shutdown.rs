@@ -0,0 +1,57 @@
+//! Coordinates a graceful shutdown on SIGTERM or SIGINT.
+//!
+//! Shutting down happens in phases: first new jobs are refused while
+//! in-flight requests and the current upkeep cycles finish, and then
+//! metrics are flushed.
+
+use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use tokio::signal::{
+    self,
+    unix::{self, SignalKind},
+};
+use tokio::sync::watch;
+use tracing::info;
+
+/// A phase of the process' life.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Running,
+    Stopping,
+    Flushing,
+}
+
+/// Static current phase.
+static PHASE: Lazy<watch::Sender<Phase>> = Lazy::new(|| watch::channel(Phase::Running).0);
+
+/// Wait for a termination signal.
+pub async fn signal() -> Result<()> {
+    let mut terminate =
+        unix::signal(SignalKind::terminate()).context("while listening for SIGTERM")?;
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        result = signal::ctrl_c() => {
+            result.context("while listening for SIGINT")?;
+            info!("Received SIGINT");
+        }
+    }
+    Ok(())
+}
+
+/// Move on to the given phase.
+pub fn enter(phase: Phase) {
+    PHASE.send_replace(phase);
+}
+
+/// Whether the process is shutting down.
+pub fn is_stopping() -> bool {
+    *PHASE.borrow() >= Phase::Stopping
+}
+
+/// Wait until the given phase is reached.
+pub async fn reached(phase: Phase) {
+    let _ = PHASE
+        .subscribe()
+        .wait_for(|current| *current >= phase)
+        .await;
+}
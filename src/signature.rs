@@ -0,0 +1,345 @@
+//! Verifies the signatures webhook providers attach to job creation
+//! requests, so that the filter only ever runs on payloads sent by
+//! whoever holds the shared secret.
+
+use crate::api_error::APIError;
+use crate::internal::equals;
+use actix_web::{
+    body::MessageBody,
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    error::PayloadError,
+    http::Method,
+    middleware::Next,
+    web, Error, HttpMessage,
+};
+use clap::ValueEnum;
+use futures::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Maximum age in seconds of timestamped signatures, past which they
+/// may be replayed payloads.
+const TOLERANCE: u64 = 300;
+
+/// Maximum size in bytes of signed bodies, the same as that of JSON
+/// bodies.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+/// How a webhook provider signs its requests.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Style {
+    /// A hex HMAC-SHA256 of the body.
+    #[default]
+    Github,
+    /// The shared secret itself, as a token.
+    Gitlab,
+    /// A timestamped HMAC-SHA256 of the body.
+    Stripe,
+}
+
+impl Style {
+    /// The header the provider puts the signature in.
+    pub fn default_header(&self) -> &'static str {
+        match self {
+            Self::Github => "X-Hub-Signature-256",
+            Self::Gitlab => "X-Gitlab-Token",
+            Self::Stripe => "Stripe-Signature",
+        }
+    }
+}
+
+/// The secret job creation requests must be signed with, and how.
+#[derive(Default)]
+pub struct Signatures {
+    pub secret: Option<String>,
+    pub style: Style,
+    pub header: String,
+}
+
+/// The hex HMAC-SHA256 of the given parts with the given secret.
+pub(crate) fn hmac_hex(secret: &str, parts: &[&[u8]]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+impl Signatures {
+    /// Whether requests must be signed.
+    pub fn is_enabled(&self) -> bool {
+        self.secret.is_some()
+    }
+
+    /// Check the signature given for a body, in the configured style.
+    fn verify(&self, signature: &str, body: &[u8]) -> Result<(), &'static str> {
+        let Some(secret) = &self.secret else {
+            return Ok(());
+        };
+        let valid = match self.style {
+            Style::Github => {
+                let given = signature.strip_prefix("sha256=").unwrap_or(signature);
+                equals(
+                    given.to_lowercase().as_bytes(),
+                    hmac_hex(secret, &[body]).as_bytes(),
+                )
+            }
+            Style::Gitlab => equals(signature.as_bytes(), secret.as_bytes()),
+            Style::Stripe => {
+                let fields = signature
+                    .split(',')
+                    .filter_map(|field| field.trim().split_once('='));
+                let timestamp = fields
+                    .clone()
+                    .find(|(key, _)| *key == "t")
+                    .map(|(_, value)| value)
+                    .ok_or("Webhook signature lacks a timestamp")?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |elapsed| elapsed.as_secs());
+                if !timestamp
+                    .parse::<u64>()
+                    .is_ok_and(|signed| now.abs_diff(signed) <= TOLERANCE)
+                {
+                    return Err("Webhook signature is expired");
+                }
+                let expected = hmac_hex(secret, &[timestamp.as_bytes(), b".", body]);
+                fields
+                    .filter(|(key, _)| *key == "v1")
+                    .any(|(_, given)| equals(given.as_bytes(), expected.as_bytes()))
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err("Invalid webhook signature")
+        }
+    }
+}
+
+/// Whether a request creates a job, either directly or from a
+/// template, rather than releasing a held job, which carries no body
+/// to sign. Templates are registered first, so a template named
+/// "release" still routes to them.
+fn is_creation(method: &Method, path: &str) -> bool {
+    if *method != Method::POST {
+        return false;
+    }
+    if path == "/job" {
+        return true;
+    }
+    let Some(rest) = path.strip_prefix("/job/") else {
+        return false;
+    };
+    let segments: Vec<&str> = rest.split('/').collect();
+    !matches!(
+        segments.as_slice(),
+        [id, "release"] if !id.is_empty() && *id != "from-template"
+    )
+}
+
+/// Reject job creation requests lacking a valid signature of their
+/// body, if signatures are required. The body is read in full, and
+/// handed back to the request for the filter to run on.
+pub async fn verify(
+    mut request: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let signatures = request
+        .app_data::<web::Data<Signatures>>()
+        .filter(|signatures| signatures.is_enabled())
+        .filter(|_| is_creation(request.method(), request.path()))
+        .cloned();
+    if let Some(signatures) = signatures {
+        let signature = request
+            .headers()
+            .get(signatures.header.as_str())
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.trim().to_string());
+        let mut payload = request.take_payload();
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            body.extend_from_slice(&chunk?);
+            if body.len() > MAX_BODY {
+                Err(PayloadError::Overflow)?;
+            }
+        }
+        let body = body.freeze();
+        let refusal = match signature {
+            Some(signature) => signatures.verify(&signature, &body).err(),
+            None => Some("Missing webhook signature"),
+        };
+        if let Some(refusal) = refusal {
+            let response = request.error_response(APIError::unauthorized(refusal));
+            return Ok(response.map_into_right_body());
+        }
+        request.set_payload(Payload::from(body));
+    }
+    next.call(request)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hmac_hex, is_creation, verify, Signatures, Style, MAX_BODY, TOLERANCE};
+    use actix_web::{
+        http::{Method, StatusCode},
+        middleware,
+        test::{init_service, try_call_service, TestRequest},
+        web, App, HttpResponse,
+    };
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// The secret requests are signed with.
+    const SECRET: &str = "secret";
+
+    /// The body of the signed requests.
+    const BODY: &[u8] = br#"{"image": "alpine"}"#;
+
+    /// Signatures required with the test secret, in the given style.
+    fn signatures(style: Style) -> Signatures {
+        Signatures {
+            secret: Some(String::from(SECRET)),
+            header: style.default_header().to_string(),
+            style,
+        }
+    }
+
+    /// A Stripe-style signature of a body, made at the given UNIX time.
+    fn stripe(timestamp: u64, body: &[u8]) -> String {
+        format!(
+            "t={},v1={}",
+            timestamp,
+            hmac_hex(SECRET, &[timestamp.to_string().as_bytes(), b".", body])
+        )
+    }
+
+    /// The current UNIX time.
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// The status given to a POST request with the given path, body and
+    /// GitHub-style signature, by an application requiring them.
+    async fn status(path: &str, body: Vec<u8>, signature: Option<&str>) -> StatusCode {
+        let application = init_service(
+            App::new()
+                .app_data(web::Data::new(signatures(Style::Github)))
+                .wrap(middleware::from_fn(verify))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut request = TestRequest::post().uri(path).set_payload(body);
+        if let Some(signature) = signature {
+            request = request.insert_header((Style::Github.default_header(), signature));
+        }
+        match try_call_service(&application, request.to_request()).await {
+            Ok(response) => response.status(),
+            Err(e) => e.as_response_error().status_code(),
+        }
+    }
+
+    #[test]
+    fn valid_signatures_are_accepted() {
+        let github = format!("sha256={}", hmac_hex(SECRET, &[BODY]));
+        assert!(signatures(Style::Github).verify(&github, BODY).is_ok());
+        assert!(signatures(Style::Github)
+            .verify(&github.to_uppercase().replace("SHA256=", ""), BODY)
+            .is_ok());
+        assert!(signatures(Style::Gitlab).verify(SECRET, BODY).is_ok());
+        assert!(signatures(Style::Stripe)
+            .verify(&stripe(now(), BODY), BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn tampered_bodies_are_refused() {
+        let tampered = br#"{"image": "evil"}"#;
+        let github = format!("sha256={}", hmac_hex(SECRET, &[BODY]));
+        assert!(signatures(Style::Github).verify(&github, tampered).is_err());
+        assert!(signatures(Style::Stripe)
+            .verify(&stripe(now(), BODY), tampered)
+            .is_err());
+        assert!(signatures(Style::Gitlab).verify("secreT", BODY).is_err());
+    }
+
+    #[test]
+    fn stale_timestamps_are_refused() {
+        let stale = now() - TOLERANCE - 60;
+        assert!(signatures(Style::Stripe)
+            .verify(&stripe(stale, BODY), BODY)
+            .is_err());
+        let early = now() + TOLERANCE + 60;
+        assert!(signatures(Style::Stripe)
+            .verify(&stripe(early, BODY), BODY)
+            .is_err());
+        let untimed = format!("v1={}", hmac_hex(SECRET, &[BODY]));
+        assert!(signatures(Style::Stripe).verify(&untimed, BODY).is_err());
+    }
+
+    #[test]
+    fn job_creations_are_told_apart() {
+        for path in [
+            "/job",
+            "/job/reports",
+            "/job/reports/nightly",
+            "/job/from-template/nightly",
+            "/job/from-template/release",
+            "/job/reports/nightly/release",
+        ] {
+            assert!(is_creation(&Method::POST, path), "{}", path);
+        }
+        assert!(!is_creation(&Method::POST, "/job/nightly/release"));
+        assert!(!is_creation(&Method::GET, "/job/nightly"));
+        assert!(!is_creation(&Method::PUT, "/job/nightly/progress"));
+        assert!(!is_creation(&Method::POST, "/jobs"));
+        assert!(!is_creation(&Method::POST, "/admin/purge"));
+    }
+
+    #[actix_web::test]
+    async fn signed_bodies_reach_the_handler() {
+        let signature = format!("sha256={}", hmac_hex(SECRET, &[BODY]));
+        assert_eq!(
+            status("/job", BODY.to_vec(), Some(&signature)).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status("/job/reports", b"{}".to_vec(), Some(&signature)).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("/job", BODY.to_vec(), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status("/job/nightly/release", Vec::new(), None).await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn unsigned_jobs_from_templates_are_refused() {
+        assert_eq!(
+            status("/job/from-template/nightly", BODY.to_vec(), None).await,
+            StatusCode::UNAUTHORIZED
+        );
+    }
+
+    #[actix_web::test]
+    async fn oversized_bodies_are_refused() {
+        let body = vec![b' '; MAX_BODY + 1];
+        let signature = format!("sha256={}", hmac_hex(SECRET, &[&body]));
+        assert_eq!(
+            status("/job", body, Some(&signature)).await,
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Long-polls an AWS SQS queue for jobs, as an alternative to the HTTP
+//! API. Each message goes through the filter like a request body
+//! would, and is deleted only once its job is created; messages whose
+//! job couldn't be created reappear once their visibility timeout
+//! expires, and are retried then.
+
+use crate::audit;
+use crate::docker_service::{self, Intake, Submission};
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_sqs::{types::Message, Client};
+use futures::future::join_all;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Seconds each receive request waits for messages, the maximum SQS
+/// allows.
+const WAIT_TIME: i32 = 20;
+
+/// Where and how to poll messages.
+pub struct Source {
+    pub queue_url: String,
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    /// Maximum number of messages received at once, processed
+    /// concurrently.
+    pub max_messages: u8,
+    /// Seconds received messages stay hidden from other consumers,
+    /// instead of the queue's default.
+    pub visibility_timeout: Option<u32>,
+}
+
+impl Source {
+    /// Build a client with credentials taken from the standard AWS
+    /// sources (environment, profiles, instance metadata).
+    async fn client(&self) -> Client {
+        let mut loader = aws_config::defaults(BehaviorVersion::latest());
+        if let Some(region) = &self.region {
+            loader = loader.region(Region::new(region.clone()));
+        }
+        let shared_config = loader.load().await;
+        let mut config = aws_sdk_sqs::config::Builder::from(&shared_config);
+        if let Some(endpoint) = &self.endpoint {
+            config = config.endpoint_url(endpoint);
+        }
+        Client::from_conf(config.build())
+    }
+}
+
+/// Create the job of a message, made available to the filter as
+/// `$MESSAGE`, and delete it once created. Messages that will never
+/// make a job are deleted as well, and the rest are left for their
+/// visibility timeout to expire.
+async fn process(
+    client: &Client,
+    source: &Source,
+    intake: &Intake,
+    message: &Message,
+) -> Result<()> {
+    let message_id = message.message_id().unwrap_or_default();
+    let outcome = match serde_json::from_str::<Value>(message.body().unwrap_or_default()) {
+        Ok(input) => {
+            let submission = Submission {
+                input,
+                path: String::from("/sqs"),
+                claims: Value::Null,
+                message: json!({"queue_url": source.queue_url, "message_id": message_id}),
+                reply_subject: None,
+                actor: audit::Actor {
+                    submitter: Some(format!("sqs:{}", source.queue_url)),
+                    ..Default::default()
+                },
+                hold: false,
+                labels: Default::default(),
+                on_duplicate: None,
+            };
+            docker_service::submit(intake, submission)
+                .await
+                .map(|_| ())
+                .map_err(|e| (e.is_permanent(), e.to_string()))
+        }
+        Err(e) => Err((true, format!("body is not valid JSON: {}", e))),
+    };
+    match outcome {
+        Ok(()) => debug!("SQS message {:?} made a job", message_id),
+        Err((true, e)) => warn!("Rejected the SQS message {:?}: {}", message_id, e),
+        Err((false, e)) => {
+            warn!(
+                "Couldn't create the job of the SQS message {:?}; \
+                 it will be retried once visible again: {}",
+                message_id, e
+            );
+            return Ok(());
+        }
+    }
+    let Some(receipt_handle) = message.receipt_handle() else {
+        return Ok(());
+    };
+    client
+        .delete_message()
+        .queue_url(&source.queue_url)
+        .receipt_handle(receipt_handle)
+        .send()
+        .await
+        .context("while deleting an SQS message")?;
+    Ok(())
+}
+
+/// Poll the queue, creating a job for each message received.
+pub async fn consume(source: Arc<Source>, intake: Intake) -> Result<()> {
+    let client = source.client().await;
+    info!(
+        "Consuming jobs from the SQS queue {:?}, {} at a time",
+        source.queue_url, source.max_messages
+    );
+    loop {
+        let received = tokio::select! {
+            received = client
+                .receive_message()
+                .queue_url(&source.queue_url)
+                .max_number_of_messages(source.max_messages.into())
+                .wait_time_seconds(WAIT_TIME)
+                .set_visibility_timeout(source.visibility_timeout.map(|timeout| timeout as i32))
+                .send() => received.context("while receiving SQS messages")?,
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        };
+        join_all(
+            received
+                .messages()
+                .iter()
+                .map(|message| process(&client, &source, &intake, message)),
+        )
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>>>()?;
+    }
+}
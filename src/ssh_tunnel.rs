@@ -0,0 +1,137 @@
+//! Forwards the socket of a remote docker daemon through SSH.
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures::future::select_all;
+use once_cell::sync::Lazy;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use tokio::process::{Child, Command};
+use tokio::time::{self, Duration};
+use tracing::{debug, info};
+
+/// Settings for connecting to the remote host.
+pub struct Destination {
+    pub host: String,
+    pub port: Option<u16>,
+    pub user: Option<String>,
+    pub key: Option<PathBuf>,
+    pub remote_socket: String,
+}
+
+/// Maximum amount of time to wait for the tunnel to be established.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Static tunnel processes, until they're taken over by the watching
+/// task.
+static TUNNELS: Lazy<Mutex<Vec<Child>>> = Lazy::new(Default::default);
+
+/// Parse a docker host URL of the form ssh://[user@]host[:port].
+pub fn parse_url(url: &str) -> Result<(Option<String>, String, Option<u16>)> {
+    let authority = url
+        .strip_prefix("ssh://")
+        .with_context(|| format!("docker host {:?} is not an SSH URL", url))?;
+    let authority = authority.trim_end_matches('/');
+    let (user, address) = match authority.rsplit_once('@') {
+        Some((user, address)) => (Some(user.to_string()), address),
+        None => (None, authority),
+    };
+    let (host, port) = match address.rsplit_once(':') {
+        Some((host, port)) => (
+            host,
+            Some(
+                port.parse()
+                    .with_context(|| format!("invalid SSH port in {:?}", url))?,
+            ),
+        ),
+        None => (address, None),
+    };
+    if host.is_empty() {
+        bail!("docker host {:?} doesn't specify a host", url);
+    }
+    Ok((user, host.to_string(), port))
+}
+
+/// Open a tunnel forwarding a local socket to the remote docker
+/// daemon's socket, and wait until it's usable. The tunnel is kept
+/// open by an ssh child process.
+pub async fn open(destination: Destination, local_socket: &Path) -> Result<()> {
+    // a socket left behind by a previous run would prevent binding
+    let _ = std::fs::remove_file(local_socket);
+    let mut command = Command::new("ssh");
+    command
+        .arg("-N")
+        .args(["-o", "BatchMode=yes"])
+        .args(["-o", "ExitOnForwardFailure=yes"])
+        .args(["-o", "StreamLocalBindUnlink=yes"])
+        .args(["-o", "ServerAliveInterval=30"])
+        .arg("-L")
+        .arg(format!(
+            "{}:{}",
+            local_socket.display(),
+            destination.remote_socket
+        ));
+    if let Some(port) = destination.port {
+        command.arg("-p").arg(port.to_string());
+    }
+    if let Some(user) = destination.user {
+        command.arg("-l").arg(user);
+    }
+    if let Some(key) = destination.key {
+        command
+            .args(["-o", "IdentitiesOnly=yes"])
+            .arg("-i")
+            .arg(key);
+    }
+    command
+        .arg(&destination.host)
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+    debug!("Opening SSH tunnel: {:?}", command);
+    let mut child = command
+        .spawn()
+        .context("while starting ssh; is the OpenSSH client installed?")?;
+    let deadline = time::Instant::now() + STARTUP_TIMEOUT;
+    while !local_socket.exists() {
+        if let Some(status) = child.try_wait()? {
+            bail!(
+                "ssh exited with {} before the tunnel was established",
+                status
+            );
+        }
+        if time::Instant::now() >= deadline {
+            bail!("timed out while waiting for the SSH tunnel to be established");
+        }
+        time::sleep(Duration::from_millis(100)).await;
+    }
+    info!(
+        "Forwarding docker socket {:?} of host {:?} through SSH",
+        destination.remote_socket, destination.host
+    );
+    TUNNELS
+        .lock()
+        .map_err(|_| anyhow!("SSH tunnels registry is poisoned"))?
+        .push(child);
+    Ok(())
+}
+
+/// Whether any tunnel has been opened.
+pub fn is_open() -> bool {
+    TUNNELS.lock().is_ok_and(|tunnels| !tunnels.is_empty())
+}
+
+/// Wait for any tunnel process to exit, which is always an error. The
+/// processes are owned by this task, so that they're killed when the
+/// task is dropped on shutdown.
+pub async fn watch() -> Result<()> {
+    let mut children = match TUNNELS.lock() {
+        Ok(mut tunnels) => std::mem::take(&mut *tunnels),
+        Err(_) => bail!("SSH tunnels registry is poisoned"),
+    };
+    if children.is_empty() {
+        return Ok(());
+    }
+    let (status, _, _) = select_all(children.iter_mut().map(|child| Box::pin(child.wait()))).await;
+    let status = status.context("while waiting for an SSH tunnel")?;
+    bail!("SSH tunnel closed unexpectedly; ssh exited with {}", status)
+}
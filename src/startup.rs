@@ -0,0 +1,95 @@
+//! Adopts the jobs left by a previous instance of the dispatcher, once
+//! on startup.
+//!
+//! Most of the dispatcher's state is read from docker on every upkeep
+//! cycle (e.g. the queue of pending jobs is ordered by creation time),
+//! but some of it is kept in memory, and is rebuilt here before the
+//! API and the background tasks start.
+
+use crate::cpusets;
+use crate::docker;
+use crate::job_store::{self, State};
+use crate::scheduler;
+use anyhow::{Context, Result};
+use bollard::models::ContainerSummary;
+use chrono::DateTime;
+use std::time::Instant;
+use tracing::info;
+
+/// Build the record of a job left by a previous instance, from its
+/// labels.
+fn record_of(job: &ContainerSummary, namespace: &str, state: State) -> job_store::Record {
+    let label = |key| docker::label(job, key).map(String::from);
+    job_store::Record {
+        namespace: namespace.to_string(),
+        path: label(docker::PATH_LABEL_KEY),
+        submitter: label(docker::SUBMITTER_LABEL_KEY),
+        api_key: label(docker::API_KEY_LABEL_KEY),
+        client_cert: label(docker::CLIENT_CERT_LABEL_KEY),
+        request_id: label(docker::REQUEST_ID_LABEL_KEY),
+        created: job.created,
+        state,
+        container_id: job.id.clone(),
+        image: job.image.clone(),
+        labels: docker::client_labels(job),
+        ..Default::default()
+    }
+}
+
+/// Scan the namespace for existing jobs, locate them in their docker
+/// hosts, recover the CPU sets of the active ones, record them in the
+/// job store if missing, and log a summary.
+pub async fn reconcile(namespace: &str) -> Result<()> {
+    let listed_at = Instant::now();
+    let (pending, active, exited) = tokio::try_join!(
+        docker::get_pending(namespace),
+        docker::get_active(namespace),
+        docker::get_exited(namespace)
+    )
+    .context("while listing existing jobs")?;
+    let located = docker::adopt(pending.iter().chain(&active).chain(&exited));
+    if cpusets::is_enabled() {
+        scheduler::track_cpusets(&active, listed_at).await?;
+    }
+    let recorded = [
+        (&pending, State::Created),
+        (&active, State::Running),
+        (&exited, State::Exited),
+    ]
+    .into_iter()
+    .flat_map(|(jobs, state)| jobs.iter().map(move |job| (job, state)))
+    .filter(|(job, state)| {
+        job.names
+            .as_ref()
+            .and_then(|names| names.first())
+            .is_some_and(|name| {
+                job_store::adopt(
+                    name.trim_start_matches('/'),
+                    &record_of(job, namespace, *state),
+                )
+            })
+    })
+    .count();
+    info!(
+        "Found {} pending, {} active and {} exited jobs in namespace {:?}",
+        pending.len(),
+        active.len(),
+        exited.len(),
+        namespace
+    );
+    // pending jobs are listed from oldest to newest
+    if let Some(created) = pending
+        .first()
+        .and_then(|job| job.created)
+        .and_then(|created| DateTime::from_timestamp(created, 0))
+    {
+        info!("The oldest pending job was created at {}", created);
+    }
+    if located > 0 || recorded > 0 {
+        info!(
+            "Located {} jobs in their docker hosts, and recorded {} in the job store",
+            located, recorded
+        );
+    }
+    Ok(())
+}
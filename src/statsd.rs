@@ -0,0 +1,95 @@
+//! Emits job lifecycle and upkeep metrics to a StatsD server, in
+//! addition to the OpenMetrics registry.
+//!
+//! Metrics are sent over UDP as they're recorded, without waiting for
+//! the server, so datagrams that can't be sent right away are dropped.
+
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use std::net::{ToSocketAddrs, UdpSocket};
+use tracing::debug;
+
+/// Static StatsD client.
+static CLIENT: OnceCell<Client> = OnceCell::new();
+
+/// A dialect of the StatsD protocol.
+#[derive(Clone, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Flavor {
+    Statsd,
+    Dogstatsd,
+}
+
+/// A socket connected to the StatsD server.
+struct Client {
+    socket: UdpSocket,
+    prefix: String,
+    flavor: Flavor,
+}
+
+/// Connect to the StatsD server at the given address (host:port).
+pub fn init(address: &str, prefix: &str, flavor: Flavor) -> Result<()> {
+    let target = address
+        .to_socket_addrs()
+        .with_context(|| format!("while resolving the StatsD address {:?}", address))?
+        .next()
+        .ok_or_else(|| anyhow!("StatsD address {:?} didn't resolve", address))?;
+    let local = if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let socket = UdpSocket::bind(local).context("while binding the StatsD socket")?;
+    socket
+        .connect(target)
+        .context("while connecting the StatsD socket")?;
+    socket
+        .set_nonblocking(true)
+        .context("while configuring the StatsD socket")?;
+    let _ = CLIENT.set(Client {
+        socket,
+        prefix: prefix.to_string(),
+        flavor,
+    });
+    Ok(())
+}
+
+/// Send a metric of the given StatsD type. Tags are only sent to
+/// DogStatsD servers, and empty ones are skipped.
+fn send(name: &str, value: &str, kind: &str, tags: &[(&str, &str)]) {
+    let Some(client) = CLIENT.get() else {
+        return;
+    };
+    let mut line = format!("{}.{}:{}|{}", client.prefix, name, value, kind);
+    if matches!(client.flavor, Flavor::Dogstatsd) {
+        let tags: Vec<String> = tags
+            .iter()
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(key, value)| format!("{}:{}", key, value))
+            .collect();
+        if !tags.is_empty() {
+            line.push_str("|#");
+            line.push_str(&tags.join(","));
+        }
+    }
+    if let Err(e) = client.socket.send(line.as_bytes()) {
+        debug!("Couldn't send StatsD metric {:?}: {:?}", line, e);
+    }
+}
+
+/// Increment a counter.
+pub fn count(name: &str, value: u64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "c", tags);
+}
+
+/// Set a gauge.
+pub fn gauge(name: &str, value: f64, tags: &[(&str, &str)]) {
+    send(name, &value.to_string(), "g", tags);
+}
+
+/// Record a duration, in milliseconds.
+pub fn timing(name: &str, milliseconds: f64, tags: &[(&str, &str)]) {
+    send(name, &milliseconds.to_string(), "ms", tags);
+}
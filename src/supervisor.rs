@@ -0,0 +1,48 @@
+//! Restarts failed background tasks, so that a transient failure of
+//! one of them (e.g. a lost docker events stream) doesn't bring down
+//! the whole dispatcher.
+
+use crate::error_report;
+use crate::metrics_service;
+use crate::retry;
+use crate::shutdown::{self, Phase};
+
+use anyhow::Result;
+use std::future::Future;
+use tokio::time::{Duration, Instant};
+use tracing::error;
+
+/// Time a task has to run for before failing for its restarts to
+/// start over without delay.
+const HEALTHY_RUN: Duration = Duration::from_secs(300);
+
+/// Run the task built by the given closure, and restart it whenever
+/// it fails, waiting an exponentially growing delay between failures
+/// in quick succession. Returns once the task finishes successfully,
+/// or once shutting down.
+pub async fn supervise<F, T>(name: &'static str, mut task: F) -> Result<()>
+where
+    F: FnMut() -> T,
+    T: Future<Output = Result<()>>,
+{
+    let mut backoff = retry::Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    loop {
+        let started = Instant::now();
+        let Err(e) = task().await else {
+            return Ok(());
+        };
+        if shutdown::is_stopping() {
+            return Err(e);
+        }
+        if started.elapsed() >= HEALTHY_RUN {
+            backoff.reset();
+        }
+        error!("The {} failed; restarting it: {:?}", name, e);
+        error_report::capture("task", format!("{:?}", e), &[("task", name)]);
+        metrics_service::record_restart(name);
+        tokio::select! {
+            _ = backoff.wait() => (),
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        }
+    }
+}
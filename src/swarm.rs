@@ -0,0 +1,361 @@
+//! Maps jobs to docker swarm services, delegating their scheduling to
+//! the swarm.
+
+use crate::backend::{self, Backend, Creation};
+use crate::registry_auth;
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bollard::{
+    container::Config,
+    errors::Error,
+    models::{
+        ContainerConfig, ContainerInspectResponse, ContainerState, ContainerStateStatusEnum,
+        ContainerSummary, Limit, Service, ServiceSpec, ServiceSpecMode,
+        ServiceSpecModeReplicatedJob, Task, TaskSpec, TaskSpecContainerSpec, TaskSpecResources,
+        TaskSpecRestartPolicy, TaskSpecRestartPolicyConditionEnum, TaskState,
+    },
+    service::ListServicesOptions,
+    Docker,
+};
+use std::collections::HashMap;
+
+/// Exit code given to jobs whose task failed without running its
+/// container, the same docker run exits with when that happens.
+const NOT_RUN_EXIT_CODE: i64 = 125;
+
+/// A backend dispatching jobs to a docker swarm through one of its
+/// managers.
+pub struct Swarm {
+    docker: Docker,
+    /// Client for the tasks API, which the docker client lacks.
+    tasks: reqwest::Client,
+    /// Base URL of the manager's API, including its version.
+    base_url: String,
+}
+
+impl Swarm {
+    /// Check that the daemon is a swarm manager, and use it to dispatch
+    /// jobs. Tasks are fetched with the given HTTP client, from the
+    /// manager's API at the given base URL.
+    pub async fn connect(docker: Docker, tasks: reqwest::Client, base_url: String) -> Result<Self> {
+        let info = docker
+            .info()
+            .await
+            .context("while fetching docker system information")?;
+        if !info
+            .swarm
+            .and_then(|swarm| swarm.control_available)
+            .unwrap_or(false)
+        {
+            bail!("the docker daemon is not a swarm manager");
+        }
+        Ok(Self {
+            docker,
+            tasks,
+            base_url,
+        })
+    }
+
+    /// Get the most recent task of a job's service, if any was created.
+    async fn latest_task(&self, job: &str) -> Result<Option<Task>> {
+        let filters = serde_json::json!({ "service": [job] }).to_string();
+        let tasks: Vec<Task> = self
+            .tasks
+            .get(format!("{}/tasks", self.base_url))
+            .query(&[("filters", filters)])
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("while fetching the tasks of job {:?}", job))?;
+        Ok(tasks.into_iter().max_by_key(|task| task.created_at))
+    }
+}
+
+/// Strip the frame headers docker interleaves in the logs of services
+/// without a TTY, each one holding the stream and size of the frame
+/// that follows. Output that isn't framed is returned as is.
+fn demultiplex(output: &[u8]) -> Vec<u8> {
+    let mut frames = Vec::with_capacity(output.len());
+    let mut rest = output;
+    while !rest.is_empty() {
+        match rest {
+            [0..=2, 0, 0, 0, a, b, c, d, frame @ ..] => {
+                let size = u32::from_be_bytes([*a, *b, *c, *d]) as usize;
+                if frame.len() < size {
+                    return output.to_vec();
+                }
+                frames.extend_from_slice(&frame[..size]);
+                rest = &frame[size..];
+            }
+            _ => return output.to_vec(),
+        }
+    }
+    frames
+}
+
+/// Build the spec of a one-off swarm job out of a container
+/// configuration. Only the fields with a swarm counterpart are
+/// mapped.
+fn service_spec(name: String, config: Config<String>) -> ServiceSpec {
+    let host_config = config.host_config.unwrap_or_default();
+    ServiceSpec {
+        name: Some(name),
+        labels: config.labels.clone(),
+        task_template: Some(TaskSpec {
+            container_spec: Some(TaskSpecContainerSpec {
+                image: config.image,
+                labels: config.labels,
+                command: config.entrypoint,
+                args: config.cmd,
+                hostname: config.hostname,
+                env: config.env,
+                dir: config.working_dir,
+                user: config.user,
+                ..Default::default()
+            }),
+            resources: Some(TaskSpecResources {
+                limits: Some(Limit {
+                    nano_cpus: host_config.nano_cpus,
+                    memory_bytes: host_config.memory,
+                    pids: host_config.pids_limit,
+                }),
+                reservations: None,
+            }),
+            restart_policy: Some(TaskSpecRestartPolicy {
+                condition: Some(TaskSpecRestartPolicyConditionEnum::NONE),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }),
+        mode: Some(ServiceSpecMode {
+            replicated_job: Some(ServiceSpecModeReplicatedJob {
+                max_concurrent: Some(1),
+                total_completions: Some(1),
+            }),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+/// Get the equivalent container state of a service, out of its task
+/// counts. Jobs whose task failed are reported as created, since the
+/// counts don't include failed tasks; inspecting them tells better.
+fn state(service: &Service) -> ContainerStateStatusEnum {
+    let status = service.service_status.as_ref();
+    if status.and_then(|s| s.running_tasks).unwrap_or(0) > 0 {
+        ContainerStateStatusEnum::RUNNING
+    } else if status.and_then(|s| s.completed_tasks).unwrap_or(0) > 0 {
+        ContainerStateStatusEnum::EXITED
+    } else {
+        ContainerStateStatusEnum::CREATED
+    }
+}
+
+/// Get the name of a service.
+fn name(service: &Service) -> Option<&str> {
+    service.spec.as_ref()?.name.as_deref()
+}
+
+/// Describe a service as a listed container.
+fn summary(service: Service) -> ContainerSummary {
+    let state = state(&service).to_string();
+    let spec = service.spec.as_ref();
+    ContainerSummary {
+        id: service.id.clone(),
+        names: name(&service).map(|name| vec![format!("/{}", name)]),
+        image: spec
+            .and_then(|spec| spec.task_template.as_ref())
+            .and_then(|task| task.container_spec.as_ref())
+            .and_then(|container| container.image.clone()),
+        created: service.created_at.map(|created| created.timestamp()),
+        labels: spec.and_then(|spec| spec.labels.clone()),
+        status: Some(state.clone()),
+        state: Some(state),
+        ..Default::default()
+    }
+}
+
+/// Get the equivalent container state of a task, if it's running or
+/// finished.
+fn task_state(task: &Task) -> Option<ContainerStateStatusEnum> {
+    match task.status.as_ref()?.state? {
+        TaskState::RUNNING => Some(ContainerStateStatusEnum::RUNNING),
+        TaskState::COMPLETE
+        | TaskState::FAILED
+        | TaskState::REJECTED
+        | TaskState::SHUTDOWN
+        | TaskState::ORPHANED => Some(ContainerStateStatusEnum::EXITED),
+        _ => None,
+    }
+}
+
+/// Get the exit code of a finished task, as reported by its container.
+fn exit_code(task: &Task) -> Option<i64> {
+    let status = task.status.as_ref()?;
+    status
+        .container_status
+        .as_ref()
+        .and_then(|container| container.exit_code)
+        .or(match status.state? {
+            TaskState::COMPLETE => Some(0),
+            _ => Some(NOT_RUN_EXIT_CODE),
+        })
+}
+
+/// Describe a service as an inspected container, along with its most
+/// recent task, which holds the job's actual state and exit code.
+fn inspection(service: Service, task: Option<Task>) -> ContainerInspectResponse {
+    let state = task
+        .as_ref()
+        .and_then(task_state)
+        .unwrap_or_else(|| state(&service));
+    let exited = state == ContainerStateStatusEnum::EXITED;
+    let spec = service.spec.as_ref();
+    let container_spec = spec
+        .and_then(|spec| spec.task_template.as_ref())
+        .and_then(|task| task.container_spec.as_ref());
+    ContainerInspectResponse {
+        id: service.id.clone(),
+        name: name(&service).map(|name| format!("/{}", name)),
+        created: service.created_at.map(|created| created.to_rfc3339()),
+        state: Some(ContainerState {
+            running: Some(state == ContainerStateStatusEnum::RUNNING),
+            exit_code: task.as_ref().filter(|_| exited).and_then(exit_code),
+            started_at: service
+                .job_status
+                .as_ref()
+                .and_then(|job| job.last_execution)
+                .map(|started| started.to_rfc3339()),
+            finished_at: exited
+                .then(|| {
+                    task.as_ref()
+                        .and_then(|task| task.status.as_ref())
+                        .and_then(|status| status.timestamp)
+                        .or(service.updated_at)
+                })
+                .flatten()
+                .map(|finished| finished.to_rfc3339()),
+            status: Some(state),
+            ..Default::default()
+        }),
+        config: Some(ContainerConfig {
+            image: container_spec.and_then(|container| container.image.clone()),
+            labels: spec.and_then(|spec| spec.labels.clone()),
+            env: container_spec.and_then(|container| container.env.clone()),
+            cmd: container_spec.and_then(|container| container.args.clone()),
+            entrypoint: container_spec.and_then(|container| container.command.clone()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    }
+}
+
+#[async_trait]
+impl Backend for Swarm {
+    async fn ping(&self) -> Result<()> {
+        self.docker.ping().await?;
+        Ok(())
+    }
+
+    async fn create(&self, creation: Creation) -> Result<Option<String>> {
+        let Creation { name, config, .. } = creation;
+        // swarm nodes pull images on their own
+        let credentials = config
+            .image
+            .as_deref()
+            .and_then(registry_auth::credentials_for);
+        match self
+            .docker
+            .create_service(service_spec(name, config), credentials)
+            .await
+        {
+            Ok(response) => Ok(Some(response.id.unwrap_or_default())),
+            Err(Error::DockerResponseServerError {
+                status_code: 409, ..
+            }) => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    async fn start(&self, _name: &str) -> Result<()> {
+        // the swarm starts jobs on its own
+        Ok(())
+    }
+
+    async fn list(&self, filters: &HashMap<&str, Vec<&str>>) -> Result<Vec<ContainerSummary>> {
+        let mut service_filters = HashMap::new();
+        if let Some(labels) = filters.get("label") {
+            service_filters.insert("label", labels.clone());
+        }
+        let names = backend::filter_names(filters);
+        Ok(self
+            .docker
+            .list_services(Some(ListServicesOptions {
+                filters: service_filters,
+                status: true,
+            }))
+            .await?
+            .into_iter()
+            .filter(|service| {
+                names
+                    .as_ref()
+                    .is_none_or(|names| name(service).is_some_and(|name| names.contains(&name)))
+            })
+            .map(summary)
+            .filter(|container| backend::status_matches(filters, container))
+            .collect())
+    }
+
+    /// Services are listed rather than inspected, since only listings
+    /// report task counts. Their latest task is fetched as well, for
+    /// its exit code.
+    async fn inspect(&self, job: &str) -> Result<ContainerInspectResponse> {
+        let mut filters = HashMap::new();
+        filters.insert("name", vec![job]);
+        let service = self
+            .docker
+            .list_services(Some(ListServicesOptions {
+                filters,
+                status: true,
+            }))
+            .await?
+            .into_iter()
+            .find(|service| name(service) == Some(job))
+            .with_context(|| format!("job {:?} doesn't exist", job))?;
+        let task = self.latest_task(job).await?;
+        Ok(inspection(service, task))
+    }
+
+    /// Services' logs are fetched through the HTTP client as well,
+    /// since the docker client doesn't expose them.
+    async fn logs(&self, name: &str) -> Result<Vec<u8>> {
+        let response = self
+            .tasks
+            .get(format!("{}/services/{}/logs", self.base_url, name))
+            .query(&[("stdout", "1"), ("stderr", "1")])
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            bail!("job {:?} doesn't exist", name);
+        }
+        let output = response
+            .error_for_status()?
+            .bytes()
+            .await
+            .with_context(|| format!("while fetching the logs of job {:?}", name))?;
+        Ok(demultiplex(&output))
+    }
+
+    async fn remove(&self, name: &str, _volumes: bool) -> Result<()> {
+        match self.docker.delete_service(name).await {
+            Ok(_)
+            | Err(Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => Ok(()),
+            Err(e) => Err(e)?,
+        }
+    }
+}
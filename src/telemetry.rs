@@ -0,0 +1,59 @@
+//! Exports traces to an OpenTelemetry collector over OTLP, and
+//! propagates the trace context of incoming requests.
+
+use actix_web::http::header::HeaderMap;
+use anyhow::{Context, Result};
+use opentelemetry::{global, propagation::Extractor, trace::TracerProvider as _, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{Layer, Registry};
+
+/// Reads the trace context from request headers.
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Build a tracing layer exporting spans to the OTLP collector at the
+/// given endpoint.
+pub fn layer(endpoint: &str) -> Result<Box<dyn Layer<Registry> + Send + Sync>> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let provider = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::Config::default().with_resource(Resource::new([
+            KeyValue::new("service.name", env!("CARGO_PKG_NAME")),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+        ])))
+        .install_batch(runtime::Tokio)
+        .context("while setting up the OTLP trace exporter")?;
+    global::set_tracer_provider(provider.clone());
+    let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+    Ok(Box::new(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Make a span continue the trace given in the traceparent header of
+/// a request, if any.
+pub fn set_parent(span: &Span, headers: &HeaderMap) {
+    let context =
+        global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    span.set_parent(context);
+}
+
+/// Flush pending spans before exiting.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
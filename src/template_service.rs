@@ -0,0 +1,167 @@
+//! Implements the registration of job templates, and the creation of
+//! jobs out of them.
+
+use crate::api_error::APIError;
+use crate::audit;
+use crate::auth;
+use crate::docker_service::{
+    check_namespace, client_labels, duplicate_handling, holds, submit, Intake, JobSummary,
+    Submission,
+};
+use crate::openapi;
+use crate::reload::Reloader;
+use crate::templates::{self, Templates};
+
+use actix_web::{post, put, web, HttpRequest, HttpResponse, Responder, Result};
+use serde_json::Value;
+use tracing::info;
+
+/// Register a template
+///
+/// Register a job template under the given name, replacing the one
+/// registered with the same name, if any. Templates registered this
+/// way are kept in memory only
+#[utoipa::path(
+    put,
+    path = "/templates/{name}",
+    tag = "admin",
+    operation_id = "putTemplate",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("name" = String, Path, description = "Name of the template, made of alphanumeric characters, dots, dashes and underscores"),
+        openapi::Namespace
+    ),
+    request_body(content = Value, example = json!({"args": ["Hello", "world!"]})),
+    responses(
+        (status = 201, description = "template was registered"),
+        (status = 204, description = "template replaced the one with the same name"),
+        (status = 400, description = "the name is invalid, or too many templates are registered", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "the client isn't allowed to administer the dispatcher", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+    )
+)]
+#[put("/templates/{name}")]
+async fn put_template(
+    request: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<Value>,
+    templates: web::Data<Templates>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let replaced = templates
+        .put(&name, body.into_inner())
+        .map_err(|e| APIError::bad_request(format!("Couldn't register the template: {}", e)))?;
+    info!("Registered template {:?}", name.as_str());
+    audit::record(audit::Entry {
+        action: "template_registered",
+        namespace: namespace.to_string(),
+        detail: Some(name.to_string()),
+        actor: audit::Actor::of(&request),
+        ..Default::default()
+    });
+    Ok(if replaced {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::Created().finish()
+    })
+}
+
+/// Create a job from a template
+///
+/// Create a job as a docker container, by merging the request body
+/// into the named template, and converting the result to a job
+/// manifest; the filter is given /job/from-template/{name} as the path
+#[utoipa::path(
+    post,
+    path = "/job/from-template/{name}",
+    tag = "job",
+    operation_id = "createJobFromTemplate",
+    security((), ("bearerAuth" = []), ("apiKey" = [])),
+    params(
+        ("name" = String, Path, description = "Name of the template to create the job from"),
+        crate::docker_service::CreateQuery,
+        openapi::Hold,
+        openapi::Namespace
+    ),
+    request_body(content = Value, example = json!({"args": ["Hello", "template!"]})),
+    responses(
+        (status = 200, description = "job with the generated name already exists", body = JobSummary),
+        (status = 201, description = "job was created", body = JobSummary),
+        (status = 400, description = "job generation failed because of an invalid job manifest", body = APIError),
+        (status = 401, description = "an API key or token is required, and the request lacks a valid one", body = APIError),
+        (status = 403, description = "job generation failed because the job manifest violates the security policy, or the client isn't allowed to create jobs through this path", body = APIError),
+        (status = 409, description = "a job with the same name exists, and duplicates are refused or the existing job hasn't finished and can't be replaced", body = APIError),
+        (status = 404, description = "template doesn't exist", body = APIError),
+        (
+            status = 421,
+            description = "the request names, in the X-Dispatcher-Namespace header, a namespace other than the one served by this instance, or selects, in the X-Namespace header, a namespace served by no known instance",
+            body = APIError,
+            headers(("Location" = String, description = "the same request at the instance serving the namespace, if known"))
+        ),
+        (
+            status = 429,
+            description = "the client exceeded its rate of job creation requests, or the namespace holds as many jobs as allowed",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying"))
+        ),
+        (status = 502, description = "job creation failed while trying to communicate with the docker daemon, or the daemon couldn't create the job (e.g. its image couldn't be pulled or built)", body = APIError),
+        (
+            status = 503,
+            description = "jobs aren't being accepted, because the dispatcher is shutting down, the docker daemon is unreachable or the dispatch queue is full",
+            body = APIError,
+            headers(("Retry-After" = i64, description = "seconds to wait before retrying, when the docker daemon is unreachable"))
+        )
+    )
+)]
+#[post("/job/from-template/{name}")]
+#[tracing::instrument(skip_all)]
+async fn create_job_from_template(
+    request: HttpRequest,
+    name: web::Path<String>,
+    body: web::Json<Value>,
+    templates: web::Data<Templates>,
+    reloader: web::Data<Reloader>,
+    can_start: web::Data<bool>,
+    namespace: web::Data<String>,
+) -> Result<impl Responder> {
+    let path = format!("/job/from-template/{}", name);
+    check_namespace(&request, &namespace)?;
+    if let Some(scope) = auth::scope(&request).filter(|scope| !scope.covers(Some(&path))) {
+        Err(APIError::forbidden(format!(
+            "The API key is restricted to paths under {:?}",
+            scope.path
+        )))?;
+    }
+    let template = templates.get(&name).ok_or_else(|| {
+        APIError::not_found(format!("Template {:?} doesn't exist", name.as_str()))
+    })?;
+    let intake = Intake {
+        filter: reloader.filter.clone(),
+        policy: reloader.policy.clone(),
+        can_start: **can_start,
+        namespace: namespace.to_string(),
+    };
+    let submission = Submission {
+        input: templates::merge(template, body.into_inner()),
+        path,
+        claims: auth::token(&request).map_or(Value::Null, |token| token.claims),
+        message: Value::Null,
+        reply_subject: None,
+        actor: audit::Actor::of(&request),
+        hold: holds(&request)?,
+        labels: client_labels(&request)?,
+        on_duplicate: duplicate_handling(&request)?,
+    };
+    let (created, summary): (bool, JobSummary) = submit(&intake, submission).await?;
+    Ok(if created {
+        HttpResponse::Created().json(summary)
+    } else {
+        HttpResponse::Ok().json(summary)
+    })
+}
@@ -0,0 +1,90 @@
+//! Keeps named job templates, so that producers sending the same job
+//! with only a few varying values may send just those. A template is
+//! a JSON value standing for a request body; the parameters of each
+//! request are merged into it before it's given to the filter.
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// Maximum amount of templates kept.
+const MAX_TEMPLATES: usize = 1000;
+
+/// Registered templates, by name.
+#[derive(Default)]
+pub struct Templates(RwLock<HashMap<String, Value>>);
+
+/// Whether a name may be used for a template.
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+}
+
+impl Templates {
+    /// Read the initial templates from a JSON file holding an object
+    /// of them by name, if given.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("while reading the templates {:?}", path))?;
+        let templates: HashMap<String, Value> = serde_json::from_str(&contents)
+            .with_context(|| format!("while parsing the templates {:?}", path))?;
+        if let Some(name) = templates.keys().find(|name| !is_valid_name(name)) {
+            bail!("template name {:?} is not alphanumeric", name);
+        }
+        if templates.len() > MAX_TEMPLATES {
+            bail!("at most {} templates may be registered", MAX_TEMPLATES);
+        }
+        Ok(Self(RwLock::new(templates)))
+    }
+
+    /// Amount of registered templates.
+    pub fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+
+    /// Get a template by name.
+    pub fn get(&self, name: &str) -> Option<Value> {
+        self.0.read().unwrap().get(name).cloned()
+    }
+
+    /// Register a template, replacing the one with the same name, if
+    /// any. Returns whether one was replaced.
+    pub fn put(&self, name: &str, template: Value) -> Result<bool> {
+        if !is_valid_name(name) {
+            bail!("template names must be alphanumeric");
+        }
+        let mut templates = self.0.write().unwrap();
+        if !templates.contains_key(name) && templates.len() >= MAX_TEMPLATES {
+            bail!("at most {} templates may be registered", MAX_TEMPLATES);
+        }
+        Ok(templates.insert(name.to_string(), template).is_some())
+    }
+}
+
+/// Merge parameters into a template, recursively for objects, the
+/// way jq's `*` operator does; other parameters replace the value
+/// they're merged into.
+pub fn merge(template: Value, parameters: Value) -> Value {
+    match (template, parameters) {
+        (Value::Object(mut template), Value::Object(parameters)) => {
+            for (key, parameter) in parameters {
+                let merged = match template.remove(&key) {
+                    Some(value) => merge(value, parameter),
+                    None => parameter,
+                };
+                template.insert(key, merged);
+            }
+            Value::Object(template)
+        }
+        (template, Value::Null) => template,
+        (_, parameters) => parameters,
+    }
+}
@@ -0,0 +1,232 @@
+//! Accounts for the resources consumed by jobs, so that their owners
+//! may be billed for them.
+//!
+//! Running jobs are sampled periodically, since docker only reports
+//! the usage of running containers. The last sample of a job stands
+//! for its total once it exits, and is kept in memory for the most
+//! recent jobs, and in the job store if it's enabled. Sidecars aren't
+//! accounted for.
+
+use crate::docker;
+use crate::job_store;
+use crate::metrics_service;
+use crate::shutdown::{self, Phase};
+use anyhow::{Context, Result};
+use bollard::container::Stats;
+use chrono::Utc;
+use futures::stream::{self, StreamExt};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use tokio::time::{self, Duration};
+use tracing::debug;
+use utoipa::ToSchema;
+
+/// Maximum amount of jobs sampled at once.
+const CONCURRENCY: usize = 8;
+
+/// Maximum amount of totals of exited jobs kept in memory.
+const MAX_RECORDS: usize = 1000;
+
+/// Resources consumed by a job, cumulative since it started.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, ToSchema)]
+pub struct Usage {
+    #[schema(example = 42.5)]
+    pub cpu_seconds: f64,
+    /// Highest memory usage observed
+    #[schema(example = 268435456)]
+    pub peak_memory_bytes: u64,
+    #[schema(example = 1048576)]
+    pub network_received_bytes: u64,
+    #[schema(example = 65536)]
+    pub network_transmitted_bytes: u64,
+    #[schema(example = 4194304)]
+    pub block_read_bytes: u64,
+    #[schema(example = 2097152)]
+    pub block_written_bytes: u64,
+    /// UNIX time of the sample the usage was read from
+    #[schema(example = 1702599010)]
+    pub sampled_at: i64,
+}
+
+impl Usage {
+    /// Read the usage of a job from a sample of its stats, keeping the
+    /// highest memory usage observed so far.
+    fn of(stats: &Stats, previous: Option<&Usage>) -> Self {
+        let (received, transmitted) = stats
+            .networks
+            .iter()
+            .flat_map(|networks| networks.values())
+            .fold((0, 0), |(rx, tx), network| {
+                (rx + network.rx_bytes, tx + network.tx_bytes)
+            });
+        let block = |op: &str| {
+            stats
+                .blkio_stats
+                .io_service_bytes_recursive
+                .iter()
+                .flatten()
+                .filter(|entry| entry.op.eq_ignore_ascii_case(op))
+                .map(|entry| entry.value)
+                .sum()
+        };
+        let memory = stats
+            .memory_stats
+            .max_usage
+            .or(stats.memory_stats.usage)
+            .unwrap_or_default();
+        Self {
+            cpu_seconds: stats.cpu_stats.cpu_usage.total_usage as f64 / 1e9,
+            peak_memory_bytes: previous
+                .map_or(memory, |previous| previous.peak_memory_bytes.max(memory)),
+            network_received_bytes: received,
+            network_transmitted_bytes: transmitted,
+            block_read_bytes: block("read"),
+            block_written_bytes: block("write"),
+            sampled_at: Utc::now().timestamp(),
+        }
+    }
+
+    /// The usage accrued since a previous sample of the same job.
+    fn since(&self, previous: Option<&Usage>) -> Self {
+        let Some(previous) = previous else {
+            return self.clone();
+        };
+        Self {
+            cpu_seconds: (self.cpu_seconds - previous.cpu_seconds).max(0.0),
+            peak_memory_bytes: self.peak_memory_bytes,
+            network_received_bytes: self
+                .network_received_bytes
+                .saturating_sub(previous.network_received_bytes),
+            network_transmitted_bytes: self
+                .network_transmitted_bytes
+                .saturating_sub(previous.network_transmitted_bytes),
+            block_read_bytes: self
+                .block_read_bytes
+                .saturating_sub(previous.block_read_bytes),
+            block_written_bytes: self
+                .block_written_bytes
+                .saturating_sub(previous.block_written_bytes),
+            sampled_at: self.sampled_at,
+        }
+    }
+}
+
+/// The latest sample of a running job.
+struct Sample {
+    usage: Usage,
+    path: Option<String>,
+}
+
+/// Latest samples of running jobs, by name.
+static RUNNING: Lazy<Mutex<HashMap<String, Sample>>> = Lazy::new(Default::default);
+
+/// Totals of the most recently exited jobs, ordered from oldest to
+/// newest.
+static EXITED: Lazy<Mutex<VecDeque<(String, Usage)>>> = Lazy::new(Default::default);
+
+/// Get the usage of a job, as of its latest sample if it's running,
+/// or in total if it exited.
+pub fn get(name: &str) -> Option<Usage> {
+    if let Some(sample) = RUNNING.lock().unwrap().get(name) {
+        return Some(sample.usage.clone());
+    }
+    EXITED
+        .lock()
+        .unwrap()
+        .iter()
+        .rev()
+        .find(|(n, _)| n == name)
+        .map(|(_, usage)| usage.clone())
+        .or_else(|| job_store::get(name).and_then(|record| record.usage))
+}
+
+/// Record the total usage of a job that's no longer running.
+fn finish(name: String, sample: Sample, namespace: &str) {
+    debug!("Recording the resource usage of job {:?}", name);
+    metrics_service::record_peak_memory(
+        namespace,
+        sample.path.as_deref(),
+        sample.usage.peak_memory_bytes,
+    );
+    job_store::update(&name, |record| record.usage = Some(sample.usage.clone()));
+    let mut exited = EXITED.lock().unwrap();
+    exited.push_back((name, sample.usage));
+    if exited.len() > MAX_RECORDS {
+        exited.pop_front();
+    }
+}
+
+/// Sample the running jobs of a namespace once, and record the totals
+/// of the jobs that stopped running since the previous sampling.
+async fn sample(namespace: &str) -> Result<()> {
+    let active = docker::get_active(namespace)
+        .await
+        .context("while fetching active jobs")?;
+    let jobs: Vec<(String, Option<String>)> = active
+        .iter()
+        .filter_map(|container| {
+            let name = container.names.as_ref()?.first()?;
+            Some((
+                name.trim_start_matches('/').to_string(),
+                docker::label(container, docker::PATH_LABEL_KEY).map(String::from),
+            ))
+        })
+        .collect();
+    let samples: Vec<_> = stream::iter(jobs)
+        .map(|(name, path)| async move {
+            let stats = docker::stats(&name).await;
+            (name, path, stats)
+        })
+        .buffer_unordered(CONCURRENCY)
+        .collect()
+        .await;
+    let mut running = RUNNING.lock().unwrap();
+    let mut sampled = HashSet::new();
+    for (name, path, stats) in samples {
+        let stats = match stats {
+            Ok(Some(stats)) => stats,
+            // jobs that exit meanwhile are recorded as of their
+            // previous sample
+            Ok(None) => continue,
+            Err(e) => {
+                debug!(
+                    "Couldn't sample the resource usage of job {:?}: {:?}",
+                    name, e
+                );
+                sampled.insert(name);
+                continue;
+            }
+        };
+        let previous = running.get(&name).map(|sample| &sample.usage);
+        let usage = Usage::of(&stats, previous);
+        metrics_service::record_usage(namespace, path.as_deref(), &usage.since(previous));
+        sampled.insert(name.clone());
+        running.insert(name, Sample { usage, path });
+    }
+    let stopped: Vec<String> = running
+        .keys()
+        .filter(|name| !sampled.contains(*name))
+        .cloned()
+        .collect();
+    for name in stopped {
+        if let Some(sample) = running.remove(&name) {
+            finish(name, sample, namespace);
+        }
+    }
+    Ok(())
+}
+
+/// Sample the running jobs of a namespace at the given interval in
+/// seconds, until shutting down.
+pub async fn watch(namespace: String, interval: u16) -> Result<()> {
+    let mut ticker = time::interval(Duration::from_secs(interval.into()));
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => (),
+            _ = shutdown::reached(Phase::Stopping) => return Ok(()),
+        }
+        sample(&namespace).await?;
+    }
+}